@@ -34,6 +34,10 @@ use sled::Db;
 use crate::{
     admin::methods::execute_method,
     balancer::format::incoming_to_value,
+    database::{
+        backend::CacheBackend,
+        metrics::CacheMetrics,
+    },
     Rpc,
     Settings,
 };
@@ -67,6 +71,8 @@ macro_rules! get_response {
         $poverty_list_rwlock:expr,
         $config:expr,
         $cache:expr,
+        $backend:expr,
+        $cache_metrics:expr,
     ) => {{
         // Execute the request and store it into rx
         let mut rx = match execute_method(
@@ -75,6 +81,8 @@ macro_rules! get_response {
             $poverty_list_rwlock,
             Arc::clone(&$config),
             Arc::clone(&$cache),
+            Arc::clone(&$backend),
+            Arc::clone(&$cache_metrics),
         ).await {
             Ok(rx) => rx,
             Err(err) => json!({
@@ -85,7 +93,7 @@ macro_rules! get_response {
         };
 
         // Set the id to whatever it was
-        rx["id"] = $id.into();
+        rx["id"] = $id;
 
         let rx_str = rx.to_string();
 
@@ -99,17 +107,25 @@ async fn forward_body(
     rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
     cache: Arc<Db>,
+    backend: Arc<dyn CacheBackend>,
+    cache_metrics: Arc<CacheMetrics>,
     config: Arc<RwLock<Settings>>,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
-    // Get the id of the request and set it to 0 for caching
-    //
-    // We're doing this ID gymnastics because we're hashing the
-    // whole request and we don't want the ID as it's arbitrary
-    // and does not impact the request result.
-    let id = tx["id"].take().as_u64().unwrap_or(0);
+    // Take the id of the request so we can put it back verbatim
+    // into the response, regardless of if its a number, string or null.
+    let id = tx["id"].take();
 
     // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(tx, id, rpc_list_rwlock, poverty_list_rwlock, config, cache,);
+    let rax = get_response!(
+        tx,
+        id,
+        rpc_list_rwlock,
+        poverty_list_rwlock,
+        config,
+        cache,
+        backend,
+        cache_metrics,
+    );
 
     // Convert rx to bytes and but it in a Buf
     let body = hyper::body::Bytes::from(rax);
@@ -129,6 +145,8 @@ pub async fn accept_admin_request(
     rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     cache: Arc<Db>,
+    backend: Arc<dyn CacheBackend>,
+    cache_metrics: Arc<CacheMetrics>,
     config: Arc<RwLock<Settings>>,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
     let mut tx = incoming_to_value(tx).await.unwrap();
@@ -166,7 +184,16 @@ pub async fn accept_admin_request(
 
     // Send the request off to be processed
     let time = Instant::now();
-    let response = forward_body(tx, &rpc_list_rwlock, &poverty_list_rwlock, cache, config).await;
+    let response = forward_body(
+        tx,
+        &rpc_list_rwlock,
+        &poverty_list_rwlock,
+        cache,
+        backend,
+        cache_metrics,
+        config,
+    )
+    .await;
     let time = time.elapsed();
     println!("\x1b[35mInfo:\x1b[0m Request time: {:?}", time);
 
@@ -176,12 +203,15 @@ pub async fn accept_admin_request(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database::sled_backend::SledBackend;
     use jsonwebtoken::DecodingKey;
 
     // Helper function to create a test Settings config
     fn create_test_settings() -> Arc<RwLock<Settings>> {
-        let mut config = Settings::default();
-        config.do_clear = true;
+        let mut config = Settings {
+            do_clear: true,
+            ..Default::default()
+        };
         config.admin.key = DecodingKey::from_secret(b"some-key");
         Arc::new(RwLock::new(config))
     }
@@ -194,6 +224,10 @@ mod tests {
         Arc::new(db)
     }
 
+    fn create_test_backend(cache: &Arc<Db>) -> Arc<dyn CacheBackend> {
+        Arc::new(SledBackend::new(Arc::clone(cache)))
+    }
+
     #[tokio::test]
     async fn test_forward_body() {
         let settings = create_test_settings();
@@ -215,6 +249,8 @@ mod tests {
             &rpc_list,
             &poverty_list,
             cache.clone(),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
             settings,
         )
         .await;
@@ -224,4 +260,39 @@ mod tests {
 
         // Additional assertions can be added based on expected behavior
     }
+
+    #[tokio::test]
+    async fn test_forward_body_preserves_id() {
+        use http_body_util::BodyExt;
+
+        let settings = create_test_settings();
+        let cache = create_test_cache();
+        let rpc_list = Arc::new(RwLock::new(vec![]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        for id in [json!("abc-123"), Null, json!(18446744073709551615u64)] {
+            let tx = json!({
+                "id": id,
+                "jsonrpc": "2.0",
+                "method": "blutgang_ttl",
+                "params": [],
+            });
+
+            let result = forward_body(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                cache.clone(),
+                create_test_backend(&cache),
+                Arc::new(CacheMetrics::default()),
+                Arc::clone(&settings),
+            )
+            .await
+            .unwrap();
+
+            let body = result.into_body().collect().await.unwrap().to_bytes();
+            let rx: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(rx["id"], id);
+        }
+    }
 }
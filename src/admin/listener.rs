@@ -7,6 +7,10 @@ use sled::Db;
 
 use crate::{
     admin::accept::accept_admin_request,
+    database::{
+        backend::CacheBackend,
+        metrics::CacheMetrics,
+    },
     Rpc,
     Settings,
 };
@@ -24,6 +28,8 @@ macro_rules! accept_admin {
         $rpc_list_rwlock:expr,
         $poverty_list_rwlock:expr,
         $cache:expr,
+        $backend:expr,
+        $cache_metrics:expr,
         $config:expr,
     ) => {
         // Bind the incoming connection to our service
@@ -37,6 +43,8 @@ macro_rules! accept_admin {
                         Arc::clone($rpc_list_rwlock),
                         Arc::clone($poverty_list_rwlock),
                         Arc::clone($cache),
+                        Arc::clone($backend),
+                        Arc::clone($cache_metrics),
                         Arc::clone($config),
                     );
                     response
@@ -56,6 +64,8 @@ pub async fn listen_for_admin_requests(
     rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     cache: Arc<Db>,
+    backend: Arc<dyn CacheBackend>,
+    cache_metrics: Arc<CacheMetrics>,
     config: Arc<RwLock<Settings>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let address;
@@ -79,6 +89,8 @@ pub async fn listen_for_admin_requests(
         let rpc_list_rwlock_clone = Arc::clone(&rpc_list_rwlock);
         let poverty_list_rwlock_clone = Arc::clone(&poverty_list_rwlock);
         let cache_clone = Arc::clone(&cache);
+        let backend_clone = Arc::clone(&backend);
+        let cache_metrics_clone = Arc::clone(&cache_metrics);
         let config_clone = Arc::clone(&config);
 
         // Spawn a tokio task to serve multiple connections concurrently
@@ -88,6 +100,8 @@ pub async fn listen_for_admin_requests(
                 &rpc_list_rwlock_clone,
                 &poverty_list_rwlock_clone,
                 &cache_clone,
+                &backend_clone,
+                &cache_metrics_clone,
                 &config_clone,
             );
         });
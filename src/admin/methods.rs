@@ -1,5 +1,21 @@
 use crate::{
     admin::error::AdminError,
+    balancer::selection::select::SelectionStrategy,
+    database::{
+        backend::CacheBackend,
+        entry::Compression,
+        eviction::{
+            cache_stats,
+            entry_stats,
+        },
+        flush::{
+            flush_all,
+            flush_blocks,
+            flush_method,
+        },
+        metrics::CacheMetrics,
+    },
+    rpc::types::hex_to_decimal,
     Rpc,
     Settings,
 };
@@ -27,6 +43,8 @@ pub async fn execute_method(
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     config: Arc<RwLock<Settings>>,
     cache: Arc<Db>,
+    backend: Arc<dyn CacheBackend>,
+    cache_metrics: Arc<CacheMetrics>,
 ) -> Result<Value, AdminError> {
     let method = tx["method"].as_str();
     println!("Method: {:?}", method.unwrap_or("None"));
@@ -50,7 +68,24 @@ pub async fn execute_method(
                 admin_flush_cache(cache).await
             }
         }
+        Some("blutgang_flushCache") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_flush_cache_entries(&cache, backend.as_ref(), tx["params"].as_array()).await
+            }
+        }
         Some("blutgang_config") => admin_config(config),
+        Some("blutgang_cache_stats") => {
+            // Resetting the counters is a write
+            let reset = reset_requested(tx["params"].as_array())?;
+            if reset && write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_cache_stats(config, cache, &cache_metrics, reset)
+            }
+        }
+        Some("blutgang_cacheInfo") => admin_cache_info(config, cache),
         Some("blutgang_poverty_list") => admin_list_rpc(poverty_list),
         Some("blutgang_ttl") => admin_blutgang_ttl(config),
         Some("blutgang_health_check_ttl") => admin_blutgang_health_check_ttl(config),
@@ -68,6 +103,20 @@ pub async fn execute_method(
                 admin_blutgang_set_health_check_ttl(config, tx["params"].as_array())
             }
         }
+        Some("blutgang_set_debug_logging") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_blutgang_set_debug_logging(config, tx["params"].as_array())
+            }
+        }
+        Some("blutgang_set_selection") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_blutgang_set_selection(config, tx["params"].as_array())
+            }
+        }
         Some("blutgang_add_to_rpc_list") => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
@@ -96,6 +145,13 @@ pub async fn execute_method(
                 admin_remove_rpc(poverty_list, tx["params"].as_array())
             }
         }
+        Some("blutgang_release_rpc") => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_release_rpc(rpc_list, tx["params"].as_array())
+            }
+        }
         _ => Err(AdminError::InvalidMethod),
     }
 }
@@ -131,6 +187,80 @@ async fn admin_flush_cache(cache: Arc<Db>) -> Result<Value, AdminError> {
     Ok(rx)
 }
 
+// Which entries `blutgang_flushCache` should remove
+#[derive(Debug, PartialEq, Eq)]
+enum FlushFilter {
+    All,
+    Method(String),
+    Blocks { from: u64, to: u64 },
+}
+
+// Parse a block number for `blutgang_flushCache`, either as an int or a hex string
+fn parse_block(block: &Value) -> Result<u64, AdminError> {
+    match block {
+        Value::Number(block) => block.as_u64().ok_or(AdminError::ParseError),
+        Value::String(block) => hex_to_decimal(block).map_err(|_| AdminError::ParseError),
+        _ => Err(AdminError::ParseError),
+    }
+}
+
+// param[0] - `"all"`, `{"method": "eth_getLogs"}` or `{"fromBlock": 100, "toBlock": 200}`
+fn flush_filter(params: Option<&Vec<Value>>) -> Result<FlushFilter, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let filter = &params[0];
+    if filter == "all" {
+        return Ok(FlushFilter::All);
+    }
+    if let Some(method) = filter["method"].as_str() {
+        return Ok(FlushFilter::Method(method.to_string()));
+    }
+
+    match (filter.get("fromBlock"), filter.get("toBlock")) {
+        (Some(from), Some(to)) => {
+            let (from, to) = (parse_block(from)?, parse_block(to)?);
+            if from > to {
+                return Err(AdminError::OutOfBounds);
+            }
+
+            Ok(FlushFilter::Blocks { from, to })
+        }
+        _ => Err(AdminError::InvalidParams),
+    }
+}
+
+// Remove entries from the cache, responding with how many were removed.
+//
+// Unlike `blutgang_flush_cache`, which writes the cache to disk, this drops cached responses.
+// Block ranges only cover blocks that aren't finalized yet, since only those are indexed.
+async fn admin_flush_cache_entries(
+    cache: &Db,
+    backend: &dyn CacheBackend,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let flushed = match flush_filter(params)? {
+        FlushFilter::All => flush_all(cache, backend).await,
+        FlushFilter::Method(method) => flush_method(cache, backend, &method).await,
+        FlushFilter::Blocks { from, to } => flush_blocks(cache, backend, from, to).await,
+    }
+    .map_err(|_| AdminError::RwError)?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": flushed,
+    });
+
+    Ok(rx)
+}
+
 // Respond with the config we started blutgang with
 fn admin_config(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
     let guard = config.read().unwrap();
@@ -147,6 +277,92 @@ fn admin_config(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
             },
             "ttl": guard.ttl,
             "health_check_ttl": guard.health_check_ttl,
+            "non_idempotent_methods": guard.non_idempotent_methods,
+            "permanent_error_codes": guard.permanent_error_codes,
+            "debug_logging": guard.debug_logging,
+            "debug_max_params_len": guard.debug_max_params_len,
+            "selection": guard.selection.name(),
+            "max_cache_size": guard.max_cache_size,
+        },
+    });
+
+    Ok(rx)
+}
+
+// `blutgang_cache_stats` takes an optional bool param for resetting the counters after reading them
+fn reset_requested(params: Option<&Vec<Value>>) -> Result<bool, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Ok(false),
+    };
+
+    match params.len() {
+        0 => Ok(false),
+        1 => params[0].as_bool().ok_or(AdminError::ParseError),
+        _ => Err(AdminError::InvalidLen),
+    }
+}
+
+// Respond with how large the cache is, how many entries were evicted to keep
+// it under the cap, and hit/miss counters since startup or the last reset.
+//
+// `live_bytes` and `evictions` are only tracked if `max_size_bytes` is set.
+fn admin_cache_stats(
+    config: Arc<RwLock<Settings>>,
+    cache: Arc<Db>,
+    cache_metrics: &CacheMetrics,
+    reset: bool,
+) -> Result<Value, AdminError> {
+    let max_cache_size = config.read().unwrap().max_cache_size;
+    let stats = cache_stats(&cache).map_err(|_| AdminError::RwError)?;
+    let size_on_disk = cache.size_on_disk().map_err(|_| AdminError::RwError)?;
+
+    let mut result = cache_metrics.to_json(reset);
+    result["size_on_disk"] = size_on_disk.into();
+    result["live_bytes"] = stats.live_bytes.into();
+    result["max_size_bytes"] = max_cache_size.into();
+    result["evictions"] = stats.evictions.into();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": result,
+    });
+
+    Ok(rx)
+}
+
+// Respond with how much space the cache takes up, roughly how many entries it
+// holds, how it's configured and how many entries got evicted or expired.
+//
+// Everything is read from counters so this stays cheap on large caches.
+// Entries are only counted with the sled backend.
+fn admin_cache_info(config: Arc<RwLock<Settings>>, cache: Arc<Db>) -> Result<Value, AdminError> {
+    let stats = cache_stats(&cache).map_err(|_| AdminError::RwError)?;
+    let entry_stats = entry_stats(&cache).map_err(|_| AdminError::RwError)?;
+    let size_on_disk = cache.size_on_disk().map_err(|_| AdminError::RwError)?;
+
+    let guard = config.read().unwrap();
+    let (compression_level, compression_threshold_bytes) = match guard.cache_compression {
+        Compression::Zstd { level, threshold } => (Some(level), Some(threshold)),
+        Compression::None => (None, None),
+    };
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "size_on_disk": size_on_disk,
+            "entries": entry_stats.entries,
+            "max_size_bytes": guard.max_cache_size,
+            "max_entry_bytes": guard.max_entry_bytes,
+            "hot_cache_entries": guard.hot_cache_entries,
+            "hot_cache_max_entry_bytes": guard.hot_cache_max_entry_bytes,
+            "compression": guard.cache_compression.name(),
+            "compression_level": compression_level,
+            "compression_threshold_bytes": compression_threshold_bytes,
+            "evictions": stats.evictions,
+            "expirations": entry_stats.expirations,
         },
     });
 
@@ -165,9 +381,29 @@ fn admin_list_rpc(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Result<Value, AdminError>
 
     // Iterate over the RPC list and format each RPC
     for rpc in rpc_list.iter() {
+        let percentiles = rpc.status.latency.window();
         rpc_list_str.push_str(&format!(
-            "{{\"url\": \"{}\", \"max_consecutive\": {}, \"last_error\": {}}}",
-            rpc.url, rpc.max_consecutive, rpc.status.last_error
+            "{{\"url\": \"{}\", \"max_consecutive\": {}, \"weight\": {}, \"archive\": {}, \"labels\": {}, \"tier\": {}, \"max_rps\": {}, \"max_concurrent\": {}, \"latency\": {{\"score\": \"{}\", \"samples\": {}, \"average\": {}, \"p50\": {}, \"p90\": {}, \"p99\": {}}}, \"head\": {}, \"circuit\": \"{}\", \"consensus\": {{\"agreements\": {}, \"divergences\": {}, \"quarantined\": {}}}, \"last_error\": {}}}",
+            rpc.url,
+            rpc.max_consecutive,
+            rpc.weight,
+            rpc.archive,
+            json!(rpc.labels),
+            rpc.tier,
+            rpc.rate_limit.max_rps(),
+            rpc.concurrency.max_concurrent(),
+            rpc.status.latency_score.name(),
+            percentiles.samples(),
+            rpc.status.latency.average(),
+            percentiles.p50,
+            percentiles.p90,
+            percentiles.p99,
+            rpc.head,
+            rpc.circuit.state().name(),
+            rpc.consensus.agreements(),
+            rpc.consensus.divergences(),
+            rpc.consensus.is_quarantined(),
+            rpc.status.last_error
         ));
     }
 
@@ -271,6 +507,45 @@ fn admin_remove_rpc(
     Ok(rx)
 }
 
+// Lift the consensus quarantine of the RPC at a specified index and start its
+// record over, return the url of the released RPC
+//
+// param[0] - RPC index
+fn admin_release_rpc(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let index = match params[0].to_string().replace('\"', "").parse::<u64>() {
+        Ok(index) => index,
+        Err(_) => return Err(AdminError::ParseError),
+    };
+
+    let rpc_list = rpc_list.read().map_err(|_| AdminError::Inaccessible)?;
+    let rpc = match rpc_list.get(index as usize) {
+        Some(rpc) => rpc,
+        None => return Err(AdminError::OutOfBounds),
+    };
+    rpc.consensus.release();
+    println!("\x1b[35mInfo:\x1b[0m Released {} from quarantine.", rpc.url);
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": rpc.url,
+    });
+
+    Ok(rx)
+}
+
 // TODO: change the following 4 fn so theyre generic
 
 // Responds with health_check_ttl
@@ -363,9 +638,80 @@ fn admin_blutgang_set_ttl(
     Ok(rx)
 }
 
+// Turns request debug logging on or off
+//
+// param[0] - debug_logging
+fn admin_blutgang_set_debug_logging(
+    config: Arc<RwLock<Settings>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let debug_logging = match params[0].to_string().replace('\"', "").parse::<bool>() {
+        Ok(debug_logging) => debug_logging,
+        Err(_) => return Err(AdminError::ParseError),
+    };
+
+    let mut guard = config.write().unwrap();
+    guard.debug_logging = debug_logging;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": guard.debug_logging,
+    });
+
+    Ok(rx)
+}
+
+// Switch how RPCs get picked, applies to the next request
+//
+// param[0] - selection, eg. `"least_connections"`
+fn admin_blutgang_set_selection(
+    config: Arc<RwLock<Settings>>,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let selection = match params[0].as_str().and_then(SelectionStrategy::from_name) {
+        Some(selection) => selection,
+        None => return Err(AdminError::ParseError),
+    };
+
+    let mut guard = config.write().unwrap();
+    guard.selection = selection;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": guard.selection.name(),
+    });
+
+    Ok(rx)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database::{
+        batch::CacheBatch,
+        expiry::prune_expired,
+        sled_backend::SledBackend,
+    };
     use jsonwebtoken::DecodingKey;
 
     // Helper function to create a test RPC list
@@ -388,8 +734,10 @@ mod tests {
 
     // Helper function to create a test Settings config
     fn create_test_settings_config() -> Arc<RwLock<Settings>> {
-        let mut config = Settings::default();
-        config.do_clear = true;
+        let mut config = Settings {
+            do_clear: true,
+            ..Default::default()
+        };
         config.admin.key = DecodingKey::from_secret(b"some-key");
         Arc::new(RwLock::new(config))
     }
@@ -402,6 +750,10 @@ mod tests {
         Arc::new(db)
     }
 
+    fn create_test_backend(cache: &Arc<Db>) -> Arc<dyn CacheBackend> {
+        Arc::new(SledBackend::new(Arc::clone(cache)))
+    }
+
     #[tokio::test]
     async fn test_execute_method_blutgang_quit() {
         // Arrange
@@ -414,7 +766,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -427,19 +781,33 @@ mod tests {
         // Arrange
         let cache = create_test_cache();
         let tx = json!({ "id":1,"method": "blutgang_rpc_list" });
+        let rpc_list = create_test_rpc_list();
+        rpc_list.read().unwrap()[0].update_latency(100.0);
+        rpc_list.read().unwrap()[0]
+            .consensus
+            .diverged("rpc", 1, false);
 
         // Act
         let result = execute_method(
             tx,
-            &create_test_rpc_list(),
+            &rpc_list,
             &create_test_poverty_list(),
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
         // Assert
-        assert!(result.is_ok());
+        let listed: Value =
+            serde_json::from_str(result.unwrap()["result"].as_str().unwrap()).unwrap();
+        assert_eq!(listed[0]["labels"], json!([]));
+        assert_eq!(listed[0]["latency"]["score"], "average");
+        assert_eq!(listed[0]["latency"]["samples"], 1);
+        assert_eq!(listed[0]["latency"]["p99"], 100.0);
+        assert_eq!(listed[0]["consensus"]["divergences"], 1);
+        assert_eq!(listed[0]["consensus"]["quarantined"], true);
     }
 
     #[tokio::test]
@@ -454,7 +822,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -462,6 +832,84 @@ mod tests {
         assert!(result.is_ok()); // Verify that flushing the cache doesn't produce an error
     }
 
+    #[test]
+    fn test_flush_filter() {
+        assert_eq!(
+            flush_filter(Some(&vec![json!("all")])).unwrap(),
+            FlushFilter::All
+        );
+        assert_eq!(
+            flush_filter(Some(&vec![json!({"method": "eth_getLogs"})])).unwrap(),
+            FlushFilter::Method("eth_getLogs".to_string())
+        );
+        assert_eq!(
+            flush_filter(Some(&vec![json!({"fromBlock": 10, "toBlock": "0x14"})])).unwrap(),
+            FlushFilter::Blocks { from: 10, to: 20 }
+        );
+
+        assert!(flush_filter(None).is_err());
+        assert!(flush_filter(Some(&vec![])).is_err());
+        assert!(flush_filter(Some(&vec![json!("everything")])).is_err());
+        assert!(flush_filter(Some(&vec![json!({"fromBlock": 10})])).is_err());
+        assert!(flush_filter(Some(&vec![json!({"fromBlock": 20, "toBlock": 10})])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_flush_cache_method() {
+        use crate::database::batch::CacheBatch;
+
+        let cache = create_test_cache();
+        let mut batch = CacheBatch::default();
+        for (method, key) in [
+            ("eth_getLogs", b"logs1"),
+            ("eth_getLogs", b"logs2"),
+            ("eth_call", b"call1"),
+        ] {
+            batch.insert(key, b"{}");
+            batch.index_method(method, key);
+        }
+        batch
+            .apply(&cache, create_test_backend(&cache).as_ref())
+            .await
+            .unwrap();
+
+        let tx = json!({ "id":1,"method": "blutgang_flushCache", "params": [{"method": "eth_getLogs"}] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
+        )
+        .await
+        .unwrap();
+
+        // Other methods survive
+        assert_eq!(result["result"], 2);
+        assert!(cache.get(b"logs1").unwrap().is_none());
+        assert!(cache.get(b"logs2").unwrap().is_none());
+        assert!(cache.get(b"call1").unwrap().is_some());
+
+        // Dropping cached responses is a write
+        let config = create_test_settings_config();
+        config.write().unwrap().admin.readonly = true;
+        let tx = json!({ "id":1,"method": "blutgang_flushCache", "params": ["all"] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            config,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(cache.get(b"call1").unwrap().is_some());
+    }
+
     #[tokio::test]
     async fn test_execute_method_blutgang_config() {
         // Arrange
@@ -474,7 +922,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -482,6 +932,162 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_execute_method_blutgang_cache_stats() {
+        // Arrange
+        let cache = create_test_cache();
+        let tx = json!({ "id":1,"method": "blutgang_cache_stats" });
+
+        // Act
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
+        )
+        .await;
+
+        // Assert
+        let result = result.unwrap();
+        assert_eq!(result["result"]["live_bytes"], 0);
+        assert_eq!(result["result"]["evictions"], 0);
+        assert_eq!(result["result"]["max_size_bytes"], 0);
+        assert_eq!(result["result"]["revalidations"]["diverged"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_cache_stats_reset() {
+        // Arrange
+        let cache = create_test_cache();
+        let config = create_test_settings_config();
+        let cache_metrics = Arc::new(CacheMetrics::default());
+        cache_metrics.call("eth_chainId").hit();
+        cache_metrics.call("eth_chainId").hit();
+
+        // Act
+        let tx = json!({ "id":1,"method": "blutgang_cache_stats", "params": [true] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::clone(&cache_metrics),
+        )
+        .await;
+
+        // Assert
+        let result = result.unwrap();
+        assert_eq!(result["result"]["hits"], 2);
+        assert_eq!(result["result"]["methods"]["eth_chainId"]["hits"], 2);
+        assert_eq!(cache_metrics.to_json(false)["hits"], 0);
+
+        // Resetting is a write
+        config.write().unwrap().admin.readonly = true;
+        let tx = json!({ "id":1,"method": "blutgang_cache_stats", "params": [true] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::clone(&cache_metrics),
+        )
+        .await;
+        assert!(result.is_err());
+
+        let tx = json!({ "id":1,"method": "blutgang_cache_stats", "params": [] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            config,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            cache_metrics,
+        )
+        .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_cache_info() {
+        // Arrange
+        let cache = create_test_cache();
+        let backend = create_test_backend(&cache);
+        let config = create_test_settings_config();
+        config.write().unwrap().cache_compression = Compression::Zstd {
+            level: 3,
+            threshold: 1024,
+        };
+
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let cache_info = || {
+            let tx = json!({ "id":1,"method": "blutgang_cacheInfo" });
+            execute_method(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                Arc::clone(&config),
+                Arc::clone(&cache),
+                Arc::clone(&backend),
+                Arc::new(CacheMetrics::default()),
+            )
+        };
+
+        let result = cache_info().await.unwrap();
+        assert_eq!(result["result"]["entries"], 0);
+        assert_eq!(result["result"]["compression"], "zstd");
+        assert_eq!(result["result"]["compression_level"], 3);
+        assert_eq!(result["result"]["max_entry_bytes"], 4194304);
+        let size_on_disk = result["result"]["size_on_disk"].as_u64().unwrap();
+
+        // Act
+        let mut batch = CacheBatch::default();
+        for i in 0u8..100 {
+            batch.insert(&[i], &[0; 1024]);
+        }
+        batch.insert_expiring(b"expiring", b"{}", 100);
+        batch.apply(&cache, backend.as_ref()).await.unwrap();
+        // Overwriting an entry doesn't count it twice
+        let mut batch = CacheBatch::default();
+        batch.insert(&[0], b"{}");
+        batch.apply(&cache, backend.as_ref()).await.unwrap();
+        cache.flush_async().await.unwrap();
+
+        // Assert
+        let result = cache_info().await.unwrap();
+        assert_eq!(result["result"]["entries"], 101);
+        assert!(result["result"]["size_on_disk"].as_u64().unwrap() > size_on_disk);
+
+        prune_expired(&cache, backend.as_ref(), 200).await.unwrap();
+        let result = cache_info().await.unwrap();
+        assert_eq!(result["result"]["entries"], 100);
+        assert_eq!(result["result"]["expirations"], 1);
+
+        let tx = json!({ "id":1,"method": "blutgang_flushCache", "params": ["all"] });
+        execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            Arc::clone(&cache),
+            Arc::clone(&backend),
+            Arc::new(CacheMetrics::default()),
+        )
+        .await
+        .unwrap();
+        let result = cache_info().await.unwrap();
+        assert_eq!(result["result"]["entries"], 0);
+        assert_eq!(result["result"]["expirations"], 1);
+    }
+
     #[tokio::test]
     async fn test_execute_method_blutgang_poverty_list() {
         // Arrange
@@ -494,7 +1100,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -514,7 +1122,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -534,7 +1144,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -554,7 +1166,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -577,7 +1191,9 @@ mod tests {
             &rpc_list,
             &create_test_poverty_list(),
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -608,6 +1224,8 @@ mod tests {
             &create_test_poverty_list(),
             create_test_settings_config(),
             cache.clone(),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -624,7 +1242,9 @@ mod tests {
             &rpc_list,
             &binding,
             create_test_settings_config(),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -633,6 +1253,39 @@ mod tests {
         assert!(rpc_list.read().unwrap().len() == len - 1);
     }
 
+    #[tokio::test]
+    async fn test_execute_method_blutgang_release_rpc() {
+        // Arrange
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        rpc_list.read().unwrap()[0]
+            .consensus
+            .diverged("rpc", 1, false);
+
+        for (index, released) in [(10, false), (0, true)] {
+            let tx = json!({ "id":1,"method": "blutgang_release_rpc", "params": [index] });
+
+            // Act
+            let result = execute_method(
+                tx,
+                &rpc_list,
+                &create_test_poverty_list(),
+                create_test_settings_config(),
+                Arc::clone(&cache),
+                create_test_backend(&cache),
+                Arc::new(CacheMetrics::default()),
+            )
+            .await;
+
+            // Assert
+            assert_eq!(result.is_ok(), released);
+            assert_eq!(
+                rpc_list.read().unwrap()[0].consensus.is_quarantined(),
+                !released
+            );
+        }
+    }
+
     #[tokio::test]
     async fn test_execute_method_blutgang_set_ttl() {
         // Arrange
@@ -648,7 +1301,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             Arc::clone(&config),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -673,7 +1328,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             Arc::clone(&config),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -683,6 +1340,93 @@ mod tests {
         assert!(config.read().unwrap().health_check_ttl == 9001)
     }
 
+    #[tokio::test]
+    async fn test_execute_method_blutgang_set_debug_logging() {
+        // Arrange
+        let cache = create_test_cache();
+        let config = create_test_settings_config();
+        config.write().unwrap().debug_logging = false;
+
+        // Act
+        let tx = json!({ "id":1,"method": "blutgang_set_debug_logging", "params": [true] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
+        )
+        .await;
+
+        // Assert
+        assert!(result.is_ok());
+        assert!(config.read().unwrap().debug_logging);
+
+        // Invalid params leave it untouched
+        let tx = json!({ "id":1,"method": "blutgang_set_debug_logging", "params": ["yes"] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(config.read().unwrap().debug_logging);
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_blutgang_set_selection() {
+        // Arrange
+        let cache = create_test_cache();
+        let config = create_test_settings_config();
+
+        // Act
+        let tx = json!({ "id":1,"method": "blutgang_set_selection", "params": ["round_robin"] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
+        )
+        .await;
+
+        // Assert
+        assert_eq!(result.unwrap()["result"], "round_robin");
+        assert_eq!(
+            config.read().unwrap().selection,
+            SelectionStrategy::RoundRobin
+        );
+
+        // Unknown strategies leave it untouched
+        let tx = json!({ "id":1,"method": "blutgang_set_selection", "params": ["fastest"] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(
+            config.read().unwrap().selection,
+            SelectionStrategy::RoundRobin
+        );
+    }
+
     #[tokio::test]
     async fn test_rw_protection() {
         // Arrange
@@ -699,6 +1443,8 @@ mod tests {
             &create_test_poverty_list(),
             Arc::clone(&config),
             cache.clone(),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
@@ -712,7 +1458,9 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             Arc::clone(&config),
-            cache,
+            Arc::clone(&cache),
+            create_test_backend(&cache),
+            Arc::new(CacheMetrics::default()),
         )
         .await;
 
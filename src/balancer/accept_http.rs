@@ -1,50 +1,118 @@
 use crate::{
+    balancer::consensus::{
+        check_consensus,
+        ConsensusSettings,
+    },
     balancer::format::{
+        canonicalize_param,
+        replace_block_tags,
+        BlockTag,
         get_block_number_from_request,
+        get_block_number_from_result,
         incoming_to_value,
     },
+    balancer::logs_cache::{
+        resolve_logs,
+        LogsRange,
+    },
+    balancer::hedge::{
+        Hedge,
+        HedgeSettings,
+    },
+    balancer::request_log::RequestLog,
+    balancer::response_errors::ErrorResponse,
+    balancer::revalidate::{
+        revalidate,
+        Revalidator,
+    },
     balancer::selection::cache_rules::{
         cache_method,
+        cache_policy,
         cache_result,
+        is_non_idempotent,
+        is_null_result,
+        near_head,
+        negative_ttl,
+        too_large,
+        CachePolicy,
     },
-    balancer::selection::select::pick,
+    balancer::selection::routing::{
+        Requirements,
+        RoutingRules,
+    },
+    balancer::selection::select::{
+        busy_for,
+        pick,
+        pick_sticky,
+        SelectionStrategy,
+    },
+    balancer::selection::sticky::sticky_key,
     cache_error,
+    config::cache_setup::VERSION_STR,
+    database::{
+        backend::CacheBackend,
+        batch::CacheBatch,
+        chain_id::cache_key,
+        entry::{
+            is_expired,
+            parse_payload,
+            unix_millis,
+            Compression,
+        },
+        hasher::CacheHasher,
+        hot_cache::HotCache,
+        metrics::{
+            CacheMetrics,
+            CallMetrics,
+        },
+        write_behind::CacheWriter,
+    },
+    invalid_request,
+    invalid_response,
+    no_capable_rpc,
     no_rpc_available,
     print_cache_error,
-    rpc::types::Rpc,
+    rate_limited,
+    rpc::{
+        error::RpcError,
+        types::{
+            InFlight,
+            Rpc,
+        },
+    },
     timed_out,
     NamedBlocknumbers,
     Settings,
 };
 
+use serde::Deserialize;
 use serde_json::{
+    json,
     to_vec,
     Value,
 };
 use simd_json;
 
-// Select either blake3 or xxhash based on the features
-#[cfg(not(feature = "xxhash"))]
-use blake3::hash;
-
-#[cfg(feature = "xxhash")]
-use xxhash_rust::xxh3::xxh3_64;
-#[cfg(feature = "xxhash")]
-use zerocopy::AsBytes; // Impls AsBytes trait for u64
-
 use http_body_util::Full;
 use hyper::{
     body::Bytes,
     header::HeaderValue,
     Request,
 };
-use sled::Db;
 
-use tokio::time::timeout;
+use tokio::{
+    task::JoinSet,
+    time::{
+        error::Elapsed,
+        sleep,
+        timeout,
+    },
+};
 
 use std::{
-    collections::BTreeMap,
+    collections::HashMap,
     convert::Infallible,
+    net::IpAddr,
     println,
     sync::{
         Arc,
@@ -56,9 +124,73 @@ use std::{
     },
 };
 
-struct RequestParams {
-    ttl: u128,
-    max_retries: u32,
+// Settings needed to resolve a call, copied out of the config once per request
+#[derive(Clone)]
+pub struct RequestParams {
+    pub ttl: u128,
+    pub max_retries: u32,
+    pub retryable_error_codes: Vec<i64>,
+    pub non_idempotent_methods: Vec<String>,
+    pub cache_methods: HashMap<String, CachePolicy>,
+    pub permanent_error_codes: Vec<i64>,
+    pub debug_logging: bool,
+    pub debug_max_params_len: usize,
+    pub max_cache_size: u64,
+    pub compression: Compression,
+    pub finality_distance: u64,
+    pub negative_cache_methods: Vec<String>,
+    pub negative_ttl: Duration,
+    pub max_entry_bytes: usize,
+    // How many calls to keep around for the warmup to replay, 0 to not log them
+    pub call_log_size: usize,
+    pub selection: SelectionStrategy,
+    // Calls with a key stick to the same RPC, see `pick_sticky`
+    pub sticky_key: Option<u64>,
+    pub routing: RoutingRules,
+    pub hedging: HedgeSettings,
+    pub consensus: ConsensusSettings,
+}
+
+impl RequestParams {
+    pub fn new(config: &Settings) -> Self {
+        RequestParams {
+            ttl: config.ttl,
+            max_retries: config.max_retries,
+            retryable_error_codes: config.retryable_error_codes.clone(),
+            non_idempotent_methods: config.non_idempotent_methods.clone(),
+            cache_methods: config.cache_methods.clone(),
+            permanent_error_codes: config.permanent_error_codes.clone(),
+            debug_logging: config.debug_logging,
+            debug_max_params_len: config.debug_max_params_len,
+            max_cache_size: config.max_cache_size,
+            compression: config.cache_compression,
+            finality_distance: config.finality_distance,
+            negative_cache_methods: config.negative_cache_methods.clone(),
+            negative_ttl: config.negative_ttl,
+            max_entry_bytes: config.max_entry_bytes,
+            call_log_size: config.warmup.replay_last,
+            selection: config.selection,
+            sticky_key: None,
+            routing: config.routing.clone(),
+            hedging: config.hedging.clone(),
+            consensus: config.consensus.clone(),
+        }
+    }
+}
+
+// Everything needed to read from and write to the cache
+#[derive(Debug, Clone)]
+pub struct CacheArgs {
+    pub backend: Arc<dyn CacheBackend>,
+    pub hot_cache: Arc<HotCache>,
+    pub cache_metrics: Arc<CacheMetrics>,
+    // Every key is prefixed with the chain id, see `cache_key`
+    pub chain_id: u64,
+    pub hasher: CacheHasher,
+    // Writes go through here so requests don't wait on sled
+    pub writer: CacheWriter,
+    // Picks which cache hits get checked against the RPCs
+    pub revalidator: Arc<Revalidator>,
 }
 
 // Macros for accepting requests
@@ -67,11 +199,11 @@ macro_rules! accept {
     (
         $io:expr,
         $rpc_list_rwlock:expr,
-        $cache:expr,
+        $cache_args:expr,
         $finalized_rx:expr,
         $named_numbers:expr,
-        $head_cache:expr,
-        $config:expr
+        $config:expr,
+        $client:expr
     ) => {
         // Bind the incoming connection to our service
         if let Err(err) = http1::Builder::new()
@@ -84,9 +216,9 @@ macro_rules! accept {
                         Arc::clone($rpc_list_rwlock),
                         $finalized_rx,
                         $named_numbers,
-                        $head_cache,
-                        Arc::clone($cache),
+                        $cache_args,
                         $config,
+                        $client,
                     );
                     response
                 }),
@@ -103,180 +235,935 @@ macro_rules! get_response {
     (
         $tx:expr,
         $cache:expr,
-        $tx_hash:expr,
+        $hot_cache:expr,
+        $batch:expr,
+        $cache_key:expr,
         $rpc_position:expr,
         $id:expr,
         $rpc_list_rwlock:expr,
         $finalized_rx:expr,
         $named_numbers:expr,
-        $head_cache:expr,
         $ttl:expr,
-        $max_retries:expr
-    ) => {
-        match $cache.get($tx_hash.as_bytes()) {
-            Ok(rax) => {
-                if let Some(mut rax) = rax {
+        $max_retries:expr,
+        $policy:expr,
+        $permanent_error_codes:expr,
+        $negative_ttl:expr,
+        $params:expr,
+        $hedge:expr,
+        $metrics:expr
+    ) => {{
+        // Skip the cache entirely for calls that must always go to a RPC.
+        //
+        // The hottest entries are served from memory, backend hits get added to the hot cache.
+        //
+        // Expired entries are treated as misses and get overwritten.
+        let cached = if $policy != CachePolicy::Never {
+            let now = unix_millis();
+            match $hot_cache.get(&$cache_key).filter(|rax| !is_expired(rax, now)) {
+                Some(rax) => Ok(Some(rax)),
+                None => {
+                    // Batches read all of their entries up front
+                    let read = match $batch.take_prefetched(&$cache_key) {
+                        Some(read) => Ok(read),
+                        None => {
+                            let generation = $hot_cache.generation(&$cache_key);
+                            $cache.get(&$cache_key).await.map(|rax| (rax, generation))
+                        }
+                    };
+
+                    read.map(|(rax, generation)| {
+                        if let Some(rax) = &rax {
+                            $hot_cache.insert_if_unchanged(&$cache_key, rax.clone(), generation);
+                        }
+                        rax
+                    })
+                }
+            }
+            .map(|rax| rax.filter(|rax| !is_expired(rax, now)))
+        } else {
+            $metrics.uncacheable();
+            Ok(None)
+        };
+
+        // Entries we can't decode are treated as misses and removed so they get written again
+        let cached = match cached {
+            Ok(Some(mut rax)) => match parse_payload(&mut rax) {
+                Ok(cached) => Ok(Some(cached)),
+                Err(err) => {
+                    println!("\x1b[93mWrn:\x1b[0m Removing undecodable cache entry: {}", err);
+                    $hot_cache.remove(&$cache_key);
+                    let _ = $cache.remove(vec![$cache_key.to_vec()]).await;
+                    Ok(None)
+                }
+            },
+            cached => cached.map(|_| None),
+        };
+
+        match cached {
+            Ok(cached) => {
+                if let Some(mut cached) = cached {
+                    $metrics.hit();
                     $rpc_position = None;
 
                     // Reconstruct ID
-                    let mut cached: Value = simd_json::serde::from_slice(&mut rax).unwrap();
-
-                    cached["id"] = $id.into();
+                    cached["id"] = $id;
                     cached.to_string()
                 } else {
+                    if $policy != CachePolicy::Never {
+                        $metrics.miss();
+                    }
+
                     // Kinda jank but set the id back to what it was before
-                    $tx["id"] = $id.into();
+                    $tx["id"] = $id;
 
-                    let tx_string = $tx.to_string();
+                    // Only RPCs that can serve the call get it, eg. archive nodes for old blocks
+                    let requirements = $params
+                        .routing
+                        .requirements(&$tx, &$named_numbers.read().unwrap());
 
                     // Loop until we get a response
-                    let rx;
-                    let mut retries = 0;
-                    loop {
-                        // Get the next Rpc in line.
-                        let mut rpc;
-                        {
-                            let mut rpc_list = $rpc_list_rwlock.write().unwrap();
-                            (rpc, $rpc_position) = pick(&mut rpc_list);
-                        }
-                        println!("\x1b[35mInfo:\x1b[0m Forwarding to: {}", rpc.url);
-
-                        // Check if we have any RPCs in the list, if not return error
-                        if $rpc_position == None {
-                            return (no_rpc_available!(), None);
-                        }
+                    let rx = match send_upstream(
+                        &$tx,
+                        $rpc_list_rwlock,
+                        $ttl,
+                        $max_retries,
+                        &$params.retryable_error_codes,
+                        $params.selection,
+                        $params.sticky_key,
+                        requirements,
+                        $hedge,
+                        &mut $rpc_position,
+                    )
+                    .await
+                    {
+                        Ok(rx) => rx,
+                        Err(err) => return (Err(err), $rpc_position),
+                    };
 
-                        // Send the request. And return a timeout if it takes too long
+                    // Don't cache responses that contain errors or missing trie nodes
+                    if $policy != CachePolicy::Never {
+                        // Replace the id with Value::Null and insert the request
                         //
-                        // Check if it contains any errors or if its `latest` and insert it if it isn't
-                        match timeout(
-                            Duration::from_millis($ttl.try_into().unwrap()),
-                            rpc.send_request($tx.clone()),
-                        )
-                        .await
-                        {
-                            Ok(rxa) => {
-                                rx = rxa.unwrap();
-                                break;
-                            },
-                            Err(_) => {
-                                println!("\x1b[93mWrn:\x1b[0m An RPC request has timed out, picking new RPC and retrying.");
-                                rpc.update_latency($ttl as f64);
-                                retries += 1;
-                            },
+                        // simd_json parses in place, so work on a copy of the response
+                        let mut rx_str = rx.clone();
+                        let rx_value = match unsafe { simd_json::serde::from_str::<Value>(&mut rx_str) } {
+                            Ok(rx_value) if cache_result(&rx_value, $permanent_error_codes) => Some(rx_value),
+                            _ => None,
                         };
 
-                        if retries == $max_retries {
-                            return (timed_out!(), $rpc_position,);
-                        }
-                    }
-
-                    let mut rx_str = rx.as_str().to_string();
-
-                    // Don't cache responses that contain errors or missing trie nodes
-                    if cache_method(&tx_string) && cache_result(&rx) {
-                        // Insert the response hash into the head_cache
-                        let num = get_block_number_from_request($tx, $named_numbers);
-
-                        // Insert the key of the request we made into our `head_cache`
-                        // so we can invalidate it and remove it from the DB if it reorgs.
-                        if let Some(num) = num {
-                            if num > *$finalized_rx.borrow() {
-                                let mut head_cache = $head_cache.write().unwrap();
-                                head_cache
-                                    .entry(num)
-                                    .or_insert_with(Vec::new)
-                                    .push($tx_hash.to_string());
-
-                            }
-
-                            // Replace the id with Value::Null and insert the request
-                            let mut rx_value: Value = unsafe {
-                                simd_json::serde::from_str(&mut rx_str).unwrap()
-                            };
-                            rx_value["id"] = Value::Null;
-
-                            $cache.insert($tx_hash.as_bytes(), to_vec(&rx_value).unwrap().as_slice()).unwrap();
+                        if let Some(rx_value) = rx_value {
+                            let finalized = *$finalized_rx.borrow();
+                            insert_response(
+                                $tx,
+                                rx_value,
+                                &$cache_key,
+                                $policy,
+                                $negative_ttl,
+                                finalized,
+                                $named_numbers,
+                                $params,
+                                &$metrics,
+                                $batch,
+                            );
                         }
                     }
 
-                    rx_str
+                    rx
                 }
             }
             Err(_) => {
                 // If anything errors send an rpc request and see if it works, if not then gg
                 print_cache_error!();
                 $rpc_position = None;
-                return (cache_error!(), $rpc_position);
+                return (Err(cache_error!($id)), $rpc_position);
             }
         }
-    };
+    }};
 }
 
-// Pick RPC and send request to it. In case the result is cached,
-// read and return from the cache.
-async fn forward_body(
-    tx: Request<hyper::body::Incoming>,
+// How many times to retry a call on another RPC, see `send_upstream`
+pub const DEFAULT_MAX_RETRIES: u32 = 2;
+// Errors RPCs return while restarting or syncing, that another RPC likely won't
+pub const DEFAULT_RETRYABLE_ERROR_CODES: [i64; 1] = [-32603];
+
+// The parts of a response from a RPC we look at before passing it on, the rest gets skipped over
+#[derive(Deserialize)]
+struct UpstreamResponse {
+    error: Option<Value>,
+}
+
+// How a single attempt at getting a response from a RPC went
+enum Attempt {
+    // The response can be passed on
+    Done(String),
+    // Worth retrying on another RPC. Holds what to return if we can't.
+    Failed(Result<String, ErrorResponse>),
+}
+
+// A call sent to a RPC in the background, see `spawn_attempt`
+type Sent = (Rpc, usize, bool, Result<Result<String, RpcError>, Elapsed>);
+
+// Send `tx` to the RPCs, retrying on another RPC until we get back a valid response.
+//
+// Transport errors, timeouts, malformed JSON and errors with one of `retryable_error_codes`
+// get retried up to `max_retries` times, each time on a RPC the call wasn't sent to yet.
+// Once we run out of retries or RPCs to try, the last error is returned.
+//
+// Only RPCs that meet `requirements` get picked. If they're all at their `max_rps`
+// or `max_concurrent`, we wait for one to free up as long as that's within `ttl`.
+// `rpc_position` is set to the RPC we used last, so its latency can get updated.
+#[allow(clippy::too_many_arguments)]
+pub async fn send_upstream(
+    tx: &Value,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    ttl: u128,
+    max_retries: u32,
+    retryable_error_codes: &[i64],
+    selection: SelectionStrategy,
+    sticky_key: Option<u64>,
+    requirements: Requirements,
+    hedge: Hedge<'_>,
+    rpc_position: &mut Option<usize>,
+) -> Result<String, ErrorResponse> {
+    let ttl = Duration::from_millis(ttl as u64);
+    let deadline = Instant::now() + ttl;
+    // Positions of the RPCs the call got sent to
+    let mut tried = Vec::new();
+    let mut retries = 0;
+    let mut last_error = None;
+    loop {
+        let picked = match pick_upstream(
+            tx,
+            rpc_list_rwlock,
+            selection,
+            sticky_key,
+            &requirements,
+            &tried,
+            deadline,
+        )
+        .await
+        {
+            Ok(picked) => picked,
+            // Every RPC that can serve the call already failed it
+            Err(err) => return last_error.unwrap_or(Err(err)),
+        };
+        tried.push(picked.1);
+
+        let error = match send_hedged(
+            tx,
+            rpc_list_rwlock,
+            picked,
+            ttl,
+            retryable_error_codes,
+            selection,
+            &requirements,
+            hedge,
+            &mut tried,
+            rpc_position,
+        )
+        .await
+        {
+            Attempt::Done(rx) => return Ok(rx),
+            Attempt::Failed(error) => error,
+        };
+
+        if retries == max_retries {
+            return error;
+        }
+        retries += 1;
+        last_error = Some(error);
+    }
+}
+
+// Pick the next RPC to send `tx` to, skipping the ones at `excluded`.
+//
+// If they're all at their `max_rps` or `max_concurrent`, wait for one to free up
+// as long as that's before `deadline`.
+// The request counts as in flight from the moment it's picked, so concurrent picks see it right away.
+//
+// Picks only read lock the RPC list, so requests don't queue up behind each other to get one.
+async fn pick_upstream(
+    tx: &Value,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    selection: SelectionStrategy,
+    sticky_key: Option<u64>,
+    requirements: &Requirements,
+    excluded: &[usize],
+    deadline: Instant,
+) -> Result<(Rpc, usize, InFlight), ErrorResponse> {
+    loop {
+        let busy = {
+            let rpc_list = rpc_list_rwlock.read().unwrap();
+            let picked = match sticky_key {
+                Some(key) => pick_sticky(&rpc_list, selection, requirements, excluded, key),
+                None => pick(&rpc_list, selection, requirements, excluded),
+            };
+            match picked {
+                Some(position) => {
+                    let rpc = rpc_list[position].clone();
+                    // A concurrent pick can take its last `max_concurrent` slot first,
+                    // then we just pick again
+                    if let Some(in_flight) = rpc.start_request() {
+                        return Ok((rpc, position, in_flight));
+                    }
+                    continue;
+                }
+                None => busy_for(&rpc_list, requirements, excluded),
+            }
+        };
+
+        match busy {
+            Some(wait) if Instant::now() + wait <= deadline => sleep(wait).await,
+            Some(_) => return Err(rate_limited!(tx["id"])),
+            None if requirements.is_empty() => return Err(no_rpc_available!(tx["id"])),
+            None => return Err(no_capable_rpc!(tx["id"], requirements)),
+        }
+    }
+}
+
+// Send `tx` to the `picked` RPC. If it doesn't answer within `hedge.delay`, also send it to
+// the next best RPC, up to `hedge.max_hedges` times. Positions of the RPCs get added to `tried`.
+//
+// Returns the first response that can be passed on, or the last failure if none can.
+#[allow(clippy::too_many_arguments)]
+async fn send_hedged(
+    tx: &Value,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    picked: (Rpc, usize, InFlight),
+    ttl: Duration,
+    retryable_error_codes: &[i64],
+    selection: SelectionStrategy,
+    requirements: &Requirements,
+    hedge: Hedge<'_>,
+    tried: &mut Vec<usize>,
+    rpc_position: &mut Option<usize>,
+) -> Attempt {
+    // Whatever is still in flight gets cancelled once we return
+    let mut attempts = JoinSet::new();
+    spawn_attempt(&mut attempts, tx, picked, ttl, false);
+
+    let mut hedges = 0;
+    let mut hedge_at = Instant::now() + hedge.delay;
+    loop {
+        let hedge_timer = sleep(hedge_at.saturating_duration_since(Instant::now()));
+        tokio::select! {
+            Some(sent) = attempts.join_next() => {
+                let (rpc, position, hedged, rx) = sent.unwrap();
+                *rpc_position = Some(position);
+
+                match check_response(tx, rx, &rpc, position, retryable_error_codes) {
+                    Attempt::Done(rx) => {
+                        rpc.circuit.success(&rpc.url);
+                        if hedged {
+                            hedge.won();
+                        }
+                        return Attempt::Done(rx);
+                    }
+                    Attempt::Failed(error) => {
+                        rpc.circuit.failure(&rpc.url);
+                        penalize_rpc(rpc_list_rwlock, position, ttl);
+                        if attempts.is_empty() {
+                            return Attempt::Failed(error);
+                        }
+                    }
+                }
+            }
+            _ = hedge_timer, if hedges < hedge.max_hedges => {
+                hedges += 1;
+                hedge_at += hedge.delay;
+
+                // Hedges go to the next best RPC and don't wait on busy ones
+                let now = Instant::now();
+                match pick_upstream(tx, rpc_list_rwlock, selection, None, requirements, tried, now).await {
+                    Ok(picked) => {
+                        tried.push(picked.1);
+                        hedge.sent();
+                        spawn_attempt(&mut attempts, tx, picked, ttl, true);
+                    }
+                    Err(_) => hedges = hedge.max_hedges,
+                }
+            }
+        }
+    }
+}
+
+// Send `tx` to the `picked` RPC in the background, giving up after `ttl`
+fn spawn_attempt(
+    attempts: &mut JoinSet<Sent>,
+    tx: &Value,
+    picked: (Rpc, usize, InFlight),
+    ttl: Duration,
+    hedged: bool,
+) {
+    let (rpc, position, in_flight) = picked;
+    let tx = tx.clone();
+    println!("\x1b[35mInfo:\x1b[0m Forwarding to: {}", rpc.url);
+
+    attempts.spawn(async move {
+        let rx = timeout(ttl, rpc.send_request(tx)).await;
+        drop(in_flight);
+        (rpc, position, hedged, rx)
+    });
+}
+
+// Check what the RPC at `position` sent back for `tx`
+fn check_response(
+    tx: &Value,
+    rx: Result<Result<String, RpcError>, Elapsed>,
+    rpc: &Rpc,
+    position: usize,
+    retryable_error_codes: &[i64],
+) -> Attempt {
+    match rx {
+        Ok(Err(err)) => {
+            println!(
+                "\x1b[93mWrn:\x1b[0m RPC {} ({}) could not be reached: {}",
+                position, rpc.url, err,
+            );
+            Attempt::Failed(Err(invalid_response!(tx["id"])))
+        }
+        // Don't trust the RPC to send back valid JSON. If it doesn't,
+        // penalize it so it gets picked less and retry on another one.
+        Ok(Ok(rxa)) => {
+            match serde_json::from_str::<UpstreamResponse>(&rxa) {
+                Ok(response) => {
+                    let code = response.error.and_then(|error| error["code"].as_i64());
+                    match code {
+                        Some(code) if retryable_error_codes.contains(&code) => {
+                            println!(
+                                "\x1b[93mWrn:\x1b[0m RPC {} ({}) returned retryable error {}",
+                                position, rpc.url, code,
+                            );
+                            // Passed on as is if no other RPC does better
+                            Attempt::Failed(Ok(rxa))
+                        }
+                        _ => Attempt::Done(rxa),
+                    }
+                }
+                Err(_) => {
+                    println!(
+                        "\x1b[93mWrn:\x1b[0m RPC {} ({}) returned malformed JSON: {}",
+                        position,
+                        rpc.url,
+                        rxa.chars().take(128).collect::<String>(),
+                    );
+                    Attempt::Failed(Err(invalid_response!(tx["id"])))
+                }
+            }
+        }
+        Err(_) => {
+            println!(
+                "\x1b[93mWrn:\x1b[0m RPC {} ({}) timed out",
+                position, rpc.url
+            );
+            Attempt::Failed(Err(timed_out!(tx["id"])))
+        }
+    }
+}
+
+// Penalize the RPC at `rpc_position` for a bad response or a timeout by adding `ttl` as a latency sample
+fn penalize_rpc(rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize, ttl: Duration) {
+    let rpc_list = rpc_list_rwlock.read().unwrap();
+    if let Some(rpc) = rpc_list.get(rpc_position) {
+        rpc.update_latency(ttl.as_nanos() as f64);
+    }
+}
+
+// Build the HTTP response for a call that errored
+fn error_response(err: ErrorResponse) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    Ok(hyper::Response::builder()
+        .status(err.status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(err.body)))
+        .unwrap())
+}
+
+// Get the response for a single JSON-RPC call from either the cache or RPC nodes.
+//
+// On failure, returns the JSON-RPC error we should send back to the client instead.
+async fn fetch_response(
+    tx: Value,
     rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
     finalized_rx: &tokio::sync::watch::Receiver<u64>,
     named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
-    cache: Arc<Db>,
-    params: RequestParams,
-) -> (
-    Result<hyper::Response<Full<Bytes>>, Infallible>,
-    Option<usize>,
-) {
-    // Check if body has application/json
-    if tx.headers().get("content-type") != Some(&HeaderValue::from_static("application/json")) {
-        return (
-            Ok(hyper::Response::builder()
-                .status(400)
-                .body(Full::new(Bytes::from("Improper content-type header")))
-                .unwrap()),
-            None,
+    cache_args: &CacheArgs,
+    params: &RequestParams,
+    batch: &mut CacheBatch,
+) -> (Result<String, ErrorResponse>, Option<usize>) {
+    // Collect what we want to log before the call gets consumed
+    let request_log = params
+        .debug_logging
+        .then(|| RequestLog::new(&tx, &tx["id"], params.debug_max_params_len));
+
+    let time = Instant::now();
+    let (response, rpc_position) = resolve_call(
+        tx,
+        rpc_list_rwlock,
+        finalized_rx,
+        named_numbers,
+        cache_args,
+        params,
+        batch,
+    )
+    .await;
+
+    if let Some(request_log) = request_log {
+        let response_size = match &response {
+            Ok(rx) => rx.len(),
+            Err(err) => err.body.len(),
+        };
+        println!(
+            "{}",
+            request_log.format(rpc_position, response_size, time.elapsed())
         );
     }
 
-    // Convert incoming body to serde value
-    let mut tx = incoming_to_value(tx).await.unwrap();
+    (response, rpc_position)
+}
+
+// Answer calls about blutgang itself, `blutgang_is_lb` lets clients tell they're talking to one
+fn local_result(tx: &Value) -> Option<&'static str> {
+    match tx["method"].as_str()? {
+        "blutgang_is_lb" | "web3_clientVersion" => Some(VERSION_STR),
+        _ => None,
+    }
+}
+
+// Return the key `tx` is cached under
+fn call_cache_key(tx: &Value, cache_args: &CacheArgs) -> Vec<u8> {
+    // Hash the params with either blake3 or xxh3 depending on `cache.hash`
+    //
+    // We hash the canonical form of the params so semantically identical calls share an entry.
+    // The method goes in the key as is, and the id is left out since it's arbitrary
+    // and does not impact the result.
+    let canonical_params = to_vec(&canonicalize_param(&tx["params"])).unwrap();
+    let params_hash = cache_args.hasher.hash(&canonical_params);
+
+    // Namespace the key by chain so a DB reused across networks never serves another chain's data
+    let method = tx["method"].as_str().unwrap_or_default();
+    cache_key(cache_args.chain_id, method, params_hash.as_bytes())
+}
+
+// Return how long we can cache the response to `tx` for.
+//
+// Non-idempotent methods never touch the cache, so sending the same
+// transaction twice results in two calls to the RPCs. Time-sensitive
+// methods only get cached for a short while.
+//
+// Cacheable calls get their `latest` tag pinned to the current head, see
+// `replace_block_tags`. Until we know the head they skip the cache, and so
+// do calls for the pending block.
+//
+// While the head is provisional, calls are only pinned to look them up in the
+// cache. Misses get forwarded with the tags they came with, see `resolve_call`.
+fn call_policy(
+    tx: &mut Value,
+    params: &RequestParams,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+) -> CachePolicy {
+    let method = tx["method"].as_str().unwrap_or_default();
+    let policy = cache_policy(
+        method,
+        &params.cache_methods,
+        &params.non_idempotent_methods,
+    );
+    if policy == CachePolicy::Never {
+        return policy;
+    }
+
+    match replace_block_tags(tx, &named_numbers.read().unwrap()) {
+        BlockTag::Unresolved | BlockTag::Pending => CachePolicy::Never,
+        _ => policy,
+    }
+}
+
+// Queue whatever calls added to `batch` to be written to the cache.
+//
+// The responses were already resolved, so we don't wait for the write to go through.
+fn apply_batch(batch: CacheBatch, cache_args: &CacheArgs) {
+    cache_args.writer.write(batch, &cache_args.hot_cache);
+}
+
+// Add `rx_value`, the response to `tx`, to `batch` if `policy` allows it.
+//
+// Returns true if the response is going to be cached.
+#[allow(clippy::too_many_arguments)]
+pub fn insert_response(
+    tx: Value,
+    mut rx_value: Value,
+    cache_key: &[u8],
+    policy: CachePolicy,
+    negative_ttl: Option<Duration>,
+    finalized: u64,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+    params: &RequestParams,
+    metrics: &CallMetrics,
+    batch: &mut CacheBatch,
+) -> bool {
+    // The head we loaded from the last run might be gone after a reorg, so nothing
+    // gets written by it until a health check confirms it
+    if named_numbers.read().unwrap().provisional {
+        return false;
+    }
+
+    // Index by method so entries can get flushed with `blutgang_flushCache`
+    let method = tx["method"].as_str().unwrap_or_default().to_string();
+    // Logged for the warmup to replay, see `call_log`
+    let call = (params.call_log_size != 0).then(|| {
+        let mut call = tx.clone();
+        call["id"] = Value::Null;
+        to_vec(&call).unwrap()
+    });
+
+    // Null results, like the receipt of a pending transaction, only get
+    // cached for a short while and are fetched again once they expire.
+    //
+    // The whole response is stored, so a cached null is still a hit.
+    let policy = match negative_ttl {
+        Some(ttl) if is_null_result(&rx_value) => CachePolicy::Ttl(ttl),
+        _ => policy,
+    };
+
+    // When the entry expires, and the block to index it by if it can reorg
+    let (expires_at, block) = match policy {
+        // Spot values get cached regardless of the block they're at.
+        //
+        // They still get indexed by the block they were pinned to so they don't outlive a reorg.
+        CachePolicy::Ttl(ttl) => {
+            let block = get_block_number_from_request(tx, named_numbers);
+            (
+                Some(unix_millis() + ttl.as_millis() as u64),
+                block.filter(|block| *block > finalized),
+            )
+        }
+        CachePolicy::Forever if cache_method(&tx.to_string()) => {
+            // By-hash calls tell us which block they depend on in the response
+            let num = match get_block_number_from_result(&tx, &rx_value) {
+                Some(num) => Some(num),
+                None => get_block_number_from_request(tx, named_numbers),
+            };
+
+            // Blocks close to the head are likely to reorg, so only cache older ones,
+            // or ones that are already finalized.
+            //
+            // Index the key of the request we made by its block
+            // so we can invalidate it and remove it from the DB if it reorgs.
+            let head = named_numbers.read().unwrap().latest;
+            match num
+                .filter(|num| *num <= finalized || !near_head(*num, head, params.finality_distance))
+            {
+                Some(num) => (None, Some(num).filter(|num| *num > finalized)),
+                None => return false,
+            }
+        }
+        _ => return false,
+    };
+
+    rx_value["id"] = Value::Null;
+
+    // Still returned to the client, just not stored
+    let rx_bytes = to_vec(&rx_value).unwrap();
+    if too_large(rx_bytes.len(), params.max_entry_bytes) {
+        metrics.too_large();
+        return false;
+    }
+
+    let rx_bytes = params.compression.encode(rx_bytes);
+    match expires_at {
+        Some(expires_at) => batch.insert_expiring(cache_key, &rx_bytes, expires_at),
+        None => batch.insert(cache_key, &rx_bytes),
+    }
+    if let Some(block) = block {
+        batch.index_block(block, cache_key);
+    }
+    batch.index_method(&method, cache_key);
+    metrics.insert();
+
+    // Entries pinned to a block by a TTL are stale by the time we restart,
+    // so only calls cached forever are worth replaying
+    if let Some(call) = call.filter(|_| expires_at.is_none()) {
+        batch.log_call(&call, params.call_log_size);
+    }
+
+    // Log the write so it can get evicted if the cache grows too large
+    if params.max_cache_size != 0 {
+        batch.record_write(cache_key, rx_bytes.len());
+    }
 
-    // Get the id of the request and set it to 0 for caching
+    true
+}
+
+async fn resolve_call(
+    mut tx: Value,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: &tokio::sync::watch::Receiver<u64>,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+    cache_args: &CacheArgs,
+    params: &RequestParams,
+    batch: &mut CacheBatch,
+) -> (Result<String, ErrorResponse>, Option<usize>) {
+    // Batch entries can be anything, only objects are valid calls
+    if !tx.is_object() {
+        return (Err(invalid_request!(Value::Null)), None);
+    }
+
+    // Take the id of the request and set it to null for caching
     //
     // We're doing this ID gymnastics because we're hashing the
     // whole request and we don't want the ID as it's arbitrary
     // and does not impact the request result.
-    let id = tx["id"].take().as_u64().unwrap_or(0);
+    //
+    // The id is kept as a `Value` so string and null ids are
+    // returned to the client exactly as they were sent.
+    let id = tx["id"].take();
 
-    // Hash the request with either blake3 or xxhash depending on the enabled feature
-    let tx_hash;
-    #[cfg(not(feature = "xxhash"))]
-    {
-        tx_hash = hash(to_vec(&tx).unwrap().as_slice());
+    // Calls about blutgang itself never go upstream
+    if let Some(result) = local_result(&tx) {
+        let rx = json!({"jsonrpc": "2.0", "id": id, "result": result});
+        return (Ok(rx.to_string()), None);
     }
-    #[cfg(feature = "xxhash")]
-    {
-        tx_hash = xxh3_64(to_vec(&tx).unwrap().as_slice());
+
+    // The head loaded from the last run can be far behind, so while it's provisional
+    // we keep the call as it was sent to forward it on a miss
+    let forwarded = named_numbers
+        .read()
+        .unwrap()
+        .provisional
+        .then(|| tx.clone());
+
+    let policy = call_policy(&mut tx, params, named_numbers);
+    let method = tx["method"].as_str().unwrap_or_default();
+
+    // Log ranges get split into chunks that are cached on their own
+    if policy == CachePolicy::Forever {
+        let named = *named_numbers.read().unwrap();
+        let range = match &forwarded {
+            // Ranges that end at the head we loaded would miss the blocks since
+            Some(forwarded) => LogsRange::from_numbered_request(forwarded, named.latest),
+            None => LogsRange::from_request(&tx, named.latest),
+        };
+        if let Some(range) = range {
+            let rx = resolve_logs(
+                &range,
+                id,
+                &named,
+                rpc_list_rwlock,
+                finalized_rx,
+                cache_args,
+                params,
+                batch,
+            )
+            .await;
+
+            // Like batches, this can span multiple calls so we don't return a RPC position
+            return (rx, None);
+        }
     }
 
+    let metrics = cache_args.cache_metrics.call(method);
+    // Sending a transaction again could get it included twice, so those never get retried
+    let max_retries = match is_non_idempotent(method, &params.non_idempotent_methods) {
+        true => 0,
+        false => params.max_retries,
+    };
+    let hedge = params.hedging.for_method(
+        method,
+        &params.non_idempotent_methods,
+        &cache_args.cache_metrics,
+    );
+    let negative_ttl = negative_ttl(method, &params.negative_cache_methods, params.negative_ttl);
+
+    let cache_key = call_cache_key(&tx, cache_args);
+
     // RPC used to get the response, we use it to update the latency for it later.
-    let mut rpc_position;
+    let mut rpc_position = None;
+
+    // Keep the call around in case we end up revalidating a cache hit
+    let revalidate_tx = cache_args.revalidator.enabled().then(|| tx.clone());
+    let mut tx = forwarded.unwrap_or(tx);
+    // Same for checking it against another RPC. Only finalized data should be the same
+    // everywhere, calls by hash don't have a block to check but can't change either.
+    let consensus_tx = (policy == CachePolicy::Forever && params.consensus.sample())
+        .then(|| tx.clone())
+        .filter(|tx| {
+            get_block_number_from_request(tx.clone(), named_numbers)
+                .map_or(true, |block| block <= *finalized_rx.borrow())
+        });
 
     // Get the response from either the DB or from a RPC. If it timeouts, retry.
     let rax = get_response!(
         tx,
-        cache,
-        tx_hash,
+        cache_args.backend,
+        &cache_args.hot_cache,
+        batch,
+        cache_key,
         rpc_position,
         id,
         rpc_list_rwlock,
         finalized_rx,
         named_numbers,
-        head_cache,
         params.ttl,
-        params.max_retries
+        max_retries,
+        policy,
+        &params.permanent_error_codes,
+        negative_ttl,
+        params,
+        hedge,
+        metrics
     );
 
+    // Only cache hits come back without a RPC position
+    if let Some(tx) = revalidate_tx {
+        if rpc_position.is_none() && cache_args.revalidator.sample() {
+            let mut cached: Value = serde_json::from_str(&rax).unwrap();
+            cached["id"] = Value::Null;
+
+            tokio::spawn(revalidate(
+                tx,
+                cached,
+                cache_key,
+                policy,
+                negative_ttl,
+                Arc::clone(rpc_list_rwlock),
+                finalized_rx.clone(),
+                Arc::clone(named_numbers),
+                cache_args.clone(),
+                params.clone(),
+            ));
+        }
+    }
+
+    // Only responses from a RPC get checked, cache hits are what `revalidate` is for
+    if let (Some(tx), Some(position)) = (consensus_tx, rpc_position) {
+        let result = match serde_json::from_str::<Value>(&rax) {
+            Ok(mut rx) => rx["result"].take(),
+            Err(_) => Value::Null,
+        };
+        let requirements = params
+            .routing
+            .requirements(&tx, &named_numbers.read().unwrap());
+
+        tokio::spawn(check_consensus(
+            tx,
+            result,
+            position,
+            Arc::clone(rpc_list_rwlock),
+            requirements,
+            params.clone(),
+        ));
+    }
+
+    (Ok(rax), rpc_position)
+}
+
+// Get the response for a call or a batch of calls and build the HTTP response.
+pub async fn forward_value(
+    tx: Value,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: &tokio::sync::watch::Receiver<u64>,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+    cache_args: &CacheArgs,
+    params: RequestParams,
+) -> (
+    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Option<usize>,
+) {
+    let rax;
+    let rpc_position;
+
+    match tx {
+        Value::Array(mut calls) => {
+            // An empty batch gets a single error back, not an empty array
+            if calls.is_empty() {
+                return (error_response(invalid_request!(Value::Null)), None);
+            }
+
+            // Read every cacheable call from the DB at once instead of one by one.
+            //
+            // Tags get pinned here so calls are read under the same key they get resolved with.
+            let mut batch = CacheBatch::default();
+            //
+            // While the head is provisional calls keep their tags, see `resolve_call`.
+            let provisional = named_numbers.read().unwrap().provisional;
+            let keys: Vec<Vec<u8>> = calls
+                .iter_mut()
+                .filter(|call| call.is_object())
+                .filter_map(|call| {
+                    let mut pinned;
+                    let call = match provisional {
+                        true => {
+                            pinned = call.clone();
+                            &mut pinned
+                        }
+                        false => call,
+                    };
+                    let policy = call_policy(call, &params, named_numbers);
+                    (policy != CachePolicy::Never).then(|| call_cache_key(call, cache_args))
+                })
+                .collect();
+            if batch
+                .prefetch(
+                    cache_args.backend.as_ref(),
+                    &cache_args.hot_cache,
+                    keys.iter().map(Vec::as_slice),
+                )
+                .await
+                .is_err()
+            {
+                print_cache_error!();
+            }
+
+            // Process every call in order and join the responses into an array.
+            //
+            // We don't return an RPC position for batches since the total time
+            // spans multiple calls and would not be a fair latency sample.
+            let mut responses = Vec::with_capacity(calls.len());
+            for call in calls {
+                match fetch_response(
+                    call,
+                    rpc_list_rwlock,
+                    finalized_rx,
+                    named_numbers,
+                    cache_args,
+                    &params,
+                    &mut batch,
+                )
+                .await
+                {
+                    // Errors are just regular entries in the batch response
+                    (Ok(rx), _) => responses.push(rx),
+                    (Err(err), _) => responses.push(err.body),
+                }
+            }
+            apply_batch(batch, cache_args);
+
+            rax = format!("[{}]", responses.join(","));
+            rpc_position = None;
+        }
+        tx => {
+            let mut batch = CacheBatch::default();
+            let response = fetch_response(
+                tx,
+                rpc_list_rwlock,
+                finalized_rx,
+                named_numbers,
+                cache_args,
+                &params,
+                &mut batch,
+            )
+            .await;
+            apply_batch(batch, cache_args);
+
+            match response {
+                (Ok(rx), position) => {
+                    rax = rx;
+                    rpc_position = position;
+                }
+                (Err(err), position) => return (error_response(err), position),
+            }
+        }
+    }
+
     // Convert rx to bytes and but it in a Buf
     let body = hyper::body::Bytes::from(rax);
 
@@ -293,29 +1180,67 @@ async fn forward_body(
     (Ok(res), rpc_position)
 }
 
-// Forward the request to *a* RPC picked by the algo set by the user.
-// Measures the time needed for a request, and updates the respective
-// RPC lself.
-// In case of a timeout, returns an error.
-pub async fn accept_request(
+// Pick RPC and send request to it. In case the result is cached,
+// read and return from the cache.
+async fn forward_body(
     tx: Request<hyper::body::Incoming>,
-    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
     finalized_rx: &tokio::sync::watch::Receiver<u64>,
     named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
-    cache: Arc<Db>,
-    config: &Arc<RwLock<Settings>>,
-) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
-    // Send request and measure time
-    let response: Result<hyper::Response<Full<Bytes>>, Infallible>;
-    let rpc_position: Option<usize>;
-
-    // RequestParams from config
+    cache_args: &CacheArgs,
+    params: RequestParams,
+) -> (
+    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Option<usize>,
+) {
+    // Check if body has application/json
+    if tx.headers().get("content-type") != Some(&HeaderValue::from_static("application/json")) {
+        return (
+            Ok(hyper::Response::builder()
+                .status(400)
+                .body(Full::new(Bytes::from("Improper content-type header")))
+                .unwrap()),
+            None,
+        );
+    }
+
+    // Convert incoming body to serde value
+    let tx = incoming_to_value(tx).await.unwrap();
+
+    forward_value(
+        tx,
+        rpc_list_rwlock,
+        finalized_rx,
+        named_numbers,
+        cache_args,
+        params,
+    )
+    .await
+}
+
+// Forward the request to *a* RPC picked by the algo set by the user.
+// Measures the time needed for a request, and updates the respective
+// RPC lself.
+// In case of a timeout, returns an error.
+pub async fn accept_request(
+    tx: Request<hyper::body::Incoming>,
+    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: &tokio::sync::watch::Receiver<u64>,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+    cache_args: &CacheArgs,
+    config: &Arc<RwLock<Settings>>,
+    client: IpAddr,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    // Send request and measure time
+    let response: Result<hyper::Response<Full<Bytes>>, Infallible>;
+    let rpc_position: Option<usize>;
+
+    // RequestParams from config. Calls from the same client can stick to the same RPC.
     let params = {
-        let config_guard = config.read().unwrap();
+        let config = config.read().unwrap();
         RequestParams {
-            ttl: config_guard.ttl,
-            max_retries: config_guard.max_retries,
+            sticky_key: config.sticky_sessions.then(|| sticky_key(client)),
+            ..RequestParams::new(&config)
         }
     };
 
@@ -329,8 +1254,7 @@ pub async fn accept_request(
         &rpc_list_rwlock,
         finalized_rx,
         named_numbers,
-        head_cache,
-        cache,
+        cache_args,
         params,
     )
     .await;
@@ -343,14 +1267,18 @@ pub async fn accept_request(
     // Here, we update the latency of the RPC that was used to process the request
     // if `rpc_position` is Some.
     if let Some(rpc_position) = rpc_position {
-        let mut rpc_list_guard = rpc_list_rwlock.write().unwrap_or_else(|e| {
+        // Latencies are shared between clones of a RPC, so a read lock is enough
+        let rpc_list_guard = rpc_list_rwlock.read().unwrap_or_else(|e| {
             // Handle the case where the RwLock is poisoned
             e.into_inner()
         });
 
         // Handle weird edge cases ¯\_(ツ)_/¯
         if rpc_list_guard.is_empty() {
-            println!("LA {}", rpc_list_guard[rpc_position].status.latency);
+            println!(
+                "LA {}",
+                rpc_list_guard[rpc_position].status.latency.average()
+            );
         } else {
             let index = if rpc_position >= rpc_list_guard.len() {
                 rpc_list_guard.len() - 1
@@ -358,9 +1286,1824 @@ pub async fn accept_request(
                 rpc_position
             };
             rpc_list_guard[index].update_latency(time.as_nanos() as f64);
-            println!("LA {}", rpc_list_guard[index].status.latency);
+            println!("LA {}", rpc_list_guard[index].status.latency.average());
         }
     }
 
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::{
+            block_index::invalidate_from,
+            chain_id::method_key_prefix,
+            hot_cache::sync_hot_cache,
+            sled_backend::SledBackend,
+        },
+        health::safe_block::{
+            load_named_numbers,
+            persist_named_numbers,
+        },
+        rpc::mock::{
+            mock_rpc,
+            mock_rpc_delayed,
+            mock_rpc_raw,
+        },
+    };
+    use http_body_util::BodyExt;
+    use serde_json::json;
+    use sled::Db;
+
+    // Send `tx` through the balancer with a single RPC and return the response body
+    async fn forward(tx: Value, url: &str) -> (u16, Value) {
+        forward_with(tx, vec![Rpc::new(url.to_string(), 10, 5.0)]).await
+    }
+
+    // Send `tx` through the balancer using `rpc_list` and return the response body
+    async fn forward_with(tx: Value, rpc_list: Vec<Rpc>) -> (u16, Value) {
+        TestBalancer::new(rpc_list).forward(tx).await
+    }
+
+    // Shared balancer state so we can send multiple requests against the same cache
+    struct TestBalancer {
+        rpc_list: Arc<RwLock<Vec<Rpc>>>,
+        cache: Arc<Db>,
+        finalized_tx: tokio::sync::watch::Sender<u64>,
+        finalized_rx: tokio::sync::watch::Receiver<u64>,
+        named_numbers: Arc<RwLock<NamedBlocknumbers>>,
+        cache_args: CacheArgs,
+        cache_methods: HashMap<String, CachePolicy>,
+        compression: Compression,
+        negative_ttl: Duration,
+        max_entry_bytes: usize,
+        // Off unless a test is about the request log, so test output stays readable
+        debug_logging: bool,
+        selection: SelectionStrategy,
+        routing: RoutingRules,
+        hedging: HedgeSettings,
+        consensus: ConsensusSettings,
+        // Queued writes get applied after every request unless taken
+        writes: std::sync::Mutex<Option<tokio::sync::mpsc::Receiver<CacheBatch>>>,
+    }
+
+    impl TestBalancer {
+        fn new(rpc_list: Vec<Rpc>) -> Self {
+            let (finalized_tx, finalized_rx) = tokio::sync::watch::channel(0);
+            let (writer, writes) = CacheWriter::new(1024);
+            let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+
+            Self {
+                rpc_list: Arc::new(RwLock::new(rpc_list)),
+                cache: Arc::clone(&cache),
+                finalized_tx,
+                finalized_rx,
+                named_numbers: Arc::new(RwLock::new(NamedBlocknumbers::default())),
+                cache_args: CacheArgs {
+                    backend: Arc::new(SledBackend::new(cache)),
+                    hot_cache: Arc::new(HotCache::new(1024)),
+                    cache_metrics: Arc::new(CacheMetrics::default()),
+                    chain_id: 1,
+                    hasher: CacheHasher::default(),
+                    writer,
+                    revalidator: Arc::new(Revalidator::default()),
+                },
+                cache_methods: HashMap::new(),
+                compression: Compression::None,
+                negative_ttl: Duration::from_secs(2),
+                max_entry_bytes: 0,
+                debug_logging: false,
+                selection: SelectionStrategy::default(),
+                routing: RoutingRules::default(),
+                hedging: HedgeSettings::default(),
+                consensus: ConsensusSettings::default(),
+                writes: std::sync::Mutex::new(Some(writes)),
+            }
+        }
+
+        async fn forward(&self, tx: Value) -> (u16, Value) {
+            let params = RequestParams {
+                ttl: 1000,
+                max_retries: 2,
+                retryable_error_codes: vec![-32603],
+                non_idempotent_methods: Vec::new(),
+                cache_methods: self.cache_methods.clone(),
+                permanent_error_codes: Vec::new(),
+                debug_logging: self.debug_logging,
+                debug_max_params_len: 128,
+                max_cache_size: 0,
+                compression: self.compression,
+                finality_distance: 64,
+                negative_cache_methods: vec!["eth_getTransactionReceipt".to_string()],
+                negative_ttl: self.negative_ttl,
+                max_entry_bytes: self.max_entry_bytes,
+                call_log_size: 0,
+                selection: self.selection,
+                sticky_key: None,
+                routing: self.routing.clone(),
+                hedging: self.hedging.clone(),
+                consensus: self.consensus.clone(),
+            };
+
+            let (response, _) = forward_value(
+                tx,
+                &self.rpc_list,
+                &self.finalized_rx,
+                &self.named_numbers,
+                &self.cache_args,
+                params,
+            )
+            .await;
+
+            let batches: Vec<_> = match self.writes.lock().unwrap().as_mut() {
+                Some(writes) => std::iter::from_fn(|| writes.try_recv().ok()).collect(),
+                None => Vec::new(),
+            };
+            for batch in batches {
+                batch
+                    .apply(&self.cache, self.cache_args.backend.as_ref())
+                    .await
+                    .unwrap();
+            }
+
+            let response = response.unwrap();
+            let status = response.status().as_u16();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_preserves_id() {
+        let url = mock_rpc(|_| json!("0x1")).await;
+
+        for id in [json!("abc-123"), Value::Null, json!(42)] {
+            let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_chainId", "params": []});
+            let (status, rx) = forward(tx, &url).await;
+
+            assert_eq!(status, 200);
+            assert_eq!(rx["id"], id);
+            assert_eq!(rx["result"], "0x1");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_batch() {
+        let url = mock_rpc(|tx| {
+            match tx["method"].as_str() {
+                Some("eth_blockNumber") => json!("0x10"),
+                Some("eth_chainId") => json!("0x1"),
+                _ => Value::Null,
+            }
+        })
+        .await;
+
+        let tx = json!([
+            {"jsonrpc": "2.0", "id": "a", "method": "eth_blockNumber", "params": []},
+            {"jsonrpc": "2.0", "id": 2, "method": "eth_chainId", "params": []},
+            1,
+        ]);
+        let (status, rx) = forward(tx, &url).await;
+
+        assert_eq!(status, 200);
+        let rx = rx.as_array().unwrap();
+        assert_eq!(rx.len(), 3);
+        assert_eq!(rx[0]["id"], "a");
+        assert_eq!(rx[0]["result"], "0x10");
+        assert_eq!(rx[1]["id"], 2);
+        assert_eq!(rx[1]["result"], "0x1");
+        assert_eq!(rx[2]["error"]["code"], -32600);
+    }
+
+    #[tokio::test]
+    async fn test_forward_empty_batch() {
+        let url = mock_rpc(|_| Value::Null).await;
+
+        let (status, rx) = forward(json!([]), &url).await;
+
+        assert_eq!(status, 400);
+        assert_eq!(rx["id"], Value::Null);
+        assert_eq!(rx["error"]["code"], -32600);
+    }
+
+    #[tokio::test]
+    async fn test_forward_no_rpc_available() {
+        let tx = json!({"jsonrpc": "2.0", "id": "abc-123", "method": "eth_chainId", "params": []});
+        let (status, rx) = forward_with(tx, Vec::new()).await;
+
+        assert_eq!(status, 500);
+        assert_eq!(rx["id"], "abc-123");
+        assert_eq!(rx["error"]["code"], -32002);
+
+        // Batches get an error entry per call
+        let tx = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []},
+            {"jsonrpc": "2.0", "id": 2, "method": "eth_chainId", "params": []},
+        ]);
+        let (status, rx) = forward_with(tx, Vec::new()).await;
+
+        assert_eq!(status, 200);
+        assert_eq!(rx[0]["id"], 1);
+        assert_eq!(rx[0]["error"]["code"], -32002);
+        assert_eq!(rx[1]["id"], 2);
+        assert_eq!(rx[1]["error"]["code"], -32002);
+    }
+
+    #[tokio::test]
+    async fn test_forward_malformed_json() {
+        let bad = mock_rpc_raw(|_| "not json".to_string()).await;
+        let good = mock_rpc(|_| json!("0x1")).await;
+
+        // The malformed response gets retried on the other RPC
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+        let rpc_list = vec![
+            Rpc::new(bad.clone(), 10, 5.0),
+            Rpc::new(good.to_string(), 10, 5.0),
+        ];
+        let (status, rx) = forward_with(tx.clone(), rpc_list).await;
+
+        assert_eq!(status, 200);
+        assert_eq!(rx["id"], 1);
+        assert_eq!(rx["result"], "0x1");
+
+        // If every RPC sends garbage we get an error back instead of a panic
+        let (status, rx) = forward(tx, &bad).await;
+
+        assert_eq!(status, 502);
+        assert_eq!(rx["id"], 1);
+        assert_eq!(rx["error"]["code"], -32603);
+    }
+
+    #[tokio::test]
+    async fn test_send_upstream_least_connections() {
+        let slow = mock_rpc_delayed(Duration::from_secs(2), |_| json!("0x1")).await;
+        let fast = mock_rpc(|_| json!("0x1")).await;
+
+        // The slow RPC looks faster going by latency
+        let rpc_list = vec![Rpc::new(slow, 10, 5.0), Rpc::new(fast, 10, 5.0)];
+        rpc_list[0].status.latency.set(1.0);
+        rpc_list[1].status.latency.set(2.0);
+        let rpc_list = Arc::new(RwLock::new(rpc_list));
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        let send = |rpc_list: Arc<RwLock<Vec<Rpc>>>, tx: Value| {
+            async move {
+                let mut rpc_position = None;
+                let rx = send_upstream(
+                    &tx,
+                    &rpc_list,
+                    5000,
+                    1,
+                    &[],
+                    SelectionStrategy::LeastConnections,
+                    None,
+                    Requirements::default(),
+                    Hedge::default(),
+                    &mut rpc_position,
+                )
+                .await;
+                assert!(rx.is_ok());
+                rpc_position
+            }
+        };
+
+        let stalled = tokio::spawn(send(Arc::clone(&rpc_list), tx.clone()));
+        while rpc_list.read().unwrap()[0].in_flight() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // New traffic goes around the stalled RPC right away
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(send(Arc::clone(&rpc_list), tx.clone()).await, Some(1));
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        assert_eq!(stalled.await.unwrap(), Some(0));
+        assert_eq!(rpc_list.read().unwrap()[0].in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_forward_archive_routing() {
+        use crate::balancer::selection::routing::ArchiveRule;
+
+        let archive = mock_rpc(|_| json!("archive")).await;
+        let pruned = mock_rpc(|_| json!("pruned")).await;
+        let routing = RoutingRules {
+            archive: HashMap::from([("eth_call".to_string(), ArchiveRule::OlderThan(128))]),
+            ..Default::default()
+        };
+
+        let mut balancer = TestBalancer::new(vec![
+            Rpc::new(pruned.clone(), 10, 5.0),
+            Rpc::new(archive, 10, 5.0).with_archive(true),
+        ]);
+        balancer.routing = routing.clone();
+        balancer.named_numbers.write().unwrap().latest = 1000;
+
+        let call = |block: u64| {
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": [{"to": "0x0000000000000000000000000000000000000001"}, format!("0x{:x}", block)]})
+        };
+
+        // Historical calls only go to the archive node
+        for block in 1..20 {
+            let (status, rx) = balancer.forward(call(block)).await;
+            assert_eq!(status, 200);
+            assert_eq!(rx["result"], "archive");
+        }
+
+        // Recent ones can go anywhere
+        let mut answered = std::collections::HashSet::new();
+        for block in 950..1000 {
+            let (status, rx) = balancer.forward(call(block)).await;
+            assert_eq!(status, 200);
+            answered.insert(rx["result"].as_str().unwrap().to_string());
+        }
+        assert!(answered.contains("pruned"));
+
+        // Without an archive node, historical calls error instead of going to a pruned one
+        let mut balancer = TestBalancer::new(vec![Rpc::new(pruned, 10, 5.0)]);
+        balancer.routing = routing;
+        balancer.named_numbers.write().unwrap().latest = 1000;
+
+        let (status, rx) = balancer.forward(call(1)).await;
+        assert_eq!(status, 500);
+        assert_eq!(rx["error"]["code"], -32004);
+        let (status, rx) = balancer.forward(call(999)).await;
+        assert_eq!(status, 200);
+        assert_eq!(rx["result"], "pruned");
+    }
+
+    #[tokio::test]
+    async fn test_forward_debug_logging() {
+        let url = mock_rpc(|tx| {
+            match tx["method"].as_str() {
+                Some("eth_sendRawTransaction") => json!("0xabc"),
+                _ => json!("0x1"),
+            }
+        })
+        .await;
+        let mut balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.debug_logging = true;
+
+        // Logged with the raw transaction redacted, see `request_log`, and answered as usual
+        let (status, rx) = balancer
+            .forward(json!({"jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": ["0xf86c0a8502540be400"]}))
+            .await;
+        assert_eq!(status, 200);
+        assert_eq!(rx["result"], "0xabc");
+
+        let (_, rx) = balancer
+            .forward(json!([{"jsonrpc": "2.0", "id": 2, "method": "eth_chainId", "params": []}]))
+            .await;
+        assert_eq!(rx[0]["result"], "0x1");
+    }
+
+    #[tokio::test]
+    async fn test_forward_local_result() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_rpc = Arc::clone(&requests);
+        let url = mock_rpc(move |_| {
+            requests_rpc.fetch_add(1, Ordering::Relaxed);
+            json!("upstream")
+        })
+        .await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+
+        for method in ["blutgang_is_lb", "web3_clientVersion"] {
+            let (status, rx) = balancer
+                .forward(json!({"jsonrpc": "2.0", "id": 7, "method": method, "params": []}))
+                .await;
+            assert_eq!(status, 200);
+            assert_eq!(rx["id"], 7);
+            assert_eq!(rx["result"], VERSION_STR);
+        }
+
+        // Batches too
+        let (_, rx) = balancer
+            .forward(json!([{"jsonrpc": "2.0", "id": 1, "method": "blutgang_is_lb"}]))
+            .await;
+        assert_eq!(rx[0]["result"], VERSION_STR);
+        assert_eq!(requests.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_forward_label_routing() {
+        let debug = mock_rpc(|_| json!("debug")).await;
+        let plain = mock_rpc(|_| json!("plain")).await;
+        let routing = RoutingRules {
+            labels: HashMap::from([("debug_".to_string(), vec!["debug".to_string()])]),
+            ..Default::default()
+        };
+
+        let mut balancer = TestBalancer::new(vec![
+            Rpc::new(plain.clone(), 10, 5.0),
+            Rpc::new(debug, 10, 5.0).with_labels(vec!["debug".to_string(), "trace".to_string()]),
+        ]);
+        balancer.routing = routing.clone();
+
+        let trace = json!({"jsonrpc": "2.0", "id": 1, "method": "debug_traceTransaction", "params": ["0x0"]});
+        let chain_id = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_chainId", "params": []});
+
+        // Debug calls only go to the labeled node
+        for _ in 0..20 {
+            let (status, rx) = balancer.forward(trace.clone()).await;
+            assert_eq!(status, 200);
+            assert_eq!(rx["result"], "debug");
+        }
+
+        // Everything else can go anywhere
+        let mut answered = std::collections::HashSet::new();
+        for _ in 0..20 {
+            let (status, rx) = balancer.forward(chain_id.clone()).await;
+            assert_eq!(status, 200);
+            answered.insert(rx["result"].as_str().unwrap().to_string());
+        }
+        assert!(answered.contains("plain"));
+
+        // Without a labeled node, debug calls error instead of going to one that can't serve them
+        let mut balancer = TestBalancer::new(vec![Rpc::new(plain, 10, 5.0)]);
+        balancer.routing = routing;
+
+        let (status, rx) = balancer.forward(trace).await;
+        assert_eq!(status, 500);
+        assert_eq!(rx["error"]["code"], -32004);
+        assert!(rx["error"]["message"]
+            .as_str()
+            .unwrap()
+            .ends_with("it needs: debug"));
+        let (status, rx) = balancer.forward(chain_id).await;
+        assert_eq!(status, 200);
+        assert_eq!(rx["result"], "plain");
+    }
+
+    #[tokio::test]
+    async fn test_send_upstream_circuit_breaker() {
+        use crate::rpc::circuit::{
+            CircuitSettings,
+            CircuitState,
+        };
+        use std::sync::atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        };
+
+        // Answers with garbage until it's healthy again
+        let healthy = Arc::new(AtomicBool::new(false));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (healthy_rpc, calls_rpc) = (Arc::clone(&healthy), Arc::clone(&calls));
+        let flaky = mock_rpc_raw(move |tx| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            if healthy_rpc.load(Ordering::SeqCst) {
+                json!({"jsonrpc": "2.0", "id": tx["id"], "result": "0x1"}).to_string()
+            } else {
+                "<html>502 Bad Gateway</html>".to_string()
+            }
+        })
+        .await;
+        let good = mock_rpc(|_| json!("0x1")).await;
+
+        let cooldown = Duration::from_millis(200);
+        let settings = CircuitSettings {
+            failure_threshold: 2,
+            window: Duration::from_secs(10),
+            cooldown,
+        };
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(flaky, 10, 5.0).with_circuit(settings),
+            Rpc::new(good, 10, 5.0).with_circuit(settings),
+        ]));
+        let state = || rpc_list.read().unwrap()[0].circuit.state();
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        // Without retries, so each call only goes to a single RPC
+        let send = || {
+            async {
+                let mut rpc_position = None;
+                let rx = send_upstream(
+                    &tx,
+                    &rpc_list,
+                    1000,
+                    0,
+                    &[],
+                    SelectionStrategy::RoundRobin,
+                    None,
+                    Requirements::default(),
+                    Hedge::default(),
+                    &mut rpc_position,
+                )
+                .await;
+                (rx.is_ok(), rpc_position)
+            }
+        };
+
+        // Closed -> open after 2 failures in a row
+        assert_eq!(send().await, (false, Some(0)));
+        assert_eq!(send().await, (true, Some(1)));
+        assert_eq!(state(), CircuitState::Closed);
+        assert_eq!(send().await, (false, Some(0)));
+        assert_eq!(state(), CircuitState::Open);
+
+        // No more requests go to it until the cooldown is over
+        for _ in 0..5 {
+            assert_eq!(send().await, (true, Some(1)));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // A failed probe opens it again
+        tokio::time::sleep(cooldown).await;
+        assert_eq!(send().await, (false, Some(0)));
+        assert_eq!(state(), CircuitState::Open);
+        assert_eq!(send().await, (true, Some(1)));
+
+        // Only the probe goes through while it's half open
+        healthy.store(true, Ordering::SeqCst);
+        tokio::time::sleep(cooldown).await;
+        let probe = pick(
+            &rpc_list.read().unwrap(),
+            SelectionStrategy::RoundRobin,
+            &Requirements::default(),
+            &[],
+        );
+        assert_eq!(probe, Some(0));
+        assert_eq!(state(), CircuitState::HalfOpen);
+        assert_eq!(send().await, (true, Some(1)));
+        assert_eq!(send().await, (true, Some(1)));
+
+        // The probe never came back, so another one gets sent. It succeeds and closes the circuit.
+        tokio::time::sleep(cooldown).await;
+        assert_eq!(send().await, (true, Some(0)));
+        assert_eq!(state(), CircuitState::Closed);
+        assert_eq!(send().await, (true, Some(1)));
+        assert_eq!(send().await, (true, Some(0)));
+    }
+
+    #[tokio::test]
+    async fn test_send_upstream_rate_limit() {
+        use std::sync::Mutex;
+
+        // Keep track of when requests show up at the limited RPC
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let arrivals_rpc = Arc::clone(&arrivals);
+        let limited = mock_rpc(move |_| {
+            arrivals_rpc.lock().unwrap().push(Instant::now());
+            json!("0x1")
+        })
+        .await;
+        let unlimited = mock_rpc(|_| json!("0x1")).await;
+
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(limited, u32::MAX, 5.0).with_max_rps(5),
+            Rpc::new(unlimited, u32::MAX, 5.0),
+        ]));
+
+        // ~100 requests per second for 2 seconds
+        let mut handles = Vec::new();
+        for id in 0..200 {
+            let rpc_list = Arc::clone(&rpc_list);
+            handles.push(tokio::spawn(async move {
+                let tx =
+                    json!({"jsonrpc": "2.0", "id": id, "method": "eth_blockNumber", "params": []});
+                send_upstream(
+                    &tx,
+                    &rpc_list,
+                    1000,
+                    1,
+                    &[],
+                    SelectionStrategy::RoundRobin,
+                    None,
+                    Requirements::default(),
+                    Hedge::default(),
+                    &mut None,
+                )
+                .await
+            }));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for handle in handles {
+            assert!(handle.await.unwrap().is_ok());
+        }
+
+        // Never more than 5 in a second, but it still got its share
+        let arrivals = arrivals.lock().unwrap();
+        for (i, start) in arrivals.iter().enumerate() {
+            let in_window = arrivals[i..]
+                .iter()
+                .filter(|arrival| arrival.duration_since(*start) < Duration::from_secs(1))
+                .count();
+            assert!(in_window <= 5, "{} requests within a second", in_window);
+        }
+        assert!(arrivals.len() >= 8, "only {} requests", arrivals.len());
+    }
+
+    #[tokio::test]
+    async fn test_send_upstream_rate_limit_wait() {
+        let rpc = mock_rpc(|_| json!("0x1")).await;
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(rpc, u32::MAX, 5.0).with_max_rps(2)
+        ]));
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        let send = |ttl| {
+            let (tx, rpc_list) = (&tx, &rpc_list);
+            async move {
+                send_upstream(
+                    tx,
+                    rpc_list,
+                    ttl,
+                    1,
+                    &[],
+                    SelectionStrategy::RoundRobin,
+                    None,
+                    Requirements::default(),
+                    Hedge::default(),
+                    &mut None,
+                )
+                .await
+            }
+        };
+
+        // The next token is 500ms away, too long to wait for with a ttl of 100ms
+        assert!(send(100).await.is_ok());
+        assert_eq!(send(100).await, Err(rate_limited!(tx["id"])));
+
+        // But fine with a ttl of 1s
+        let start = Instant::now();
+        assert!(send(1000).await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_send_upstream_max_concurrent() {
+        use crate::rpc::mock::mock_rpc_loaded;
+
+        let send_all = |rpc_list: Arc<RwLock<Vec<Rpc>>>, count, ttl| {
+            async move {
+                let mut handles = Vec::new();
+                for id in 0..count {
+                    let rpc_list = Arc::clone(&rpc_list);
+                    handles.push(tokio::spawn(async move {
+                        let tx = json!({"jsonrpc": "2.0", "id": id, "method": "eth_blockNumber", "params": []});
+                        send_upstream(
+                            &tx,
+                            &rpc_list,
+                            ttl,
+                            0,
+                            &[],
+                            SelectionStrategy::LowestLatency,
+                            None,
+                            Requirements::default(),
+                            Hedge::default(),
+                            &mut None,
+                        )
+                        .await
+                    }));
+                }
+                let mut results = Vec::new();
+                for handle in handles {
+                    results.push(handle.await.unwrap());
+                }
+                results
+            }
+        };
+
+        // The fastest RPC only gets 3 calls at a time, the rest go to the other one
+        let (capped, capped_load) =
+            mock_rpc_loaded(Duration::from_millis(100), |_| json!("0x1")).await;
+        let (other, other_load) =
+            mock_rpc_loaded(Duration::from_millis(100), |_| json!("0x1")).await;
+        let rpc_list = vec![
+            Rpc::new(capped, u32::MAX, 5.0).with_max_concurrent(3),
+            Rpc::new(other, u32::MAX, 5.0),
+        ];
+        rpc_list[0].status.latency.set(1.0);
+        rpc_list[1].status.latency.set(2.0);
+        let results = send_all(Arc::new(RwLock::new(rpc_list)), 20, 1000).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(capped_load.peak(), 3);
+        assert!(other_load.peak() > 0);
+
+        // Once every RPC is busy, calls wait their turn until the ttl runs out:
+        // 2 at 0ms, 2 at 200ms, 2 at 400ms, and 2 that would have to wait until 600ms
+        let (capped, capped_load) =
+            mock_rpc_loaded(Duration::from_millis(200), |_| json!("0x1")).await;
+        let rpc_list = vec![Rpc::new(capped, u32::MAX, 5.0).with_max_concurrent(2)];
+        let results = send_all(Arc::new(RwLock::new(rpc_list)), 8, 500).await;
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 6);
+        assert!(results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .all(|err| err.status == 429));
+        assert_eq!(capped_load.peak(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_upstream_retry() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let restarting_calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&restarting_calls);
+        let restarting = mock_rpc_raw(move |tx| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            json!({"jsonrpc": "2.0", "id": tx["id"], "error": {"code": -32603, "message": "internal error"}})
+                .to_string()
+        })
+        .await;
+        let good_calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&good_calls);
+        let good = mock_rpc(move |_| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            json!("0x1")
+        })
+        .await;
+
+        // Gets tried in order: nothing listening, then restarting, then good
+        let rpc_list = |urls: &[&String]| {
+            let rpc_list = urls
+                .iter()
+                .enumerate()
+                .map(|(position, url)| {
+                    let rpc = Rpc::new(url.to_string(), u32::MAX, 5.0);
+                    rpc.status.latency.set(position as f64 + 1.0);
+                    rpc
+                })
+                .collect();
+            Arc::new(RwLock::new(rpc_list))
+        };
+        let down = "http://127.0.0.1:1".to_string();
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        let send = |rpc_list: Arc<RwLock<Vec<Rpc>>>, max_retries| {
+            let tx = &tx;
+            async move {
+                let mut rpc_position = None;
+                let rx = send_upstream(
+                    tx,
+                    &rpc_list,
+                    1000,
+                    max_retries,
+                    &[-32603],
+                    SelectionStrategy::LowestLatency,
+                    None,
+                    Requirements::default(),
+                    Hedge::default(),
+                    &mut rpc_position,
+                )
+                .await;
+                (rx, rpc_position)
+            }
+        };
+
+        // Succeeds on the third RPC
+        let (rx, rpc_position) = send(rpc_list(&[&down, &restarting, &good]), 2).await;
+        let rx: Value = serde_json::from_str(&rx.unwrap()).unwrap();
+        assert_eq!(rx["result"], "0x1");
+        assert_eq!(rpc_position, Some(2));
+        assert_eq!(restarting_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(good_calls.load(Ordering::SeqCst), 1);
+
+        // Out of retries, so the last error gets passed on
+        let (rx, rpc_position) = send(rpc_list(&[&down, &restarting, &good]), 1).await;
+        let rx: Value = serde_json::from_str(&rx.unwrap()).unwrap();
+        assert_eq!(rx["error"]["code"], -32603);
+        assert_eq!(rpc_position, Some(1));
+        assert_eq!(restarting_calls.load(Ordering::SeqCst), 2);
+        assert_eq!(good_calls.load(Ordering::SeqCst), 1);
+
+        // Out of RPCs, a call never goes to the same RPC twice
+        let (rx, _) = send(rpc_list(&[&restarting, &down]), 32).await;
+        assert_eq!(rx, Err(invalid_response!(tx["id"])));
+        assert_eq!(restarting_calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_send_upstream_hedge() {
+        let slow = mock_rpc_delayed(Duration::from_millis(500), |_| json!("0x2")).await;
+        let fast = mock_rpc(|_| json!("0x1")).await;
+
+        // The slow RPC gets picked first
+        let rpc_list = vec![Rpc::new(slow, u32::MAX, 5.0), Rpc::new(fast, u32::MAX, 5.0)];
+        rpc_list[0].status.latency.set(1.0);
+        rpc_list[1].status.latency.set(2.0);
+        let rpc_list = Arc::new(RwLock::new(rpc_list));
+
+        let metrics = CacheMetrics::default();
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        let send = |hedge| {
+            let (tx, rpc_list) = (&tx, &rpc_list);
+            async move {
+                let mut rpc_position = None;
+                let start = Instant::now();
+                let rx = send_upstream(
+                    tx,
+                    rpc_list,
+                    1000,
+                    0,
+                    &[],
+                    SelectionStrategy::LowestLatency,
+                    None,
+                    Requirements::default(),
+                    hedge,
+                    &mut rpc_position,
+                )
+                .await;
+                let rx: Value = serde_json::from_str(&rx.unwrap()).unwrap();
+                (rx["result"].clone(), rpc_position, start.elapsed())
+            }
+        };
+
+        // The hedge answers first
+        let hedge = Hedge {
+            delay: Duration::from_millis(20),
+            max_hedges: 1,
+            metrics: Some(&metrics),
+        };
+        let (result, rpc_position, elapsed) = send(hedge).await;
+        assert_eq!(result, "0x1");
+        assert_eq!(rpc_position, Some(1));
+        assert!(elapsed < Duration::from_millis(400));
+        assert_eq!(
+            metrics.to_json(false)["hedges"],
+            json!({"sent": 1, "won": 1})
+        );
+
+        // The call to the slow RPC got cancelled
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(rpc_list.read().unwrap()[0].in_flight(), 0);
+
+        // Without hedging we wait on the slow RPC
+        let (result, rpc_position, elapsed) = send(Hedge::default()).await;
+        assert_eq!(result, "0x2");
+        assert_eq!(rpc_position, Some(0));
+        assert!(elapsed >= Duration::from_millis(500));
+        assert_eq!(
+            metrics.to_json(false)["hedges"],
+            json!({"sent": 1, "won": 1})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_non_idempotent_not_retried() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut rpc_list = Vec::new();
+        for _ in 0..2 {
+            let calls_rpc = Arc::clone(&calls);
+            let url = mock_rpc_raw(move |tx| {
+                calls_rpc.fetch_add(1, Ordering::SeqCst);
+                json!({"jsonrpc": "2.0", "id": tx["id"], "error": {"code": -32603, "message": "internal error"}})
+                    .to_string()
+            })
+            .await;
+            rpc_list.push(Rpc::new(url, 10, 5.0));
+        }
+        let balancer = TestBalancer::new(rpc_list);
+
+        // The transaction could have gone through anyway, so it only gets sent once
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": ["0xf86c"]});
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["error"]["code"], -32603);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Reads get retried on the other RPC
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["error"]["code"], -32603);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_forward_non_idempotent_not_cached() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |_| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            json!("0xabc")
+        })
+        .await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+
+        // Sending the same transaction twice should hit the RPC twice
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_sendRawTransaction", "params": ["0xf86c"]});
+        balancer.forward(tx.clone()).await;
+        balancer.forward(tx).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // While historical reads get cached
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        balancer.forward(tx.clone()).await;
+        balancer.forward(tx).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_forward_ttl_policy() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |tx| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            match tx["method"].as_str() {
+                Some("eth_getBlockByHash") => json!({"number": "0x1", "hash": tx["params"][0]}),
+                _ => json!("0x3b9aca00"),
+            }
+        })
+        .await;
+        let mut balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.cache_methods.insert(
+            "eth_gasPrice".to_string(),
+            CachePolicy::Ttl(Duration::from_millis(100)),
+        );
+
+        // The gas price is cached until its TTL runs out
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_gasPrice", "params": []});
+        balancer.forward(tx.clone()).await;
+        balancer.forward(tx.clone()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "0x3b9aca00");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // And the refreshed entry is cached again
+        balancer.forward(tx).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Blocks by hash stay cached
+        let tx = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getBlockByHash",
+            "params": ["0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae", false]
+        });
+        balancer.forward(tx.clone()).await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["result"]["number"], "0x1");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_forward_error_not_cached() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        // Node A hasn't synced the block yet, node B has
+        let counted = |calls: &Arc<AtomicUsize>, rx: Value| {
+            let calls = Arc::clone(calls);
+            move |tx: &Value| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let mut rx = rx.clone();
+                rx["id"] = tx["id"].clone();
+                rx.to_string()
+            }
+        };
+        let (calls_a, calls_b) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let node_a = mock_rpc_raw(counted(
+            &calls_a,
+            json!({"jsonrpc": "2.0", "error": {"code": -32000, "message": "header not found"}}),
+        ))
+        .await;
+        let node_b = mock_rpc_raw(counted(
+            &calls_b,
+            json!({"jsonrpc": "2.0", "result": "0x1"}),
+        ))
+        .await;
+
+        // A is the fastest to begin with, and only gets one call in a row
+        let mut balancer =
+            TestBalancer::new(vec![Rpc::new(node_a, 1, 5.0), Rpc::new(node_b, 1, 5.0)]);
+        balancer.selection = SelectionStrategy::LowestLatency;
+        {
+            let rpc_list = balancer.rpc_list.read().unwrap();
+            rpc_list[0].status.latency.set(1.0);
+            rpc_list[1].status.latency.set(2.0);
+        }
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["error"]["code"], -32000);
+        assert_eq!(calls_a.load(Ordering::SeqCst), 1);
+
+        // The error wasn't cached, so we go upstream again, skip A and cache what B returns
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "0x1");
+        assert_eq!(calls_a.load(Ordering::SeqCst), 1);
+        assert_eq!(calls_b.load(Ordering::SeqCst), 1);
+
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["result"], "0x1");
+        assert_eq!(calls_a.load(Ordering::SeqCst), 1);
+        assert_eq!(calls_b.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_canonical_cache_key() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |_| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            json!("0x1")
+        })
+        .await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407D73d8a49eeb85D32Cf465507dd71d507100c1", "0x1"]});
+        balancer.forward(tx).await;
+
+        // Same call, different casing, fields and id
+        let tx = json!({"params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"], "id": "abc", "method": "eth_getBalance"});
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["id"], "abc");
+        assert_eq!(rx["result"], "0x1");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_method_key_prefix() {
+        let url = mock_rpc(|_| json!("0x1")).await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+
+        let balance = |block: &str| {
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", block]})
+        };
+        let code = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getCode", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        for tx in [balance("0x1"), balance("0x2"), code.clone()] {
+            balancer.forward(tx).await;
+        }
+
+        // Every entry of a method can be found by its prefix
+        let backend = balancer.cache_args.backend.as_ref();
+        let keys = backend
+            .scan_prefix(&method_key_prefix(1, "eth_getBalance"))
+            .await
+            .unwrap();
+        let mut expected = vec![
+            call_cache_key(&balance("0x1"), &balancer.cache_args),
+            call_cache_key(&balance("0x2"), &balancer.cache_args),
+        ];
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        let keys = backend
+            .scan_prefix(&method_key_prefix(1, "eth_getCode"))
+            .await
+            .unwrap();
+        assert_eq!(keys, vec![call_cache_key(&code, &balancer.cache_args)]);
+        assert!(backend
+            .scan_prefix(&method_key_prefix(2, "eth_getCode"))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forward_hot_cache_invalidated() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |_| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            json!("0x1")
+        })
+        .await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        tokio::task::spawn(sync_hot_cache(
+            Arc::clone(&balancer.cache),
+            Arc::clone(&balancer.cache_args.hot_cache),
+        ));
+        tokio::task::yield_now().await;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        balancer.forward(tx.clone()).await;
+
+        // Served from the hot cache
+        balancer.forward(tx.clone()).await;
+        balancer.forward(tx.clone()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Removing the entry from sled, eg. on a reorg, removes it from the hot cache too
+        let key = balancer.cache.iter().keys().next().unwrap().unwrap();
+        balancer.cache.remove(&key).unwrap();
+        for _ in 0..100 {
+            if balancer.cache_args.hot_cache.get(&key).is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        balancer.forward(tx).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_forward_revalidate() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        // The RPC changes its mind about the balance after it gets cached
+        let balance = Arc::new(AtomicUsize::new(1));
+        let balance_rpc = Arc::clone(&balance);
+        let url = mock_rpc(move |_| json!(format!("0x{:x}", balance_rpc.load(Ordering::SeqCst))))
+            .await;
+        let mut balancer = TestBalancer::new(vec![Rpc::new(url.clone(), 10, 5.0)]);
+        balancer.cache_args.revalidator = Arc::new(Revalidator::new(1.0));
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        assert_eq!(balancer.forward(tx.clone()).await.1["result"], "0x1");
+
+        // The stale entry is still served, and gets repaired in the background
+        balance.store(2, Ordering::SeqCst);
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "0x1");
+        assert_eq!(rx["id"], 1);
+
+        let metrics = &balancer.cache_args.cache_metrics;
+        for _ in 0..100 {
+            if metrics.to_json(false)["revalidations"]["diverged"] == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let stats = metrics.to_json(false)["revalidations"].clone();
+        assert_eq!(stats["diverged"], 1);
+        assert_eq!(stats["methods"]["eth_getBalance"], 1);
+        assert_eq!(stats["nodes"][&url], 1);
+
+        // Matching responses are checked but don't count as divergences
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "0x2");
+        let key = call_cache_key(&tx, &balancer.cache_args);
+        let mut entry = balancer.cache.get(&key).unwrap().unwrap().to_vec();
+        assert_eq!(parse_payload(&mut entry).unwrap()["result"], "0x2");
+
+        for _ in 0..100 {
+            if metrics.to_json(false)["revalidations"]["checked"] == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(metrics.to_json(false)["revalidations"]["checked"], 2);
+        assert_eq!(metrics.to_json(false)["revalidations"]["diverged"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_too_large() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |_| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            json!(format!("0x{}", "ab".repeat(256)))
+        })
+        .await;
+        let mut balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.max_entry_bytes = 256;
+
+        // Served every time, but never stored
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getCode", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        for _ in 0..2 {
+            let (status, rx) = balancer.forward(tx.clone()).await;
+            assert_eq!(status, 200);
+            assert_eq!(rx["result"].as_str().unwrap().len(), 514);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(balancer.cache.is_empty());
+
+        let stats = balancer.cache_args.cache_metrics.to_json(false);
+        assert_eq!(stats["too_large"], 2);
+        assert_eq!(stats["inserts"], 0);
+
+        // Small enough with a higher limit
+        balancer.max_entry_bytes = 1024;
+        balancer.forward(tx.clone()).await;
+        balancer.forward(tx).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_forward_pending_uncacheable() {
+        // Answers with the block it got asked about
+        let url = mock_rpc(|tx| {
+            match tx["method"].as_str() {
+                Some("eth_getBlockByNumber") => json!({"number": tx["params"][0]}),
+                _ => tx["params"][1].clone(),
+            }
+        })
+        .await;
+        let mut balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.cache_methods.insert(
+            "eth_getTransactionCount".to_string(),
+            CachePolicy::Ttl(Duration::from_secs(60)),
+        );
+        balancer.named_numbers.write().unwrap().latest = 100;
+
+        let nonce = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getTransactionCount", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "pending"]});
+        let block = json!({"jsonrpc": "2.0", "id": 2, "method": "eth_getBlockByNumber", "params": ["pending", false]});
+        for _ in 0..2 {
+            let (_, rx) = balancer.forward(nonce.clone()).await;
+            assert_eq!(rx["result"], "pending");
+
+            let (_, rx) = balancer.forward(json!([block.clone()])).await;
+            assert_eq!(rx[0]["result"]["number"], "pending");
+        }
+
+        // Never read from or written to the cache
+        assert!(balancer.cache.is_empty());
+        let stats = balancer.cache_args.cache_metrics.to_json(false);
+        assert_eq!(stats["uncacheable"], 4);
+        assert_eq!(stats["hits"], 0);
+        assert_eq!(stats["misses"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_forward_pin_latest() {
+        // Answers with the block it got asked about
+        let url = mock_rpc(|tx| tx["params"][1].clone()).await;
+        let mut balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.cache_methods.insert(
+            "eth_call".to_string(),
+            CachePolicy::Ttl(Duration::from_secs(60)),
+        );
+        let metrics = Arc::clone(&balancer.cache_args.cache_metrics);
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": [{"to": "0x407d73d8a49eeb85d32cf465507dd71d507100c1"}, "latest"]});
+
+        // We don't know the head yet, so the call skips the cache and keeps its tag
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "latest");
+        assert!(balancer.cache.is_empty());
+        assert_eq!(metrics.to_json(false)["uncacheable"], 1);
+
+        // Cached under the block it was pinned to
+        balancer.named_numbers.write().unwrap().latest = 100;
+        for _ in 0..2 {
+            let (_, rx) = balancer.forward(tx.clone()).await;
+            assert_eq!(rx["result"], "0x64");
+        }
+        assert_eq!(metrics.to_json(false)["hits"], 1);
+
+        // The next block gets its own entry instead of the stale one
+        balancer.named_numbers.write().unwrap().latest = 101;
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "0x65");
+        assert_eq!(metrics.to_json(false)["misses"], 2);
+
+        // Both are indexed by their block so they get invalidated on a reorg
+        let backend = balancer.cache_args.backend.as_ref();
+        assert_eq!(invalidate_from(&balancer.cache, backend, 100).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_forward_safe_finalized_tags() {
+        // Answers with the block it got asked about
+        let url = mock_rpc(|tx| tx["params"][1].clone()).await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        let metrics = Arc::clone(&balancer.cache_args.cache_metrics);
+        {
+            let mut named_numbers = balancer.named_numbers.write().unwrap();
+            named_numbers.latest = 100;
+            named_numbers.safe = 90;
+            named_numbers.finalized = 80;
+        }
+        balancer.finalized_tx.send(80).unwrap();
+
+        let address = "0x407d73d8a49eeb85d32cf465507dd71d507100c1";
+        let balance = |tag: &str| {
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": [address, tag]})
+        };
+
+        // Finalized blocks can't reorg, so they're cached even within finality_distance
+        for _ in 0..2 {
+            let (_, rx) = balancer.forward(balance("finalized")).await;
+            assert_eq!(rx["result"], "0x50");
+        }
+        assert_eq!(metrics.to_json(false)["hits"], 1);
+
+        // Same entry as asking for the block by number
+        let (_, rx) = balancer.forward(balance("0x50")).await;
+        assert_eq!(rx["result"], "0x50");
+        assert_eq!(metrics.to_json(false)["hits"], 2);
+
+        // Safe blocks are only cached once they're past finality_distance
+        let entries = balancer.cache.len();
+        let (_, rx) = balancer.forward(balance("safe")).await;
+        assert_eq!(rx["result"], "0x5a");
+        assert_eq!(balancer.cache.len(), entries);
+
+        balancer.named_numbers.write().unwrap().latest = 200;
+        balancer.forward(balance("safe")).await;
+        assert_eq!(balancer.cache.len(), entries + 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_provisional_head() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_rpc = Arc::clone(&requests);
+        let url = mock_rpc(move |tx| {
+            requests_rpc.fetch_add(1, Ordering::Relaxed);
+            tx["params"].clone()
+        })
+        .await;
+        let mut balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.cache_methods.insert(
+            "eth_call".to_string(),
+            CachePolicy::Ttl(Duration::from_secs(60)),
+        );
+        let call = |to: &str| {
+            json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": [{"to": to}, "latest"]})
+        };
+        let cached = call("0x407d73d8a49eeb85d32cf465507dd71d507100c1");
+        let uncached = call("0x0000000000000000000000000000000000000001");
+
+        // Cached during the last run, which saved the head it got pinned to
+        balancer.named_numbers.write().unwrap().latest = 100;
+        balancer.forward(cached.clone()).await;
+        persist_named_numbers(&balancer.cache, &balancer.named_numbers.read().unwrap()).unwrap();
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Restart, the first request comes in before any health check
+        *balancer.named_numbers.write().unwrap() = load_named_numbers(&balancer.cache).unwrap();
+        let (_, rx) = balancer.forward(cached).await;
+        assert_eq!(rx["result"][1], "0x64");
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Misses get forwarded with the tag they came with, as the head could have
+        // moved on since, and aren't written by the provisional head
+        let entries = balancer.cache.len();
+        let (_, rx) = balancer.forward(uncached.clone()).await;
+        assert_eq!(rx["result"][1], "latest");
+        let (_, rx) = balancer.forward(json!([uncached.clone()])).await;
+        assert_eq!(rx[0]["result"][1], "latest");
+
+        // Same for log ranges up to the head
+        let logs = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getLogs", "params": [{"fromBlock": "0x10"}]});
+        let (_, rx) = balancer.forward(logs).await;
+        assert_eq!(rx["result"], json!([{"fromBlock": "0x10"}]));
+        assert_eq!(balancer.cache.len(), entries);
+
+        // Once a health check confirms the head, we're back to caching
+        {
+            let mut named_numbers = balancer.named_numbers.write().unwrap();
+            named_numbers.latest = 101;
+            named_numbers.provisional = false;
+        }
+        balancer.forward(uncached).await;
+        assert_eq!(balancer.cache.len(), entries + 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_cache_metrics() {
+        let url = mock_rpc(|tx| {
+            match tx["method"].as_str() {
+                Some("eth_blockNumber") => json!("0x10"),
+                _ => json!("0x1"),
+            }
+        })
+        .await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+
+        let balance = json!(["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]);
+        for (method, params) in [
+            ("eth_getBalance", balance.clone()),
+            ("eth_getBalance", balance),
+            ("eth_blockNumber", json!([])),
+            ("eth_gasPrice", json!([])),
+        ] {
+            let tx = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+            balancer.forward(tx).await;
+        }
+
+        let stats = balancer.cache_args.cache_metrics.to_json(false);
+        assert_eq!(stats["hits"], 1);
+        assert_eq!(stats["misses"], 2);
+        assert_eq!(stats["inserts"], 2);
+        assert_eq!(stats["uncacheable"], 1);
+        assert_eq!(
+            stats["methods"]["eth_getBalance"],
+            json!({"hits": 1, "misses": 1, "inserts": 1, "uncacheable": 0, "too_large": 0})
+        );
+        assert_eq!(
+            stats["methods"]["eth_blockNumber"],
+            json!({"hits": 0, "misses": 0, "inserts": 0, "uncacheable": 1, "too_large": 0})
+        );
+        assert_eq!(
+            stats["methods"]["eth_gasPrice"],
+            json!({"hits": 0, "misses": 1, "inserts": 1, "uncacheable": 0, "too_large": 0})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_chain_id_namespace() {
+        let mainnet = mock_rpc(|_| json!("0x1")).await;
+        let sepolia = mock_rpc(|_| json!("0x2")).await;
+
+        // Run against mainnet, then point the same DB at sepolia
+        let mut balancer = TestBalancer::new(vec![Rpc::new(mainnet.clone(), 10, 5.0)]);
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "0x1");
+
+        balancer.rpc_list = Arc::new(RwLock::new(vec![Rpc::new(sepolia, 10, 5.0)]));
+        balancer.cache_args.chain_id = 11155111;
+        balancer.cache_args.hot_cache = Arc::new(HotCache::new(1024));
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "0x2");
+
+        // Both chains keep their own entries
+        balancer.rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "0x2");
+
+        balancer.cache_args.chain_id = 1;
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["result"], "0x1");
+    }
+
+    #[tokio::test]
+    async fn test_forward_compressed() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let transactions: Vec<_> = (0..100)
+            .map(|i| json!({"to": "0x407d73d8a49eeb85d32cf465507dd71d507100c1", "transactionIndex": format!("0x{:x}", i)}))
+            .collect();
+        let block = json!({"number": "0x1", "transactions": transactions});
+        let block_rpc = block.clone();
+        let url = mock_rpc(move |_| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            block_rpc.clone()
+        })
+        .await;
+        let mut balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.compression = Compression::Zstd {
+            level: 3,
+            threshold: 1024,
+        };
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": ["0x1", true]});
+        balancer.forward(tx.clone()).await;
+
+        // Stored compressed, served decompressed
+        let (_, stored) = balancer.cache.iter().next().unwrap().unwrap();
+        assert!(serde_json::from_slice::<Value>(&stored).is_err());
+
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["id"], 1);
+        assert_eq!(rx["result"], block);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_near_head_not_cached() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |tx| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            json!({"number": tx["params"][0]})
+        })
+        .await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.named_numbers.write().unwrap().latest = 2000;
+
+        // head-2 can still reorg, so it goes upstream every time
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": [format!("0x{:x}", 1998), false]});
+        balancer.forward(tx.clone()).await;
+        balancer.forward(tx).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // head-1000 is final
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": [format!("0x{:x}", 1000), false]});
+        balancer.forward(tx.clone()).await;
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["result"]["number"], "0x3e8");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_forward_logs_chunked() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |_| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            json!([{"blockNumber": "0x3e8", "logIndex": "0x0"}])
+        })
+        .await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.named_numbers.write().unwrap().latest = 5000;
+
+        let tx = json!({"jsonrpc": "2.0", "id": 7, "method": "eth_getLogs", "params": [{"fromBlock": "0x3e8", "toBlock": "0x3e9"}]});
+        balancer.forward(tx.clone()).await;
+        let (status, rx) = balancer.forward(tx).await;
+        assert_eq!(status, 200);
+        assert_eq!(rx["id"], 7);
+        assert_eq!(rx["result"], json!([{"blockNumber": "0x3e8", "logIndex": "0x0"}]));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_batch_cached() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |tx| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            tx["params"][1].clone()
+        })
+        .await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+
+        let tx = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]},
+            {"jsonrpc": "2.0", "id": 2, "method": "eth_sendRawTransaction", "params": ["0xf86c", "0x2"]},
+            {"jsonrpc": "2.0", "id": 3, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x3"]},
+        ]);
+        balancer.forward(tx.clone()).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Writes from the first batch are read back in one go, the non-idempotent call still goes upstream
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        let rx = rx.as_array().unwrap();
+        assert_eq!(rx[0]["id"], 1);
+        assert_eq!(rx[0]["result"], "0x1");
+        assert_eq!(rx[1]["result"], "0x2");
+        assert_eq!(rx[2]["id"], 3);
+        assert_eq!(rx[2]["result"], "0x3");
+    }
+
+    #[tokio::test]
+    async fn test_forward_write_behind() {
+        use std::{
+            sync::atomic::{
+                AtomicUsize,
+                Ordering,
+            },
+            time::Instant,
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |tx| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            tx["params"][1].clone()
+        })
+        .await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+
+        // Writer that takes way longer than any request should
+        let mut writes = balancer.writes.lock().unwrap().take().unwrap();
+        let cache = Arc::clone(&balancer.cache);
+        let backend = Arc::clone(&balancer.cache_args.backend);
+        tokio::spawn(async move {
+            while let Some(batch) = writes.recv().await {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                batch.apply(&cache, backend.as_ref()).await.unwrap();
+            }
+        });
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        let time = Instant::now();
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], "0x1");
+        assert!(time.elapsed() < Duration::from_millis(250));
+
+        // Not in sled yet, but served from the hot cache in the meantime
+        let key = call_cache_key(&tx, &balancer.cache_args);
+        assert!(balancer.cache.get(&key).unwrap().is_none());
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["result"], "0x1");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(750)).await;
+        assert!(balancer.cache.get(&key).unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_forward_negative_cache() {
+        use std::sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        };
+
+        // The transaction gets included after the 3rd poll
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc(move |_| {
+            if calls_rpc.fetch_add(1, Ordering::SeqCst) < 3 {
+                Value::Null
+            } else {
+                json!({"blockNumber": "0x10", "status": "0x1"})
+            }
+        })
+        .await;
+        let mut balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.negative_ttl = Duration::from_millis(50);
+
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getTransactionReceipt", "params": ["0xabcd"]});
+
+        // A cached null is served until it expires
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["result"], Value::Null);
+        let (_, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(rx["id"], 1);
+        assert_eq!(rx["result"], Value::Null);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Keep polling until the receipt shows up
+        let mut polls = 0;
+        let receipt = loop {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            let (_, rx) = balancer.forward(tx.clone()).await;
+            polls += 1;
+            if !rx["result"].is_null() {
+                break rx;
+            }
+            assert!(polls < 10);
+        };
+        assert_eq!(receipt["result"]["status"], "0x1");
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // Once it's there it stays cached
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["result"]["status"], "0x1");
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // Methods that aren't listed don't get their nulls cached
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getTransactionByHash", "params": ["0xabcd"]});
+        let url = mock_rpc(|_| Value::Null).await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+        balancer.forward(tx.clone()).await;
+        assert!(balancer.cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forward_undecodable_entry() {
+        let url = mock_rpc(|_| json!("0x10")).await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+
+        // Seed the cache with garbage where the response should be
+        let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        let key = call_cache_key(&tx, &balancer.cache_args);
+        balancer
+            .cache
+            .insert(&key, b"\x02not zstd".as_slice())
+            .unwrap();
+
+        // Served from upstream instead of panicking, and the entry gets replaced
+        let (status, rx) = balancer.forward(tx.clone()).await;
+        assert_eq!(status, 200);
+        assert_eq!(rx["result"], "0x10");
+
+        let mut entry = balancer.cache.get(&key).unwrap().unwrap();
+        assert_eq!(parse_payload(&mut entry).unwrap()["result"], "0x10");
+
+        let (_, rx) = balancer.forward(tx).await;
+        assert_eq!(rx["result"], "0x10");
+    }
+
+    #[tokio::test]
+    async fn test_forward_flush_method() {
+        use crate::database::flush::flush_method;
+
+        let url = mock_rpc(|tx| json!({"number": tx["params"][0]})).await;
+        let balancer = TestBalancer::new(vec![Rpc::new(url, 10, 5.0)]);
+
+        let balance = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        let block = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": ["0x1", false]});
+        balancer.forward(balance.clone()).await;
+        balancer.forward(block.clone()).await;
+        assert_eq!(balancer.cache.len(), 2);
+
+        // Responses are indexed by method as they get cached
+        let cache = &balancer.cache;
+        let backend = balancer.cache_args.backend.as_ref();
+        assert_eq!(
+            flush_method(cache, backend, "eth_getBalance")
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(cache
+            .get(call_cache_key(&balance, &balancer.cache_args))
+            .unwrap()
+            .is_none());
+        assert!(cache
+            .get(call_cache_key(&block, &balancer.cache_args))
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_forward_consensus_quarantine() {
+        let block = |tx: &Value, hash: &str| json!({"number": tx["params"][0], "hash": hash});
+        let mut balancer = TestBalancer::new(vec![
+            Rpc::new(mock_rpc(move |tx| block(tx, "0xABC")).await, 10, 5.0),
+            Rpc::new(mock_rpc(move |tx| block(tx, "0xabc")).await, 10, 5.0),
+            // On a minority fork
+            Rpc::new(mock_rpc(move |tx| block(tx, "0xdef")).await, 10, 5.0),
+        ]);
+        balancer.consensus = ConsensusSettings {
+            fraction: 1.0,
+            max_divergences: 3,
+        };
+        balancer.finalized_tx.send(1000).unwrap();
+        let quarantined = |balancer: &TestBalancer| {
+            balancer
+                .rpc_list
+                .read()
+                .unwrap()
+                .iter()
+                .map(|rpc| rpc.consensus.is_quarantined())
+                .collect::<Vec<bool>>()
+        };
+
+        // Checks run in the background, after the response went out
+        for number in 1..500 {
+            let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": [format!("0x{:x}", number), false]});
+            balancer.forward(tx).await;
+            sleep(Duration::from_millis(5)).await;
+            if quarantined(&balancer)[2] {
+                break;
+            }
+        }
+        assert_eq!(quarantined(&balancer), vec![false, false, true]);
+
+        // Only the honest RPCs get calls from then on
+        for number in 500..520 {
+            let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": [format!("0x{:x}", number), false]});
+            let (status, rx) = balancer.forward(tx).await;
+            assert_eq!(status, 200);
+            assert_eq!(
+                rx["result"]["hash"].as_str().unwrap().to_lowercase(),
+                "0xabc"
+            );
+        }
+    }
+}
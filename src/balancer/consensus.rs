@@ -0,0 +1,268 @@
+use crate::{
+    balancer::accept_http::RequestParams,
+    balancer::format::canonicalize_param,
+    balancer::selection::{
+        routing::Requirements,
+        select::pick,
+    },
+    rpc::types::Rpc,
+};
+
+use rand::Rng;
+use serde_json::Value;
+use std::{
+    cmp::Ordering,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+use tokio::time::timeout;
+
+// How often responses get checked against a second RPC, set in `[consensus]`
+#[derive(Debug, Clone)]
+pub struct ConsensusSettings {
+    // Fraction of the calls for finalized data that get checked, 0 turns this off
+    pub fraction: f64,
+    // How many of its recent checks a RPC can be wrong in before it gets quarantined
+    pub max_divergences: u32,
+}
+
+impl Default for ConsensusSettings {
+    fn default() -> Self {
+        Self {
+            fraction: 0.0,
+            max_divergences: 3,
+        }
+    }
+}
+
+impl ConsensusSettings {
+    pub fn enabled(&self) -> bool {
+        self.fraction > 0.0
+    }
+
+    // Returns true if a call should get checked
+    pub fn sample(&self) -> bool {
+        self.enabled() && rand::thread_rng().gen_bool(self.fraction.min(1.0))
+    }
+}
+
+// Send `tx` to a second RPC and compare what it returns with `result`, what the RPC at
+// `position` returned for it. Differences in key order or hex case don't count.
+//
+// If they agree, both get it on their record. If they don't, the one that agreed with
+// the others less often lately is taken to be wrong, so a RPC serving a minority fork
+// ends up quarantined once the rest of the RPCs have checked out against each other.
+//
+// This runs after the response was returned, so it doesn't slow down the request.
+pub async fn check_consensus(
+    tx: Value,
+    result: Value,
+    position: usize,
+    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    requirements: Requirements,
+    params: RequestParams,
+) {
+    let (first, second) = {
+        let rpc_list = rpc_list_rwlock.read().unwrap();
+        let first = match rpc_list.get(position) {
+            Some(first) => first.clone(),
+            None => return,
+        };
+        match pick(&rpc_list, params.selection, &requirements, &[position]) {
+            Some(second) => (first, rpc_list[second].clone()),
+            // Nothing to compare against
+            None => return,
+        }
+    };
+
+    let rx = {
+        let _in_flight = match second.start_request() {
+            Some(in_flight) => in_flight,
+            // Busy with requests that can't wait
+            None => return,
+        };
+        timeout(
+            Duration::from_millis(params.ttl as u64),
+            second.send_request(tx.clone()),
+        )
+        .await
+    };
+    let other = match rx {
+        Ok(Ok(rx)) => {
+            match serde_json::from_str::<Value>(&rx) {
+                Ok(mut rx) => rx["result"].take(),
+                Err(_) => return,
+            }
+        }
+        _ => return,
+    };
+
+    // Errors and missing data, eg. from a RPC that's behind, don't mean it's on another fork
+    if result.is_null() || other.is_null() {
+        return;
+    }
+
+    if canonicalize_param(&result) == canonicalize_param(&other) {
+        first.consensus.agreed();
+        second.consensus.agreed();
+        return;
+    }
+
+    println!(
+        "\x1b[93mWrn:\x1b[0m {} and {} disagree on {}",
+        first.url, second.url, tx["method"]
+    );
+    let blamed = match first
+        .consensus
+        .agreements()
+        .cmp(&second.consensus.agreements())
+    {
+        Ordering::Less => first,
+        Ordering::Greater => second,
+        // No way to tell which one is wrong yet
+        Ordering::Equal => return,
+    };
+
+    // Quarantining every RPC would take us down over a bad run of checks, so the
+    // last one that isn't quarantined keeps serving requests either way.
+    //
+    // Write locked so checks finishing at the same time can't both take the last two.
+    let rpc_list = rpc_list_rwlock.write().unwrap();
+    let serving = rpc_list
+        .iter()
+        .filter(|rpc| !rpc.consensus.is_quarantined())
+        .count();
+    blamed
+        .consensus
+        .diverged(&blamed.url, params.consensus.max_divergences, serving <= 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::selection::select::SelectionStrategy,
+        rpc::mock::mock_rpc,
+        Settings,
+    };
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_check_consensus() {
+        let honest = |_: &Value| json!({"hash": "0xABC", "number": "0x1"});
+        let mut rpc_list = vec![
+            Rpc::new(mock_rpc(honest).await, u32::MAX, 5.0),
+            Rpc::new(mock_rpc(honest).await, u32::MAX, 5.0),
+            Rpc::new(
+                mock_rpc(|_| json!({"hash": "0xdef", "number": "0x1"})).await,
+                u32::MAX,
+                5.0,
+            ),
+        ];
+        // Second opinions come from the fastest RPC that didn't answer
+        for (latency, rpc) in rpc_list.iter_mut().enumerate() {
+            rpc.status.latency.set(latency as f64 + 1.0);
+        }
+        let rpc_list = Arc::new(RwLock::new(rpc_list));
+        let params = RequestParams {
+            selection: SelectionStrategy::LowestLatency,
+            ..RequestParams::new(&Settings::default())
+        };
+
+        let tx = json!({"jsonrpc": "2.0", "id": null, "method": "eth_getBlockByNumber", "params": ["0x1", false]});
+        let check = |result: Value, position| {
+            check_consensus(
+                tx.clone(),
+                result,
+                position,
+                Arc::clone(&rpc_list),
+                Requirements::default(),
+                params.clone(),
+            )
+        };
+        let record = |position: usize| {
+            let rpc_list = rpc_list.read().unwrap();
+            (
+                rpc_list[position].consensus.agreements(),
+                rpc_list[position].consensus.divergences(),
+            )
+        };
+
+        // Nobody to blame until the honest RPCs checked out against each other
+        check(json!({"hash": "0xdef", "number": "0x1"}), 2).await;
+        assert_eq!((record(0), record(2)), ((0, 0), (0, 0)));
+
+        // Key order and hex case don't matter
+        check(json!({"number": "0x1", "hash": "0xabc"}), 1).await;
+        assert_eq!((record(0), record(1)), ((1, 0), (1, 0)));
+
+        // The RPC on its own gets the blame, until it's quarantined
+        for _ in 0..3 {
+            check(json!({"hash": "0xdef", "number": "0x1"}), 2).await;
+        }
+        assert_eq!(record(2), (0, 3));
+        let quarantined = |position: usize| {
+            rpc_list.read().unwrap()[position]
+                .consensus
+                .is_quarantined()
+        };
+        assert_eq!(
+            (quarantined(0), quarantined(1), quarantined(2)),
+            (false, false, true)
+        );
+
+        // Missing data isn't held against anyone
+        check(Value::Null, 0).await;
+        assert_eq!(record(0).1, 0);
+    }
+
+    #[tokio::test]
+    async fn test_check_consensus_last_serving() {
+        let rpc_list = vec![
+            Rpc::new(mock_rpc(|_| json!("0xabc")).await, u32::MAX, 5.0),
+            Rpc::new(mock_rpc(|_| json!("0xdef")).await, u32::MAX, 5.0),
+        ];
+        // The first one has a good record, but got quarantined while its call was in flight
+        rpc_list[0].consensus.agreed();
+        rpc_list[0].consensus.diverged(&rpc_list[0].url, 1, false);
+        assert!(rpc_list[0].consensus.is_quarantined());
+        let rpc_list = Arc::new(RwLock::new(rpc_list));
+
+        let params = RequestParams {
+            consensus: ConsensusSettings {
+                fraction: 1.0,
+                max_divergences: 1,
+            },
+            ..RequestParams::new(&Settings::default())
+        };
+        check_consensus(
+            json!({"jsonrpc": "2.0", "id": null, "method": "eth_getBalance", "params": ["0x0", "0x1"]}),
+            json!("0xabc"),
+            0,
+            Arc::clone(&rpc_list),
+            Requirements::default(),
+            params,
+        )
+        .await;
+
+        // The second one gets the blame, but it's the only one left serving requests
+        let second = &rpc_list.read().unwrap()[1];
+        assert_eq!(second.consensus.divergences(), 1);
+        assert!(!second.consensus.is_quarantined());
+    }
+
+    #[test]
+    fn test_consensus_sample() {
+        let settings = |fraction| {
+            ConsensusSettings {
+                fraction,
+                ..Default::default()
+            }
+        };
+        assert!(!settings(0.0).sample());
+        assert!((0..100).all(|_| settings(1.0).sample()));
+    }
+}
@@ -53,11 +53,96 @@ fn has_named_number(param: &str) -> NamedNumber {
     NamedNumber::Null
 }
 
+// Return the index of the block number param of `method`, if it takes one
+fn block_param_position(method: &str) -> Option<usize> {
+    // The JSON-RPC standard is all over the place so depending on the method, we need to look at
+    // different param indexes. Why? Has i ever???
+    match method {
+        "eth_getBalance" => Some(1),
+        "eth_getStorageAt" => Some(2),
+        "eth_getTransactionCount" => Some(1),
+        "eth_getBlockTransactionCountByNumber" => Some(0),
+        "eth_getUncleCountByBlockNumber" => Some(0),
+        "eth_getCode" => Some(1),
+        "eth_call" => Some(1),
+        "eth_getBlockByNumber" => Some(0),
+        "eth_getTransactionByBlockNumberAndIndex" => Some(0),
+        "eth_getUncleByBlockNumberAndIndex" => Some(0),
+        _ => None,
+    }
+}
+
+// What `replace_block_tags` did to a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockTag {
+    // Nothing to replace, the block is a number or the method doesn't take one
+    None,
+    // The tag was replaced with this block number
+    Pinned(u64),
+    // We don't know which block the tag refers to yet
+    Unresolved,
+    // The call is for the pending block, which only the mempool of the node it
+    // gets sent to knows about. The tag is left as is.
+    Pending,
+}
+
+// Replace the block tag in `tx` with the block it currently refers to.
+//
+// Responses are cached by request, so one for `latest` would still be served
+// after the head moves on. Pinned to a number, the next block gets its own entry.
+// The same goes for `safe` and `finalized`, and `earliest` is always block 0.
+//
+// Methods where the block is the last param default to `latest` when it's
+// left out, so those get the number added.
+pub fn replace_block_tags(tx: &mut Value, named_blocknumbers: &NamedBlocknumbers) -> BlockTag {
+    let position = match tx["method"].as_str().and_then(block_param_position) {
+        Some(position) => position,
+        None => return BlockTag::None,
+    };
+    let params = match tx.get_mut("params").and_then(Value::as_array_mut) {
+        Some(params) => params,
+        None => return BlockTag::None,
+    };
+
+    let number = match params.get(position).map(|param| param.as_str()) {
+        Some(Some("pending")) => return BlockTag::Pending,
+        Some(Some("earliest")) => return pin_block(params, position, 0),
+        Some(Some("latest")) => named_blocknumbers.latest,
+        Some(Some("safe")) => named_blocknumbers.safe,
+        Some(Some("finalized")) => named_blocknumbers.finalized,
+        None if position > 0 && params.len() == position => named_blocknumbers.latest,
+        _ => return BlockTag::None,
+    };
+
+    // Not tracked yet, or not supported by the RPCs
+    if number == 0 {
+        return BlockTag::Unresolved;
+    }
+
+    pin_block(params, position, number)
+}
+
+// Set the block param at `position` to `number`, adding it if it was left out
+fn pin_block(params: &mut Vec<Value>, position: usize, number: u64) -> BlockTag {
+    let block = Value::String(format!("0x{:x}", number));
+    match params.get_mut(position) {
+        Some(param) => *param = block,
+        None => params.push(block),
+    }
+
+    BlockTag::Pinned(number)
+}
+
 // Return the blocknumber from a json-rpc request as a Option<String>, returning None if it cant find anything
 pub fn get_block_number_from_request(
     tx: Value,
     named_blocknumbers: &Arc<RwLock<NamedBlocknumbers>>,
 ) -> Option<u64> {
+    block_number_of(&tx, &named_blocknumbers.read().unwrap())
+}
+
+// Same as `get_block_number_from_request`, for when we already hold the named blocknumbers
+pub fn block_number_of(tx: &Value, named_blocknumbers: &NamedBlocknumbers) -> Option<u64> {
     // Return none if `params` is not a thing
     let params = tx["params"].as_array();
     if let Some(params) = params {
@@ -68,21 +153,7 @@ pub fn get_block_number_from_request(
         return None;
     }
 
-    // The JSON-RPC standard is all over the place so depending on the method, we need to look at
-    // different param indexes. Why? Has i ever???
-    let position = match tx["method"].as_str() {
-        Some("eth_getBalance") => 1,
-        Some("eth_getStorageAt") => 2,
-        Some("eth_getTransactionCount") => 1,
-        Some("eth_getBlockTransactionCountByNumber") => 0,
-        Some("eth_getUncleCountByBlockNumber") => 0,
-        Some("eth_getCode") => 1,
-        Some("eth_call") => 1,
-        Some("eth_getBlockByNumber") => 0,
-        Some("eth_getTransactionByBlockNumberAndIndex") => 0,
-        Some("eth_getUncleByBlockNumberAndIndex") => 0,
-        _ => return None,
-    };
+    let position = tx["method"].as_str().and_then(block_param_position)?;
 
     // Get the corresponding blockbumber from the params
     let block_number = tx["params"][position].to_string().replace('\"', "");
@@ -95,14 +166,12 @@ pub fn get_block_number_from_request(
     // Return the corresponding named parameter from the RwLock is present
     let nn = has_named_number(&block_number);
     if nn != NamedNumber::Null {
-        let rwlock_guard = named_blocknumbers.read().unwrap();
-
         match nn {
-            NamedNumber::Latest => return Some(rwlock_guard.latest),
-            NamedNumber::Earliest => return Some(rwlock_guard.earliest),
-            NamedNumber::Safe => return Some(rwlock_guard.safe),
-            NamedNumber::Finalized => return Some(rwlock_guard.finalized),
-            NamedNumber::Pending => return Some(rwlock_guard.pending),
+            NamedNumber::Latest => return Some(named_blocknumbers.latest),
+            NamedNumber::Earliest => return Some(named_blocknumbers.earliest),
+            NamedNumber::Safe => return Some(named_blocknumbers.safe),
+            NamedNumber::Finalized => return Some(named_blocknumbers.finalized),
+            NamedNumber::Pending => return Some(named_blocknumbers.pending),
             NamedNumber::Null => return None,
         }
     }
@@ -116,10 +185,70 @@ pub fn get_block_number_from_request(
     Some(block_number)
 }
 
-pub async fn incoming_to_value(tx: Request<Incoming>) -> Result<Value, hyper::Error> {
-    #[cfg(feature = "debug-verbose")]
-    println!("Incoming request: {:?}", tx);
+// Return the blocknumber a by-hash request resolved to, taken from the response.
+//
+// Returns None if the response is null, which happens when the block or
+// transaction is unknown or still pending.
+pub fn get_block_number_from_result(tx: &Value, rx: &Value) -> Option<u64> {
+    let field = match tx["method"].as_str() {
+        Some("eth_getBlockByHash") => "number",
+        Some("eth_getTransactionByHash") => "blockNumber",
+        Some("eth_getTransactionReceipt") => "blockNumber",
+        _ => return None,
+    };
 
+    let block_number = rx["result"][field].as_str()?;
+
+    u64::from_str_radix(block_number.strip_prefix("0x")?, 16).ok()
+}
+
+// Return the canonical form of a request. The params in it are what we hash for the cache key.
+//
+// Semantically identical calls should end up with the same key, so we strip
+// the `id` and `jsonrpc` fields, sort object keys and lowercase hex strings.
+// Hex is case insensitive for every JSON-RPC param (addresses, hashes, data and quantities).
+pub fn canonicalize(tx: &Value) -> Value {
+    match tx {
+        Value::Object(tx) => {
+            Value::Object(
+                tx.iter()
+                    .filter(|(key, _)| *key != "id" && *key != "jsonrpc")
+                    .map(|(key, value)| (key.clone(), canonicalize_param(value)))
+                    .collect(),
+            )
+        }
+        tx => tx.clone(),
+    }
+}
+
+// Return the canonical form of a single param, or of the params of a call as a whole
+pub fn canonicalize_param(param: &Value) -> Value {
+    match param {
+        Value::String(param) if is_hex(param) => Value::String(param.to_ascii_lowercase()),
+        Value::Array(params) => Value::Array(params.iter().map(canonicalize_param).collect()),
+        Value::Object(params) => {
+            // Collect into a Vec first so the keys are sorted even if
+            // serde_json is built with `preserve_order`
+            let mut params: Vec<(String, Value)> = params
+                .iter()
+                .map(|(key, value)| (key.clone(), canonicalize_param(value)))
+                .collect();
+            params.sort_by(|a, b| a.0.cmp(&b.0));
+
+            Value::Object(params.into_iter().collect())
+        }
+        param => param.clone(),
+    }
+}
+
+fn is_hex(param: &str) -> bool {
+    match param.strip_prefix("0x") {
+        Some(digits) => digits.bytes().all(|digit| digit.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+pub async fn incoming_to_value(tx: Request<Incoming>) -> Result<Value, hyper::Error> {
     let tx = tx.collect().await?.to_bytes().clone();
     let mut tx = from_utf8(&tx).unwrap().to_owned();
 
@@ -188,6 +317,123 @@ mod tests {
         assert_eq!(has_named_number("0"), NamedNumber::Null);
     }
 
+    #[test]
+    fn replace_block_tags_test() {
+        let named_blocknumbers = NamedBlocknumbers {
+            latest: 100,
+            safe: 90,
+            finalized: 80,
+            ..Default::default()
+        };
+        let address = "0x407d73d8a49eeb85d32cf465507dd71d507100c1";
+
+        let mut tx = json!({"method": "eth_getBalance", "params": [address, "latest"]});
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers),
+            BlockTag::Pinned(100)
+        );
+        assert_eq!(tx["params"], json!([address, "0x64"]));
+
+        // Left out, defaults to latest
+        let mut tx = json!({"method": "eth_call", "params": [{"to": address}]});
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers),
+            BlockTag::Pinned(100)
+        );
+        assert_eq!(tx["params"], json!([{"to": address}, "0x64"]));
+
+        let mut tx = json!({"method": "eth_getBlockByNumber", "params": ["latest", false]});
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers),
+            BlockTag::Pinned(100)
+        );
+        assert_eq!(tx["params"], json!(["0x64", false]));
+
+        // Every tag we track gets pinned, wherever the method takes its block
+        for (tag, pinned) in [
+            ("latest", 100),
+            ("earliest", 0),
+            ("safe", 90),
+            ("finalized", 80),
+        ] {
+            let block = format!("0x{:x}", pinned);
+            for (mut tx, expected) in [
+                (
+                    json!({"method": "eth_getBalance", "params": [address, tag]}),
+                    json!([address, block]),
+                ),
+                (
+                    json!({"method": "eth_call", "params": [{"to": address}, tag]}),
+                    json!([{"to": address}, block]),
+                ),
+                (
+                    json!({"method": "eth_getStorageAt", "params": [address, "0x0", tag]}),
+                    json!([address, "0x0", block]),
+                ),
+                (
+                    json!({"method": "eth_getBlockByNumber", "params": [tag, true]}),
+                    json!([block, true]),
+                ),
+                (
+                    json!({"method": "eth_getTransactionByBlockNumberAndIndex", "params": [tag, "0x1"]}),
+                    json!([block, "0x1"]),
+                ),
+            ] {
+                assert_eq!(
+                    replace_block_tags(&mut tx, &named_blocknumbers),
+                    BlockTag::Pinned(pinned)
+                );
+                assert_eq!(tx["params"], expected);
+            }
+        }
+
+        // Already a number, or no block to pin
+        for tx in [
+            json!({"method": "eth_getBalance", "params": [address, "0x1"]}),
+            json!({"method": "eth_getBlockByNumber", "params": []}),
+            json!({"method": "eth_chainId", "params": []}),
+            json!({"method": "eth_getBalance"}),
+        ] {
+            let mut pinned = tx.clone();
+            assert_eq!(
+                replace_block_tags(&mut pinned, &named_blocknumbers),
+                BlockTag::None
+            );
+            assert_eq!(pinned, tx);
+        }
+
+        // Pending is never pinned, wherever the method takes its block
+        for tx in [
+            json!({"method": "eth_getTransactionCount", "params": [address, "pending"]}),
+            json!({"method": "eth_getBlockByNumber", "params": ["pending", false]}),
+            json!({"method": "eth_getStorageAt", "params": [address, "0x0", "pending"]}),
+        ] {
+            let mut pinned = tx.clone();
+            assert_eq!(
+                replace_block_tags(&mut pinned, &named_blocknumbers),
+                BlockTag::Pending
+            );
+            assert_eq!(pinned, tx);
+        }
+
+        // We don't know the head yet, or the RPCs don't support safe and finalized
+        for tag in ["latest", "safe", "finalized"] {
+            let mut tx = json!({"method": "eth_getBalance", "params": [address, tag]});
+            assert_eq!(
+                replace_block_tags(&mut tx, &NamedBlocknumbers::default()),
+                BlockTag::Unresolved
+            );
+            assert_eq!(tx["params"][1], tag);
+        }
+
+        // Earliest doesn't depend on anything we track
+        let mut tx = json!({"method": "eth_getBalance", "params": [address, "earliest"]});
+        assert_eq!(
+            replace_block_tags(&mut tx, &NamedBlocknumbers::default()),
+            BlockTag::Pinned(0)
+        );
+    }
+
     #[test]
     fn get_block_number_from_request_test() {
         // Set up a fake NamedBlocknumbers
@@ -198,6 +444,7 @@ mod tests {
             finalized: 4,
             pending: 5,
             number: 6,
+            provisional: false,
         }));
 
         let request = json!({
@@ -393,6 +640,135 @@ mod tests {
         );
     }
 
+    #[test]
+    fn get_block_number_from_result_test() {
+        let request = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "method":"eth_getBlockByHash",
+            "params":["0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae", false]
+        });
+
+        let response = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "result":{"number":"0x1b4"}
+        });
+        assert_eq!(get_block_number_from_result(&request, &response), Some(436));
+
+        // Unknown block
+        let response = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "result":null
+        });
+        assert_eq!(get_block_number_from_result(&request, &response), None);
+
+        let request = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "method":"eth_getTransactionByHash",
+            "params":["0x88df016429689c079f3b2f6ad39fa052532c56795b733da78a91ebe6a713944b"]
+        });
+
+        let response = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "result":{"blockNumber":"0x5daf3b"}
+        });
+        assert_eq!(
+            get_block_number_from_result(&request, &response),
+            Some(6139707)
+        );
+
+        // Pending transaction
+        let response = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "result":{"blockNumber":null}
+        });
+        assert_eq!(get_block_number_from_result(&request, &response), None);
+
+        // Not a by-hash method
+        let request = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "method":"eth_getBlockByNumber",
+            "params":["0x1b4", false]
+        });
+
+        let response = json!({
+            "id":1,
+            "jsonrpc":"2.0",
+            "result":{"number":"0x1b4"}
+        });
+        assert_eq!(get_block_number_from_result(&request, &response), None);
+    }
+
+    #[test]
+    fn canonicalize_test() {
+        // eth_getBalance with a checksummed address, different key order and an id
+        let a = json!({
+            "params": ["0x407D73d8a49eeb85D32Cf465507dd71d507100c1", "latest"],
+            "method": "eth_getBalance",
+            "id": 1,
+            "jsonrpc": "2.0",
+        });
+        let b = json!({
+            "method": "eth_getBalance",
+            "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "latest"],
+        });
+        assert_eq!(canonicalize(&a), canonicalize(&b));
+        assert_eq!(
+            serde_json::to_string(&canonicalize(&a)).unwrap(),
+            r#"{"method":"eth_getBalance","params":["0x407d73d8a49eeb85d32cf465507dd71d507100c1","latest"]}"#
+        );
+
+        // eth_call with a nested object
+        let tx = json!({
+            "id": "abc",
+            "jsonrpc": "2.0",
+            "method": "eth_call",
+            "params": [{"to": "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48", "data": "0x70A08231"}, "0x10"],
+        });
+        assert_eq!(
+            serde_json::to_string(&canonicalize(&tx)).unwrap(),
+            r#"{"method":"eth_call","params":[{"data":"0x70a08231","to":"0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48"},"0x10"]}"#
+        );
+
+        // eth_getLogs with topics and null wildcards
+        let tx = json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "method": "eth_getLogs",
+            "params": [{
+                "toBlock": "0x10",
+                "fromBlock": "0x1",
+                "topics": ["0xDDF252AD1BE2C89B69C2B068FC378DAA952BA7F163C4A11628F55A4DF523B3EF", null],
+            }],
+        });
+        assert_eq!(
+            serde_json::to_string(&canonicalize(&tx)).unwrap(),
+            r#"{"method":"eth_getLogs","params":[{"fromBlock":"0x1","toBlock":"0x10","topics":["0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",null]}]}"#
+        );
+
+        // eth_getBlockByHash, non-hex strings are left alone
+        let tx = json!({
+            "id": 1,
+            "method": "eth_getBlockByHash",
+            "params": ["0xDC0818CF78F21A8E70579CB46A43643F78291264DDA342AE31049421C82D21AE", false],
+        });
+        assert_eq!(
+            serde_json::to_string(&canonicalize(&tx)).unwrap(),
+            r#"{"method":"eth_getBlockByHash","params":["0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae",false]}"#
+        );
+        let tx = json!({"method": "web3_sha3", "params": ["Hello"]});
+        assert_eq!(
+            serde_json::to_string(&canonicalize(&tx)).unwrap(),
+            r#"{"method":"web3_sha3","params":["Hello"]}"#
+        );
+    }
+
     #[test]
     fn replace_id_test() {
         let tx = r#"{"id":1,"jsonrpc":"2.0","method":"eth_call","params":...}"#;
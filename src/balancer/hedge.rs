@@ -0,0 +1,130 @@
+use crate::{
+    balancer::selection::cache_rules::is_non_idempotent,
+    database::metrics::CacheMetrics,
+};
+
+use std::time::Duration;
+
+// When to also send a call to other RPCs if the first one is slow to answer, set in `[hedging]`
+#[derive(Debug, Clone)]
+pub struct HedgeSettings {
+    // Methods that get hedged. None by default, since every hedge is an extra call to a RPC.
+    pub methods: Vec<String>,
+    // How long to wait for an answer before sending the call to the next RPC
+    pub delay: Duration,
+    // How many other RPCs a call can get sent to on top of the first one
+    pub max_hedges: u32,
+}
+
+impl Default for HedgeSettings {
+    fn default() -> Self {
+        Self {
+            methods: Vec::new(),
+            delay: Duration::from_millis(50),
+            max_hedges: 1,
+        }
+    }
+}
+
+impl HedgeSettings {
+    // Return how a call to `method` gets hedged. Non-idempotent methods never are,
+    // since a hedge could get eg. the same transaction submitted twice.
+    pub fn for_method<'a>(
+        &self,
+        method: &str,
+        non_idempotent_methods: &[String],
+        metrics: &'a CacheMetrics,
+    ) -> Hedge<'a> {
+        if !self.methods.iter().any(|hedged| hedged == method)
+            || is_non_idempotent(method, non_idempotent_methods)
+        {
+            return Hedge::default();
+        }
+
+        Hedge {
+            delay: self.delay,
+            max_hedges: self.max_hedges,
+            metrics: Some(metrics),
+        }
+    }
+}
+
+// How a single call gets hedged, see `send_upstream`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Hedge<'a> {
+    pub delay: Duration,
+    // 0 if the call doesn't get hedged
+    pub max_hedges: u32,
+    // Where to count the hedges that got sent and the ones that won
+    pub metrics: Option<&'a CacheMetrics>,
+}
+
+impl Hedge<'_> {
+    pub fn sent(&self) {
+        if let Some(metrics) = self.metrics {
+            metrics.hedge_sent();
+        }
+    }
+
+    pub fn won(&self) {
+        if let Some(metrics) = self.metrics {
+            metrics.hedge_won();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hedge_for_method() {
+        let settings = HedgeSettings {
+            methods: vec!["eth_blockNumber".to_string()],
+            delay: Duration::from_millis(20),
+            max_hedges: 2,
+        };
+        let metrics = CacheMetrics::default();
+
+        let hedge = settings.for_method("eth_blockNumber", &[], &metrics);
+        assert_eq!(hedge.max_hedges, 2);
+        assert_eq!(hedge.delay, Duration::from_millis(20));
+        hedge.sent();
+        hedge.won();
+        assert_eq!(metrics.to_json(false)["hedges"]["sent"], 1);
+        assert_eq!(metrics.to_json(false)["hedges"]["won"], 1);
+
+        // Other methods only go to a single RPC at a time
+        let hedge = settings.for_method("eth_call", &[], &metrics);
+        assert_eq!(hedge.max_hedges, 0);
+        hedge.sent();
+        assert_eq!(metrics.to_json(false)["hedges"]["sent"], 1);
+    }
+
+    #[test]
+    fn test_hedge_non_idempotent() {
+        let settings = HedgeSettings {
+            methods: vec![
+                "eth_blockNumber".to_string(),
+                "eth_sendRawTransaction".to_string(),
+                "custom_submit".to_string(),
+            ],
+            delay: Duration::from_millis(20),
+            max_hedges: 2,
+        };
+        let metrics = CacheMetrics::default();
+        let non_idempotent_methods = vec!["custom_submit".to_string()];
+
+        // Listed, but sending them twice could submit them twice
+        let hedge =
+            settings.for_method("eth_sendRawTransaction", &non_idempotent_methods, &metrics);
+        assert_eq!(hedge.max_hedges, 0);
+        assert!(hedge.metrics.is_none());
+        let hedge = settings.for_method("custom_submit", &non_idempotent_methods, &metrics);
+        assert_eq!(hedge.max_hedges, 0);
+        assert!(hedge.metrics.is_none());
+
+        let hedge = settings.for_method("eth_blockNumber", &non_idempotent_methods, &metrics);
+        assert_eq!(hedge.max_hedges, 2);
+    }
+}
@@ -0,0 +1,814 @@
+use crate::{
+    balancer::{
+        accept_http::{
+            send_upstream,
+            CacheArgs,
+            RequestParams,
+        },
+        format::canonicalize,
+        hedge::Hedge,
+        response_errors::ErrorResponse,
+        selection::cache_rules::{
+            near_head,
+            too_large,
+        },
+    },
+    cache_error,
+    database::{
+        batch::CacheBatch,
+        chain_id::cache_key,
+        entry::parse_payload,
+    },
+    print_cache_error,
+    rpc::types::{
+        hex_to_decimal,
+        Rpc,
+    },
+    NamedBlocknumbers,
+};
+
+use serde_json::{
+    json,
+    to_vec,
+    Map,
+    Value,
+};
+use simd_json;
+
+use std::sync::{
+    Arc,
+    RwLock,
+};
+use tokio::task::JoinSet;
+
+// eth_getLogs ranges get split into aligned chunks of this many blocks.
+//
+// Each chunk is cached on its own, so overlapping ranges with different
+// bounds still share entries.
+pub const CHUNK_SIZE: u64 = 1024;
+// Most chunks a range gets split into, wider ones are fetched in a single uncached call
+pub const MAX_CHUNKS: usize = 64;
+// How many chunks of a range get fetched at once
+const CHUNK_FETCHES: usize = 4;
+
+// eth_getLogs call over a block range we can serve from cached chunks
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogsRange {
+    // Filter without `fromBlock` and `toBlock`, used for the chunk keys
+    pub filter: Map<String, Value>,
+    pub from: u64,
+    pub to: u64,
+}
+
+impl LogsRange {
+    // Return the range of `tx` if it's an eth_getLogs call we can split into chunks.
+    //
+    // Calls by `blockHash`, with tags we can't resolve or with a range past `head`
+    // are left to the regular path.
+    pub fn from_request(tx: &Value, head: u64) -> Option<Self> {
+        // If no-cache feature is on, return None
+        if cfg!(feature = "no-cache") {
+            return None;
+        }
+
+        if tx["method"] != "eth_getLogs" || head == 0 {
+            return None;
+        }
+
+        let params = tx["params"].as_array()?;
+        if params.len() != 1 {
+            return None;
+        }
+
+        let mut filter = params[0].as_object()?.clone();
+        if filter.contains_key("blockHash") {
+            return None;
+        }
+
+        let from = resolve_block(filter.remove("fromBlock"), head)?;
+        let to = resolve_block(filter.remove("toBlock"), head)?;
+        if from > to || to > head {
+            return None;
+        }
+
+        Some(LogsRange { filter, from, to })
+    }
+
+    // Same as `from_request`, but only for ranges with both ends set by number.
+    //
+    // Used while `head` is provisional, so ranges ending at it could be missing newer blocks.
+    pub fn from_numbered_request(tx: &Value, head: u64) -> Option<Self> {
+        let filter = tx["params"][0].as_object()?;
+        let numbered = |bound: &str| {
+            filter
+                .get(bound)
+                .and_then(Value::as_str)
+                .is_some_and(|block| block.starts_with("0x"))
+        };
+        if !numbered("fromBlock") || !numbered("toBlock") {
+            return None;
+        }
+
+        Self::from_request(tx, head)
+    }
+
+    // Build the eth_getLogs call for `from..=to` with our filter
+    fn request(&self, id: &Value, from: u64, to: u64) -> Value {
+        let mut filter = self.filter.clone();
+        filter.insert("fromBlock".to_string(), json!(format!("0x{:x}", from)));
+        filter.insert("toBlock".to_string(), json!(format!("0x{:x}", to)));
+
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "eth_getLogs",
+            "params": [filter],
+        })
+    }
+
+    // Cache key of the chunk starting at `chunk_start`
+    fn chunk_key(&self, cache_args: &CacheArgs, chunk_start: u64) -> Vec<u8> {
+        let chunk = canonicalize(&json!({
+            "params": [self.filter],
+            "chunk": chunk_start,
+        }));
+        let hash = cache_args.hasher.hash(&to_vec(&chunk).unwrap());
+
+        cache_key(cache_args.chain_id, "eth_getLogs", hash.as_bytes())
+    }
+}
+
+// Resolve a `fromBlock`/`toBlock` value to a block number.
+//
+// Missing values default to `latest`. safe/finalized/pending are not resolved
+// since we don't track them closely enough to split on.
+fn resolve_block(block: Option<Value>, head: u64) -> Option<u64> {
+    match block {
+        None | Some(Value::Null) => Some(head),
+        Some(Value::String(block)) => {
+            match block.as_str() {
+                "latest" => Some(head),
+                "earliest" => Some(0),
+                block if block.starts_with("0x") => hex_to_decimal(block).ok(),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Return the aligned chunks covering `from..=to` as inclusive `(start, end)` pairs
+pub fn chunks(from: u64, to: u64) -> Vec<(u64, u64)> {
+    let mut chunks = Vec::new();
+    let mut start = from - from % CHUNK_SIZE;
+    while start <= to {
+        let end = start.saturating_add(CHUNK_SIZE - 1);
+        chunks.push((start, end));
+        start = match end.checked_add(1) {
+            Some(start) => start,
+            None => break,
+        };
+    }
+
+    chunks
+}
+
+// Return the `(blockNumber, logIndex)` of a log, which is unique within a chain
+fn log_position(log: &Value) -> Option<(u64, u64)> {
+    Some((
+        hex_to_decimal(log["blockNumber"].as_str()?).ok()?,
+        hex_to_decimal(log["logIndex"].as_str()?).ok()?,
+    ))
+}
+
+// Join logs from multiple chunks into the response for `from..=to`.
+//
+// Chunks cover whole aligned ranges, so we drop anything outside of what was
+// requested, sort by position and remove duplicates in case upstream ranges overlap.
+pub fn merge_logs(logs: Vec<Value>, from: u64, to: u64) -> Vec<Value> {
+    let mut logs: Vec<((u64, u64), Value)> = logs
+        .into_iter()
+        .filter_map(|log| Some((log_position(&log)?, log)))
+        .filter(|((block, _), _)| (from..=to).contains(block))
+        .collect();
+
+    logs.sort_by_key(|(position, _)| *position);
+    logs.dedup_by_key(|(position, _)| *position);
+
+    logs.into_iter().map(|(_, log)| log).collect()
+}
+
+// Result of fetching logs from a RPC
+enum Fetched {
+    Logs(Vec<Value>),
+    // Anything that isn't a list of logs gets passed back to the client as is
+    Passthrough(String),
+}
+
+async fn fetch_logs(
+    request: &Value,
+    named_numbers: &NamedBlocknumbers,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    params: &RequestParams,
+) -> Result<Fetched, ErrorResponse> {
+    let mut rpc_position = None;
+    let rx = send_upstream(
+        request,
+        rpc_list_rwlock,
+        params.ttl,
+        params.max_retries,
+        &params.retryable_error_codes,
+        params.selection,
+        params.sticky_key,
+        params.routing.requirements(request, named_numbers),
+        Hedge::default(),
+        &mut rpc_position,
+    )
+    .await?;
+
+    // simd_json parses in place, so work on a copy of the response
+    let mut rx_str = rx.clone();
+    match unsafe { simd_json::serde::from_str::<Value>(&mut rx_str) } {
+        Ok(Value::Object(mut rx_value)) => {
+            match rx_value.remove("result") {
+                Some(Value::Array(logs)) if !rx_value.contains_key("error") => {
+                    Ok(Fetched::Logs(logs))
+                }
+                _ => Ok(Fetched::Passthrough(rx)),
+            }
+        }
+        _ => Ok(Fetched::Passthrough(rx)),
+    }
+}
+
+// Logs fetched for a chunk that wasn't cached
+enum ChunkLogs {
+    // The whole chunk, to be cached under `key`
+    Whole {
+        end: u64,
+        key: Vec<u8>,
+        logs: Vec<Value>,
+    },
+    // Only the part of the chunk in the range, which doesn't get cached
+    Part(Vec<Value>),
+    Passthrough(String),
+}
+
+// Fetch the chunk `start..=end` of `range`.
+//
+// The whole chunk can be more than a RPC is willing to return even when the part
+// of it we were asked for isn't, so if it fails we fall back to fetching just that.
+async fn fetch_chunk(
+    range: LogsRange,
+    id: Value,
+    (start, end, key): (u64, u64, Vec<u8>),
+    named_numbers: NamedBlocknumbers,
+    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    params: RequestParams,
+) -> Result<ChunkLogs, ErrorResponse> {
+    let request = range.request(&id, start, end);
+    match fetch_logs(&request, &named_numbers, &rpc_list_rwlock, &params).await {
+        Ok(Fetched::Logs(logs)) => Ok(ChunkLogs::Whole { end, key, logs }),
+        _ if start < range.from || end > range.to => {
+            let request = range.request(&id, start.max(range.from), end.min(range.to));
+            match fetch_logs(&request, &named_numbers, &rpc_list_rwlock, &params).await? {
+                Fetched::Logs(logs) => Ok(ChunkLogs::Part(logs)),
+                Fetched::Passthrough(rx) => Ok(ChunkLogs::Passthrough(rx)),
+            }
+        }
+        Ok(Fetched::Passthrough(rx)) => Ok(ChunkLogs::Passthrough(rx)),
+        Err(err) => Err(err),
+    }
+}
+
+// Get the response to an eth_getLogs call for `range`, from cached chunks where possible.
+//
+// Chunks that are past `finality_distance` get fetched whole, `CHUNK_FETCHES` at a time,
+// and added to `batch`. If a RPC won't return a whole chunk, the part of it in `range`
+// gets fetched uncached. The rest of the range is likely to reorg, so it's fetched in a
+// single uncached call, and so are ranges over more than `MAX_CHUNKS` chunks.
+//
+// While `named_numbers` are provisional, cached chunks are read but new ones aren't written.
+#[allow(clippy::too_many_arguments)]
+pub async fn resolve_logs(
+    range: &LogsRange,
+    id: Value,
+    named_numbers: &NamedBlocknumbers,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: &tokio::sync::watch::Receiver<u64>,
+    cache_args: &CacheArgs,
+    params: &RequestParams,
+    batch: &mut CacheBatch,
+) -> Result<String, ErrorResponse> {
+    let metrics = cache_args.cache_metrics.call("eth_getLogs");
+    let head = named_numbers.latest;
+
+    let mut logs = Vec::new();
+    let mut head_from = None;
+    let mut missing = Vec::new();
+
+    // A call per chunk would be too many for a single request
+    let chunks = match chunks(range.from, range.to) {
+        chunks if chunks.len() > MAX_CHUNKS => {
+            head_from = Some(range.from);
+            Vec::new()
+        }
+        chunks => chunks,
+    };
+
+    for (chunk_start, chunk_end) in chunks {
+        // Every chunk after this one is closer to the head as well
+        if near_head(chunk_end, head, params.finality_distance) {
+            head_from = Some(chunk_start.max(range.from));
+            break;
+        }
+
+        // Chunks that are still queued to be written are only in the hot cache
+        let key = range.chunk_key(cache_args, chunk_start);
+        let cached = match cache_args.hot_cache.get(&key) {
+            Some(cached) => Some(cached),
+            None => match cache_args.backend.get(&key).await {
+                Ok(cached) => cached,
+                Err(_) => {
+                    print_cache_error!();
+                    return Err(cache_error!(id));
+                }
+            },
+        };
+
+        if let Some(mut cached) = cached {
+            if let Ok(Value::Array(chunk_logs)) = parse_payload(&mut cached) {
+                metrics.hit();
+                logs.extend(chunk_logs);
+                continue;
+            }
+        }
+        metrics.miss();
+        missing.push((chunk_start, chunk_end, key));
+    }
+
+    // Dropping `fetches` on an early return cancels whatever is still in flight
+    let mut missing = missing.into_iter();
+    let mut fetches = JoinSet::new();
+    loop {
+        while fetches.len() < CHUNK_FETCHES {
+            match missing.next() {
+                Some(chunk) => {
+                    fetches.spawn(fetch_chunk(
+                        range.clone(),
+                        id.clone(),
+                        chunk,
+                        *named_numbers,
+                        Arc::clone(rpc_list_rwlock),
+                        params.clone(),
+                    ));
+                }
+                None => break,
+            }
+        }
+
+        let fetched = match fetches.join_next().await {
+            Some(fetched) => fetched.expect("eth_getLogs chunk fetch panicked")?,
+            None => break,
+        };
+        let (chunk_end, key, chunk_logs) = match fetched {
+            ChunkLogs::Whole { end, key, logs } => (end, key, logs),
+            ChunkLogs::Part(part_logs) => {
+                logs.extend(part_logs);
+                continue;
+            }
+            ChunkLogs::Passthrough(rx) => return Ok(rx),
+        };
+
+        if named_numbers.provisional {
+            logs.extend(chunk_logs);
+            continue;
+        }
+
+        let rx_bytes = to_vec(&chunk_logs).unwrap();
+        if too_large(rx_bytes.len(), params.max_entry_bytes) {
+            metrics.too_large();
+            logs.extend(chunk_logs);
+            continue;
+        }
+
+        // Index the chunk by its last block so it gets invalidated if anything in it reorgs
+        let rx_bytes = params.compression.encode(rx_bytes);
+        if chunk_end > *finalized_rx.borrow() {
+            batch.index_block(chunk_end, &key);
+        }
+        batch.insert(&key, &rx_bytes);
+        batch.index_method("eth_getLogs", &key);
+
+        // Log the write so it can get evicted if the cache grows too large
+        if params.max_cache_size != 0 {
+            batch.record_write(&key, rx_bytes.len());
+        }
+        metrics.insert();
+
+        logs.extend(chunk_logs);
+    }
+
+    if let Some(head_from) = head_from {
+        metrics.uncacheable();
+
+        let request = range.request(&id, head_from, range.to);
+        match fetch_logs(&request, named_numbers, rpc_list_rwlock, params).await? {
+            Fetched::Logs(head_logs) => logs.extend(head_logs),
+            Fetched::Passthrough(rx) => return Ok(rx),
+        }
+    }
+
+    Ok(json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": merge_logs(logs, range.from, range.to),
+    })
+    .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::consensus::ConsensusSettings,
+        balancer::hedge::HedgeSettings,
+        balancer::revalidate::Revalidator,
+        balancer::selection::routing::RoutingRules,
+        balancer::selection::select::SelectionStrategy,
+        database::{
+            entry::Compression,
+            hasher::CacheHasher,
+            hot_cache::HotCache,
+            metrics::CacheMetrics,
+            sled_backend::SledBackend,
+            write_behind::CacheWriter,
+        },
+        rpc::mock::{
+            mock_rpc,
+            mock_rpc_loaded,
+            mock_rpc_raw,
+        },
+    };
+    use std::{
+        collections::HashMap,
+        time::Duration,
+        sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+    };
+
+    fn log(block: u64, index: u64) -> Value {
+        json!({
+            "address": "0x0000000000000000000000000000000000000001",
+            "blockNumber": format!("0x{:x}", block),
+            "logIndex": format!("0x{:x}", index),
+        })
+    }
+
+    fn get_logs(from: &str, to: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getLogs",
+            "params": [{"fromBlock": from, "toBlock": to, "address": "0x0000000000000000000000000000000000000001"}],
+        })
+    }
+
+    fn request_params() -> RequestParams {
+        RequestParams {
+            ttl: 1000,
+            max_retries: 2,
+            retryable_error_codes: Vec::new(),
+            non_idempotent_methods: Vec::new(),
+            cache_methods: HashMap::new(),
+            permanent_error_codes: Vec::new(),
+            debug_logging: false,
+            debug_max_params_len: 128,
+            max_cache_size: 0,
+            compression: Compression::None,
+            finality_distance: 64,
+            negative_cache_methods: Vec::new(),
+            negative_ttl: Duration::ZERO,
+            max_entry_bytes: 0,
+            call_log_size: 0,
+            selection: SelectionStrategy::default(),
+            sticky_key: None,
+            routing: RoutingRules::default(),
+            hedging: HedgeSettings::default(),
+            consensus: ConsensusSettings::default(),
+        }
+    }
+
+    fn cache_args() -> CacheArgs {
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+
+        CacheArgs {
+            backend: Arc::new(SledBackend::new(cache)),
+            hot_cache: Arc::new(HotCache::new(1024)),
+            cache_metrics: Arc::new(CacheMetrics::default()),
+            chain_id: 1,
+            hasher: CacheHasher::default(),
+            writer: CacheWriter::new(16).0,
+            revalidator: Arc::new(Revalidator::default()),
+        }
+    }
+
+    // Mock RPC with two logs every 250 blocks, sent back in reverse order.
+    //
+    // Returns its URL and a list of the ranges it got asked for.
+    async fn mock_logs_rpc() -> (String, Arc<RwLock<Vec<(u64, u64)>>>) {
+        let requests = Arc::new(RwLock::new(Vec::new()));
+        let requests_rpc = Arc::clone(&requests);
+        let url = mock_rpc(move |tx| {
+            let filter = &tx["params"][0];
+            let from = hex_to_decimal(filter["fromBlock"].as_str().unwrap()).unwrap();
+            let to = hex_to_decimal(filter["toBlock"].as_str().unwrap()).unwrap();
+            requests_rpc.write().unwrap().push((from, to));
+
+            let logs: Vec<Value> = (from..=to)
+                .rev()
+                .filter(|block| block % 250 == 0)
+                .flat_map(|block| [log(block, 1), log(block, 0)])
+                .collect();
+            json!(logs)
+        })
+        .await;
+
+        (url, requests)
+    }
+
+    // Ranges a mock RPC got asked for, chunks are fetched in any order
+    fn sorted(requests: &RwLock<Vec<(u64, u64)>>) -> Vec<(u64, u64)> {
+        let mut requests = requests.read().unwrap().clone();
+        requests.sort_unstable();
+        requests
+    }
+
+    // What the response for `from..=to` should be
+    fn expected_logs(from: u64, to: u64) -> Value {
+        let logs: Vec<Value> = (from..=to)
+            .filter(|block| block % 250 == 0)
+            .flat_map(|block| [log(block, 0), log(block, 1)])
+            .collect();
+        json!(logs)
+    }
+
+    async fn resolve(
+        tx: &Value,
+        head: u64,
+        rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+        cache_args: &CacheArgs,
+    ) -> Value {
+        let (_finalized_tx, finalized_rx) = tokio::sync::watch::channel(0);
+        let range = LogsRange::from_request(tx, head).unwrap();
+
+        let mut batch = CacheBatch::default();
+        let named_numbers = NamedBlocknumbers {
+            latest: head,
+            ..Default::default()
+        };
+        let rx = resolve_logs(
+            &range,
+            tx["id"].clone(),
+            &named_numbers,
+            rpc_list,
+            &finalized_rx,
+            cache_args,
+            &request_params(),
+            &mut batch,
+        )
+        .await
+        .unwrap();
+        // Indexes aren't checked here, only the entries
+        let index = sled::Config::new().temporary(true).open().unwrap();
+        batch
+            .apply(&index, cache_args.backend.as_ref())
+            .await
+            .unwrap();
+
+        serde_json::from_str(&rx).unwrap()
+    }
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(chunks(0, 0), vec![(0, 1023)]);
+        assert_eq!(chunks(1023, 1024), vec![(0, 1023), (1024, 2047)]);
+        assert_eq!(chunks(1024, 2047), vec![(1024, 2047)]);
+        assert_eq!(
+            chunks(1000, 3000),
+            vec![(0, 1023), (1024, 2047), (2048, 3071)]
+        );
+        assert_eq!(chunks(u64::MAX, u64::MAX).len(), 1);
+    }
+
+    #[test]
+    fn test_logs_range() {
+        let range = LogsRange::from_request(&get_logs("0x10", "0x20"), 5000).unwrap();
+        assert_eq!((range.from, range.to), (16, 32));
+        assert!(!range.filter.contains_key("fromBlock"));
+        assert!(range.filter.contains_key("address"));
+
+        let range = LogsRange::from_request(&get_logs("earliest", "latest"), 5000).unwrap();
+        assert_eq!((range.from, range.to), (0, 5000));
+
+        // Defaults to latest
+        let tx = json!({"method": "eth_getLogs", "params": [{"fromBlock": "0x10"}]});
+        assert_eq!(LogsRange::from_request(&tx, 5000).unwrap().to, 5000);
+
+        // Left to the regular path
+        assert!(LogsRange::from_request(&get_logs("0x10", "0x20"), 0).is_none());
+        assert!(LogsRange::from_request(&get_logs("0x20", "0x10"), 5000).is_none());
+        assert!(LogsRange::from_request(&get_logs("0x10", "0x2000"), 5000).is_none());
+        assert!(LogsRange::from_request(&get_logs("0x10", "pending"), 5000).is_none());
+        assert!(LogsRange::from_request(&get_logs("safe", "latest"), 5000).is_none());
+        let tx = json!({"method": "eth_getLogs", "params": [{"blockHash": "0xabcd"}]});
+        assert!(LogsRange::from_request(&tx, 5000).is_none());
+        let tx = json!({"method": "eth_getBalance", "params": [{"fromBlock": "0x10"}]});
+        assert!(LogsRange::from_request(&tx, 5000).is_none());
+    }
+
+    #[test]
+    fn test_logs_range_numbered() {
+        let range = LogsRange::from_numbered_request(&get_logs("0x10", "0x20"), 5000).unwrap();
+        assert_eq!((range.from, range.to), (16, 32));
+
+        // Anything that depends on the head is left to the regular path
+        assert!(LogsRange::from_numbered_request(&get_logs("0x10", "latest"), 5000).is_none());
+        assert!(LogsRange::from_numbered_request(&get_logs("latest", "0x20"), 5000).is_none());
+        let tx = json!({"method": "eth_getLogs", "params": [{"fromBlock": "0x10"}]});
+        assert!(LogsRange::from_numbered_request(&tx, 5000).is_none());
+    }
+
+    #[test]
+    fn test_merge_logs() {
+        let logs = vec![
+            log(2048, 0),
+            log(1023, 1),
+            log(1023, 0),
+            log(1024, 0),
+            // Overlapping upstream ranges
+            log(1024, 0),
+            log(10, 0),
+            // No position, pending
+            json!({"blockNumber": null, "logIndex": null}),
+        ];
+
+        assert_eq!(
+            merge_logs(logs, 1000, 2047),
+            vec![log(1023, 0), log(1023, 1), log(1024, 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_logs_cached_chunks() {
+        let (url, requests) = mock_logs_rpc().await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(url, 10, 5.0)]));
+        let cache_args = cache_args();
+
+        let tx = get_logs("0x3e8", "0xbb8");
+        let rx = resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(rx["id"], 1);
+        assert_eq!(rx["result"], expected_logs(1000, 3000));
+        assert_eq!(
+            sorted(&requests),
+            vec![(0, 1023), (1024, 2047), (2048, 3071)]
+        );
+
+        // Same chunks, different bounds
+        let tx = get_logs("0x400", "0x7d0");
+        let rx = resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(rx["result"], expected_logs(1024, 2000));
+        assert_eq!(requests.read().unwrap().len(), 3);
+
+        // Other filters get their own chunks
+        let mut tx = get_logs("0x400", "0x7d0");
+        tx["params"][0]["address"] = json!("0x0000000000000000000000000000000000000002");
+        resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(requests.read().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_logs_head_region() {
+        let (url, requests) = mock_logs_rpc().await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(url, 10, 5.0)]));
+        let cache_args = cache_args();
+
+        // 4096..=5119 is within 64 blocks of the head so it only gets fetched for what was asked
+        let tx = get_logs("0xbb8", "latest");
+        let rx = resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(rx["result"], expected_logs(3000, 5000));
+        assert_eq!(
+            sorted(&requests),
+            vec![(2048, 3071), (3072, 4095), (4096, 5000)]
+        );
+
+        // The head region is never cached
+        let rx = resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(rx["result"], expected_logs(3000, 5000));
+        assert_eq!(requests.read().unwrap()[3..], [(4096, 5000)]);
+
+        // Range entirely within the head region
+        let tx = get_logs("0x1388", "0x1388");
+        let rx = resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(rx["result"], expected_logs(5000, 5000));
+        assert_eq!(requests.read().unwrap()[4..], [(5000, 5000)]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_logs_error_passthrough() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_rpc = Arc::clone(&calls);
+        let url = mock_rpc_raw(move |tx| {
+            calls_rpc.fetch_add(1, Ordering::SeqCst);
+            json!({"jsonrpc": "2.0", "id": tx["id"], "error": {"code": -32005, "message": "query returned more than 10000 results"}}).to_string()
+        })
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(url, 10, 5.0)]));
+        let cache_args = cache_args();
+
+        let tx = get_logs("0x0", "0x10");
+        let rx = resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(rx["id"], 1);
+        assert_eq!(rx["error"]["code"], -32005);
+
+        // Errors don't get cached, the range we were asked for gets tried after the chunk
+        resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_logs_max_chunks() {
+        let (url, requests) = mock_logs_rpc().await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(url, 10, 5.0)]));
+        let cache_args = cache_args();
+
+        // Too wide to split, so it's a single call that doesn't get cached
+        let to = MAX_CHUNKS as u64 * CHUNK_SIZE;
+        let tx = get_logs("0x0", &format!("0x{:x}", to));
+        let head = to + 1000;
+        for _ in 0..2 {
+            let rx = resolve(&tx, head, &rpc_list, &cache_args).await;
+            assert_eq!(rx["result"], expected_logs(0, to));
+        }
+        assert_eq!(*requests.read().unwrap(), vec![(0, to), (0, to)]);
+
+        // Just under the limit still gets split
+        let tx = get_logs("0x0", &format!("0x{:x}", to - 1));
+        resolve(&tx, head, &rpc_list, &cache_args).await;
+        assert_eq!(requests.read().unwrap().len(), 2 + MAX_CHUNKS);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_logs_concurrent_chunks() {
+        let (url, load) = mock_rpc_loaded(Duration::from_millis(50), |_| json!([])).await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(url, 10, 5.0)]));
+        let cache_args = cache_args();
+
+        let tx = get_logs("0x0", &format!("0x{:x}", 16 * CHUNK_SIZE - 1));
+        let rx = resolve(&tx, 100_000, &rpc_list, &cache_args).await;
+        assert_eq!(rx["result"], json!([]));
+        assert_eq!(load.peak(), CHUNK_FETCHES);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_logs_range_limit() {
+        // Rejects ranges wider than 100 blocks, like a lot of providers do
+        let requests = Arc::new(RwLock::new(Vec::new()));
+        let requests_rpc = Arc::clone(&requests);
+        let url = mock_rpc_raw(move |tx| {
+            let filter = &tx["params"][0];
+            let from = hex_to_decimal(filter["fromBlock"].as_str().unwrap()).unwrap();
+            let to = hex_to_decimal(filter["toBlock"].as_str().unwrap()).unwrap();
+            requests_rpc.write().unwrap().push((from, to));
+
+            if to - from >= 100 {
+                return json!({"jsonrpc": "2.0", "id": tx["id"], "error": {"code": -32005, "message": "block range too wide"}}).to_string();
+            }
+            let logs: Vec<Value> = (from..=to)
+                .filter(|block| block % 250 == 0)
+                .flat_map(|block| [log(block, 0), log(block, 1)])
+                .collect();
+            json!({"jsonrpc": "2.0", "id": tx["id"], "result": logs}).to_string()
+        })
+        .await;
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(url, 10, 5.0)]));
+        let cache_args = cache_args();
+
+        // The client's own range is narrow enough
+        let tx = get_logs("0x1f4", "0x226");
+        let rx = resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(rx["result"], expected_logs(500, 550));
+        assert_eq!(*requests.read().unwrap(), vec![(0, 1023), (500, 550)]);
+
+        // So nothing got cached, and we try the whole chunk again next time
+        resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(requests.read().unwrap().len(), 4);
+
+        // Wider ranges still get the error
+        let tx = get_logs("0x1f4", "0x3e8");
+        let rx = resolve(&tx, 5000, &rpc_list, &cache_args).await;
+        assert_eq!(rx["error"]["code"], -32005);
+    }
+}
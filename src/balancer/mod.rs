@@ -1,4 +1,10 @@
 pub mod accept_http;
+pub mod consensus;
 pub mod format;
+pub mod hedge;
+pub mod logs_cache;
+pub mod request_log;
 mod response_errors;
+pub mod revalidate;
 pub mod selection;
+pub mod warmup;
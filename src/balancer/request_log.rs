@@ -0,0 +1,149 @@
+use serde_json::Value;
+
+use std::time::Duration;
+
+// Methods whose params contain signed transactions, keys or passwords.
+// We never log their params, only their size.
+const SENSITIVE_PREFIXES: [&str; 3] = ["eth_send", "eth_sign", "personal_"];
+
+// Debug info about a single call, collected before we send it so we
+// don't have to hold on to the whole request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLog {
+    method: String,
+    id: Value,
+    params: String,
+    request_size: usize,
+}
+
+impl RequestLog {
+    pub fn new(tx: &Value, id: &Value, max_params_len: usize) -> Self {
+        let method = tx["method"].as_str().unwrap_or_default().to_string();
+        let params = redact_params(&method, &tx["params"], max_params_len);
+
+        Self {
+            method,
+            id: id.clone(),
+            params,
+            request_size: tx.to_string().len(),
+        }
+    }
+
+    // Format the log line once we got a response.
+    //
+    // `rpc_position` is None if the response came from the cache.
+    pub fn format(
+        &self,
+        rpc_position: Option<usize>,
+        response_size: usize,
+        latency: Duration,
+    ) -> String {
+        let node = match rpc_position {
+            Some(rpc_position) => rpc_position.to_string(),
+            None => "cache".to_string(),
+        };
+
+        format!(
+            "\x1b[36mDebug:\x1b[0m method={} id={} node={} request_size={} response_size={} latency={:?} params={}",
+            self.method, self.id, node, self.request_size, response_size, latency, self.params,
+        )
+    }
+}
+
+// Return `params` as a string that is safe to log.
+//
+// Params of sensitive methods are replaced with their size, everything
+// else is truncated to `max_len` characters.
+pub fn redact_params(method: &str, params: &Value, max_len: usize) -> String {
+    let params = params.to_string();
+
+    if SENSITIVE_PREFIXES
+        .iter()
+        .any(|prefix| method.starts_with(prefix))
+    {
+        return format!("<redacted {} bytes>", params.len());
+    }
+
+    if params.chars().count() > max_len {
+        return format!(
+            "{}...<truncated {} bytes>",
+            params.chars().take(max_len).collect::<String>(),
+            params.len(),
+        );
+    }
+
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_send_raw_transaction() {
+        let raw_tx = "0xf86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83";
+        let tx = json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "method": "eth_sendRawTransaction",
+            "params": [raw_tx],
+        });
+
+        let log = RequestLog::new(&tx, &json!(1), 1024);
+        let line = log.format(Some(0), 66, Duration::from_millis(5));
+
+        assert!(!line.contains(raw_tx));
+        assert!(!line.contains(&raw_tx[2..16]));
+        assert!(line.contains("method=eth_sendRawTransaction"));
+        assert!(line.contains(&format!(
+            "params=<redacted {} bytes>",
+            tx["params"].to_string().len()
+        )));
+    }
+
+    #[test]
+    fn test_redact_params() {
+        // Other signing methods are redacted too
+        let params = json!(["0xdeadbeef", "hunter2"]);
+        assert_eq!(
+            redact_params("personal_unlockAccount", &params, 1024),
+            format!("<redacted {} bytes>", params.to_string().len())
+        );
+        assert!(redact_params("eth_signTypedData_v4", &params, 1024).starts_with("<redacted"));
+
+        // Short params are logged as is
+        let params = json!(["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "latest"]);
+        assert_eq!(
+            redact_params("eth_getBalance", &params, 1024),
+            params.to_string()
+        );
+
+        // Long params get truncated
+        let truncated = redact_params("eth_getBalance", &params, 8);
+        assert_eq!(
+            truncated,
+            format!("[\"0x407d...<truncated {} bytes>", params.to_string().len())
+        );
+    }
+
+    #[test]
+    fn test_request_log_format() {
+        let tx = json!({
+            "id": "abc",
+            "jsonrpc": "2.0",
+            "method": "eth_chainId",
+            "params": [],
+        });
+
+        let log = RequestLog::new(&tx, &json!("abc"), 1024);
+        let line = log.format(None, 40, Duration::from_millis(1));
+
+        assert!(line.contains("method=eth_chainId"));
+        assert!(line.contains("id=\"abc\""));
+        assert!(line.contains("node=cache"));
+        assert!(line.contains(&format!("request_size={}", tx.to_string().len())));
+        assert!(line.contains("response_size=40"));
+        assert!(line.contains("params=[]"));
+    }
+}
@@ -1,29 +1,115 @@
+use serde_json::{
+    json,
+    Value,
+};
+
 // Due to how schizophrenic hyper is, we're defining our http errors like this.
 // ???
+//
+// Errors are returned as spec-compliant JSON-RPC error objects together with the
+// HTTP status code to use when the error is the only thing we're responding with.
+// Inside of batches, only the JSON-RPC error object is used.
+
+// JSON-RPC error we're responding with instead of a response from a RPC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+// Format a JSON-RPC error object, echoing the id of the call that errored
+pub fn jsonrpc_error(id: &Value, code: i64, message: &str) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": code,
+            "message": message,
+        },
+    })
+    .to_string()
+}
 
 #[macro_export]
 macro_rules! no_rpc_available {
-    () => {
-        Ok(hyper::Response::builder()
-            .status(500)
-            .body(Full::new(Bytes::from(
-                "{code:-32002, message:\"error: No working RPC available! Try again later...\"}"
-                    .to_string(),
-            )))
-            .unwrap())
+    ($id:expr) => {
+        $crate::balancer::response_errors::ErrorResponse {
+            status: 500,
+            body: $crate::balancer::response_errors::jsonrpc_error(
+                &$id,
+                -32002,
+                "error: No working RPC available! Try again later...",
+            ),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! no_capable_rpc {
+    ($id:expr, $requirements:expr) => {
+        $crate::balancer::response_errors::ErrorResponse {
+            status: 500,
+            body: $crate::balancer::response_errors::jsonrpc_error(
+                &$id,
+                -32004,
+                &format!(
+                    "error: No RPC able to serve this call is available, it needs: {}",
+                    $requirements
+                ),
+            ),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! rate_limited {
+    ($id:expr) => {
+        $crate::balancer::response_errors::ErrorResponse {
+            status: 429,
+            body: $crate::balancer::response_errors::jsonrpc_error(
+                &$id,
+                -32005,
+                "error: All RPCs are at their rate or concurrency limit! Try again later...",
+            ),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! invalid_request {
+    ($id:expr) => {
+        $crate::balancer::response_errors::ErrorResponse {
+            status: 400,
+            body: $crate::balancer::response_errors::jsonrpc_error(&$id, -32600, "Invalid Request"),
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! invalid_response {
+    ($id:expr) => {
+        $crate::balancer::response_errors::ErrorResponse {
+            status: 502,
+            body: $crate::balancer::response_errors::jsonrpc_error(
+                &$id,
+                -32603,
+                "error: Received a malformed response from the RPC! Try again later...",
+            ),
+        }
     };
 }
 
 #[macro_export]
 macro_rules! timed_out {
-    () => {
-        Ok(hyper::Response::builder()
-            .status(408)
-            .body(Full::new(Bytes::from(
-                "{code:-32001, message:\"error: Request timed out! Try again later...\"}"
-                    .to_string(),
-            )))
-            .unwrap())
+    ($id:expr) => {
+        $crate::balancer::response_errors::ErrorResponse {
+            status: 408,
+            body: $crate::balancer::response_errors::jsonrpc_error(
+                &$id,
+                -32001,
+                "error: Request timed out! Try again later...",
+            ),
+        }
     };
 }
 
@@ -38,13 +124,15 @@ macro_rules! print_cache_error {
 
 #[macro_export]
 macro_rules! cache_error {
-    () => {
-        Ok(hyper::Response::builder()
-            .status(500)
-            .body(Full::new(Bytes::from(
-                "{code:-32003, message:\"error: Cache error! Try again later...\"}".to_string(),
-            )))
-            .unwrap())
+    ($id:expr) => {
+        $crate::balancer::response_errors::ErrorResponse {
+            status: 500,
+            body: $crate::balancer::response_errors::jsonrpc_error(
+                &$id,
+                -32003,
+                "error: Cache error! Try again later...",
+            ),
+        }
     };
 }
 
@@ -60,3 +148,65 @@ macro_rules! rpc_response {
             .unwrap())
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value::Null;
+
+    // Assert that `rx` is a valid JSON-RPC error response
+    fn assert_error_object(rx: &str, id: &Value, code: i64) {
+        let rx: Value = serde_json::from_str(rx).unwrap();
+
+        assert_eq!(rx["jsonrpc"], "2.0");
+        assert_eq!(&rx["id"], id);
+        assert_eq!(rx["error"]["code"], code);
+        assert!(rx["error"]["message"].is_string());
+        assert!(rx.get("result").is_none());
+    }
+
+    #[test]
+    fn test_jsonrpc_error() {
+        let rx = jsonrpc_error(&json!(1), -32602, "Invalid params");
+        assert_error_object(&rx, &json!(1), -32602);
+
+        let rx = jsonrpc_error(&json!("abc-123"), -32603, "Internal error");
+        assert_error_object(&rx, &json!("abc-123"), -32603);
+
+        let rx = jsonrpc_error(&Null, -32601, "Method not found");
+        assert_error_object(&rx, &Null, -32601);
+    }
+
+    #[test]
+    fn test_error_macros() {
+        let id = json!("abc-123");
+
+        let err = no_rpc_available!(id);
+        assert_eq!(err.status, 500);
+        assert_error_object(&err.body, &id, -32002);
+
+        let err = no_capable_rpc!(id, "archive");
+        assert_eq!(err.status, 500);
+        assert_error_object(&err.body, &id, -32004);
+
+        let err = rate_limited!(id);
+        assert_eq!(err.status, 429);
+        assert_error_object(&err.body, &id, -32005);
+
+        let err = timed_out!(id);
+        assert_eq!(err.status, 408);
+        assert_error_object(&err.body, &id, -32001);
+
+        let err = cache_error!(id);
+        assert_eq!(err.status, 500);
+        assert_error_object(&err.body, &id, -32003);
+
+        let err = invalid_response!(id);
+        assert_eq!(err.status, 502);
+        assert_error_object(&err.body, &id, -32603);
+
+        let err = invalid_request!(Null);
+        assert_eq!(err.status, 400);
+        assert_error_object(&err.body, &Null, -32600);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{
+    balancer::accept_http::{
+        insert_response,
+        send_upstream,
+        CacheArgs,
+        RequestParams,
+    },
+    balancer::hedge::Hedge,
+    balancer::selection::cache_rules::{
+        cache_result,
+        CachePolicy,
+    },
+    database::batch::CacheBatch,
+    rpc::types::Rpc,
+    NamedBlocknumbers,
+};
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::sync::watch;
+
+// Picks which cache hits get fetched again, set with `cache.revalidate_fraction`.
+//
+// Hits are sampled evenly instead of at random, so a fraction of 0.01
+// revalidates every 100th hit.
+#[derive(Debug, Default)]
+pub struct Revalidator {
+    fraction: f64,
+    hits: AtomicU64,
+}
+
+impl Revalidator {
+    pub fn new(fraction: f64) -> Self {
+        Revalidator {
+            fraction: fraction.clamp(0.0, 1.0),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.fraction > 0.0
+    }
+
+    // Count a cache hit, returning true if it should be revalidated
+    pub fn sample(&self) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+
+        let hits = self.hits.fetch_add(1, Ordering::Relaxed) as f64;
+        ((hits + 1.0) * self.fraction).floor() > (hits * self.fraction).floor()
+    }
+}
+
+// Send `tx` to a RPC again and replace `cached`, the response we served for it,
+// if the RPC disagrees with the cache.
+//
+// This runs after the cached response was returned, so it doesn't slow down the request.
+// Both `tx` and `cached` are expected to have a null id.
+#[allow(clippy::too_many_arguments)]
+pub async fn revalidate(
+    tx: Value,
+    cached: Value,
+    cache_key: Vec<u8>,
+    policy: CachePolicy,
+    negative_ttl: Option<Duration>,
+    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: watch::Receiver<u64>,
+    named_numbers: Arc<RwLock<NamedBlocknumbers>>,
+    cache_args: CacheArgs,
+    params: RequestParams,
+) {
+    let method = tx["method"].as_str().unwrap_or_default().to_string();
+
+    let requirements = params
+        .routing
+        .requirements(&tx, &named_numbers.read().unwrap());
+    let mut rpc_position = None;
+    let rx = match send_upstream(
+        &tx,
+        &rpc_list_rwlock,
+        params.ttl,
+        params.max_retries,
+        &params.retryable_error_codes,
+        params.selection,
+        None,
+        requirements,
+        Hedge::default(),
+        &mut rpc_position,
+    )
+    .await
+    {
+        Ok(rx) => rx,
+        // Nothing to compare against, we'll check again on a later hit
+        Err(_) => return,
+    };
+
+    // Errors don't tell us anything about whether the cached response is right
+    let mut fresh = match serde_json::from_str::<Value>(&rx) {
+        Ok(fresh) if cache_result(&fresh, &params.permanent_error_codes) => fresh,
+        _ => return,
+    };
+    fresh["id"] = Value::Null;
+
+    cache_args.cache_metrics.revalidated();
+    if fresh == cached {
+        return;
+    }
+
+    let node = rpc_position
+        .and_then(|position| {
+            let rpc_list = rpc_list_rwlock.read().unwrap();
+            rpc_list.get(position).map(|rpc| rpc.url.clone())
+        })
+        .unwrap_or_default();
+    cache_args.cache_metrics.diverged(&method, &node);
+    println!(
+        "\x1b[93mWrn:\x1b[0m {} disagrees with the cached response for {}, replacing it.",
+        node, method
+    );
+
+    let finalized = *finalized_rx.borrow();
+    let mut batch = CacheBatch::default();
+    if insert_response(
+        tx,
+        fresh,
+        &cache_key,
+        policy,
+        negative_ttl,
+        finalized,
+        &named_numbers,
+        &params,
+        &cache_args.cache_metrics.call(&method),
+        &mut batch,
+    ) {
+        cache_args.writer.write(batch, &cache_args.hot_cache);
+        return;
+    }
+
+    // The new response can't be cached, eg. because its block is close to the head now
+    cache_args.hot_cache.remove(&cache_key);
+    if let Err(err) = cache_args.backend.remove(vec![cache_key]).await {
+        println!(
+            "\x1b[31mErr:\x1b[0m Could not remove diverging cache entry: {}",
+            err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_revalidator_sample() {
+        let sampled = |fraction| {
+            let revalidator = Revalidator::new(fraction);
+            (0..1000).filter(|_| revalidator.sample()).count()
+        };
+
+        assert_eq!(sampled(0.0), 0);
+        assert_eq!(sampled(0.01), 10);
+        assert_eq!(sampled(0.25), 250);
+        assert_eq!(sampled(1.0), 1000);
+        assert!(!Revalidator::default().enabled());
+    }
+}
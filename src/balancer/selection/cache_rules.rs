@@ -1,4 +1,9 @@
 use memchr::memmem;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    time::Duration,
+};
 
 // Return true if we are supposed to be caching the input.
 //
@@ -32,21 +37,320 @@ pub fn cache_method(rx: &str) -> bool {
     true
 }
 
+// Methods that change state, or whose result depends on state kept by the node.
+//
+// These always get sent to a RPC, are never cached, and should never be sent twice.
+const NON_IDEMPOTENT_METHODS: [&str; 17] = [
+    "eth_sendRawTransaction",
+    "eth_sendTransaction",
+    "eth_sign",
+    "eth_signTransaction",
+    "eth_signTypedData",
+    "eth_signTypedData_v3",
+    "eth_signTypedData_v4",
+    "eth_submitWork",
+    "eth_submitHashrate",
+    "eth_newFilter",
+    "eth_newBlockFilter",
+    "eth_newPendingTransactionFilter",
+    "eth_uninstallFilter",
+    "eth_getFilterChanges",
+    "eth_getFilterLogs",
+    "eth_subscribe",
+    "eth_unsubscribe",
+];
+
+// Whole namespaces that are non-idempotent
+const NON_IDEMPOTENT_PREFIXES: [&str; 3] = ["personal_", "admin_", "miner_"];
+
+// Return true if `method` is non-idempotent, either by default or because
+// the user added it to `non_idempotent_methods` in the config.
+pub fn is_non_idempotent(method: &str, extra_methods: &[String]) -> bool {
+    NON_IDEMPOTENT_METHODS.contains(&method)
+        || NON_IDEMPOTENT_PREFIXES
+            .iter()
+            .any(|prefix| method.starts_with(prefix))
+        || extra_methods.iter().any(|extra| extra == method)
+}
+
+// How long we're allowed to keep the response to a method in the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    // Always send to a RPC
+    Never,
+    // Keep it until the block it depends on gets reorged out
+    Forever,
+    // Keep it for the duration, regardless of which block it depends on
+    Ttl(Duration),
+}
+
+// TTL for values that change every block, like the gas price
+const SPOT_TTL: Duration = Duration::from_secs(1);
+
+// Sensible defaults for the standard eth namespace
+fn default_cache_policy(method: &str) -> CachePolicy {
+    match method {
+        "eth_gasPrice" | "eth_maxPriorityFeePerGas" | "eth_blobBaseFee" | "eth_feeHistory" => {
+            CachePolicy::Ttl(SPOT_TTL)
+        }
+        "eth_blockNumber" | "eth_syncing" | "eth_mining" | "eth_hashrate" | "eth_accounts"
+        | "eth_coinbase" | "net_peerCount" | "net_listening" => CachePolicy::Never,
+        _ if method.starts_with("txpool_") => CachePolicy::Never,
+        _ => CachePolicy::Forever,
+    }
+}
+
+// Return the cache policy for `method`.
+//
+// Non-idempotent methods are never cached. Otherwise, whatever is set
+// in `[cache.methods]` takes precedence over the defaults.
+pub fn cache_policy(
+    method: &str,
+    overrides: &HashMap<String, CachePolicy>,
+    extra_non_idempotent: &[String],
+) -> CachePolicy {
+    if is_non_idempotent(method, extra_non_idempotent) {
+        return CachePolicy::Never;
+    }
+
+    match overrides.get(method) {
+        Some(policy) => *policy,
+        None => default_cache_policy(method),
+    }
+}
+
+// By-hash lookups return null until the block or transaction is known, so
+// polling clients would keep hitting the RPCs while waiting for it.
+pub const DEFAULT_NEGATIVE_CACHE_METHODS: [&str; 3] = [
+    "eth_getTransactionReceipt",
+    "eth_getTransactionByHash",
+    "eth_getBlockByHash",
+];
+
+// How long to keep null results around for by default
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(2);
+
+// Largest response we cache by default, see `too_large`
+pub const DEFAULT_MAX_ENTRY_BYTES: usize = 4 * 1024 * 1024;
+// Largest entry we keep in the hot cache by default
+pub const DEFAULT_HOT_CACHE_MAX_ENTRY_BYTES: usize = 256 * 1024;
+
+// Return how long a null result for `method` can be cached for, if at all.
+//
+// Only methods in `negative_methods` get their null results cached. A TTL of 0 disables it.
+pub fn negative_ttl(method: &str, negative_methods: &[String], ttl: Duration) -> Option<Duration> {
+    if ttl.is_zero() || !negative_methods.iter().any(|negative| negative == method) {
+        return None;
+    }
+
+    Some(ttl)
+}
+
+// Return true if `rx` is a successful response with a null result
+pub fn is_null_result(rx: &Value) -> bool {
+    rx.get("result") == Some(&Value::Null)
+}
+
+// Return true if `block` is within `finality_distance` blocks of `head`.
+//
+// Responses for those blocks are likely to reorg so we don't cache them.
+// If we don't know the head yet, nothing is considered recent.
+pub fn near_head(block: u64, head: u64, finality_distance: u64) -> bool {
+    head != 0 && block.saturating_add(finality_distance) > head
+}
+
+// Return true if a response of `len` bytes is over `max_entry_bytes`.
+//
+// Huge responses bloat the cache and are expensive to read back, so we don't store them.
+// A limit of 0 means there is none.
+pub fn too_large(len: usize, max_entry_bytes: usize) -> bool {
+    max_entry_bytes != 0 && len > max_entry_bytes
+}
+
 // Same as cache_method but for results
-pub fn cache_result(rx: &str) -> bool {
+//
+// Error responses are never cached, since most of them are temporary (e.g. a node
+// that hasn't synced the block yet), unless their code is in `permanent_error_codes`.
+pub fn cache_result(rx: &Value, permanent_error_codes: &[i64]) -> bool {
     // If no-cache feature is on, return false
     #[cfg(feature = "no-cache")]
     return false;
 
-    // just checking if `error` is present should be enough, but include the beggining error
-    // codes juuuust to be extra safe
-    let blacklist = ["error", "-320", "-326", "-327"];
-
-    for item in blacklist.iter() {
-        if memmem::find(rx.as_bytes(), item.as_bytes()).is_some() {
-            return false;
+    match rx.get("error") {
+        Some(error) => {
+            match error["code"].as_i64() {
+                Some(code) => permanent_error_codes.contains(&code),
+                None => false,
+            }
         }
+        None => rx.get("result").is_some(),
     }
+}
 
-    true
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_non_idempotent() {
+        assert!(is_non_idempotent("eth_sendRawTransaction", &[]));
+        assert!(is_non_idempotent("eth_sendTransaction", &[]));
+        assert!(is_non_idempotent("eth_getFilterChanges", &[]));
+        assert!(is_non_idempotent("personal_sign", &[]));
+        assert!(is_non_idempotent("personal_unlockAccount", &[]));
+
+        assert!(!is_non_idempotent("eth_call", &[]));
+        assert!(!is_non_idempotent("eth_getBalance", &[]));
+        assert!(!is_non_idempotent("", &[]));
+
+        // User supplied methods
+        let extra = vec!["eth_call".to_string()];
+        assert!(is_non_idempotent("eth_call", &extra));
+        assert!(!is_non_idempotent("eth_getBalance", &extra));
+    }
+
+    #[test]
+    fn test_negative_ttl() {
+        let methods: Vec<String> = DEFAULT_NEGATIVE_CACHE_METHODS
+            .iter()
+            .map(|method| method.to_string())
+            .collect();
+
+        assert_eq!(
+            negative_ttl("eth_getTransactionReceipt", &methods, DEFAULT_NEGATIVE_TTL),
+            Some(DEFAULT_NEGATIVE_TTL)
+        );
+        assert_eq!(
+            negative_ttl("eth_getBalance", &methods, DEFAULT_NEGATIVE_TTL),
+            None
+        );
+        assert_eq!(
+            negative_ttl("eth_getTransactionReceipt", &methods, Duration::ZERO),
+            None
+        );
+
+        use serde_json::json;
+        assert!(is_null_result(&json!({"jsonrpc": "2.0", "id": null, "result": null})));
+        assert!(!is_null_result(&json!({"jsonrpc": "2.0", "id": null, "result": "0x1"})));
+        // Missing result isn't a null result
+        assert!(!is_null_result(
+            &json!({"jsonrpc": "2.0", "id": null, "error": {"code": -32000}})
+        ));
+    }
+
+    #[test]
+    fn test_too_large() {
+        assert!(too_large(101, 100));
+        assert!(!too_large(100, 100));
+        assert!(!too_large(usize::MAX, 0));
+    }
+
+    #[test]
+    fn test_near_head() {
+        assert!(near_head(1998, 2000, 64));
+        assert!(near_head(1937, 2000, 64));
+        assert!(!near_head(1936, 2000, 64));
+        assert!(!near_head(1000, 2000, 64));
+
+        // Blocks we haven't seen yet are recent too
+        assert!(near_head(2001, 2000, 64));
+
+        // Unknown head
+        assert!(!near_head(1998, 0, 64));
+        assert!(near_head(2000, 2000, 1));
+        assert!(!near_head(2000, 2000, 0));
+    }
+
+    #[test]
+    fn test_cache_result() {
+        use serde_json::json;
+
+        assert!(cache_result(
+            &json!({"jsonrpc": "2.0", "id": null, "result": "0x1"}),
+            &[]
+        ));
+        assert!(cache_result(
+            &json!({"jsonrpc": "2.0", "id": null, "result": null}),
+            &[]
+        ));
+
+        // Results that happen to contain the word error are fine
+        assert!(cache_result(
+            &json!({"jsonrpc": "2.0", "id": null, "result": "error"}),
+            &[]
+        ));
+
+        let error = json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": {"code": -32000, "message": "header not found"},
+        });
+        assert!(!cache_result(&error, &[]));
+        assert!(!cache_result(&error, &[3]));
+        assert!(cache_result(&error, &[-32000]));
+
+        // Malformed errors are never cached
+        let error = json!({"jsonrpc": "2.0", "id": null, "error": "header not found"});
+        assert!(!cache_result(&error, &[-32000]));
+
+        // Neither are responses without a result
+        assert!(!cache_result(&json!({"jsonrpc": "2.0", "id": null}), &[]));
+    }
+
+    #[test]
+    fn test_cache_policy() {
+        let overrides = HashMap::new();
+
+        assert_eq!(
+            cache_policy("eth_gasPrice", &overrides, &[]),
+            CachePolicy::Ttl(SPOT_TTL)
+        );
+        assert_eq!(
+            cache_policy("eth_blockNumber", &overrides, &[]),
+            CachePolicy::Never
+        );
+        assert_eq!(
+            cache_policy("txpool_content", &overrides, &[]),
+            CachePolicy::Never
+        );
+        assert_eq!(
+            cache_policy("eth_getBlockByHash", &overrides, &[]),
+            CachePolicy::Forever
+        );
+        assert_eq!(
+            cache_policy("eth_sendRawTransaction", &overrides, &[]),
+            CachePolicy::Never
+        );
+
+        // Overrides take precedence over the defaults, but not over non-idempotent methods
+        let overrides = HashMap::from([
+            (
+                "eth_gasPrice".to_string(),
+                CachePolicy::Ttl(Duration::from_secs(12)),
+            ),
+            ("eth_call".to_string(), CachePolicy::Never),
+            ("eth_sendRawTransaction".to_string(), CachePolicy::Forever),
+        ]);
+
+        assert_eq!(
+            cache_policy("eth_gasPrice", &overrides, &[]),
+            CachePolicy::Ttl(Duration::from_secs(12))
+        );
+        assert_eq!(
+            cache_policy("eth_call", &overrides, &[]),
+            CachePolicy::Never
+        );
+        assert_eq!(
+            cache_policy("eth_sendRawTransaction", &overrides, &[]),
+            CachePolicy::Never
+        );
+        assert_eq!(
+            cache_policy(
+                "eth_getBalance",
+                &overrides,
+                &["eth_getBalance".to_string()]
+            ),
+            CachePolicy::Never
+        );
+    }
 }
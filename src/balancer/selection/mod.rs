@@ -1,2 +1,4 @@
 pub mod cache_rules;
+pub mod routing;
 pub mod select;
+pub mod sticky;
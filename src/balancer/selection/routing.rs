@@ -0,0 +1,196 @@
+use crate::{
+    balancer::format::block_number_of,
+    NamedBlocknumbers,
+    Rpc,
+};
+
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt,
+};
+
+// What a RPC needs to be able to serve a call, see `RoutingRules`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Requirements {
+    // The call needs state pruned nodes don't have anymore
+    pub archive: bool,
+    // Labels the RPC has to have, eg. `trace` for calls only some clients support
+    pub labels: Vec<String>,
+}
+
+impl Requirements {
+    pub fn met_by(&self, rpc: &Rpc) -> bool {
+        (!self.archive || rpc.archive) && self.labels.iter().all(|label| rpc.labels.contains(label))
+    }
+
+    // If any RPC can serve the call
+    pub fn is_empty(&self) -> bool {
+        *self == Requirements::default()
+    }
+}
+
+impl fmt::Display for Requirements {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let archive = self.archive.then_some("archive");
+        let needs = archive
+            .into_iter()
+            .chain(self.labels.iter().map(String::as_str))
+            .collect::<Vec<&str>>();
+
+        write!(f, "{}", needs.join(", "))
+    }
+}
+
+// When a call has to go to an archive node, set in `[routing.archive]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveRule {
+    Always,
+    // Only if its block is more than this many blocks behind the head
+    OlderThan(u64),
+}
+
+// Which RPCs calls can be sent to, on top of how healthy they are
+#[derive(Debug, Clone, Default)]
+pub struct RoutingRules {
+    pub archive: HashMap<String, ArchiveRule>,
+    // Method prefixes, eg. `debug_`, mapped to the labels RPCs need to serve them
+    pub labels: HashMap<String, Vec<String>>,
+}
+
+impl RoutingRules {
+    // Return what a RPC needs to serve `tx`.
+    //
+    // Block tags get resolved with `named_numbers`. Until we know the head, only
+    // calls that always need an archive node get routed to one.
+    pub fn requirements(&self, tx: &Value, named_numbers: &NamedBlocknumbers) -> Requirements {
+        let method = tx["method"].as_str().unwrap_or_default();
+
+        let archive = match self.archive.get(method) {
+            Some(ArchiveRule::Always) => true,
+            Some(ArchiveRule::OlderThan(age)) => {
+                let head = named_numbers.latest;
+                match block_number_of(tx, named_numbers) {
+                    Some(block) if head != 0 => head.saturating_sub(block) > *age,
+                    _ => false,
+                }
+            }
+            None => false,
+        };
+
+        // Every rule the method matches applies
+        let mut labels = Vec::new();
+        for (prefix, required) in &self.labels {
+            if method.starts_with(prefix.as_str()) {
+                labels.extend(required.iter().cloned());
+            }
+        }
+        labels.sort_unstable();
+        labels.dedup();
+
+        Requirements { archive, labels }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rules() -> RoutingRules {
+        RoutingRules {
+            archive: HashMap::from([
+                ("eth_call".to_string(), ArchiveRule::OlderThan(128)),
+                ("eth_getStorageAt".to_string(), ArchiveRule::OlderThan(128)),
+                ("debug_traceTransaction".to_string(), ArchiveRule::Always),
+            ]),
+            labels: HashMap::from([
+                ("debug_".to_string(), vec!["debug".to_string()]),
+                ("trace_".to_string(), vec!["trace".to_string()]),
+                (
+                    "debug_trace".to_string(),
+                    vec!["trace".to_string(), "debug".to_string()],
+                ),
+            ]),
+        }
+    }
+
+    fn call(method: &str, params: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params})
+    }
+
+    #[test]
+    fn test_requirements() {
+        let named_numbers = NamedBlocknumbers {
+            latest: 1000,
+            ..Default::default()
+        };
+        let archive = |tx: Value| rules().requirements(&tx, &named_numbers).archive;
+
+        // Block age is checked against the head
+        assert!(archive(call("eth_call", json!([{}, "0x1"]))));
+        assert!(archive(call("eth_call", json!([{}, "earliest"]))));
+        assert!(!archive(call("eth_call", json!([{}, "0x3e8"]))));
+        assert!(!archive(call("eth_call", json!([{}, "latest"]))));
+        assert!(!archive(call("eth_call", json!([{}]))));
+        assert!(archive(call(
+            "eth_getStorageAt",
+            json!(["0x0", "0x0", "0x300"])
+        )));
+        assert!(!archive(call(
+            "eth_getStorageAt",
+            json!(["0x0", "0x0", "0x368"])
+        )));
+
+        assert!(archive(call("debug_traceTransaction", json!(["0x0"]))));
+        assert!(!archive(call("eth_getBalance", json!(["0x0", "0x1"]))));
+
+        // Without a head we can't tell how old a block is
+        let requirements =
+            rules().requirements(&call("eth_call", json!([{}, "0x1"])), &Default::default());
+        assert!(requirements.is_empty());
+    }
+
+    #[test]
+    fn test_requirements_labels() {
+        let labels = |method: &str| {
+            rules()
+                .requirements(&call(method, json!([])), &Default::default())
+                .labels
+        };
+
+        // Methods need the labels of every prefix they match
+        assert_eq!(labels("trace_block"), vec!["trace"]);
+        assert_eq!(labels("debug_getRawBlock"), vec!["debug"]);
+        assert_eq!(labels("debug_traceTransaction"), vec!["debug", "trace"]);
+        assert!(labels("eth_call").is_empty());
+        assert!(labels("debugger").is_empty());
+    }
+
+    #[test]
+    fn test_requirements_met_by() {
+        let archive = Rpc::default().with_archive(true);
+        let pruned = Rpc::default();
+        let tracing = Rpc::default().with_labels(vec!["trace".to_string(), "debug".to_string()]);
+        let needs = |archive: bool, labels: &[&str]| {
+            Requirements {
+                archive,
+                labels: labels.iter().map(|label| label.to_string()).collect(),
+            }
+        };
+
+        assert!(Requirements::default().met_by(&pruned));
+        assert!(needs(true, &[]).met_by(&archive));
+        assert!(!needs(true, &[]).met_by(&pruned));
+        assert_eq!(needs(true, &[]).to_string(), "archive");
+
+        // RPCs need all of the labels
+        assert!(needs(false, &["debug", "trace"]).met_by(&tracing));
+        assert!(!needs(false, &["debug"]).met_by(&archive));
+        assert!(!needs(true, &["debug"]).met_by(&tracing));
+        assert_eq!(
+            needs(true, &["debug", "trace"]).to_string(),
+            "archive, debug, trace"
+        );
+    }
+}
@@ -1,63 +1,308 @@
-use crate::Rpc;
+use crate::{
+    balancer::selection::{
+        routing::Requirements,
+        sticky::assigned,
+    },
+    Rpc,
+};
 
-// Generic entry point fn to select the next rpc and return its position
-pub fn pick(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
-    // If len is 1, return the only element
-    if list.len() == 1 {
-        return (list[0].clone(), Some(0));
-    } else if list.is_empty() {
-        return (Rpc::default(), None);
+use rand::Rng;
+use std::time::Duration;
+
+// How to pick the RPC a request gets sent to, set with `selection`.
+//
+// Read for every request, so changing it with `blutgang_set_selection` applies right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionStrategy {
+    // Take turns going down the list, for fairness across identical RPCs
+    RoundRobin,
+    // Stick to the fastest RPC until it maxes out `max_consecutive`
+    LowestLatency,
+    // Spread requests in proportion to each RPC's weight, scaled by its latency
+    Weighted,
+    // Send requests to the RPC with the fewest of them in flight, ties go to the fastest.
+    // Reacts right away when a RPC gets slow, instead of once its responses come back.
+    LeastConnections,
+    // Pick any RPC at random
+    Random,
+}
+
+// The `selection-random` feature only changes the default, it can still be set in the config
+impl Default for SelectionStrategy {
+    fn default() -> Self {
+        if cfg!(feature = "selection-random") {
+            SelectionStrategy::Random
+        } else {
+            SelectionStrategy::Weighted
+        }
     }
+}
 
-    algo(list)
+impl SelectionStrategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "round_robin" => Some(SelectionStrategy::RoundRobin),
+            "lowest_latency" => Some(SelectionStrategy::LowestLatency),
+            "weighted" => Some(SelectionStrategy::Weighted),
+            "least_connections" => Some(SelectionStrategy::LeastConnections),
+            "random" => Some(SelectionStrategy::Random),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SelectionStrategy::RoundRobin => "round_robin",
+            SelectionStrategy::LowestLatency => "lowest_latency",
+            SelectionStrategy::Weighted => "weighted",
+            SelectionStrategy::LeastConnections => "least_connections",
+            SelectionStrategy::Random => "random",
+        }
+    }
 }
 
-// Sorting algo
-pub fn argsort(data: &Vec<Rpc>) -> Vec<usize> {
-    let mut indices = (0..data.len()).collect::<Vec<usize>>();
+// Generic entry point fn to select the next rpc that meets `requirements` and return its position.
+// None if there's no RPC to send the request to.
+//
+// RPCs at the positions in `excluded`, eg. ones a call already failed on, never get picked.
+// They don't count towards their tier either, so the call can go to a fallback instead.
+// Neither do RPCs quarantined for disagreeing with the others, see `balancer::consensus`.
+pub fn pick(
+    list: &[Rpc],
+    strategy: SelectionStrategy,
+    requirements: &Requirements,
+    excluded: &[usize],
+) -> Option<usize> {
+    let capable = |index: usize, rpc: &Rpc| {
+        requirements.met_by(rpc) && !excluded.contains(&index) && !rpc.consensus.is_quarantined()
+    };
+
+    // RPCs with an open circuit don't get requests, other than the probe
+    let healthy = (0..list.len())
+        .filter(|&index| capable(index, &list[index]) && list[index].circuit.is_closed())
+        .collect::<Vec<usize>>();
+    // Only the lowest tier with a healthy RPC gets requests, the ones above are fallbacks.
+    // Busy RPCs still count, we'd rather wait for them than go to a fallback.
+    let tier = healthy.iter().map(|&index| list[index].tier).min();
+    let candidates = healthy
+        .into_iter()
+        .filter(|&index| is_free(&list[index]))
+        .collect::<Vec<usize>>();
+
+    // Once its cooldown is over, a RPC with an open circuit gets the next request as a probe,
+    // unless it's a fallback for RPCs that are still healthy
+    let probe = (0..list.len()).find(|&index| {
+        let rpc = &list[index];
+        capable(index, rpc)
+            && tier.map_or(true, |tier| rpc.tier <= tier)
+            && rpc.circuit.ready_to_probe()
+            && is_free(rpc)
+    });
+    // Concurrent picks can find the same RPC, only one of them gets to send the probe
+    let probe = probe.filter(|&probe| list[probe].circuit.try_probe(&list[probe].url));
+    let index = if let Some(probe) = probe {
+        probe
+    } else {
+        let candidates = candidates
+            .into_iter()
+            .filter(|&index| Some(list[index].tier) == tier)
+            .collect::<Vec<usize>>();
 
-    // Use sort_by_cached_key with a closure that compares latency
-    // Uses pdqsort and does not allocate so should be fast
-    indices.sort_unstable_by_key(|&index| data[index].status.latency as u64);
+        match candidates.len() {
+            0 => return None,
+            1 => candidates[0],
+            _ => {
+                match strategy {
+                    SelectionStrategy::RoundRobin => round_robin(list, &candidates),
+                    SelectionStrategy::LowestLatency => lowest_latency(list, &candidates),
+                    SelectionStrategy::Weighted => weighted(list, &candidates),
+                    SelectionStrategy::LeastConnections => least_connections(list, &candidates),
+                    SelectionStrategy::Random => {
+                        candidates[rand::thread_rng().gen_range(0..candidates.len())]
+                    }
+                }
+            }
+        }
+    };
 
-    indices
+    picked(list, index)
 }
 
-// Selection algorithms
+// Same as `pick`, but calls with the same `key`, eg. from the same client, go to the same RPC.
+//
+// Falls back to `pick` while the RPC assigned to `key` can't take the call.
+pub fn pick_sticky(
+    list: &[Rpc],
+    strategy: SelectionStrategy,
+    requirements: &Requirements,
+    excluded: &[usize],
+    key: u64,
+) -> Option<usize> {
+    if let Some(index) = assigned(list, requirements, key) {
+        let rpc = &list[index];
+        if !excluded.contains(&index)
+            && !rpc.consensus.is_quarantined()
+            && rpc.circuit.is_closed()
+            && is_free(rpc)
+        {
+            return picked(list, index);
+        }
+    }
+
+    pick(list, strategy, requirements, excluded)
+}
+
+// Count the RPC at `index` as picked and return its position.
+//
+// Picks only read lock the RPC list, so a concurrent one can take the RPC's last
+// token under `max_rps` first. Then this returns None and the caller waits for the next one.
+fn picked(list: &[Rpc], index: usize) -> Option<usize> {
+    if !list[index].rate_limit.take() {
+        return None;
+    }
+
+    // Keep track of runs on the same RPC, for `max_consecutive` and `round_robin`
+    for (position, rpc) in list.iter().enumerate() {
+        rpc.set_consecutive(if position == index {
+            rpc.consecutive() + 1
+        } else {
+            0
+        });
+    }
+
+    Some(index)
+}
+
+// If `rpc` is under both its `max_rps` and its `max_concurrent`
+fn is_free(rpc: &Rpc) -> bool {
+    rpc.rate_limit.has_token() && rpc.concurrency.has_capacity()
+}
+
+// How long until one of the busy RPCs `pick` skipped over, ones at their `max_rps`
+// or `max_concurrent`, can take requests again.
 //
-// Selected via features. selection-weighed-round-robin is a default feature.
-// In order to have custom algos, you must add and enable the feature,
-// as well as modify the cfg of the default algo to accomodate your new feature.
+// None if there are no such RPCs, in which case waiting won't help.
+pub fn busy_for(list: &[Rpc], requirements: &Requirements, excluded: &[usize]) -> Option<Duration> {
+    let healthy = list.iter().enumerate().filter_map(|(index, rpc)| {
+        (requirements.met_by(rpc)
+            && !excluded.contains(&index)
+            && !rpc.consensus.is_quarantined()
+            && rpc.circuit.is_closed())
+        .then_some(rpc)
+    });
+    let tier = healthy.clone().map(|rpc| rpc.tier).min()?;
+
+    healthy
+        .filter(|rpc| rpc.tier == tier)
+        .filter_map(|rpc| {
+            match (rpc.rate_limit.wait(), rpc.concurrency.wait()) {
+                (Some(rate_limit), Some(concurrency)) => Some(rate_limit.max(concurrency)),
+                (rate_limit, concurrency) => rate_limit.or(concurrency),
+            }
+        })
+        .min()
+}
+
+// Sorting algo, sorts the positions in `candidates` by the latency score of their RPC in `data`
+pub fn argsort(data: &[Rpc], mut candidates: Vec<usize>) -> Vec<usize> {
+    // Calls coming back update the scores while we sort, so each one only gets read once
+    candidates.sort_by_cached_key(|&index| data[index].status.score() as u64);
+
+    candidates
+}
+
+// Selection algorithms
 //
-#[cfg(all(
-    feature = "selection-weighed-round-robin",
-    not(feature = "selection-random")
-))]
-fn algo(list: &mut Vec<Rpc>) -> (Rpc, Option<usize>) {
-    // Sort by latency
-    let indices = argsort(list);
+// Each one returns the position of the RPC to use, out of at least 2 `candidates`.
+// To add your own, add it to `SelectionStrategy` and call it from `pick`.
+
+// Pick the candidate after the RPC we picked last
+fn round_robin(list: &[Rpc], candidates: &[usize]) -> usize {
+    match list.iter().position(|rpc| rpc.consecutive() > 0) {
+        Some(last) => {
+            candidates
+                .iter()
+                .copied()
+                .find(|&index| index > last)
+                .unwrap_or(candidates[0])
+        }
+        None => candidates[0],
+    }
+}
 
-    // Picks the second fastest one if the fastest one has maxed out
-    if list[indices[0]].max_consecutive <= list[indices[0]].consecutive {
-        list[indices[1]].consecutive = 1;
-        list[indices[0]].consecutive = 0;
-        return (list[indices[1]].clone(), Some(indices[1]));
+// Pick the fastest RPC, or the second fastest if the fastest one has maxed out
+fn lowest_latency(list: &[Rpc], candidates: &[usize]) -> usize {
+    let indices = argsort(list, candidates.to_vec());
+
+    if list[indices[0]].max_consecutive <= list[indices[0]].consecutive() {
+        return indices[1];
     }
 
-    list[indices[0]].consecutive += 1;
-    (list[indices[0]].clone(), Some(indices[0]))
+    indices[0]
+}
+
+// Pick the RPC with the fewest requests in flight, the fastest one if there's a tie
+fn least_connections(list: &[Rpc], candidates: &[usize]) -> usize {
+    argsort(list, candidates.to_vec())
+        .into_iter()
+        .min_by_key(|&index| list[index].in_flight())
+        .unwrap()
+}
+
+// Lowest latency we have for any of the `candidates`, infinite if there's none yet
+fn fastest_latency(list: &[Rpc], candidates: &[usize]) -> f64 {
+    candidates
+        .iter()
+        .map(|&index| list[index].status.score())
+        .filter(|latency| *latency > 0.0)
+        .fold(f64::INFINITY, f64::min)
 }
 
-#[cfg(all(
-    feature = "selection-weighed-round-robin",
-    feature = "selection-random"
-))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
-    use rand::Rng;
+// How much traffic `rpc` should get: its weight, scaled down by how much slower
+// it is than the `fastest` latency. RPCs we don't have a latency for yet count as the fastest.
+fn effective_weight(rpc: &Rpc, fastest: f64) -> f64 {
+    let latency = rpc.status.score();
+    if latency > 0.0 && fastest.is_finite() {
+        rpc.weight * fastest / latency
+    } else {
+        rpc.weight
+    }
+}
+
+// Smooth weighted round robin, like nginx does it.
+//
+// Every pick, each candidate's `current_weight` grows by its effective weight. The one
+// with the highest total gets picked and its total drops by the sum of all
+// effective weights, so over time each RPC gets picked in proportion to its
+// effective weight without long runs on the same one.
+//
+// If the picked RPC has maxed out `max_consecutive`, the runner up gets picked instead.
+fn weighted(list: &[Rpc], candidates: &[usize]) -> usize {
+    let fastest = fastest_latency(list, candidates);
+
+    let mut total = 0.0;
+    for &index in candidates {
+        let weight = effective_weight(&list[index], fastest);
+        list[index].add_current_weight(weight);
+        total += weight;
+    }
+
+    // Highest total first, ties go to the fastest. Concurrent picks update the
+    // totals while we sort, so each one only gets read once.
+    let mut indices = argsort(list, candidates.to_vec())
+        .into_iter()
+        .map(|index| (index, list[index].current_weight()))
+        .collect::<Vec<(usize, f64)>>();
+    indices.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let (index, _) = if list[indices[0].0].max_consecutive <= list[indices[0].0].consecutive() {
+        indices[1]
+    } else {
+        indices[0]
+    };
+    list[index].add_current_weight(-total);
 
-    let mut rng = rand::thread_rng();
-    let index = rng.gen_range(0..list.len());
-    (list[index].clone(), Some(index))
+    index
 }
 
 // Tests
@@ -67,17 +312,17 @@ mod tests {
 
     #[test]
     fn test_sort_algo() {
-        let mut rpc1 = Rpc::default();
-        let mut rpc2 = Rpc::default();
-        let mut rpc3 = Rpc::default();
+        let rpc1 = Rpc::default();
+        let rpc2 = Rpc::default();
+        let rpc3 = Rpc::default();
 
-        rpc1.status.latency = 1.0;
-        rpc2.status.latency = 2.0;
-        rpc3.status.latency = 3.0;
+        rpc1.status.latency.set(1.0);
+        rpc2.status.latency.set(2.0);
+        rpc3.status.latency.set(3.0);
 
         let v = vec![rpc2, rpc3, rpc1];
         let vx = v.clone();
-        let i = argsort(&v);
+        let i = argsort(&v, vec![0, 1, 2]);
         assert_eq!(i, &[2, 0, 1]);
         assert_eq!(v[0].url, vx[0].url);
     }
@@ -91,31 +336,646 @@ mod tests {
         let mut rpc2 = Rpc::default();
         let mut rpc3 = Rpc::default();
 
-        rpc1.status.latency = 1.0;
+        rpc1.status.latency.set(1.0);
         rpc1.max_consecutive = 10;
-        rpc2.status.latency = 6.0;
+        rpc2.status.latency.set(6.0);
         rpc2.max_consecutive = 10;
-        rpc3.status.latency = 3.0;
+        rpc3.status.latency.set(3.0);
         rpc3.max_consecutive = 10;
 
-        let mut rpc_list = vec![rpc1, rpc2, rpc3];
+        let rpc_list = vec![rpc1, rpc2, rpc3];
 
-        let (rpc, index) = pick(&mut rpc_list);
-        println!("rpc: {:?}", rpc);
-        assert_eq!(rpc.status.latency, 1.0);
+        let index = pick(
+            &rpc_list,
+            SelectionStrategy::LowestLatency,
+            &Requirements::default(),
+            &[],
+        );
+        println!("rpc: {:?}", rpc_list[index.unwrap()]);
+        assert_eq!(rpc_list[index.unwrap()].status.latency.average(), 1.0);
         assert_eq!(index, Some(0));
 
-        rpc_list[0].status.latency = 10000.0;
+        rpc_list[0].status.latency.set(10000.0);
 
-        let (rpc, index) = pick(&mut rpc_list);
+        let index = pick(
+            &rpc_list,
+            SelectionStrategy::LowestLatency,
+            &Requirements::default(),
+            &[],
+        );
         println!("rpc index: {:?}", index);
-        assert_eq!(rpc.status.latency, 3.0);
+        assert_eq!(rpc_list[index.unwrap()].status.latency.average(), 3.0);
         assert_eq!(index, Some(2));
 
-        rpc_list[2].status.latency = 100000.0;
+        rpc_list[2].status.latency.set(100000.0);
+
+        let index = pick(
+            &rpc_list,
+            SelectionStrategy::LowestLatency,
+            &Requirements::default(),
+            &[],
+        );
+        assert_eq!(rpc_list[index.unwrap()].status.latency.average(), 6.0);
+        assert_eq!(index, Some(1));
+    }
+
+    // Count how many of `picks` go to each RPC
+    fn distribution(rpc_list: &mut [Rpc], picks: usize) -> Vec<usize> {
+        let mut picked = vec![0; rpc_list.len()];
+        for _ in 0..picks {
+            let index = pick(
+                rpc_list,
+                SelectionStrategy::Weighted,
+                &Requirements::default(),
+                &[],
+            );
+            picked[index.unwrap()] += 1;
+        }
+
+        picked
+    }
+
+    fn weighted_rpc(weight: f64, latency: f64) -> Rpc {
+        let mut rpc = Rpc::default().with_weight(weight);
+        rpc.status.latency.set(latency);
+        rpc.max_consecutive = u32::MAX;
+
+        rpc
+    }
+
+    #[test]
+    fn test_pick_weighted() {
+        // Same latency, traffic follows the weights
+        let mut rpc_list = vec![
+            weighted_rpc(1.0, 10.0),
+            weighted_rpc(2.0, 10.0),
+            weighted_rpc(7.0, 10.0),
+        ];
+        let picked = distribution(&mut rpc_list, 10_000);
+        for (picked, expected) in picked.iter().zip([1000, 2000, 7000]) {
+            assert!(picked.abs_diff(expected) <= 200);
+        }
+
+        // A RPC that's twice as slow needs twice the weight to get the same traffic
+        let mut rpc_list = vec![
+            weighted_rpc(1.0, 10.0),
+            weighted_rpc(2.0, 20.0),
+            weighted_rpc(1.0, 20.0),
+        ];
+        let picked = distribution(&mut rpc_list, 10_000);
+        for (picked, expected) in picked.iter().zip([4000, 4000, 2000]) {
+            assert!(picked.abs_diff(expected) <= 200);
+        }
+    }
+
+    #[test]
+    fn test_pick_weighted_max_consecutive() {
+        let mut rpc_list = vec![weighted_rpc(1.0, 10.0), weighted_rpc(100.0, 10.0)];
+        rpc_list[1].max_consecutive = 3;
+
+        // The heavy RPC never gets more than 3 requests in a row
+        let mut run = 0;
+        for _ in 0..1000 {
+            let index = pick(
+                &rpc_list,
+                SelectionStrategy::Weighted,
+                &Requirements::default(),
+                &[],
+            );
+            run = if index == Some(1) { run + 1 } else { 0 };
+            assert!(run <= 3);
+        }
+    }
+
+    #[test]
+    fn test_pick_least_connections() {
+        let rpc_list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        rpc_list[0].status.latency.set(3.0);
+        rpc_list[1].status.latency.set(1.0);
+        rpc_list[2].status.latency.set(2.0);
 
-        let (rpc, index) = pick(&mut rpc_list);
-        assert_eq!(rpc.status.latency, 6.0);
+        // Nothing in flight, the fastest one wins
+        let index = pick(
+            &rpc_list,
+            SelectionStrategy::LeastConnections,
+            &Requirements::default(),
+            &[],
+        );
         assert_eq!(index, Some(1));
+
+        let _fastest = rpc_list[1].start_request();
+        let index = pick(
+            &rpc_list,
+            SelectionStrategy::LeastConnections,
+            &Requirements::default(),
+            &[],
+        );
+        assert_eq!(index, Some(2));
+
+        let _second = rpc_list[2].start_request();
+        let index = pick(
+            &rpc_list,
+            SelectionStrategy::LeastConnections,
+            &Requirements::default(),
+            &[],
+        );
+        assert_eq!(index, Some(0));
+
+        // Requests stop counting once they're done, even if they went through a clone
+        {
+            let clone = rpc_list[0].clone();
+            let _first = clone.start_request();
+            let _first_again = rpc_list[0].start_request();
+            assert_eq!(rpc_list[0].in_flight(), 2);
+            let index = pick(
+                &rpc_list,
+                SelectionStrategy::LeastConnections,
+                &Requirements::default(),
+                &[],
+            );
+            assert_eq!(index, Some(1));
+        }
+        assert_eq!(rpc_list[0].in_flight(), 0);
+        let index = pick(
+            &rpc_list,
+            SelectionStrategy::LeastConnections,
+            &Requirements::default(),
+            &[],
+        );
+        assert_eq!(index, Some(0));
+    }
+
+    #[test]
+    fn test_pick_round_robin() {
+        let rpc_list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        rpc_list[0].status.latency.set(3.0);
+        rpc_list[1].status.latency.set(1.0);
+        rpc_list[2].status.latency.set(2.0);
+
+        // Latency doesn't matter, every RPC gets its turn
+        let picked = (0..7)
+            .map(|_| {
+                pick(
+                    &rpc_list,
+                    SelectionStrategy::RoundRobin,
+                    &Requirements::default(),
+                    &[],
+                )
+                .unwrap()
+            })
+            .collect::<Vec<usize>>();
+        assert_eq!(picked, vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn test_pick_lowest_latency_max_consecutive() {
+        let mut rpc_list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        for (rpc, latency) in rpc_list.iter_mut().zip([3.0, 1.0, 2.0]) {
+            rpc.status.latency.set(latency);
+            rpc.max_consecutive = 2;
+        }
+
+        // The fastest one gets 2 in a row, then the second fastest takes over once
+        let picked = (0..6)
+            .map(|_| {
+                pick(
+                    &rpc_list,
+                    SelectionStrategy::LowestLatency,
+                    &Requirements::default(),
+                    &[],
+                )
+                .unwrap()
+            })
+            .collect::<Vec<usize>>();
+        assert_eq!(picked, vec![1, 1, 2, 1, 1, 2]);
+    }
+
+    #[test]
+    fn test_pick_random() {
+        let rpc_list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        rpc_list[0].status.latency.set(1.0);
+
+        // Every RPC gets picked eventually, regardless of latency
+        let mut picked = [0; 3];
+        for _ in 0..3000 {
+            picked[pick(
+                &rpc_list,
+                SelectionStrategy::Random,
+                &Requirements::default(),
+                &[],
+            )
+            .unwrap()] += 1;
+        }
+        assert!(picked.iter().all(|picked| *picked > 800));
+    }
+
+    #[test]
+    fn test_pick_tiers() {
+        use crate::rpc::circuit::CircuitSettings;
+        use std::time::Duration;
+
+        let settings = |cooldown| {
+            CircuitSettings {
+                failure_threshold: 1,
+                cooldown,
+                ..Default::default()
+            }
+        };
+        let primary = settings(Duration::from_secs(60));
+        let mut rpc_list = vec![
+            Rpc::default()
+                .with_tier(1)
+                .with_circuit(settings(Duration::ZERO)),
+            Rpc::default().with_circuit(primary),
+            Rpc::default().with_circuit(primary),
+        ];
+        let pick_any = |rpc_list: &mut Vec<Rpc>| {
+            pick(
+                rpc_list,
+                SelectionStrategy::RoundRobin,
+                &Requirements::default(),
+                &[],
+            )
+        };
+
+        // The fallback doesn't get anything while the primaries are healthy
+        for _ in 0..10 {
+            assert_ne!(pick_any(&mut rpc_list), Some(0));
+        }
+
+        // Not even a probe
+        rpc_list[0].circuit.failure("fallback");
+        for _ in 0..10 {
+            assert_ne!(pick_any(&mut rpc_list), Some(0));
+        }
+        rpc_list[0].circuit.probe("fallback");
+        rpc_list[0].circuit.success("fallback");
+
+        // Fall back once both primaries are out
+        rpc_list[1].circuit.failure("primary");
+        for _ in 0..10 {
+            assert_eq!(pick_any(&mut rpc_list), Some(2));
+        }
+        rpc_list[2].circuit.failure("primary");
+        for _ in 0..10 {
+            assert_eq!(pick_any(&mut rpc_list), Some(0));
+        }
+
+        // And go back as soon as one of them recovers
+        rpc_list[1].circuit.probe("primary");
+        rpc_list[1].circuit.success("primary");
+        assert_eq!(pick_any(&mut rpc_list), Some(1));
+    }
+
+    #[test]
+    fn test_pick_excluded() {
+        use crate::rpc::circuit::{
+            CircuitSettings,
+            CircuitState,
+        };
+
+        // The second RPC is out, and would get the next request as a probe
+        let mut rpc_list = vec![
+            Rpc::default(),
+            Rpc::default().with_circuit(CircuitSettings {
+                failure_threshold: 1,
+                cooldown: Duration::ZERO,
+                ..Default::default()
+            }),
+        ];
+        rpc_list[1].circuit.failure("probe");
+        let requirements = Requirements::default();
+        let strategies = [
+            SelectionStrategy::RoundRobin,
+            SelectionStrategy::LowestLatency,
+            SelectionStrategy::Weighted,
+            SelectionStrategy::LeastConnections,
+            SelectionStrategy::Random,
+        ];
+
+        // Not if the call already failed on it
+        for strategy in strategies {
+            assert_eq!(pick(&rpc_list, strategy, &requirements, &[1]), Some(0));
+        }
+
+        // Excluding the only healthy RPC leaves nothing to pick, or to wait for
+        for strategy in strategies {
+            assert_eq!(pick(&rpc_list, strategy, &requirements, &[0, 1]), None);
+        }
+        assert_eq!(busy_for(&rpc_list, &requirements, &[0, 1]), None);
+
+        // Excluded RPCs don't hold up their tier, the call goes to a fallback instead
+        rpc_list.push(Rpc::default().with_tier(1));
+        for strategy in strategies {
+            assert_eq!(pick(&rpc_list, strategy, &requirements, &[0, 1]), Some(2));
+        }
+        assert_eq!(rpc_list[1].circuit.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_pick_quarantined() {
+        let rpc_list = vec![
+            Rpc::default().with_max_rps(1),
+            Rpc::default(),
+            Rpc::default().with_tier(1),
+        ];
+        rpc_list[0].consensus.diverged("quarantined", 1, false);
+        let requirements = Requirements::default();
+
+        // Not picked, not waited for, and not holding up its tier
+        for strategy in [
+            SelectionStrategy::RoundRobin,
+            SelectionStrategy::LowestLatency,
+            SelectionStrategy::Weighted,
+            SelectionStrategy::LeastConnections,
+            SelectionStrategy::Random,
+        ] {
+            assert_eq!(pick(&rpc_list, strategy, &requirements, &[]), Some(1));
+            assert_eq!(pick(&rpc_list, strategy, &requirements, &[1]), Some(2));
+            assert_eq!(
+                pick_sticky(&rpc_list, strategy, &requirements, &[1], 0),
+                Some(2)
+            );
+        }
+        assert_eq!(busy_for(&rpc_list, &requirements, &[1, 2]), None);
+    }
+
+    #[test]
+    fn test_selection_strategy_names() {
+        for strategy in [
+            SelectionStrategy::RoundRobin,
+            SelectionStrategy::LowestLatency,
+            SelectionStrategy::Weighted,
+            SelectionStrategy::LeastConnections,
+            SelectionStrategy::Random,
+        ] {
+            assert_eq!(
+                SelectionStrategy::from_name(strategy.name()),
+                Some(strategy)
+            );
+        }
+        assert_eq!(SelectionStrategy::from_name("fastest"), None);
+    }
+
+    #[test]
+    fn test_pick_rate_limit() {
+        let mut rpc_list = vec![
+            Rpc::default().with_max_rps(1),
+            Rpc::default().with_max_rps(1).with_tier(1),
+            Rpc::default(),
+        ];
+        // Make the limited RPC the fastest one
+        for rpc in rpc_list.iter_mut() {
+            rpc.max_consecutive = u32::MAX;
+        }
+        rpc_list[2].status.latency.set(100.0);
+        let pick_any = |rpc_list: &mut Vec<Rpc>| {
+            pick(
+                rpc_list,
+                SelectionStrategy::LowestLatency,
+                &Requirements::default(),
+                &[],
+            )
+        };
+
+        // Once it's used up its token, requests go to the others
+        assert_eq!(pick_any(&mut rpc_list), Some(0));
+        for _ in 0..10 {
+            assert_eq!(pick_any(&mut rpc_list), Some(2));
+        }
+
+        // We'd rather wait on a limited RPC than go to a fallback
+        rpc_list.remove(2);
+        assert_eq!(pick_any(&mut rpc_list), None);
+        let wait = busy_for(&rpc_list, &Requirements::default(), &[]).unwrap();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+
+        // Nothing to wait for if the limited RPCs can't serve the call anyway
+        let archive = Requirements {
+            archive: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            pick(&rpc_list, SelectionStrategy::Random, &archive, &[]),
+            None
+        );
+        assert_eq!(busy_for(&rpc_list, &archive, &[]), None);
+    }
+
+    #[test]
+    fn test_pick_sticky() {
+        use crate::rpc::circuit::CircuitSettings;
+
+        let settings = CircuitSettings {
+            failure_threshold: 1,
+            cooldown: std::time::Duration::from_secs(60),
+            ..Default::default()
+        };
+        let mut rpc_list = (0..4)
+            .map(|i| Rpc::new(format!("http://rpc-{}", i), 10, 5.0).with_circuit(settings))
+            .collect::<Vec<Rpc>>();
+        let requirements = Requirements::default();
+        let pick_key = |rpc_list: &mut Vec<Rpc>, key| {
+            pick_sticky(
+                rpc_list,
+                SelectionStrategy::RoundRobin,
+                &requirements,
+                &[],
+                key,
+            )
+            .unwrap()
+        };
+
+        // Every call from a client goes to the same RPC
+        let sticky = (0..20)
+            .map(|key| pick_key(&mut rpc_list, key))
+            .collect::<Vec<usize>>();
+        for _ in 0..10 {
+            for key in 0..20 {
+                assert_eq!(pick_key(&mut rpc_list, key), sticky[key as usize]);
+            }
+        }
+
+        // Unless the RPC is unhealthy or the call already failed on it
+        let key = 0;
+        assert_ne!(
+            pick_sticky(
+                &rpc_list,
+                SelectionStrategy::RoundRobin,
+                &requirements,
+                &[sticky[0]],
+                key,
+            ),
+            Some(sticky[0])
+        );
+        rpc_list[sticky[0]].circuit.failure("sticky");
+        for _ in 0..10 {
+            assert_ne!(pick_key(&mut rpc_list, key), sticky[0]);
+        }
+        rpc_list[sticky[0]].circuit.probe("sticky");
+        rpc_list[sticky[0]].circuit.success("sticky");
+        assert_eq!(pick_key(&mut rpc_list, key), sticky[0]);
+
+        // Removing a RPC only moves the clients that were on it
+        let removed = sticky[0];
+        let url = |rpc_list: &Vec<Rpc>, position: usize| rpc_list[position].url.clone();
+        let before = sticky
+            .iter()
+            .map(|&position| url(&rpc_list, position))
+            .collect::<Vec<String>>();
+        let removed_url = rpc_list.remove(removed).url;
+        for key in 0..20 {
+            let position = pick_key(&mut rpc_list, key);
+            let after = url(&rpc_list, position);
+            if before[key as usize] != removed_url {
+                assert_eq!(after, before[key as usize]);
+            }
+            assert_ne!(after, removed_url);
+        }
+    }
+
+    #[test]
+    fn test_pick_latency_score() {
+        use crate::rpc::latency::LatencyScore;
+
+        // Faster on average, but stalls on every 20th call
+        let mut rpc_list = vec![Rpc::default(), Rpc::default()];
+        for i in 0..200 {
+            rpc_list[0].update_latency(if i % 20 == 0 { 500.0 } else { 10.0 });
+            rpc_list[1].update_latency(40.0);
+        }
+        let pick_by = |rpc_list: &mut Vec<Rpc>, score| {
+            for rpc in rpc_list.iter_mut() {
+                *rpc = rpc.clone().with_latency_score(score);
+                rpc.max_consecutive = u32::MAX;
+            }
+            pick(
+                rpc_list,
+                SelectionStrategy::LowestLatency,
+                &Requirements::default(),
+                &[],
+            )
+        };
+
+        assert_eq!(pick_by(&mut rpc_list, LatencyScore::Average), Some(0));
+        assert_eq!(pick_by(&mut rpc_list, LatencyScore::P50), Some(0));
+        assert_eq!(pick_by(&mut rpc_list, LatencyScore::P99), Some(1));
+    }
+
+    #[test]
+    fn test_pick_concurrent() {
+        use std::sync::{
+            Arc,
+            RwLock,
+        };
+
+        let rpc_list = (0..4)
+            .map(|_| Rpc::default().with_max_concurrent(1))
+            .collect::<Vec<Rpc>>();
+        let rpc_list = Arc::new(RwLock::new(rpc_list));
+
+        // Picks run side by side under a read lock, and limits still hold
+        let threads = (0..16)
+            .map(|_| {
+                let rpc_list = Arc::clone(&rpc_list);
+                std::thread::spawn(move || {
+                    let requirements = Requirements::default();
+                    for _ in 0..1000 {
+                        let rpc_list = rpc_list.read().unwrap();
+                        let index = match pick(
+                            &rpc_list,
+                            SelectionStrategy::Weighted,
+                            &requirements,
+                            &[],
+                        ) {
+                            Some(index) => index,
+                            None => continue,
+                        };
+                        if let Some(_in_flight) = rpc_list[index].start_request() {
+                            assert_eq!(rpc_list[index].in_flight(), 1);
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(rpc_list
+            .read()
+            .unwrap()
+            .iter()
+            .all(|rpc| rpc.in_flight() == 0));
+    }
+
+    // Compare dispatching with 1 and with 16 tasks at once, with the RPC list write
+    // locked for every pick like it used to be, and read locked like it is now.
+    //
+    // Run with `cargo test --release bench_pick_concurrent -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_pick_concurrent() {
+        use std::{
+            sync::{
+                Arc,
+                RwLock,
+            },
+            time::Instant,
+        };
+
+        const PICKS: u32 = 100_000;
+
+        let rpc_list = (0..4)
+            .map(|latency| {
+                let rpc = Rpc::default();
+                rpc.status.latency.set(latency as f64 + 1.0);
+                rpc
+            })
+            .collect::<Vec<Rpc>>();
+        let rpc_list = Arc::new(RwLock::new(rpc_list));
+
+        // Picks per second, everything `pick_upstream` does short of sending the request
+        let run = |tasks: u32, write: bool| {
+            let time = Instant::now();
+            let threads = (0..tasks)
+                .map(|_| {
+                    let rpc_list = Arc::clone(&rpc_list);
+                    std::thread::spawn(move || {
+                        let requirements = Requirements::default();
+                        for _ in 0..PICKS {
+                            let dispatch = |rpc_list: &[Rpc]| {
+                                let index =
+                                    pick(rpc_list, SelectionStrategy::Weighted, &requirements, &[])
+                                        .unwrap();
+                                let rpc = rpc_list[index].clone();
+                                let _in_flight = rpc.start_request().unwrap();
+                            };
+                            match write {
+                                true => dispatch(&rpc_list.write().unwrap()),
+                                false => dispatch(&rpc_list.read().unwrap()),
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            (tasks * PICKS) as f64 / time.elapsed().as_secs_f64()
+        };
+
+        for (lock, write) in [("write", true), ("read", false)] {
+            let single = run(1, write);
+            let concurrent = run(16, write);
+            println!(
+                "{} locked: {:.0} picks/s with 1 task, {:.0} with 16 ({:.1}x)",
+                lock,
+                single,
+                concurrent,
+                concurrent / single
+            );
+        }
     }
 }
@@ -0,0 +1,118 @@
+use crate::{
+    balancer::selection::routing::Requirements,
+    Rpc,
+};
+
+use std::net::IpAddr;
+use xxhash_rust::xxh3::{
+    xxh3_64,
+    xxh3_64_with_seed,
+};
+
+// Key calls from `client` stick to the same RPC when `sticky_sessions` is on
+pub fn sticky_key(client: IpAddr) -> u64 {
+    match client {
+        IpAddr::V4(ip) => xxh3_64(&ip.octets()),
+        IpAddr::V6(ip) => xxh3_64(&ip.octets()),
+    }
+}
+
+// Return the position of the RPC calls with `key` stick to, out of the lowest tier
+// of RPCs that meet `requirements`.
+//
+// Uses rendezvous hashing: every RPC scores the key by its url and the highest score wins.
+// When a RPC joins or leaves the list only the keys it wins or won move, no matter
+// where in the list it is.
+pub fn assigned(list: &[Rpc], requirements: &Requirements, key: u64) -> Option<usize> {
+    let capable = || {
+        list.iter()
+            .enumerate()
+            .filter(|(_, rpc)| requirements.met_by(rpc))
+    };
+    let tier = capable().map(|(_, rpc)| rpc.tier).min()?;
+
+    capable()
+        .filter(|(_, rpc)| rpc.tier == tier)
+        .max_by_key(|(_, rpc)| xxh3_64_with_seed(rpc.url.as_bytes(), key))
+        .map(|(position, _)| position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc_list(count: usize) -> Vec<Rpc> {
+        (0..count)
+            .map(|i| Rpc::new(format!("http://rpc-{}", i), 10, 5.0))
+            .collect()
+    }
+
+    #[test]
+    fn test_assigned() {
+        let list = rpc_list(4);
+        let requirements = Requirements::default();
+
+        // Keys spread out over every RPC
+        let mut counts = [0; 4];
+        for key in 0..1000 {
+            counts[assigned(&list, &requirements, key).unwrap()] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 150), "{:?}", counts);
+
+        // Only the keys of a RPC that leaves move, and they go to every other RPC
+        let mut without = list.clone();
+        without.remove(1);
+        let mut moved_to = [0; 3];
+        for key in 0..1000 {
+            let before = &list[assigned(&list, &requirements, key).unwrap()].url;
+            let after = &without[assigned(&without, &requirements, key).unwrap()].url;
+            if before != "http://rpc-1" {
+                assert_eq!(before, after);
+            } else {
+                moved_to[assigned(&without, &requirements, key).unwrap()] += 1;
+            }
+        }
+        assert!(moved_to.iter().all(|&count| count > 0), "{:?}", moved_to);
+
+        // Order in the list doesn't matter
+        let mut reversed = list.clone();
+        reversed.reverse();
+        for key in 0..100 {
+            assert_eq!(
+                list[assigned(&list, &requirements, key).unwrap()].url,
+                reversed[assigned(&reversed, &requirements, key).unwrap()].url
+            );
+        }
+    }
+
+    #[test]
+    fn test_assigned_requirements() {
+        let mut list = rpc_list(4);
+        list[2] = list[2].clone().with_archive(true);
+        list[3] = list[3].clone().with_tier(1);
+
+        // Fallbacks and RPCs that can't serve the call never get keys
+        for key in 0..100 {
+            assert_ne!(assigned(&list, &Requirements::default(), key), Some(3));
+            assert_eq!(
+                assigned(
+                    &list,
+                    &Requirements {
+                        archive: true,
+                        ..Default::default()
+                    },
+                    key
+                ),
+                Some(2)
+            );
+        }
+        assert_eq!(assigned(&[], &Requirements::default(), 1), None);
+    }
+
+    #[test]
+    fn test_sticky_key() {
+        let a = sticky_key("10.0.0.1".parse().unwrap());
+        assert_eq!(a, sticky_key("10.0.0.1".parse().unwrap()));
+        assert_ne!(a, sticky_key("10.0.0.2".parse().unwrap()));
+    }
+}
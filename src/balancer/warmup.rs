@@ -0,0 +1,486 @@
+use crate::{
+    balancer::accept_http::{
+        forward_value,
+        CacheArgs,
+        RequestParams,
+    },
+    database::call_log::recent_calls,
+    rpc::types::Rpc,
+    NamedBlocknumbers,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use http_body_util::BodyExt;
+use serde_json::Value;
+use sled::Db;
+use tokio::{
+    sync::watch,
+    task::JoinSet,
+};
+
+// How often to check if the health check found the head yet
+const HEAD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long to wait for the head before warming up the cache without it
+pub const HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Calls to send on startup so popular responses are cached before clients ask for them,
+// set in `[cache.warmup]`
+#[derive(Debug, Clone)]
+pub struct WarmupSettings {
+    pub calls: Vec<Value>,
+    // How many warmup calls can be in flight at once
+    pub concurrency: usize,
+    // How many of the calls cached during the last run to replay, 0 to not log them
+    pub replay_last: usize,
+}
+
+impl Default for WarmupSettings {
+    fn default() -> Self {
+        Self {
+            calls: Vec::new(),
+            concurrency: 4,
+            replay_last: 0,
+        }
+    }
+}
+
+// Return the calls to warm the cache with, the configured ones first
+// followed by the ones replayed from the call log
+pub fn warmup_calls(cache: &Db, settings: &WarmupSettings) -> Vec<Value> {
+    let mut calls = settings.calls.clone();
+
+    if settings.replay_last != 0 {
+        match recent_calls(cache, settings.replay_last) {
+            Ok(replayed) => {
+                calls.extend(
+                    replayed
+                        .iter()
+                        .filter_map(|call| serde_json::from_slice::<Value>(call).ok()),
+                )
+            }
+            Err(err) => {
+                println!(
+                    "\x1b[93mWrn:\x1b[0m Could not read calls to replay: {}",
+                    err
+                );
+            }
+        }
+    }
+
+    // Configured calls only need a method
+    for call in calls.iter_mut() {
+        if call.get("jsonrpc").is_none() {
+            call["jsonrpc"] = "2.0".into();
+        }
+        if call.get("params").is_none() {
+            call["params"] = Value::Array(Vec::new());
+        }
+    }
+
+    calls
+}
+
+// Wait until the health check found the head so calls for `latest` can get cached.
+//
+// A head loaded from the last run doesn't count, nothing gets cached until it's confirmed.
+// Returns false if it didn't within `timeout`.
+pub async fn wait_for_head(
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+    timeout: Duration,
+) -> bool {
+    let unknown = || {
+        let named_numbers = named_numbers.read().unwrap();
+        named_numbers.latest == 0 || named_numbers.provisional
+    };
+
+    let start = Instant::now();
+    while unknown() {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        tokio::time::sleep(HEAD_POLL_INTERVAL).await;
+    }
+
+    true
+}
+
+// Send `calls` through the balancer like regular requests so their responses
+// get cached, with at most `concurrency` of them in flight.
+//
+// Failed calls are skipped, returns how many succeeded.
+pub async fn warmup(
+    calls: Vec<Value>,
+    concurrency: usize,
+    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: watch::Receiver<u64>,
+    named_numbers: Arc<RwLock<NamedBlocknumbers>>,
+    cache_args: CacheArgs,
+    params: RequestParams,
+) -> usize {
+    let mut tasks = JoinSet::new();
+    let mut warmed = 0;
+
+    for call in calls {
+        if tasks.len() >= concurrency.max(1) {
+            if let Some(Ok(true)) = tasks.join_next().await {
+                warmed += 1;
+            }
+        }
+
+        tasks.spawn(warm_call(
+            call,
+            Arc::clone(&rpc_list_rwlock),
+            finalized_rx.clone(),
+            Arc::clone(&named_numbers),
+            cache_args.clone(),
+            params.clone(),
+        ));
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        if let Ok(true) = result {
+            warmed += 1;
+        }
+    }
+
+    warmed
+}
+
+// Resolve a single warmup call, returning true if we got a response without an error
+async fn warm_call(
+    call: Value,
+    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    finalized_rx: watch::Receiver<u64>,
+    named_numbers: Arc<RwLock<NamedBlocknumbers>>,
+    cache_args: CacheArgs,
+    params: RequestParams,
+) -> bool {
+    let method = call["method"].as_str().unwrap_or_default().to_string();
+
+    let (response, _) = forward_value(
+        call,
+        &rpc_list_rwlock,
+        &finalized_rx,
+        &named_numbers,
+        &cache_args,
+        params,
+    )
+    .await;
+
+    let response = match response {
+        Ok(response) if response.status() == 200 => response,
+        _ => {
+            println!(
+                "\x1b[93mWrn:\x1b[0m Could not warm up the cache with {}",
+                method
+            );
+            return false;
+        }
+    };
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    match serde_json::from_slice::<Value>(&body) {
+        Ok(rx) if rx.get("error").is_none() => true,
+        _ => {
+            println!(
+                "\x1b[93mWrn:\x1b[0m {} returned an error while warming up the cache",
+                method
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::consensus::ConsensusSettings,
+        balancer::hedge::HedgeSettings,
+        balancer::revalidate::Revalidator,
+        balancer::selection::cache_rules::CachePolicy,
+        balancer::selection::routing::RoutingRules,
+        balancer::selection::select::SelectionStrategy,
+        database::{
+            batch::CacheBatch,
+            entry::Compression,
+            hasher::CacheHasher,
+            hot_cache::HotCache,
+            metrics::CacheMetrics,
+            sled_backend::SledBackend,
+            write_behind::CacheWriter,
+        },
+        health::safe_block::{
+            load_named_numbers,
+            persist_named_numbers,
+        },
+        rpc::mock::mock_rpc,
+    };
+    use serde_json::json;
+    use std::{
+        collections::HashMap,
+        sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+    };
+    use tokio::sync::mpsc;
+
+    fn request_params(call_log_size: usize) -> RequestParams {
+        RequestParams {
+            ttl: 1000,
+            max_retries: 2,
+            retryable_error_codes: Vec::new(),
+            non_idempotent_methods: Vec::new(),
+            cache_methods: HashMap::from([(
+                "eth_chainId".to_string(),
+                CachePolicy::Ttl(Duration::from_secs(60)),
+            )]),
+            permanent_error_codes: Vec::new(),
+            debug_logging: false,
+            debug_max_params_len: 128,
+            max_cache_size: 0,
+            compression: Compression::None,
+            finality_distance: 64,
+            negative_cache_methods: Vec::new(),
+            negative_ttl: Duration::ZERO,
+            max_entry_bytes: 0,
+            call_log_size,
+            selection: SelectionStrategy::default(),
+            sticky_key: None,
+            routing: RoutingRules::default(),
+            hedging: HedgeSettings::default(),
+            consensus: ConsensusSettings::default(),
+        }
+    }
+
+    fn cache_args(cache: &Arc<Db>) -> (CacheArgs, mpsc::Receiver<CacheBatch>) {
+        let (writer, writes) = CacheWriter::new(1024);
+
+        (
+            CacheArgs {
+                backend: Arc::new(SledBackend::new(Arc::clone(cache))),
+                hot_cache: Arc::new(HotCache::new(1024)),
+                cache_metrics: Arc::new(CacheMetrics::default()),
+                chain_id: 1,
+                hasher: CacheHasher::default(),
+                writer,
+                revalidator: Arc::new(Revalidator::default()),
+            },
+            writes,
+        )
+    }
+
+    // Write whatever the warmup queued to sled
+    async fn apply_writes(
+        cache: &Db,
+        cache_args: &CacheArgs,
+        writes: &mut mpsc::Receiver<CacheBatch>,
+    ) {
+        while let Ok(batch) = writes.try_recv() {
+            batch
+                .apply(cache, cache_args.backend.as_ref())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warmup() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_rpc = Arc::clone(&requests);
+        let url = mock_rpc(move |tx| {
+            requests_rpc.fetch_add(1, Ordering::Relaxed);
+            match tx["method"].as_str() {
+                Some("eth_chainId") => json!("0x1"),
+                _ => json!("0x10"),
+            }
+        })
+        .await;
+
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+        let (cache_args, mut writes) = cache_args(&cache);
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(url, 10, 5.0)]));
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+
+        let settings = WarmupSettings {
+            calls: vec![
+                json!({"method": "eth_chainId"}),
+                json!({"method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]}),
+                json!({"method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000001", "0x1"]}),
+            ],
+            concurrency: 2,
+            replay_last: 0,
+        };
+        let warmed = warmup(
+            warmup_calls(&cache, &settings),
+            settings.concurrency,
+            Arc::clone(&rpc_list),
+            finalized_rx.clone(),
+            Arc::clone(&named_numbers),
+            cache_args.clone(),
+            request_params(0),
+        )
+        .await;
+        assert_eq!(warmed, 3);
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+
+        // Everything is cached before any client shows up
+        apply_writes(&cache, &cache_args, &mut writes).await;
+        assert_eq!(cache.len(), 3);
+
+        for call in settings.calls {
+            let mut tx = call;
+            tx["jsonrpc"] = "2.0".into();
+            tx["id"] = 1.into();
+            if tx.get("params").is_none() {
+                tx["params"] = json!([]);
+            }
+
+            let (response, _) = forward_value(
+                tx,
+                &rpc_list,
+                &finalized_rx,
+                &named_numbers,
+                &cache_args,
+                request_params(0),
+            )
+            .await;
+            assert_eq!(response.unwrap().status(), 200);
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+        assert_eq!(cache_args.cache_metrics.to_json(false)["hits"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_failures() {
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+        let (cache_args, _writes) = cache_args(&cache);
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+
+        // No RPCs to send the calls to, the warmup gives up without hanging
+        let warmed = warmup(
+            vec![json!({"jsonrpc": "2.0", "method": "eth_chainId", "params": []})],
+            4,
+            Arc::new(RwLock::new(Vec::new())),
+            finalized_rx,
+            Arc::new(RwLock::new(NamedBlocknumbers::default())),
+            cache_args,
+            request_params(0),
+        )
+        .await;
+        assert_eq!(warmed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_replay() {
+        let url = mock_rpc(|_| json!("0x10")).await;
+
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+        let (cache_args, mut writes) = cache_args(&cache);
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+
+        // A regular request during the previous run
+        let balance = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]});
+        let (response, _) = forward_value(
+            balance.clone(),
+            &Arc::new(RwLock::new(vec![Rpc::new(url, 10, 5.0)])),
+            &finalized_rx,
+            &named_numbers,
+            &cache_args,
+            request_params(8),
+        )
+        .await;
+        assert_eq!(response.unwrap().status(), 200);
+        apply_writes(&cache, &cache_args, &mut writes).await;
+
+        let settings = WarmupSettings {
+            replay_last: 8,
+            ..Default::default()
+        };
+        let mut replayed = balance;
+        replayed["id"] = Value::Null;
+        assert_eq!(warmup_calls(&cache, &settings), vec![replayed]);
+
+        // Replaying is opt in
+        assert!(warmup_calls(&cache, &WarmupSettings::default()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_head() {
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        assert!(!wait_for_head(&named_numbers, Duration::ZERO).await);
+
+        named_numbers.write().unwrap().latest = 10;
+        assert!(wait_for_head(&named_numbers, Duration::ZERO).await);
+
+        // The head from the last run has to be confirmed first
+        named_numbers.write().unwrap().provisional = true;
+        assert!(!wait_for_head(&named_numbers, Duration::ZERO).await);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_after_restart() {
+        let url = mock_rpc(|_| json!("0x10")).await;
+
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+        let (cache_args, mut writes) = cache_args(&cache);
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(url, 10, 5.0)]));
+        let (_finalized_tx, finalized_rx) = watch::channel(0);
+
+        // Restart with the head saved by the last run
+        persist_named_numbers(
+            &cache,
+            &NamedBlocknumbers {
+                latest: 100,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let named_numbers = Arc::new(RwLock::new(load_named_numbers(&cache).unwrap()));
+        assert!(named_numbers.read().unwrap().provisional);
+
+        // The first health check confirms it a bit later
+        let named_numbers_health = Arc::clone(&named_numbers);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let mut named_numbers = named_numbers_health.write().unwrap();
+            named_numbers.latest = 101;
+            named_numbers.provisional = false;
+        });
+        assert!(wait_for_head(&named_numbers, Duration::from_secs(5)).await);
+
+        let calls = vec![
+            json!({"jsonrpc": "2.0", "method": "eth_getBalance", "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x1"]}),
+            json!({"jsonrpc": "2.0", "method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000001", "0x1"]}),
+        ];
+        let warmed = warmup(
+            calls,
+            2,
+            rpc_list,
+            finalized_rx,
+            named_numbers,
+            cache_args.clone(),
+            request_params(0),
+        )
+        .await;
+        assert_eq!(warmed, 2);
+
+        // The named numbers are saved as metadata, so only the warmed up calls count
+        let entries = cache.len();
+        apply_writes(&cache, &cache_args, &mut writes).await;
+        assert_eq!(cache.len(), entries + 2);
+    }
+}
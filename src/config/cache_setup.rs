@@ -1,45 +1,45 @@
 use sled::Db;
 use std::sync::Arc;
 
+// What `blutgang_is_lb` and `web3_clientVersion` get answered with by the balancer
+pub const VERSION_STR: &str = "blutgang 0.2.1 Myrddin nc; `I won't run away!`";
+
+// Keys older versions seeded the answers to `blutgang_is_lb` and `web3_clientVersion` under.
+//
+// They're blake3 hashes of the whole request, which nothing looks up since
+// cache keys have the chain id and method in them.
+const LEGACY_KEYS: [[u8; 32]; 2] = [
+    [
+        176, 76, 1, 109, 13, 127, 134, 25, 55, 111, 28, 182, 82, 155, 135, 143, 204, 161, 53, 4,
+        158, 140, 22, 219, 138, 5, 57, 150, 8, 154, 17, 252,
+    ],
+    [
+        36, 20, 170, 125, 105, 107, 149, 148, 52, 126, 215, 218, 112, 55, 222, 60, 186, 44, 67,
+        121, 225, 160, 31, 209, 9, 99, 81, 233, 137, 37, 62, 79,
+    ],
+];
+
 pub fn setup_data(cache: Arc<Db>) {
-    let version_str = "{\"jsonrpc\":\"2.0\",\"id\":null,\"result\":\"blutgang 0.2.1 Myrddin nc; `I won't run away!`\"}";
+    // Calls about blutgang get answered without the cache now, so drop what older versions left
+    for key in LEGACY_KEYS {
+        let _ = cache.remove(key);
+    }
+}
 
-    // Insert kv pair `blutgang_is_lb` `true` to know what we're interacting with
-    // `blutgang_is_lb` is cached as a blake3 cache
-    let _ = cache.insert(
-        [
-            176, 76, 1, 109, 13, 127, 134, 25, 55, 111, 28, 182, 82, 155, 135, 143, 204, 161, 53,
-            4, 158, 140, 22, 219, 138, 5, 57, 150, 8, 154, 17, 252,
-        ],
-        version_str,
-    );
-    // Insert kv pair `web3_clientVersion` `true` to know what we're interacting with
-    // `web3_clientVersion` is cached as a blake3 cache
-    let _ = cache.insert(
-        [
-            36, 20, 170, 125, 105, 107, 149, 148, 52, 126, 215, 218, 112, 55, 222, 60, 186, 44, 67,
-            121, 225, 160, 31, 209, 9, 99, 81, 233, 137, 37, 62, 79,
-        ],
-        version_str,
-    );
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Insert which hashing algo we're using based on the selected features.
-    // If `xxhash` is enabled we're using xxhash3, otherwise blake3.
-    //
-    // Print a warning if we see an keys are in an unexpectd hash format.
-    if cfg!(feature = "xxhash") {
-        let _ = cache.insert(b"xxhash", b"true");
-        if cache.get(b"blake3").unwrap().is_some() {
-            println!("\x1b[31mErr:\x1b[0m Blutgang has detected that your DB is using blake3 while we're currently using xxhash! \
-                Please remove all cache entries and try again.");
-            println!("If you believe this is an error, please open a pull request!");
-        }
-    } else {
-        let _ = cache.insert(b"blake3", b"true");
-        if cache.get(b"xxhash").unwrap().is_some() {
-            println!("\x1b[31mErr:\x1b[0m Blutgang has detected that your DB is using xxhash while we're currently using blake3! \
-                Please remove all cache entries and try again.");
-            println!("If you believe this is an error, please open a pull request!");
+    #[test]
+    fn test_setup_data_removes_legacy_keys() {
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+        for key in LEGACY_KEYS {
+            cache.insert(key, "legacy").unwrap();
         }
+        cache.insert("key", "value").unwrap();
+
+        setup_data(Arc::clone(&cache));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("key").unwrap().is_some());
     }
 }
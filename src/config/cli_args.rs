@@ -79,8 +79,8 @@ pub fn create_match() -> clap::Command {
         .arg(Arg::new("max_retries")
             .long("max_retries")
             .num_args(1..)
-            .default_value("32")
-            .help("Maximum amount of retries before we drop the current request."))
+            .default_value("2")
+            .help("Maximum amount of retries on other RPCs before we drop the current request."))
         .arg(Arg::new("health_check_ttl")
             .long("health_check_ttl")
             .num_args(1..)
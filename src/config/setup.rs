@@ -1,6 +1,15 @@
-use crate::Rpc;
-use std::time::Instant;
-use tokio::sync::mpsc;
+use crate::{
+    rpc::error::RpcError,
+    Rpc,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use tokio::{
+    sync::mpsc,
+    time::timeout,
+};
 
 // Do `ma_length`amount eth_blockNumber calls per rpc and then sort them by latency
 pub async fn sort_by_latency(mut rpc_list: Vec<Rpc>, ma_length: f64) -> Vec<Rpc> {
@@ -13,7 +22,7 @@ pub async fn sort_by_latency(mut rpc_list: Vec<Rpc>, ma_length: f64) -> Vec<Rpc>
     let (tx, mut rx) = mpsc::channel(rpc_list.len());
 
     // Iterate over each RPC
-    for mut rpc in rpc_list.drain(..) {
+    for rpc in rpc_list.drain(..) {
         let tx = tx.clone();
 
         // Spawn a new asynchronous task for each RPC
@@ -31,7 +40,7 @@ pub async fn sort_by_latency(mut rpc_list: Vec<Rpc>, ma_length: f64) -> Vec<Rpc>
             let avg_latency = latencies.iter().sum::<f64>() / latencies.len() as f64;
             rpc.update_latency(avg_latency);
 
-            println!("{}: {}ns", rpc.url, rpc.status.latency);
+            println!("{}: {}ns", rpc.url, rpc.status.latency.average());
 
             tx.send(rpc).await.expect("Failed to send RPC result.");
         });
@@ -47,7 +56,72 @@ pub async fn sort_by_latency(mut rpc_list: Vec<Rpc>, ma_length: f64) -> Vec<Rpc>
     }
 
     // Sort the RPCs by latency
-    sorted_rpc_list.sort_by(|a, b| a.status.latency.partial_cmp(&b.status.latency).unwrap());
+    sorted_rpc_list.sort_by(|a, b| {
+        a.status
+            .latency
+            .average()
+            .partial_cmp(&b.status.latency.average())
+            .unwrap()
+    });
 
     sorted_rpc_list
 }
+
+// Ask every RPC for its chain id, giving up on each after `ttl` ms.
+//
+// Returns None if no RPC answered, and errors if they don't all agree.
+pub async fn fetch_chain_id(rpc_list: &[Rpc], ttl: u128) -> Result<Option<u64>, RpcError> {
+    let mut chain_id: Option<(u64, &str)> = None;
+
+    for rpc in rpc_list {
+        let reported = match timeout(Duration::from_millis(ttl as u64), rpc.chain_id()).await {
+            Ok(Ok(reported)) => reported,
+            _ => {
+                println!(
+                    "\x1b[93mWrn:\x1b[0m Could not get the chain id from {}",
+                    rpc.url
+                );
+                continue;
+            }
+        };
+
+        match chain_id {
+            Some((chain_id, url)) if chain_id != reported => {
+                return Err(RpcError::InvalidResponse(format!(
+                    "{} reports chain id {} while {} reports chain id {}",
+                    url, chain_id, rpc.url, reported
+                )));
+            }
+            Some(_) => {}
+            None => chain_id = Some((reported, &rpc.url)),
+        }
+    }
+
+    Ok(chain_id.map(|(chain_id, _)| chain_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::mock::mock_rpc;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_fetch_chain_id() {
+        let mainnet = Rpc::new(mock_rpc(|_| json!("0x1")).await, 10, 5.0);
+        let sepolia = Rpc::new(mock_rpc(|_| json!("0xaa36a7")).await, 10, 5.0);
+        let broken = Rpc::new(mock_rpc(|_| json!("not a number")).await, 10, 5.0);
+
+        assert_eq!(
+            fetch_chain_id(&[mainnet.clone(), broken.clone(), mainnet.clone()], 1000)
+                .await
+                .unwrap(),
+            Some(1)
+        );
+        assert_eq!(fetch_chain_id(&[broken], 1000).await.unwrap(), None);
+        assert_eq!(fetch_chain_id(&[], 1000).await.unwrap(), None);
+
+        // Nodes on different chains can't share a cache
+        assert!(fetch_chain_id(&[mainnet, sepolia], 1000).await.is_err());
+    }
+}
@@ -1,5 +1,32 @@
 use crate::{
+    balancer::accept_http::{
+        DEFAULT_MAX_RETRIES,
+        DEFAULT_RETRYABLE_ERROR_CODES,
+    },
+    balancer::consensus::ConsensusSettings,
+    balancer::hedge::HedgeSettings,
+    balancer::selection::cache_rules::{
+        is_non_idempotent,
+        CachePolicy,
+        DEFAULT_HOT_CACHE_MAX_ENTRY_BYTES,
+        DEFAULT_MAX_ENTRY_BYTES,
+        DEFAULT_NEGATIVE_CACHE_METHODS,
+        DEFAULT_NEGATIVE_TTL,
+    },
+    balancer::selection::routing::{
+        ArchiveRule,
+        RoutingRules,
+    },
+    balancer::selection::select::SelectionStrategy,
+    balancer::warmup::WarmupSettings,
     config::setup::sort_by_latency,
+    database::{
+        backend::BackendConfig,
+        entry::Compression,
+        hasher::CacheHasher,
+    },
+    rpc::circuit::CircuitSettings,
+    rpc::latency::LatencyScore,
     Rpc,
 };
 use clap::{
@@ -11,12 +38,14 @@ use jsonwebtoken::DecodingKey;
 use sled::Config;
 
 use std::{
+    collections::HashMap,
     fmt,
     fmt::Debug,
     fs::{
         self,
     },
     net::SocketAddr,
+    time::Duration,
 };
 
 use toml::Value;
@@ -61,7 +90,37 @@ pub struct Settings {
     pub health_check: bool,
     pub ttl: u128,
     pub max_retries: u32,
+    pub retryable_error_codes: Vec<i64>,
     pub health_check_ttl: u64,
+    pub max_head_lag: u64,
+    pub non_idempotent_methods: Vec<String>,
+    pub cache_methods: HashMap<String, CachePolicy>,
+    pub cache_prune_interval: u64,
+    pub permanent_error_codes: Vec<i64>,
+    pub max_reorg_depth: u64,
+    pub finality_distance: u64,
+    pub negative_cache_methods: Vec<String>,
+    pub negative_ttl: Duration,
+    pub max_cache_size: u64,
+    pub eviction_interval: u64,
+    pub hot_cache_entries: usize,
+    pub hot_cache_max_entry_bytes: usize,
+    pub max_entry_bytes: usize,
+    pub write_queue_size: usize,
+    pub revalidate_fraction: f64,
+    pub warmup: WarmupSettings,
+    pub allow_chain_id_change: bool,
+    pub cache_compression: Compression,
+    pub cache_backend: BackendConfig,
+    pub cache_hasher: CacheHasher,
+    pub flush_on_hash_mismatch: bool,
+    pub debug_logging: bool,
+    pub debug_max_params_len: usize,
+    pub selection: SelectionStrategy,
+    pub sticky_sessions: bool,
+    pub routing: RoutingRules,
+    pub hedging: HedgeSettings,
+    pub consensus: ConsensusSettings,
     pub sled_config: Config,
     pub admin: AdminSettings,
 }
@@ -74,8 +133,38 @@ impl Default for Settings {
             address: "127.0.0.1:3000".parse::<SocketAddr>().unwrap(),
             health_check: false,
             ttl: 1000,
-            max_retries: 32,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retryable_error_codes: DEFAULT_RETRYABLE_ERROR_CODES.to_vec(),
             health_check_ttl: 1000,
+            max_head_lag: 3,
+            non_idempotent_methods: Vec::new(),
+            cache_methods: HashMap::new(),
+            cache_prune_interval: 60000,
+            permanent_error_codes: Vec::new(),
+            max_reorg_depth: 64,
+            finality_distance: 64,
+            negative_cache_methods: default_negative_cache_methods(),
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            max_cache_size: 0,
+            eviction_interval: 1000,
+            hot_cache_entries: 10000,
+            hot_cache_max_entry_bytes: DEFAULT_HOT_CACHE_MAX_ENTRY_BYTES,
+            max_entry_bytes: DEFAULT_MAX_ENTRY_BYTES,
+            write_queue_size: 1024,
+            revalidate_fraction: 0.0,
+            warmup: WarmupSettings::default(),
+            allow_chain_id_change: false,
+            cache_compression: Compression::None,
+            cache_backend: BackendConfig::Sled,
+            cache_hasher: CacheHasher::default(),
+            flush_on_hash_mismatch: false,
+            debug_logging: cfg!(feature = "debug-verbose"),
+            debug_max_params_len: 128,
+            selection: SelectionStrategy::default(),
+            sticky_sessions: false,
+            routing: RoutingRules::default(),
+            hedging: HedgeSettings::default(),
+            consensus: ConsensusSettings::default(),
             sled_config: sled::Config::default(),
             admin: AdminSettings::default(),
         }
@@ -149,6 +238,25 @@ impl Settings {
             .as_integer()
             .expect("\x1b[31mErr:\x1b[0m Could not parse ma_length as int!")
             as f64;
+        // Weight of each new latency sample, overrides the one derived from `ma_length`. Optional.
+        let latency_alpha = blutgang_table.get("latency_alpha").map(|latency_alpha| {
+            let latency_alpha = latency_alpha
+                .as_float()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse latency_alpha as float!");
+            if latency_alpha <= 0.0 || latency_alpha > 1.0 {
+                panic!("\x1b[31mErr:\x1b[0m latency_alpha must be greater than 0 and at most 1!");
+            }
+            latency_alpha
+        });
+        // Which latency figure RPCs get compared by when picking one. Optional.
+        let latency_score = match blutgang_table.get("latency_score") {
+            Some(latency_score) => {
+                latency_score.as_str().and_then(LatencyScore::from_name).expect(
+                    "\x1b[31mErr:\x1b[0m Could not parse latency_score, expected \"average\", \"p50\", \"p90\" or \"p99\"!",
+                )
+            }
+            None => LatencyScore::default(),
+        };
 
         let health_check = blutgang_table
             .get("health_check")
@@ -160,12 +268,31 @@ impl Settings {
             .expect("\x1b[31mErr:\x1b[0m Missing ttl!")
             .as_integer()
             .expect("\x1b[31mErr:\x1b[0m Could not parse ttl as int!") as u128;
-        let max_retries = blutgang_table
-            .get("max_retries")
-            .expect("\x1b[31mErr:\x1b[0m Missing max_retries!")
-            .as_integer()
-            .expect("\x1b[31mErr:\x1b[0m Could not parse max_retries as int!")
-            as u32;
+        let max_retries = match blutgang_table.get("max_retries") {
+            Some(max_retries) => {
+                max_retries
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_retries as int!")
+                    as u32
+            }
+            None => DEFAULT_MAX_RETRIES,
+        };
+        // Error codes that get retried on another RPC. Optional.
+        let retryable_error_codes = match blutgang_table.get("retryable_error_codes") {
+            Some(codes) => {
+                codes
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse retryable_error_codes as array!")
+                    .iter()
+                    .map(|code| {
+                        code.as_integer().expect(
+                            "\x1b[31mErr:\x1b[0m Could not parse retryable_error_codes entry as int!",
+                        )
+                    })
+                    .collect()
+            }
+            None => DEFAULT_RETRYABLE_ERROR_CODES.to_vec(),
+        };
 
         let health_check_ttl = if health_check {
             blutgang_table
@@ -177,6 +304,411 @@ impl Settings {
         } else {
             u64::MAX
         };
+        // How many blocks a RPC can fall behind the others before it stops getting requests
+        let max_head_lag = match blutgang_table.get("max_head_lag") {
+            Some(max_head_lag) => {
+                max_head_lag
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse max_head_lag as int!")
+                    as u64
+            }
+            None => 3,
+        };
+
+        // When to stop sending requests to a RPC that keeps failing. Optional.
+        let circuit_defaults = CircuitSettings::default();
+        let circuit_failure_threshold = match blutgang_table.get("circuit_failure_threshold") {
+            Some(circuit_failure_threshold) => {
+                circuit_failure_threshold
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse circuit_failure_threshold as int!")
+                    as u32
+            }
+            None => circuit_defaults.failure_threshold,
+        };
+        let circuit_window = match blutgang_table.get("circuit_window_ms") {
+            Some(circuit_window_ms) => {
+                Duration::from_millis(
+                    circuit_window_ms
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse circuit_window_ms as int!")
+                        as u64,
+                )
+            }
+            None => circuit_defaults.window,
+        };
+        let circuit_cooldown = match blutgang_table.get("circuit_cooldown_ms") {
+            Some(circuit_cooldown_ms) => {
+                Duration::from_millis(
+                    circuit_cooldown_ms
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse circuit_cooldown_ms as int!")
+                        as u64,
+                )
+            }
+            None => circuit_defaults.cooldown,
+        };
+        let circuit = CircuitSettings {
+            failure_threshold: circuit_failure_threshold,
+            window: circuit_window,
+            cooldown: circuit_cooldown,
+        };
+
+        // Methods to treat as non-idempotent on top of the default ones. Optional.
+        let non_idempotent_methods = match blutgang_table.get("non_idempotent_methods") {
+            Some(methods) => methods
+                .as_array()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse non_idempotent_methods as array!")
+                .iter()
+                .map(|method| {
+                    method
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse non_idempotent_methods entry as str!")
+                        .to_string()
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // Debug logging of requests. Optional and can be toggled at runtime from the admin namespace.
+        let debug_logging = match blutgang_table.get("debug_logging") {
+            Some(debug_logging) => {
+                debug_logging
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse debug_logging as bool!")
+            }
+            None => cfg!(feature = "debug-verbose"),
+        } || debug_logging_from_env();
+        let debug_max_params_len = match blutgang_table.get("debug_max_params_len") {
+            Some(debug_max_params_len) => {
+                debug_max_params_len
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse debug_max_params_len as int!")
+                    as usize
+            }
+            None => 128,
+        };
+
+        // How to pick the RPC each request goes to. Optional.
+        let selection = match blutgang_table.get("selection") {
+            Some(selection) => {
+                selection.as_str().and_then(SelectionStrategy::from_name).expect(
+                    "\x1b[31mErr:\x1b[0m Could not parse selection, expected \"round_robin\", \"lowest_latency\", \"weighted\", \"least_connections\" or \"random\"!",
+                )
+            }
+            None => SelectionStrategy::default(),
+        };
+        // Whether calls from the same client keep going to the same RPC. Optional.
+        let sticky_sessions = match blutgang_table.get("sticky_sessions") {
+            Some(sticky_sessions) => {
+                sticky_sessions
+                    .as_bool()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse sticky_sessions as bool!")
+            }
+            None => false,
+        };
+
+        // Parse the optional `cache` table
+        //
+        // `[cache.methods]` maps method names to how long their responses can be cached for
+        let cache_table = parsed_toml.get("cache").map(|cache_table| {
+            cache_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse cache table!")
+        });
+        let cache_methods = match cache_table.and_then(|cache_table| cache_table.get("methods")) {
+            Some(methods) => {
+                methods
+                    .as_table()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse cache.methods as table!")
+                    .iter()
+                    .map(|(method, policy)| (method.clone(), parse_cache_policy(policy)))
+                    .collect()
+            }
+            None => HashMap::new(),
+        };
+        // Parse the optional `routing` table
+        //
+        // `[routing.archive]` maps method names to when their calls need an archive node,
+        // `[routing.labels]` maps method prefixes to the labels RPCs need to serve them
+        let routing_table = parsed_toml.get("routing").map(|routing_table| {
+            routing_table
+                .as_table()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse routing table!")
+        });
+        let routing = RoutingRules {
+            archive: match routing_table.and_then(|routing_table| routing_table.get("archive")) {
+                Some(methods) => {
+                    methods
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse routing.archive as table!")
+                        .iter()
+                        .map(|(method, rule)| (method.clone(), parse_archive_rule(rule)))
+                        .collect()
+                }
+                None => HashMap::new(),
+            },
+            labels: match routing_table.and_then(|routing_table| routing_table.get("labels")) {
+                Some(prefixes) => {
+                    prefixes
+                        .as_table()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse routing.labels as table!")
+                        .iter()
+                        .map(|(prefix, labels)| {
+                            (prefix.clone(), parse_labels(labels, "routing.labels entry"))
+                        })
+                        .collect()
+                }
+                None => HashMap::new(),
+            },
+        };
+
+        // Calls to also send to other RPCs if the first one is slow to answer. Optional.
+        let hedging = match parsed_toml.get("hedging") {
+            Some(hedging) => parse_hedging(hedging, &non_idempotent_methods),
+            None => HedgeSettings::default(),
+        };
+
+        // Share of finalized calls to check against a second RPC. Optional.
+        let consensus = match parsed_toml.get("consensus") {
+            Some(consensus) => parse_consensus(consensus),
+            None => ConsensusSettings::default(),
+        };
+
+        // How often to remove expired entries from the cache in ms
+        let cache_prune_interval =
+            match cache_table.and_then(|cache_table| cache_table.get("prune_interval_ms")) {
+                Some(prune_interval) => {
+                    prune_interval
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse prune_interval_ms as int!")
+                        as u64
+                }
+                None => 60000,
+            };
+
+        // Error codes that are safe to cache since they'll never change
+        let permanent_error_codes = match cache_table
+            .and_then(|cache_table| cache_table.get("permanent_error_codes"))
+        {
+            Some(codes) => {
+                codes
+                    .as_array()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse permanent_error_codes as array!")
+                    .iter()
+                    .map(|code| {
+                        code.as_integer().expect(
+                        "\x1b[31mErr:\x1b[0m Could not parse permanent_error_codes entry as int!",
+                    )
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        // How many blocks to walk back when checking for reorgs
+        let max_reorg_depth =
+            match cache_table.and_then(|cache_table| cache_table.get("max_reorg_depth")) {
+                Some(max_reorg_depth) => {
+                    max_reorg_depth
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse max_reorg_depth as int!")
+                        as u64
+                }
+                None => 64,
+            };
+
+        // Responses for blocks within this many blocks of the head don't get cached
+        let finality_distance =
+            match cache_table.and_then(|cache_table| cache_table.get("finality_distance")) {
+                Some(finality_distance) => {
+                    finality_distance
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse finality_distance as int!")
+                        as u64
+                }
+                None => 64,
+            };
+
+        // Null results for these methods get cached for `negative_ttl_ms`, see `DEFAULT_NEGATIVE_CACHE_METHODS`
+        let negative_cache_methods =
+            match cache_table.and_then(|cache_table| cache_table.get("negative_methods")) {
+                Some(methods) => {
+                    methods
+                        .as_array()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse negative_methods as array!")
+                        .iter()
+                        .map(|method| {
+                            method
+                                .as_str()
+                                .expect(
+                                    "\x1b[31mErr:\x1b[0m Could not parse negative_methods entry as str!",
+                                )
+                                .to_string()
+                        })
+                        .collect()
+                }
+                None => default_negative_cache_methods(),
+            };
+        let negative_ttl =
+            match cache_table.and_then(|cache_table| cache_table.get("negative_ttl_ms")) {
+                Some(negative_ttl) => {
+                    Duration::from_millis(
+                        negative_ttl
+                            .as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse negative_ttl_ms as int!")
+                            as u64,
+                    )
+                }
+                None => DEFAULT_NEGATIVE_TTL,
+            };
+
+        // Approximate size the cache is allowed to grow to before we start evicting entries
+        let max_cache_size =
+            match cache_table.and_then(|cache_table| cache_table.get("max_size_bytes")) {
+                Some(max_cache_size) => {
+                    max_cache_size
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse max_size_bytes as int!")
+                        as u64
+                }
+                None => 0,
+            };
+
+        let eviction_interval =
+            match cache_table.and_then(|cache_table| cache_table.get("eviction_interval_ms")) {
+                Some(eviction_interval) => {
+                    eviction_interval
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse eviction_interval_ms as int!")
+                        as u64
+                }
+                None => 1000,
+            };
+
+        // How many of the hottest entries to keep in memory in front of sled
+        let hot_cache_entries =
+            match cache_table.and_then(|cache_table| cache_table.get("hot_cache_entries")) {
+                Some(hot_cache_entries) => {
+                    hot_cache_entries
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse hot_cache_entries as int!")
+                        as usize
+                }
+                None => 10000,
+            };
+
+        // Responses larger than this don't get cached at all, 0 means there is no limit
+        let max_entry_bytes =
+            match cache_table.and_then(|cache_table| cache_table.get("max_entry_bytes")) {
+                Some(max_entry_bytes) => {
+                    max_entry_bytes
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse max_entry_bytes as int!")
+                        as usize
+                }
+                None => DEFAULT_MAX_ENTRY_BYTES,
+            };
+        // Same for the hot cache, which should hold a lot of small entries rather than a few large ones
+        let hot_cache_max_entry_bytes = match cache_table
+            .and_then(|cache_table| cache_table.get("hot_cache_max_entry_bytes"))
+        {
+            Some(hot_cache_max_entry_bytes) => {
+                hot_cache_max_entry_bytes.as_integer().expect(
+                    "\x1b[31mErr:\x1b[0m Could not parse hot_cache_max_entry_bytes as int!",
+                ) as usize
+            }
+            None => DEFAULT_HOT_CACHE_MAX_ENTRY_BYTES,
+        };
+
+        // How many cache writes can be queued before new ones get dropped
+        let write_queue_size =
+            match cache_table.and_then(|cache_table| cache_table.get("write_queue_size")) {
+                Some(write_queue_size) => {
+                    write_queue_size
+                        .as_integer()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse write_queue_size as int!")
+                        as usize
+                }
+                None => 1024,
+            };
+
+        // Fraction of cache hits that get fetched again in the background to check they're still right
+        let revalidate_fraction =
+            match cache_table.and_then(|cache_table| cache_table.get("revalidate_fraction")) {
+                Some(revalidate_fraction) => {
+                    let revalidate_fraction = revalidate_fraction.as_float().expect(
+                        "\x1b[31mErr:\x1b[0m Could not parse revalidate_fraction as float!",
+                    );
+                    if !(0.0..=1.0).contains(&revalidate_fraction) {
+                        panic!("\x1b[31mErr:\x1b[0m revalidate_fraction must be between 0 and 1!");
+                    }
+                    revalidate_fraction
+                }
+                None => 0.0,
+            };
+
+        // Calls to warm the cache with on startup
+        let warmup = match cache_table.and_then(|cache_table| cache_table.get("warmup")) {
+            Some(warmup) => parse_warmup(warmup),
+            None => WarmupSettings::default(),
+        };
+
+        // Whether the cache can switch over to another chain if the RPCs report a different chain id
+        let allow_chain_id_change =
+            match cache_table.and_then(|cache_table| cache_table.get("allow_chain_id_change")) {
+                Some(allow_chain_id_change) => {
+                    allow_chain_id_change.as_bool().expect(
+                        "\x1b[31mErr:\x1b[0m Could not parse allow_chain_id_change as bool!",
+                    )
+                }
+                None => false,
+            };
+
+        // Compress large responses before writing them to the cache
+        let cache_compression =
+            match cache_table.and_then(|cache_table| cache_table.get("compression")) {
+                Some(compression) => {
+                    parse_compression(
+                        compression,
+                        cache_table.and_then(|cache_table| cache_table.get("compression_level")),
+                        cache_table
+                            .and_then(|cache_table| cache_table.get("compression_threshold_bytes")),
+                    )
+                }
+                None => Compression::None,
+            };
+
+        // Where to store cached responses, sled unless we're sharing them through Redis
+        let cache_backend = match cache_table.and_then(|cache_table| cache_table.get("backend")) {
+            Some(backend) => {
+                parse_backend(
+                    backend,
+                    cache_table.and_then(|cache_table| cache_table.get("redis_url")),
+                    cache_table.and_then(|cache_table| cache_table.get("redis_pool_size")),
+                )
+            }
+            None => BackendConfig::Sled,
+        };
+
+        // Hash function for cache keys, defaults to whatever the enabled features pick
+        let cache_hasher = match cache_table.and_then(|cache_table| cache_table.get("hash")) {
+            Some(hash) => {
+                hash.as_str().and_then(CacheHasher::from_name).expect(
+                    "\x1b[31mErr:\x1b[0m Could not parse cache.hash, expected \"blake3\" or \"xxh3\"!",
+                )
+            }
+            None => CacheHasher::default(),
+        };
+        let flush_on_hash_mismatch =
+            match cache_table.and_then(|cache_table| cache_table.get("flush_on_hash_mismatch")) {
+                Some(flush_on_hash_mismatch) => {
+                    flush_on_hash_mismatch.as_bool().expect(
+                        "\x1b[31mErr:\x1b[0m Could not parse flush_on_hash_mismatch as bool!",
+                    )
+                }
+                None => false,
+            };
 
         // Parse `sled` table
         let sled_table = parsed_toml
@@ -237,7 +769,14 @@ impl Settings {
         // Sort RPCs by latency if enabled
         let mut rpc_list: Vec<Rpc> = Vec::new();
         for table_name in table_names {
-            if table_name != "blutgang" && table_name != "sled" && table_name != "admin" {
+            if table_name != "blutgang"
+                && table_name != "sled"
+                && table_name != "admin"
+                && table_name != "cache"
+                && table_name != "routing"
+                && table_name != "hedging"
+                && table_name != "consensus"
+            {
                 let rpc_table = parsed_toml.get(table_name).unwrap().as_table().unwrap();
 
                 let max_consecutive = rpc_table
@@ -253,7 +792,62 @@ impl Settings {
                     .expect("\x1b[31mErr:\x1b[0m Could not parse URL from a RPC as str!")
                     .to_string();
 
-                let rpc = Rpc::new(url, max_consecutive, ma_length);
+                let weight = match rpc_table.get("weight") {
+                    Some(weight) => parse_weight(weight),
+                    None => 1.0,
+                };
+                let archive = match rpc_table.get("archive") {
+                    Some(archive) => {
+                        archive
+                            .as_bool()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse archive as bool!")
+                    }
+                    None => false,
+                };
+                let labels = match rpc_table.get("labels") {
+                    Some(labels) => parse_labels(labels, "labels"),
+                    None => Vec::new(),
+                };
+                let tier = match rpc_table.get("tier") {
+                    Some(tier) => {
+                        tier
+                            .as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse tier as int!")
+                            as u32
+                    }
+                    None => 0,
+                };
+                let max_rps = match rpc_table.get("max_rps") {
+                    Some(max_rps) => {
+                        max_rps
+                            .as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse max_rps as int!")
+                            as u32
+                    }
+                    None => 0,
+                };
+                let max_concurrent = match rpc_table.get("max_concurrent") {
+                    Some(max_concurrent) => {
+                        max_concurrent
+                            .as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse max_concurrent as int!")
+                            as u32
+                    }
+                    None => 0,
+                };
+
+                let mut rpc = Rpc::new(url, max_consecutive, ma_length)
+                    .with_weight(weight)
+                    .with_archive(archive)
+                    .with_labels(labels)
+                    .with_tier(tier)
+                    .with_max_rps(max_rps)
+                    .with_max_concurrent(max_concurrent)
+                    .with_latency_score(latency_score)
+                    .with_circuit(circuit);
+                if let Some(latency_alpha) = latency_alpha {
+                    rpc = rpc.with_latency_alpha(latency_alpha);
+                }
                 rpc_list.push(rpc);
             }
         }
@@ -327,7 +921,37 @@ impl Settings {
             health_check,
             ttl,
             max_retries,
+            retryable_error_codes,
             health_check_ttl,
+            max_head_lag,
+            non_idempotent_methods,
+            cache_methods,
+            cache_prune_interval,
+            permanent_error_codes,
+            max_reorg_depth,
+            finality_distance,
+            negative_cache_methods,
+            negative_ttl,
+            max_cache_size,
+            eviction_interval,
+            hot_cache_entries,
+            hot_cache_max_entry_bytes,
+            max_entry_bytes,
+            write_queue_size,
+            revalidate_fraction,
+            warmup,
+            allow_chain_id_change,
+            cache_compression,
+            cache_backend,
+            cache_hasher,
+            flush_on_hash_mismatch,
+            debug_logging,
+            debug_max_params_len,
+            selection,
+            sticky_sessions,
+            routing,
+            hedging,
+            consensus,
             sled_config,
             admin,
         }
@@ -414,6 +1038,7 @@ impl Settings {
             .expect("Invalid max_retries")
             .parse::<u32>()
             .expect("Invalid max_retries");
+        let retryable_error_codes = DEFAULT_RETRYABLE_ERROR_CODES.to_vec();
 
         let health_check_ttl = matches
             .get_one::<String>("health_check_ttl")
@@ -455,9 +1080,346 @@ impl Settings {
             health_check,
             ttl,
             max_retries,
+            retryable_error_codes,
             health_check_ttl,
+            max_head_lag: 3,
+            non_idempotent_methods: Vec::new(),
+            cache_methods: HashMap::new(),
+            cache_prune_interval: 60000,
+            permanent_error_codes: Vec::new(),
+            max_reorg_depth: 64,
+            finality_distance: 64,
+            negative_cache_methods: default_negative_cache_methods(),
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            max_cache_size: 0,
+            eviction_interval: 1000,
+            hot_cache_entries: 10000,
+            hot_cache_max_entry_bytes: DEFAULT_HOT_CACHE_MAX_ENTRY_BYTES,
+            max_entry_bytes: DEFAULT_MAX_ENTRY_BYTES,
+            write_queue_size: 1024,
+            revalidate_fraction: 0.0,
+            warmup: WarmupSettings::default(),
+            allow_chain_id_change: false,
+            cache_compression: Compression::None,
+            cache_backend: BackendConfig::Sled,
+            cache_hasher: CacheHasher::default(),
+            flush_on_hash_mismatch: false,
+            debug_logging: cfg!(feature = "debug-verbose") || debug_logging_from_env(),
+            debug_max_params_len: 128,
+            selection: SelectionStrategy::default(),
+            sticky_sessions: false,
+            routing: RoutingRules::default(),
+            hedging: HedgeSettings::default(),
+            consensus: ConsensusSettings::default(),
             sled_config,
             admin,
         }
     }
 }
+
+fn default_negative_cache_methods() -> Vec<String> {
+    DEFAULT_NEGATIVE_CACHE_METHODS
+        .iter()
+        .map(|method| method.to_string())
+        .collect()
+}
+
+// Parse a `[cache.methods]` entry.
+//
+// Either `"never"`, `"forever"`, or a TTL in ms where `0` means forever.
+fn parse_cache_policy(policy: &Value) -> CachePolicy {
+    match policy {
+        Value::String(policy) if policy == "never" => CachePolicy::Never,
+        Value::String(policy) if policy == "forever" => CachePolicy::Forever,
+        Value::Integer(0) => CachePolicy::Forever,
+        Value::Integer(ttl) if *ttl > 0 => CachePolicy::Ttl(Duration::from_millis(*ttl as u64)),
+        _ => panic!(
+            "\x1b[31mErr:\x1b[0m Could not parse cache.methods entry, expected \"never\", \"forever\" or a TTL in ms!"
+        ),
+    }
+}
+
+// Parse a `[routing.archive]` entry.
+//
+// Either `"always"`, or how many blocks behind the head a call's block has to be to need an archive node.
+fn parse_archive_rule(rule: &Value) -> ArchiveRule {
+    match rule {
+        Value::String(rule) if rule == "always" => ArchiveRule::Always,
+        Value::Integer(age) if *age >= 0 => ArchiveRule::OlderThan(*age as u64),
+        _ => panic!(
+            "\x1b[31mErr:\x1b[0m Could not parse routing.archive entry, expected \"always\" or a block age!"
+        ),
+    }
+}
+
+// Parse an array of labels, `name` is what to call it in errors
+fn parse_labels(labels: &Value, name: &str) -> Vec<String> {
+    labels
+        .as_array()
+        .unwrap_or_else(|| panic!("\x1b[31mErr:\x1b[0m Could not parse {} as array!", name))
+        .iter()
+        .map(|label| {
+            label
+                .as_str()
+                .unwrap_or_else(|| {
+                    panic!("\x1b[31mErr:\x1b[0m Could not parse {} label as str!", name)
+                })
+                .to_string()
+        })
+        .collect()
+}
+
+// Parse `cache.compression`, either `"none"` or `"zstd"`.
+//
+// The zstd level defaults to 3, and only responses over 1024 bytes get compressed by default.
+fn parse_compression(
+    compression: &Value,
+    level: Option<&Value>,
+    threshold: Option<&Value>,
+) -> Compression {
+    match compression.as_str() {
+        Some("none") => Compression::None,
+        Some("zstd") => {
+            Compression::Zstd {
+                level: match level {
+                    Some(level) => {
+                        level
+                            .as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse compression_level as int!")
+                            as i32
+                    }
+                    None => 3,
+                },
+                threshold: match threshold {
+                    Some(threshold) => {
+                        threshold.as_integer().expect(
+                            "\x1b[31mErr:\x1b[0m Could not parse compression_threshold_bytes as int!",
+                        ) as usize
+                    }
+                    None => 1024,
+                },
+            }
+        }
+        _ => {
+            panic!(
+                "\x1b[31mErr:\x1b[0m Could not parse cache.compression, expected \"none\" or \"zstd\"!"
+            )
+        }
+    }
+}
+
+// Parse `cache.backend`, along with the Redis settings if it's set to "redis"
+fn parse_backend(
+    backend: &Value,
+    url: Option<&Value>,
+    pool_size: Option<&Value>,
+) -> BackendConfig {
+    match backend.as_str() {
+        Some("sled") => BackendConfig::Sled,
+        Some("redis") => {
+            BackendConfig::Redis {
+                url: match url {
+                    Some(url) => {
+                        url.as_str()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse redis_url as str!")
+                            .to_string()
+                    }
+                    None => "redis://127.0.0.1:6379".to_string(),
+                },
+                pool_size: match pool_size {
+                    Some(pool_size) => {
+                        pool_size
+                            .as_integer()
+                            .expect("\x1b[31mErr:\x1b[0m Could not parse redis_pool_size as int!")
+                            as usize
+                    }
+                    None => 4,
+                },
+            }
+        }
+        _ => {
+            panic!(
+                "\x1b[31mErr:\x1b[0m Could not parse cache.backend, expected \"sled\" or \"redis\"!"
+            )
+        }
+    }
+}
+
+// Parse the `[cache.warmup]` table.
+//
+// Calls are tables with a method and optionally params, like `{ method = "eth_chainId" }`.
+fn parse_warmup(warmup: &Value) -> WarmupSettings {
+    let warmup = warmup
+        .as_table()
+        .expect("\x1b[31mErr:\x1b[0m Could not parse cache.warmup as table!");
+
+    let calls = match warmup.get("calls") {
+        Some(calls) => {
+            calls
+                .as_array()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse cache.warmup.calls as array!")
+                .iter()
+                .map(|call| {
+                    if call.get("method").and_then(Value::as_str).is_none() {
+                        panic!("\x1b[31mErr:\x1b[0m Every cache.warmup call needs a method!");
+                    }
+                    serde_json::to_value(call).expect(
+                        "\x1b[31mErr:\x1b[0m Could not parse cache.warmup call as JSON-RPC!",
+                    )
+                })
+                .collect()
+        }
+        None => Vec::new(),
+    };
+
+    let concurrency = match warmup.get("concurrency") {
+        Some(concurrency) => {
+            let concurrency = concurrency
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse cache.warmup.concurrency as int!");
+            if concurrency < 1 {
+                panic!("\x1b[31mErr:\x1b[0m cache.warmup.concurrency must be at least 1!");
+            }
+            concurrency as usize
+        }
+        None => 4,
+    };
+
+    let replay_last = match warmup.get("replay_last") {
+        Some(replay_last) => {
+            replay_last
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse cache.warmup.replay_last as int!")
+                as usize
+        }
+        None => 0,
+    };
+
+    WarmupSettings {
+        calls,
+        concurrency,
+        replay_last,
+    }
+}
+
+// Parse the `[hedging]` table
+fn parse_hedging(hedging: &Value, non_idempotent_methods: &[String]) -> HedgeSettings {
+    let hedging = hedging
+        .as_table()
+        .expect("\x1b[31mErr:\x1b[0m Could not parse hedging as table!");
+    let defaults = HedgeSettings::default();
+
+    let methods = match hedging.get("methods") {
+        Some(methods) => {
+            methods
+                .as_array()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse hedging.methods as array!")
+                .iter()
+                .map(|method| {
+                    method
+                        .as_str()
+                        .expect("\x1b[31mErr:\x1b[0m Could not parse hedging.methods entry as str!")
+                        .to_string()
+                })
+                .collect()
+        }
+        None => defaults.methods,
+    };
+
+    // These would be sent to more than one RPC, so they're never hedged
+    for method in &methods {
+        if is_non_idempotent(method, non_idempotent_methods) {
+            println!(
+                "\x1b[93mWrn:\x1b[0m {} is non-idempotent and will not be hedged!",
+                method
+            );
+        }
+    }
+
+    let delay = match hedging.get("delay_ms") {
+        Some(delay) => {
+            Duration::from_millis(
+                delay
+                    .as_integer()
+                    .expect("\x1b[31mErr:\x1b[0m Could not parse hedging.delay_ms as int!")
+                    as u64,
+            )
+        }
+        None => defaults.delay,
+    };
+
+    let max_hedges = match hedging.get("max_hedges") {
+        Some(max_hedges) => {
+            max_hedges
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse hedging.max_hedges as int!")
+                as u32
+        }
+        None => defaults.max_hedges,
+    };
+
+    HedgeSettings {
+        methods,
+        delay,
+        max_hedges,
+    }
+}
+
+// Parse the `[consensus]` table
+fn parse_consensus(consensus: &Value) -> ConsensusSettings {
+    let consensus = consensus
+        .as_table()
+        .expect("\x1b[31mErr:\x1b[0m Could not parse consensus as table!");
+    let defaults = ConsensusSettings::default();
+
+    let fraction = match consensus.get("fraction") {
+        Some(fraction) => {
+            match fraction {
+                Value::Integer(fraction) => *fraction as f64,
+                Value::Float(fraction) => *fraction,
+                _ => panic!("\x1b[31mErr:\x1b[0m Could not parse consensus.fraction as float!"),
+            }
+        }
+        None => defaults.fraction,
+    };
+    if !(0.0..=1.0).contains(&fraction) {
+        panic!("\x1b[31mErr:\x1b[0m consensus.fraction must be between 0 and 1!");
+    }
+
+    let max_divergences = match consensus.get("max_divergences") {
+        Some(max_divergences) => {
+            max_divergences
+                .as_integer()
+                .expect("\x1b[31mErr:\x1b[0m Could not parse consensus.max_divergences as int!")
+                as u32
+        }
+        None => defaults.max_divergences,
+    };
+
+    ConsensusSettings {
+        fraction,
+        max_divergences,
+    }
+}
+
+// Parse the `weight` of a RPC, either as a float or an int
+fn parse_weight(weight: &Value) -> f64 {
+    let weight = match weight {
+        Value::Integer(weight) => *weight as f64,
+        Value::Float(weight) => *weight,
+        _ => panic!("\x1b[31mErr:\x1b[0m Could not parse weight of a RPC as float!"),
+    };
+    if !weight.is_finite() || weight <= 0.0 {
+        panic!("\x1b[31mErr:\x1b[0m The weight of a RPC must be greater than 0!");
+    }
+
+    weight
+}
+
+// Debug logging can also be turned on by setting `BLUTGANG_DEBUG`
+fn debug_logging_from_env() -> bool {
+    matches!(
+        std::env::var("BLUTGANG_DEBUG").as_deref(),
+        Ok("1") | Ok("true")
+    )
+}
@@ -0,0 +1,217 @@
+use crate::database::{
+    error::DatabaseError,
+    hot_cache::HotCache,
+    redis_backend::RedisBackend,
+    sled_backend::SledBackend,
+};
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
+
+use sled::{
+    Db,
+    IVec,
+};
+
+pub type BackendFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T, DatabaseError>> + Send + 'a>>;
+
+// Cache entry as it gets written to a backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredEntry {
+    pub key: Vec<u8>,
+    // Encoded the same way regardless of the backend, see `entry`
+    pub value: Vec<u8>,
+    // Unix ms after which the backend can drop the entry on its own
+    pub expires_at: Option<u64>,
+}
+
+// Where cached responses are stored.
+//
+// Only the entries themselves go through here. Indexes, the write log and
+// metadata always stay in the local sled DB, so each instance keeps track
+// of (and invalidates) the entries it wrote.
+pub trait CacheBackend: std::fmt::Debug + Send + Sync {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BackendFuture<'a, Option<IVec>>;
+
+    fn insert(&self, entry: StoredEntry) -> BackendFuture<'_, ()>;
+
+    fn insert_batch(&self, entries: Vec<StoredEntry>) -> BackendFuture<'_, ()>;
+
+    // Remove every key in `keys`, returning how many of them were there
+    fn remove(&self, keys: Vec<Vec<u8>>) -> BackendFuture<'_, usize>;
+
+    // Keys of every entry starting with `prefix`
+    fn scan_prefix<'a>(&'a self, prefix: &'a [u8]) -> BackendFuture<'a, Vec<IVec>>;
+}
+
+// Which backend to store entries in, set with `cache.backend`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BackendConfig {
+    #[default]
+    Sled,
+    Redis {
+        url: String,
+        pool_size: usize,
+    },
+}
+
+impl BackendConfig {
+    pub async fn open(
+        &self,
+        cache: &Arc<Db>,
+        hot_cache: &Arc<HotCache>,
+    ) -> Result<Arc<dyn CacheBackend>, DatabaseError> {
+        match self {
+            BackendConfig::Sled => Ok(Arc::new(SledBackend::new(Arc::clone(cache)))),
+            BackendConfig::Redis { url, pool_size } => Ok(Arc::new(
+                RedisBackend::connect(url, *pool_size, Arc::clone(hot_cache)).await?,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        batch::CacheBatch,
+        block_index::invalidate_from,
+        expiry::prune_expired,
+        flush::{
+            flush_all,
+            flush_blocks,
+            flush_method,
+        },
+    };
+
+    fn create_test_cache() -> Arc<Db> {
+        Arc::new(sled::Config::new().temporary(true).open().unwrap())
+    }
+
+    async fn clear(backend: &dyn CacheBackend) {
+        let keys = backend.scan_prefix(b"").await.unwrap();
+        backend
+            .remove(keys.into_iter().map(|key| key.to_vec()).collect())
+            .await
+            .unwrap();
+    }
+
+    async fn entry_suite(backend: &dyn CacheBackend) {
+        assert!(backend.get(b"key").await.unwrap().is_none());
+
+        backend
+            .insert(StoredEntry {
+                key: b"key".to_vec(),
+                value: b"{}".to_vec(),
+                expires_at: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(backend.get(b"key").await.unwrap().unwrap(), b"{}");
+
+        backend
+            .insert_batch(vec![
+                StoredEntry {
+                    key: b"prefix1".to_vec(),
+                    value: b"1".to_vec(),
+                    expires_at: None,
+                },
+                StoredEntry {
+                    key: b"prefix2".to_vec(),
+                    value: b"2".to_vec(),
+                    expires_at: Some(u64::MAX / 2),
+                },
+                StoredEntry {
+                    key: b"pre*x".to_vec(),
+                    value: b"3".to_vec(),
+                    expires_at: None,
+                },
+            ])
+            .await
+            .unwrap();
+        assert_eq!(backend.get(b"prefix2").await.unwrap().unwrap(), b"2");
+
+        // Glob characters in keys are matched literally
+        let mut keys = backend.scan_prefix(b"prefix").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![IVec::from(b"prefix1"), IVec::from(b"prefix2")]);
+        assert_eq!(backend.scan_prefix(b"pre*").await.unwrap().len(), 1);
+
+        // Only keys that were there count
+        assert_eq!(
+            backend
+                .remove(vec![
+                    b"key".to_vec(),
+                    b"prefix1".to_vec(),
+                    b"missing".to_vec()
+                ])
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(backend.get(b"key").await.unwrap().is_none());
+        assert_eq!(backend.scan_prefix(b"").await.unwrap().len(), 2);
+    }
+
+    // Maintenance that removes entries through the indexes in sled
+    async fn index_suite(backend: &dyn CacheBackend) {
+        let cache = create_test_cache();
+
+        let mut batch = CacheBatch::default();
+        batch.insert(b"forever", b"{}");
+        batch.index_block(10, b"forever");
+        batch.insert_expiring(b"expiring", b"{}", 100);
+        batch.insert(b"logs", b"[]");
+        batch.index_method("eth_getLogs", b"logs");
+        batch.index_block(20, b"logs");
+        batch.insert(b"other", b"{}");
+        batch.apply(&cache, backend).await.unwrap();
+
+        assert_eq!(prune_expired(&cache, backend, 200).await.unwrap(), 1);
+        assert!(backend.get(b"expiring").await.unwrap().is_none());
+
+        assert_eq!(
+            flush_method(&cache, backend, "eth_getLogs").await.unwrap(),
+            1
+        );
+        assert!(backend.get(b"logs").await.unwrap().is_none());
+        assert_eq!(flush_blocks(&cache, backend, 20, 30).await.unwrap(), 0);
+
+        assert_eq!(invalidate_from(&cache, backend, 10).await.unwrap(), 1);
+        assert!(backend.get(b"forever").await.unwrap().is_none());
+
+        assert_eq!(flush_all(&cache, backend).await.unwrap(), 1);
+        assert!(backend.get(b"other").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sled_backend() {
+        entry_suite(&SledBackend::new(create_test_cache())).await;
+        index_suite(&SledBackend::new(create_test_cache())).await;
+    }
+
+    // Needs a Redis server, skipped unless `BLUTGANG_TEST_REDIS_URL` is set.
+    // Anything in it written by blutgang gets removed.
+    #[tokio::test]
+    async fn test_redis_backend() {
+        let Ok(url) = std::env::var("BLUTGANG_TEST_REDIS_URL") else {
+            println!("Skipping, set BLUTGANG_TEST_REDIS_URL to run against Redis");
+            return;
+        };
+
+        let config = BackendConfig::Redis { url, pool_size: 2 };
+        let backend = config
+            .open(&create_test_cache(), &Arc::new(HotCache::new(0)))
+            .await
+            .unwrap();
+
+        clear(backend.as_ref()).await;
+        entry_suite(backend.as_ref()).await;
+        clear(backend.as_ref()).await;
+        index_suite(backend.as_ref()).await;
+    }
+}
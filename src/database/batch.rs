@@ -0,0 +1,335 @@
+use crate::database::{
+    backend::{
+        CacheBackend,
+        StoredEntry,
+    },
+    block_index::{
+        block_index_key,
+        BLOCK_TREE,
+    },
+    call_log::record_call,
+    entry::encode_expiring,
+    error::DatabaseError,
+    eviction::record_write,
+    expiry::{
+        expiry_index_key,
+        EXPIRY_TREE,
+    },
+    hot_cache::HotCache,
+    method_index::{
+        method_index_key,
+        METHOD_TREE,
+    },
+};
+
+use std::collections::HashMap;
+
+use sled::{
+    Batch,
+    Db,
+    IVec,
+};
+
+// Cache reads and writes for a JSON-RPC batch, so we don't hit sled once per call.
+//
+// Entries are read up front with `prefetch`, and writes are collected as calls
+// get resolved and applied all at once with `apply`. Single calls go through
+// here as well.
+#[derive(Debug, Default)]
+pub struct CacheBatch {
+    // Key -> (entry, hot cache generation from before the read)
+    prefetched: HashMap<Vec<u8>, (Option<IVec>, u64)>,
+    entries: Vec<StoredEntry>,
+    expiry_index: Vec<Vec<u8>>,
+    block_index: Vec<Vec<u8>>,
+    method_index: Vec<Vec<u8>>,
+    // Key and size of every write, for eviction
+    writes: Vec<(Vec<u8>, usize)>,
+    // Calls to replay on startup and the size of the log, for the warmup
+    calls: Vec<(Vec<u8>, usize)>,
+}
+
+impl CacheBatch {
+    // Read the entries at `keys`, to be picked up with `take_prefetched`
+    pub async fn prefetch<'a>(
+        &mut self,
+        backend: &dyn CacheBackend,
+        hot_cache: &HotCache,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<(), DatabaseError> {
+        for key in keys {
+            let generation = hot_cache.generation(key);
+            let entry = backend.get(key).await?;
+            self.prefetched.insert(key.to_vec(), (entry, generation));
+        }
+
+        Ok(())
+    }
+
+    // Take the entry at `key` if it was prefetched, along with the
+    // hot cache generation to pass to `HotCache::insert_if_unchanged`
+    pub fn take_prefetched(&mut self, key: &[u8]) -> Option<(Option<IVec>, u64)> {
+        self.prefetched.remove(key)
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.entries.push(StoredEntry {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expires_at: None,
+        });
+    }
+
+    // Insert `value` under `key` so it expires at `expires_at`,
+    // and index it so it can get pruned once it does.
+    pub fn insert_expiring(&mut self, key: &[u8], value: &[u8], expires_at: u64) {
+        self.expiry_index.push(expiry_index_key(expires_at, key));
+        self.entries.push(StoredEntry {
+            key: key.to_vec(),
+            value: encode_expiring(value, expires_at),
+            expires_at: Some(expires_at),
+        });
+    }
+
+    // Same as `block_index::index_block`
+    pub fn index_block(&mut self, block: u64, key: &[u8]) {
+        self.block_index.push(block_index_key(block, key));
+    }
+
+    // Same as `method_index::index_method`
+    pub fn index_method(&mut self, method: &str, key: &[u8]) {
+        self.method_index.push(method_index_key(method, key));
+    }
+
+    // Same as `eviction::record_write`
+    pub fn record_write(&mut self, key: &[u8], value_len: usize) {
+        self.writes.push((key.to_vec(), value_len));
+    }
+
+    // Same as `call_log::record_call`
+    pub fn log_call(&mut self, call: &[u8], log_size: usize) {
+        self.calls.push((call.to_vec(), log_size));
+    }
+
+    // Return true if applying the batch wouldn't write anything
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+            && self.expiry_index.is_empty()
+            && self.block_index.is_empty()
+            && self.method_index.is_empty()
+            && self.writes.is_empty()
+            && self.calls.is_empty()
+    }
+
+    // Entries that get written when the batch is applied, as they'll be stored in sled
+    pub fn entries(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.key.as_slice(), entry.value.as_slice()))
+    }
+
+    // Add the writes of `other` after ours, so both can be applied at once
+    pub fn append(&mut self, mut other: CacheBatch) {
+        self.entries.append(&mut other.entries);
+        self.expiry_index.append(&mut other.expiry_index);
+        self.block_index.append(&mut other.block_index);
+        self.method_index.append(&mut other.method_index);
+        self.writes.append(&mut other.writes);
+        self.calls.append(&mut other.calls);
+    }
+
+    // Write the indexes to sled and the entries to `backend`.
+    //
+    // Indexes go first so entries can never be written without them.
+    pub async fn apply(
+        self,
+        cache: &Db,
+        backend: &dyn CacheBackend,
+    ) -> Result<(), DatabaseError> {
+        for (tree, index) in [
+            (BLOCK_TREE, self.block_index),
+            (EXPIRY_TREE, self.expiry_index),
+            (METHOD_TREE, self.method_index),
+        ] {
+            let mut batch = Batch::default();
+            for index_key in index {
+                batch.insert(index_key, &[]);
+            }
+            cache.open_tree(tree)?.apply_batch(batch)?;
+        }
+
+        // Single calls don't need the overhead of a batch
+        let mut entries = self.entries;
+        match entries.len() {
+            0 => {}
+            1 => backend.insert(entries.pop().unwrap()).await?,
+            _ => backend.insert_batch(entries).await?,
+        }
+
+        for (key, value_len) in self.writes {
+            record_write(cache, &key, value_len)?;
+        }
+
+        for (call, log_size) in self.calls {
+            record_call(cache, &call, log_size)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        block_index::invalidate_from,
+        call_log::recent_calls,
+        entry::is_expired,
+        eviction::Evictor,
+        expiry::prune_expired,
+        sled_backend::SledBackend,
+    };
+    use std::{
+        sync::Arc,
+        time::Instant,
+    };
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+        let hot_cache = HotCache::new(16);
+        cache.insert(b"hit", b"{}").unwrap();
+
+        let mut batch = CacheBatch::default();
+        batch
+            .prefetch(&backend, &hot_cache, [b"hit".as_slice(), b"miss"])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            batch.take_prefetched(b"hit"),
+            Some((Some(IVec::from(b"{}")), hot_cache.generation(b"hit")))
+        );
+        assert_eq!(batch.take_prefetched(b"miss").unwrap().0, None);
+
+        // Not prefetched, or already taken
+        assert!(batch.take_prefetched(b"hit").is_none());
+        assert!(batch.take_prefetched(b"other").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        let mut batch = CacheBatch::default();
+        assert!(batch.is_empty());
+        batch.insert(b"first", b"1");
+
+        let mut other = CacheBatch::default();
+        other.insert(b"second", b"2");
+        other.insert(b"first", b"3");
+        other.index_method("eth_call", b"second");
+        batch.append(other);
+
+        // Later writes win
+        assert_eq!(batch.entries().count(), 3);
+        batch.apply(&cache, &backend).await.unwrap();
+        assert_eq!(cache.get(b"first").unwrap().unwrap(), b"3");
+        assert_eq!(cache.get(b"second").unwrap().unwrap(), b"2");
+        assert_eq!(cache.open_tree(METHOD_TREE).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_apply() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        let mut batch = CacheBatch::default();
+        batch.insert(b"forever", b"{}");
+        batch.index_block(10, b"forever");
+        batch.record_write(b"forever", 2);
+        batch.insert_expiring(b"expiring", b"{}", 100);
+        batch.record_write(b"expiring", 2);
+        batch.log_call(br#"{"method":"eth_chainId"}"#, 8);
+
+        // Nothing gets written until the batch is applied
+        assert!(cache.get(b"forever").unwrap().is_none());
+        batch.apply(&cache, &backend).await.unwrap();
+
+        assert_eq!(cache.get(b"forever").unwrap().unwrap(), b"{}");
+        let expiring = cache.get(b"expiring").unwrap().unwrap();
+        assert!(!is_expired(&expiring, 99));
+        assert!(is_expired(&expiring, 100));
+
+        let mut evictor = Evictor::new(&cache, 0).unwrap();
+        evictor.count_writes(&cache).unwrap();
+        assert_eq!(evictor.stats().live_bytes, 19);
+
+        // Indexed like regular writes
+        assert_eq!(prune_expired(&cache, &backend, 200).await.unwrap(), 1);
+        assert!(cache.get(b"expiring").unwrap().is_none());
+        assert_eq!(invalidate_from(&cache, &backend, 10).await.unwrap(), 1);
+        assert!(cache.get(b"forever").unwrap().is_none());
+        assert_eq!(recent_calls(&cache, 8).unwrap().len(), 1);
+    }
+
+    // Run with `cargo test --release bench_cache_batch -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn bench_cache_batch() {
+        const CALLS: u32 = 1000;
+
+        let hot_cache = HotCache::new(0);
+        let response = br#"{"id":null,"jsonrpc":"2.0","result":"0x10d4f"}"#.as_slice();
+        let keys: Vec<_> = (0..CALLS)
+            .map(|i| blake3::hash(&i.to_be_bytes()).as_bytes().to_vec())
+            .collect();
+
+        // Half of the batch is cached, the other half gets written
+        let setup = || {
+            let cache = create_test_cache();
+            for key in keys.iter().step_by(2) {
+                cache.insert(key.as_slice(), response).unwrap();
+            }
+            cache
+        };
+
+        let cache = setup();
+        let time = Instant::now();
+        for (block, key) in keys.iter().enumerate() {
+            if cache.get(key).unwrap().is_none() {
+                crate::database::block_index::index_block(&cache, block as u64, key).unwrap();
+                cache.insert(key.as_slice(), response).unwrap();
+            }
+        }
+        let per_item = time.elapsed();
+
+        let cache = setup();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+        let time = Instant::now();
+        let mut batch = CacheBatch::default();
+        batch
+            .prefetch(&backend, &hot_cache, keys.iter().map(Vec::as_slice))
+            .await
+            .unwrap();
+        for (block, key) in keys.iter().enumerate() {
+            if batch.take_prefetched(key).unwrap().0.is_none() {
+                batch.index_block(block as u64, key);
+                batch.insert(key, response);
+            }
+        }
+        batch.apply(&cache, &backend).await.unwrap();
+        let batched = time.elapsed();
+
+        println!(
+            "{} calls, per item: {:?}, batched: {:?}",
+            CALLS, per_item, batched
+        );
+    }
+}
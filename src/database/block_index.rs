@@ -0,0 +1,132 @@
+use crate::database::{
+    backend::CacheBackend,
+    error::DatabaseError,
+};
+
+use sled::{
+    Batch,
+    Db,
+};
+
+// Tree indexing cache entries by the block they depend on.
+//
+// Keys are the block number as a big endian u64 followed by the key of the
+// cache entry, so we can range over everything at or above a block when it reorgs.
+// Only blocks that aren't finalized yet are indexed.
+pub const BLOCK_TREE: &[u8] = b"blocks";
+
+pub fn block_index_key(block: u64, key: &[u8]) -> Vec<u8> {
+    let mut index_key = Vec::with_capacity(8 + key.len());
+    index_key.extend_from_slice(&block.to_be_bytes());
+    index_key.extend_from_slice(key);
+
+    index_key
+}
+
+// Record that the cache entry at `key` depends on `block`
+//
+// Requests go through `CacheBatch`, this is for writing entries directly in tests.
+#[cfg(test)]
+pub fn index_block(cache: &Db, block: u64, key: &[u8]) -> Result<(), sled::Error> {
+    cache
+        .open_tree(BLOCK_TREE)?
+        .insert(block_index_key(block, key), &[])?;
+
+    Ok(())
+}
+
+// Remove every cache entry that depends on `block` or anything above it,
+// returning how many were removed.
+pub async fn invalidate_from(
+    cache: &Db,
+    backend: &dyn CacheBackend,
+    block: u64,
+) -> Result<usize, DatabaseError> {
+    let blocks = cache.open_tree(BLOCK_TREE)?;
+
+    let mut keys = Vec::new();
+    let mut index_batch = Batch::default();
+
+    for index_key in blocks.range(block.to_be_bytes()..).keys() {
+        let index_key = index_key?;
+
+        keys.push(index_key[8..].to_vec());
+        index_batch.remove(index_key);
+    }
+
+    let invalidated = backend.remove(keys).await?;
+    blocks.apply_batch(index_batch)?;
+
+    Ok(invalidated)
+}
+
+// Once a block finalizes, entries depending on it can't reorg anymore,
+// so they're safe to stay in the cache without being indexed.
+pub fn remove_finalized(cache: &Db, finalized: u64) -> Result<(), sled::Error> {
+    let blocks = cache.open_tree(BLOCK_TREE)?;
+
+    let mut index_batch = Batch::default();
+    for index_key in blocks
+        .range(..finalized.saturating_add(1).to_be_bytes())
+        .keys()
+    {
+        index_batch.remove(index_key?);
+    }
+
+    blocks.apply_batch(index_batch)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::sled_backend::SledBackend;
+    use std::sync::Arc;
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_from() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        for (block, key) in [(1, "key1"), (2, "key2"), (3, "key3"), (3, "key4")] {
+            cache.insert(key, "value").unwrap();
+            index_block(&cache, block, key.as_bytes()).unwrap();
+        }
+
+        assert_eq!(invalidate_from(&cache, &backend, 2).await.unwrap(), 3);
+        assert!(cache.get("key1").unwrap().is_some());
+        assert!(cache.get("key2").unwrap().is_none());
+        assert!(cache.get("key3").unwrap().is_none());
+        assert!(cache.get("key4").unwrap().is_none());
+
+        // Only the entry for block 1 should still be indexed
+        assert_eq!(cache.open_tree(BLOCK_TREE).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_remove_finalized() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        for (block, key) in [(1, "key1"), (2, "key2"), (3, "key3")] {
+            cache.insert(key, "value").unwrap();
+            index_block(&cache, block, key.as_bytes()).unwrap();
+        }
+
+        remove_finalized(&cache, 2).unwrap();
+        assert_eq!(cache.open_tree(BLOCK_TREE).unwrap().len(), 1);
+
+        // Finalized entries stay in the cache even if we reorg below them
+        assert_eq!(invalidate_from(&cache, &backend, 1).await.unwrap(), 1);
+        assert!(cache.get("key1").unwrap().is_some());
+        assert!(cache.get("key2").unwrap().is_some());
+        assert!(cache.get("key3").unwrap().is_none());
+
+        remove_finalized(&cache, u64::MAX).unwrap();
+    }
+}
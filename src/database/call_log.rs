@@ -0,0 +1,86 @@
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+};
+
+use sled::Db;
+
+// Tree logging the calls whose responses got cached forever, so the
+// warmup can replay them after a restart.
+//
+// It's a ring buffer of `cache.warmup.replay_last` slots. Keys are the
+// slot as a big endian u64, values are the id sled generated for the write
+// as a big endian u64 followed by the call as JSON.
+pub const CALL_LOG_TREE: &[u8] = b"call_log";
+
+// Log `call` so it can get replayed, overwriting the oldest call once
+// the log holds `log_size` of them
+pub fn record_call(cache: &Db, call: &[u8], log_size: usize) -> Result<(), sled::Error> {
+    if log_size == 0 {
+        return Ok(());
+    }
+
+    let id = cache.generate_id()?;
+    let slot = id % log_size as u64;
+
+    let mut logged = Vec::with_capacity(8 + call.len());
+    logged.extend_from_slice(&id.to_be_bytes());
+    logged.extend_from_slice(call);
+
+    cache
+        .open_tree(CALL_LOG_TREE)?
+        .insert(slot.to_be_bytes(), logged)?;
+
+    Ok(())
+}
+
+// Return up to `limit` distinct logged calls, most recent first
+pub fn recent_calls(cache: &Db, limit: usize) -> Result<Vec<Vec<u8>>, sled::Error> {
+    let mut logged = Vec::new();
+    for entry in cache.open_tree(CALL_LOG_TREE)?.iter() {
+        let (_, value) = entry?;
+        if value.len() > 8 {
+            let id = u64::from_be_bytes(value[..8].try_into().unwrap());
+            logged.push((id, value[8..].to_vec()));
+        }
+    }
+    logged.sort_unstable_by_key(|(id, _)| Reverse(*id));
+
+    let mut seen = HashSet::new();
+    Ok(logged
+        .into_iter()
+        .map(|(_, call)| call)
+        .filter(|call| seen.insert(call.clone()))
+        .take(limit)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_call_log() {
+        let cache = create_test_cache();
+
+        // Nothing gets logged unless we're replaying calls
+        record_call(&cache, b"ignored", 0).unwrap();
+        assert!(recent_calls(&cache, 10).unwrap().is_empty());
+
+        for call in [b"first", b"other", b"first", b"third"] {
+            record_call(&cache, call, 3).unwrap();
+        }
+
+        // The oldest call got overwritten, duplicates only show up once
+        assert_eq!(
+            recent_calls(&cache, 10).unwrap(),
+            vec![b"third".to_vec(), b"first".to_vec(), b"other".to_vec()]
+        );
+        assert_eq!(recent_calls(&cache, 1).unwrap(), vec![b"third".to_vec()]);
+        assert_eq!(cache.open_tree(CALL_LOG_TREE).unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::database::{
+    error::DatabaseError,
+    meta::{
+        get_meta,
+        set_meta,
+    },
+};
+
+use sled::Db;
+
+const CHAIN_ID_KEY: &[u8] = b"chain_id";
+
+// Key of the entry for a call to `method` on `chain_id`, where `hash` is the hash of its params.
+//
+// Keys look like `{chain_id}:{method}:{hash}` so responses from different chains never share
+// an entry. The chain id is a big endian u64, so every entry of a chain sits in one contiguous
+// range, and every entry of a method in one within it.
+pub fn cache_key(chain_id: u64, method: &str, hash: &[u8]) -> Vec<u8> {
+    let mut key = method_key_prefix(chain_id, method);
+    key.extend_from_slice(hash);
+
+    key
+}
+
+// Prefix of the key of every entry for `method` on `chain_id`
+pub fn method_key_prefix(chain_id: u64, method: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(8 + method.len() + 2);
+    prefix.extend_from_slice(&chain_id.to_be_bytes());
+    prefix.push(b':');
+
+    // Method names are picked by clients. Escape `:` so one can't be the prefix of another.
+    for byte in method.bytes() {
+        match byte {
+            b':' => prefix.extend_from_slice(b"%3A"),
+            b'%' => prefix.extend_from_slice(b"%25"),
+            byte => prefix.push(byte),
+        }
+    }
+    prefix.push(b':');
+
+    prefix
+}
+
+// Read the chain id the cache was last used with
+pub fn stored_chain_id(cache: &Db) -> Result<Option<u64>, sled::Error> {
+    Ok(get_meta(cache, CHAIN_ID_KEY)?
+        .and_then(|chain_id| Some(u64::from_be_bytes(chain_id.as_ref().try_into().ok()?))))
+}
+
+// Check the chain id reported by the RPCs against the one stored in the cache,
+// and return the chain id to namespace cache keys with.
+//
+// If the RPCs couldn't be reached we keep using the stored one. If they report
+// another chain we refuse to start, unless `allow_change` is set in which case
+// the cache switches over to the new chain. Entries of the old chain stay around
+// under their own prefix.
+pub fn pin_chain_id(
+    cache: &Db,
+    reported: Option<u64>,
+    allow_change: bool,
+) -> Result<u64, DatabaseError> {
+    let stored = stored_chain_id(cache)?;
+
+    let chain_id = match (stored, reported) {
+        (Some(stored), None) => {
+            println!(
+                "\x1b[93mWrn:\x1b[0m Could not get the chain id from any RPC, using chain id {} from the cache.",
+                stored
+            );
+            return Ok(stored);
+        }
+        (None, None) => return Err(DatabaseError::UnknownChainId),
+        (Some(stored), Some(reported)) if stored == reported => return Ok(stored),
+        (Some(stored), Some(reported)) => {
+            if !allow_change {
+                return Err(DatabaseError::ChainIdMismatch { stored, reported });
+            }
+
+            println!(
+                "\x1b[93mWrn:\x1b[0m RPCs report chain id {} while the cache was used with chain id {}. Switching over.",
+                reported, stored
+            );
+            reported
+        }
+        (None, Some(reported)) => reported,
+    };
+
+    set_meta(cache, CHAIN_ID_KEY, &chain_id.to_be_bytes())?;
+
+    Ok(chain_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let key = cache_key(1, "eth_call", &[0xab; 32]);
+
+        assert_eq!(key.len(), 50);
+        assert_eq!(key[..8], 1u64.to_be_bytes());
+        assert_eq!(&key[8..18], b":eth_call:");
+        assert_ne!(key, cache_key(11155111, "eth_call", &[0xab; 32]));
+        assert_ne!(key, cache_key(1, "eth_getCode", &[0xab; 32]));
+        assert!(key.starts_with(&method_key_prefix(1, "eth_call")));
+    }
+
+    #[test]
+    fn test_method_key_prefix() {
+        assert_eq!(method_key_prefix(1, "a:b")[8..], *b":a%3Ab:");
+        assert_eq!(method_key_prefix(1, "a%3Ab")[8..], *b":a%253Ab:");
+
+        // No method's prefix is the prefix of another's
+        let key = cache_key(1, "eth_getLogs:x", &[0xab; 32]);
+        assert!(!key.starts_with(&method_key_prefix(1, "eth_getLogs")));
+    }
+
+    #[test]
+    fn test_pin_chain_id() {
+        let cache = create_test_cache();
+
+        // Nothing to go off of
+        assert!(matches!(
+            pin_chain_id(&cache, None, false),
+            Err(DatabaseError::UnknownChainId)
+        ));
+
+        // First run pins the chain id
+        assert_eq!(pin_chain_id(&cache, Some(1), false).unwrap(), 1);
+        assert_eq!(stored_chain_id(&cache).unwrap(), Some(1));
+        assert_eq!(pin_chain_id(&cache, Some(1), false).unwrap(), 1);
+
+        // RPCs are unreachable, keep using what we had
+        assert_eq!(pin_chain_id(&cache, None, false).unwrap(), 1);
+
+        // RPCs are on another chain
+        assert!(matches!(
+            pin_chain_id(&cache, Some(11155111), false),
+            Err(DatabaseError::ChainIdMismatch {
+                stored: 1,
+                reported: 11155111
+            })
+        ));
+        assert_eq!(stored_chain_id(&cache).unwrap(), Some(1));
+
+        assert_eq!(pin_chain_id(&cache, Some(11155111), true).unwrap(), 11155111);
+        assert_eq!(stored_chain_id(&cache).unwrap(), Some(11155111));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::database::error::DatabaseError;
+
+use serde_json::Value;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+// Cached responses are stored in sled as plain JSON, which always starts with `{`.
+//
+// Responses that can expire are prefixed with a marker byte, followed by
+// the unix timestamp in milliseconds at which they expire as a big endian u64.
+const EXPIRING_MARKER: u8 = 0x01;
+const EXPIRING_HEADER_LEN: usize = 9;
+
+// Compressed responses are prefixed with a marker byte followed by the zstd frame.
+// If the response can also expire, the expiry header comes first.
+const COMPRESSED_MARKER: u8 = 0x02;
+
+// How cached responses get compressed before being written to sled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    // Only responses larger than `threshold` bytes get compressed
+    Zstd { level: i32, threshold: usize },
+}
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd { .. } => "zstd",
+        }
+    }
+
+    // Compress `value` if it's large enough to be worth it
+    pub fn encode(&self, value: Vec<u8>) -> Vec<u8> {
+        let level = match self {
+            Compression::Zstd { level, threshold } if value.len() > *threshold => *level,
+            _ => return value,
+        };
+
+        match zstd::encode_all(value.as_slice(), level) {
+            Ok(compressed) => {
+                let mut entry = Vec::with_capacity(1 + compressed.len());
+                entry.push(COMPRESSED_MARKER);
+                entry.extend_from_slice(&compressed);
+                entry
+            }
+            // Should never happen, storing it as is still works
+            Err(_) => value,
+        }
+    }
+}
+
+// Current unix timestamp in milliseconds
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Wrap `value` so that it expires at `expires_at`
+pub fn encode_expiring(value: &[u8], expires_at: u64) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(EXPIRING_HEADER_LEN + value.len());
+    entry.push(EXPIRING_MARKER);
+    entry.extend_from_slice(&expires_at.to_be_bytes());
+    entry.extend_from_slice(value);
+
+    entry
+}
+
+// Return true if `entry` has expired by `now`.
+//
+// Truncated headers are treated as expired so they get overwritten.
+pub fn is_expired(entry: &[u8], now: u64) -> bool {
+    if entry.first() != Some(&EXPIRING_MARKER) {
+        return false;
+    }
+
+    match entry.get(1..EXPIRING_HEADER_LEN) {
+        Some(expires_at) => u64::from_be_bytes(expires_at.try_into().unwrap()) <= now,
+        None => true,
+    }
+}
+
+// Strip the header of `entry` if it has one, returning the JSON response
+pub fn payload_mut(entry: &mut [u8]) -> &mut [u8] {
+    if entry.first() == Some(&EXPIRING_MARKER) && entry.len() >= EXPIRING_HEADER_LEN {
+        return &mut entry[EXPIRING_HEADER_LEN..];
+    }
+
+    entry
+}
+
+// Parse the JSON response stored in `entry`, decompressing it if needed.
+//
+// Uncompressed entries are parsed as is, so entries written before
+// compression was turned on still read correctly.
+pub fn parse_payload(entry: &mut [u8]) -> Result<Value, DatabaseError> {
+    let payload = payload_mut(entry);
+
+    let parsed = match payload.split_first() {
+        Some((&COMPRESSED_MARKER, compressed)) => {
+            let mut decompressed = zstd::decode_all(compressed)
+                .map_err(|err| DatabaseError::InvalidEntry(err.to_string()))?;
+            simd_json::serde::from_slice(&mut decompressed)
+        }
+        _ => simd_json::serde::from_slice(payload),
+    };
+
+    parsed.map_err(|err| DatabaseError::InvalidEntry(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiring_entry() {
+        let value = br#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#;
+        let mut entry = encode_expiring(value, 1000);
+
+        assert!(!is_expired(&entry, 999));
+        assert!(is_expired(&entry, 1000));
+        assert!(is_expired(&entry, 1001));
+        assert_eq!(payload_mut(&mut entry), value);
+    }
+
+    #[test]
+    fn test_plain_entry() {
+        let mut value = br#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#.to_vec();
+
+        assert!(!is_expired(&value, u64::MAX));
+        assert_eq!(
+            payload_mut(&mut value),
+            br#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#
+        );
+    }
+
+    #[test]
+    fn test_truncated_entry() {
+        let entry = [EXPIRING_MARKER, 0, 0];
+        assert!(is_expired(&entry, 0));
+    }
+
+    // Full block with transactions, which is mostly repetitive hex
+    fn fixture_block() -> Vec<u8> {
+        let transactions: Vec<_> = (0..200u64)
+            .map(|i| {
+                serde_json::json!({
+                    "blockHash": "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae",
+                    "blockNumber": "0x10d4f",
+                    "from": format!("0x{:040x}", i * 7919),
+                    "gas": "0x5208",
+                    "gasPrice": "0x3b9aca00",
+                    "hash": format!("0x{:064x}", i * 104729),
+                    "input": "0x",
+                    "nonce": format!("0x{:x}", i),
+                    "to": "0x407d73d8a49eeb85d32cf465507dd71d507100c1",
+                    "transactionIndex": format!("0x{:x}", i),
+                    "value": "0xde0b6b3a7640000",
+                    "type": "0x2",
+                })
+            })
+            .collect();
+
+        serde_json::to_vec(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "result": {
+                "number": "0x10d4f",
+                "hash": "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae",
+                "transactions": transactions,
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let compression = Compression::Zstd {
+            level: 3,
+            threshold: 1024,
+        };
+        let block = fixture_block();
+
+        let mut entry = compression.encode(block.clone());
+        assert_eq!(entry[0], COMPRESSED_MARKER);
+        assert_eq!(
+            parse_payload(&mut entry).unwrap(),
+            serde_json::from_slice::<Value>(&block).unwrap()
+        );
+
+        // Compressed entries can expire too
+        let mut entry = encode_expiring(&compression.encode(block.clone()), 1000);
+        assert!(is_expired(&entry, 1000));
+        assert_eq!(
+            parse_payload(&mut entry).unwrap(),
+            serde_json::from_slice::<Value>(&block).unwrap()
+        );
+
+        // The block compresses to a fraction of its size
+        let compressed = compression.encode(block.clone());
+        println!("{} -> {} bytes", block.len(), compressed.len());
+        assert!(compressed.len() * 4 < block.len());
+    }
+
+    #[test]
+    fn test_compression_threshold() {
+        let compression = Compression::Zstd {
+            level: 3,
+            threshold: 1024,
+        };
+
+        // Small responses aren't worth compressing
+        let value = br#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#.to_vec();
+        assert_eq!(compression.encode(value.clone()), value);
+        assert_eq!(Compression::None.encode(fixture_block()), fixture_block());
+    }
+
+    #[test]
+    fn test_parse_legacy_entry() {
+        // Entries written without compression still read correctly
+        let value = br#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#;
+
+        let mut entry = value.to_vec();
+        assert_eq!(parse_payload(&mut entry).unwrap()["result"], "0x1");
+
+        let mut entry = encode_expiring(value, 1000);
+        assert_eq!(parse_payload(&mut entry).unwrap()["result"], "0x1");
+
+        // Garbage errors instead of panicking
+        let mut entry = vec![COMPRESSED_MARKER, 1, 2, 3];
+        assert!(parse_payload(&mut entry).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+// Errors
+use crate::database::hasher::CacheHasher;
+use std::error::Error;
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Sled(sled::Error),
+    Redis(redis::RedisError),
+    UnknownChainId,
+    ChainIdMismatch { stored: u64, reported: u64 },
+    HasherMismatch {
+        stored: CacheHasher,
+        configured: CacheHasher,
+    },
+    InvalidEntry(String),
+    UnsupportedVersion { stored: u64, supported: u64 },
+}
+
+impl std::fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DatabaseError::Sled(err) => write!(f, "Cache error: {}", err),
+            DatabaseError::Redis(err) => write!(f, "Redis error: {}", err),
+            DatabaseError::UnknownChainId => {
+                write!(
+                    f,
+                    "Could not get the chain id from any RPC and the cache has none stored"
+                )
+            }
+            DatabaseError::ChainIdMismatch { stored, reported } => {
+                write!(
+                    f,
+                    "The cache belongs to chain id {} but the RPCs report chain id {}. \
+                    Clear the cache, use another db_path or set cache.allow_chain_id_change",
+                    stored, reported
+                )
+            }
+            DatabaseError::HasherMismatch { stored, configured } => {
+                write!(
+                    f,
+                    "The cache was written with {} but cache.hash is set to {}. \
+                    Clear the cache, use another db_path or set cache.flush_on_hash_mismatch",
+                    stored, configured
+                )
+            }
+            DatabaseError::InvalidEntry(reason) => write!(f, "Invalid cache entry: {}", reason),
+            DatabaseError::UnsupportedVersion { stored, supported } => {
+                write!(
+                    f,
+                    "The cache was written by a newer version of blutgang (format version {}, \
+                    this version supports up to {}). Start with --clear or use another db_path",
+                    stored, supported
+                )
+            }
+        }
+    }
+}
+
+impl Error for DatabaseError {}
+
+impl From<sled::Error> for DatabaseError {
+    fn from(error: sled::Error) -> Self {
+        DatabaseError::Sled(error)
+    }
+}
+
+impl From<redis::RedisError> for DatabaseError {
+    fn from(error: redis::RedisError) -> Self {
+        DatabaseError::Redis(error)
+    }
+}
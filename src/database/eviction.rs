@@ -0,0 +1,479 @@
+use crate::database::{
+    backend::CacheBackend,
+    error::DatabaseError,
+};
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use sled::{
+    Batch,
+    Db,
+};
+
+// Tree logging writes to the cache in the order they happened.
+//
+// Keys are an id generated by sled as a big endian u64 followed by the key of
+// the cache entry, so iterating the tree yields the oldest writes first.
+// Values are the size of the write as a big endian u64, followed by the id
+// of the write it replaced if there was one.
+pub const WRITE_TREE: &[u8] = b"writes";
+
+// Tree mapping the key of each cache entry to the id of its latest write
+pub const WRITE_ID_TREE: &[u8] = b"write_ids";
+
+// Tree the evictor saves its stats to so the admin namespace can read them.
+// Also holds the entry and expiration counters kept by the backend and `prune_expired`.
+pub const STATS_TREE: &[u8] = b"stats";
+const LIVE_BYTES_KEY: &[u8] = b"live_bytes";
+const EVICTIONS_KEY: &[u8] = b"evictions";
+const ENTRIES_KEY: &[u8] = b"entries";
+const EXPIRATIONS_KEY: &[u8] = b"expirations";
+
+// How many writes to go over at once before yielding back to the runtime
+const EVICTION_BATCH_SIZE: usize = 1024;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub live_bytes: u64,
+    pub evictions: u64,
+}
+
+// Counters that are kept up to date on every write, unlike `CacheStats`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EntryStats {
+    // Approximate number of entries in the cache, since `sled::Tree::len` has to iterate it
+    pub entries: u64,
+    // Entries removed by `prune_expired`
+    pub expirations: u64,
+}
+
+fn write_key(id: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut write_key = Vec::with_capacity(8 + key.len());
+    write_key.extend_from_slice(id);
+    write_key.extend_from_slice(key);
+
+    write_key
+}
+
+fn write_size(write: &[u8]) -> u64 {
+    write
+        .get(..8)
+        .map(|size| u64::from_be_bytes(size.try_into().unwrap()))
+        .unwrap_or_default()
+}
+
+// Log that `value_len` bytes were written to `key` so the entry can be evicted later
+pub fn record_write(cache: &Db, key: &[u8], value_len: usize) -> Result<(), sled::Error> {
+    let id = cache.generate_id()?.to_be_bytes();
+    let replaced = cache.open_tree(WRITE_ID_TREE)?.insert(key, &id)?;
+
+    let mut write = Vec::with_capacity(16);
+    write.extend_from_slice(&((key.len() + value_len) as u64).to_be_bytes());
+    if let Some(replaced) = replaced {
+        write.extend_from_slice(&replaced);
+    }
+
+    cache
+        .open_tree(WRITE_TREE)?
+        .insert(write_key(&id, key), write)?;
+
+    Ok(())
+}
+
+// Read the stats last saved by the evictor
+pub fn cache_stats(cache: &Db) -> Result<CacheStats, sled::Error> {
+    let stats = cache.open_tree(STATS_TREE)?;
+
+    Ok(CacheStats {
+        live_bytes: stats
+            .get(LIVE_BYTES_KEY)?
+            .map(|live_bytes| write_size(&live_bytes))
+            .unwrap_or_default(),
+        evictions: stats
+            .get(EVICTIONS_KEY)?
+            .map(|evictions| write_size(&evictions))
+            .unwrap_or_default(),
+    })
+}
+
+fn update_stat(cache: &Db, key: &[u8], added: u64, removed: u64) -> Result<(), sled::Error> {
+    if added == removed {
+        return Ok(());
+    }
+
+    cache.open_tree(STATS_TREE)?.update_and_fetch(key, |stat| {
+        let stat = stat.map(write_size).unwrap_or_default();
+        Some(
+            stat.saturating_add(added)
+                .saturating_sub(removed)
+                .to_be_bytes()
+                .to_vec(),
+        )
+    })?;
+
+    Ok(())
+}
+
+// Add `added` new entries and subtract `removed` ones from the entry count
+pub fn count_entries(cache: &Db, added: usize, removed: usize) -> Result<(), sled::Error> {
+    update_stat(cache, ENTRIES_KEY, added as u64, removed as u64)
+}
+
+// Count entries removed because they expired
+pub fn count_expirations(cache: &Db, expired: usize) -> Result<(), sled::Error> {
+    update_stat(cache, EXPIRATIONS_KEY, expired as u64, 0)
+}
+
+// Start counting entries from scratch after the cache got cleared
+pub fn reset_entry_count(cache: &Db) -> Result<(), sled::Error> {
+    cache.open_tree(STATS_TREE)?.remove(ENTRIES_KEY)?;
+
+    Ok(())
+}
+
+// Read the entry counters without going over the cache
+pub fn entry_stats(cache: &Db) -> Result<EntryStats, sled::Error> {
+    let stats = cache.open_tree(STATS_TREE)?;
+
+    Ok(EntryStats {
+        entries: stats
+            .get(ENTRIES_KEY)?
+            .map(|entries| write_size(&entries))
+            .unwrap_or_default(),
+        expirations: stats
+            .get(EXPIRATIONS_KEY)?
+            .map(|expirations| write_size(&expirations))
+            .unwrap_or_default(),
+    })
+}
+
+// Keeps an approximate count of the bytes logged by `record_write` that are
+// still in the cache, and evicts the oldest writes once it goes over the limit.
+//
+// Entries removed by something else, like expiry or reorgs, are only
+// subtracted from the count once the evictor gets to them.
+#[derive(Debug)]
+pub struct Evictor {
+    max_size: u64,
+    stats: CacheStats,
+    // Id following the last write we counted
+    next_id: u64,
+}
+
+impl Evictor {
+    pub fn new(cache: &Db, max_size: u64) -> Result<Self, sled::Error> {
+        // Live bytes get counted again from scratch, only evictions carry over
+        let evictions = cache_stats(cache)?.evictions;
+
+        Ok(Self {
+            max_size,
+            stats: CacheStats {
+                live_bytes: 0,
+                evictions,
+            },
+            next_id: 0,
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn over_limit(&self) -> bool {
+        self.stats.live_bytes > self.max_size
+    }
+
+    // Count every write that happened since the last call.
+    //
+    // Writes that got replaced by a newer one stop counting and are removed from the log.
+    pub fn count_writes(&mut self, cache: &Db) -> Result<(), sled::Error> {
+        let writes = cache.open_tree(WRITE_TREE)?;
+
+        let mut write_batch = Batch::default();
+        for entry in writes.range(self.next_id.to_be_bytes()..) {
+            let (key, write) = entry?;
+
+            // Should never happen but skip over garbage
+            if key.len() < 8 {
+                write_batch.remove(key);
+                continue;
+            }
+
+            self.stats.live_bytes += write_size(&write);
+
+            if let Some(replaced) = write.get(8..16) {
+                let replaced_key = write_key(replaced, &key[8..]);
+                if let Some(replaced_write) = writes.get(&replaced_key)? {
+                    self.stats.live_bytes = self
+                        .stats
+                        .live_bytes
+                        .saturating_sub(write_size(&replaced_write));
+                    write_batch.remove(replaced_key);
+                }
+            }
+
+            self.next_id = u64::from_be_bytes(key[..8].try_into().unwrap()) + 1;
+        }
+
+        writes.apply_batch(write_batch)?;
+
+        Ok(())
+    }
+
+    // Evict up to `limit` of the oldest entries while we're over the limit,
+    // returning how many were evicted.
+    pub async fn evict(
+        &mut self,
+        cache: &Db,
+        backend: &dyn CacheBackend,
+        limit: usize,
+    ) -> Result<usize, DatabaseError> {
+        let writes = cache.open_tree(WRITE_TREE)?;
+        let write_ids = cache.open_tree(WRITE_ID_TREE)?;
+
+        let mut keys = Vec::new();
+        let mut write_batch = Batch::default();
+        let mut write_id_batch = Batch::default();
+
+        for entry in writes.range(..self.next_id.to_be_bytes()).take(limit) {
+            if !self.over_limit() {
+                break;
+            }
+
+            let (key, write) = entry?;
+            let (id, cache_key) = key.split_at(8.min(key.len()));
+
+            // Only remove the entry if this is still its latest write. If it
+            // was removed by something else we just stop counting it.
+            if write_ids.get(cache_key)?.as_deref() == Some(id) {
+                keys.push(cache_key.to_vec());
+                write_id_batch.remove(cache_key);
+            }
+
+            write_batch.remove(key.as_ref());
+            self.stats.live_bytes = self.stats.live_bytes.saturating_sub(write_size(&write));
+        }
+
+        let evicted = backend.remove(keys).await?;
+        write_ids.apply_batch(write_id_batch)?;
+        writes.apply_batch(write_batch)?;
+
+        self.stats.evictions += evicted as u64;
+
+        Ok(evicted)
+    }
+
+    fn save_stats(&self, cache: &Db) -> Result<(), sled::Error> {
+        let stats = cache.open_tree(STATS_TREE)?;
+        stats.insert(LIVE_BYTES_KEY, &self.stats.live_bytes.to_be_bytes())?;
+        stats.insert(EVICTIONS_KEY, &self.stats.evictions.to_be_bytes())?;
+
+        Ok(())
+    }
+}
+
+// Periodically evict the oldest entries so the cache stays under `max_size` bytes.
+//
+// A `max_size` of 0 means the cache can grow without bound.
+pub async fn evict_loop(
+    cache: Arc<Db>,
+    backend: Arc<dyn CacheBackend>,
+    max_size: u64,
+    interval: Duration,
+) -> Result<(), DatabaseError> {
+    if max_size == 0 || interval.is_zero() {
+        return Ok(());
+    }
+
+    let mut evictor = Evictor::new(&cache, max_size)?;
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        evictor.count_writes(&cache)?;
+
+        // Evict in batches so we don't hog the runtime if we're way over the limit
+        let mut evicted = 0;
+        while evictor.over_limit() {
+            let live_bytes = evictor.stats().live_bytes;
+            evicted += evictor
+                .evict(&cache, backend.as_ref(), EVICTION_BATCH_SIZE)
+                .await?;
+
+            // Nothing left to evict
+            if evictor.stats().live_bytes == live_bytes {
+                break;
+            }
+
+            tokio::task::yield_now().await;
+        }
+
+        evictor.save_stats(&cache)?;
+
+        if evicted > 0 {
+            println!(
+                "\x1b[35mInfo:\x1b[0m Evicted {} entries from the cache to stay under {} bytes.",
+                evicted, max_size
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::sled_backend::SledBackend;
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn insert(cache: &Db, key: &[u8], value: &[u8]) {
+        cache.insert(key, value).unwrap();
+        record_write(cache, key, value.len()).unwrap();
+    }
+
+    async fn evict_all(evictor: &mut Evictor, cache: &Db) {
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        evictor.count_writes(cache).unwrap();
+        while evictor.over_limit() {
+            evictor
+                .evict(cache, &backend, EVICTION_BATCH_SIZE)
+                .await
+                .unwrap();
+        }
+    }
+
+    // Size of every entry in the cache itself, without the other trees
+    fn actual_size(cache: &Db) -> u64 {
+        cache
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                (key.len() + value.len()) as u64
+            })
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn test_count_writes() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+        let mut evictor = Evictor::new(&cache, u64::MAX).unwrap();
+
+        insert(&cache, b"key1", &[0; 96]);
+        insert(&cache, b"key2", &[0; 96]);
+        evictor.count_writes(&cache).unwrap();
+        assert_eq!(evictor.stats().live_bytes, 200);
+
+        // Overwriting an entry only counts its latest write
+        insert(&cache, b"key1", &[0; 46]);
+        evictor.count_writes(&cache).unwrap();
+        assert_eq!(evictor.stats().live_bytes, 150);
+        assert_eq!(cache.open_tree(WRITE_TREE).unwrap().len(), 2);
+
+        // Nothing gets evicted while we're under the limit
+        assert_eq!(evictor.evict(&cache, &backend, usize::MAX).await.unwrap(), 0);
+
+        // Counting starts over on restart
+        let mut evictor = Evictor::new(&cache, u64::MAX).unwrap();
+        evictor.count_writes(&cache).unwrap();
+        assert_eq!(evictor.stats().live_bytes, 150);
+    }
+
+    #[tokio::test]
+    async fn test_evict_oldest() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+        let mut evictor = Evictor::new(&cache, 200).unwrap();
+
+        for key in [b"key1", b"key2", b"key3", b"key4"] {
+            insert(&cache, key, &[0; 96]);
+        }
+
+        // key1 got refreshed so it's now the newest
+        insert(&cache, b"key1", &[0; 96]);
+
+        // Removed by something else, eg. a reorg
+        cache.remove(b"key2").unwrap();
+
+        evictor.count_writes(&cache).unwrap();
+        assert_eq!(evictor.stats().live_bytes, 400);
+
+        assert_eq!(evictor.evict(&cache, &backend, usize::MAX).await.unwrap(), 1);
+        assert!(cache.get(b"key3").unwrap().is_none());
+        assert!(cache.get(b"key4").unwrap().is_some());
+        assert!(cache.get(b"key1").unwrap().is_some());
+        assert_eq!(evictor.stats().live_bytes, 200);
+        assert_eq!(evictor.stats().evictions, 1);
+
+        // Evictions persist across restarts
+        evictor.save_stats(&cache).unwrap();
+        assert_eq!(Evictor::new(&cache, 200).unwrap().stats().evictions, 1);
+        assert_eq!(
+            cache_stats(&cache).unwrap(),
+            CacheStats {
+                live_bytes: 200,
+                evictions: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cap_sustained_writes() {
+        let cache = create_test_cache();
+        let max_size = 100_000;
+        let mut evictor = Evictor::new(&cache, max_size).unwrap();
+
+        // Write 10x the cap, with the evictor catching up every 64 writes.
+        // Some keys are written more than once.
+        for i in 0u32..1000 {
+            let key = (i % 900).to_be_bytes();
+            insert(&cache, &key, &[0; 1020]);
+
+            if i % 64 == 0 {
+                evict_all(&mut evictor, &cache).await;
+            }
+
+            // We can go over by at most what was written since the last run
+            assert!(actual_size(&cache) <= max_size + 64 * 1024);
+        }
+
+        evict_all(&mut evictor, &cache).await;
+
+        assert!(actual_size(&cache) <= max_size);
+        assert!(actual_size(&cache) > max_size - 1024);
+        assert_eq!(evictor.stats().live_bytes, actual_size(&cache));
+    }
+
+    #[test]
+    fn test_entry_stats() {
+        let cache = create_test_cache();
+        assert_eq!(entry_stats(&cache).unwrap(), EntryStats::default());
+
+        count_entries(&cache, 5, 0).unwrap();
+        count_entries(&cache, 1, 2).unwrap();
+        count_expirations(&cache, 3).unwrap();
+        assert_eq!(
+            entry_stats(&cache).unwrap(),
+            EntryStats {
+                entries: 4,
+                expirations: 3,
+            }
+        );
+
+        // The count never goes below 0 if we miss an insert
+        count_entries(&cache, 0, 10).unwrap();
+        assert_eq!(entry_stats(&cache).unwrap().entries, 0);
+
+        count_entries(&cache, 2, 0).unwrap();
+        reset_entry_count(&cache).unwrap();
+        assert_eq!(entry_stats(&cache).unwrap().entries, 0);
+        assert_eq!(entry_stats(&cache).unwrap().expirations, 3);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::database::{
+    backend::CacheBackend,
+    entry::{
+        is_expired,
+        unix_millis,
+    },
+    error::DatabaseError,
+    eviction::count_expirations,
+};
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use sled::{
+    Batch,
+    Db,
+};
+
+// Tree indexing expiring entries by when they expire.
+//
+// Keys are the expiry timestamp as a big endian u64 followed by the key
+// of the cache entry, so iterating the tree yields the oldest entries first.
+pub const EXPIRY_TREE: &[u8] = b"expiry";
+
+pub fn expiry_index_key(expires_at: u64, key: &[u8]) -> Vec<u8> {
+    let mut index_key = Vec::with_capacity(8 + key.len());
+    index_key.extend_from_slice(&expires_at.to_be_bytes());
+    index_key.extend_from_slice(key);
+
+    index_key
+}
+
+// Remove every entry that expired by `now`, returning how many were removed.
+//
+// Entries that were refreshed after being indexed are left alone.
+pub async fn prune_expired(
+    cache: &Db,
+    backend: &dyn CacheBackend,
+    now: u64,
+) -> Result<usize, DatabaseError> {
+    let expiry = cache.open_tree(EXPIRY_TREE)?;
+
+    let mut keys = Vec::new();
+    let mut index_batch = Batch::default();
+
+    for index_key in expiry.iter().keys() {
+        let index_key = index_key?;
+
+        // Should never happen but skip over garbage
+        if index_key.len() < 8 {
+            index_batch.remove(index_key);
+            continue;
+        }
+
+        let expires_at = u64::from_be_bytes(index_key[..8].try_into().unwrap());
+        if expires_at > now {
+            break;
+        }
+
+        let key = &index_key[8..];
+        if let Some(entry) = backend.get(key).await? {
+            if is_expired(&entry, now) {
+                keys.push(key.to_vec());
+            }
+        }
+
+        index_batch.remove(index_key);
+    }
+
+    let pruned = backend.remove(keys).await?;
+    expiry.apply_batch(index_batch)?;
+    count_expirations(cache, pruned)?;
+
+    Ok(pruned)
+}
+
+// Periodically prune expired entries so the DB doesn't grow without bound.
+//
+// An interval of 0 disables pruning, expired entries still get overwritten when requested.
+pub async fn prune_expired_loop(
+    cache: Arc<Db>,
+    backend: Arc<dyn CacheBackend>,
+    interval: Duration,
+) -> Result<(), DatabaseError> {
+    if interval.is_zero() {
+        return Ok(());
+    }
+
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        let pruned = prune_expired(&cache, backend.as_ref(), unix_millis()).await?;
+        if pruned > 0 {
+            println!(
+                "\x1b[35mInfo:\x1b[0m Pruned {} expired entries from the cache.",
+                pruned
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        batch::CacheBatch,
+        eviction::entry_stats,
+        sled_backend::SledBackend,
+    };
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    async fn insert_expiring(
+        cache: &Db,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> Result<(), DatabaseError> {
+        let mut batch = CacheBatch::default();
+        batch.insert_expiring(key, value, expires_at);
+        batch
+            .apply(cache, &SledBackend::new(Arc::new(cache.clone())))
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_prune_expired() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        insert_expiring(&cache, b"expired", b"{}", 100).await.unwrap();
+        insert_expiring(&cache, b"live", b"{}", 300).await.unwrap();
+        cache.insert(b"forever", b"{}").unwrap();
+
+        assert_eq!(prune_expired(&cache, &backend, 200).await.unwrap(), 1);
+        assert!(cache.get(b"expired").unwrap().is_none());
+        assert!(cache.get(b"live").unwrap().is_some());
+        assert!(cache.get(b"forever").unwrap().is_some());
+
+        // Pruned entries are removed from the index as well
+        assert_eq!(cache.open_tree(EXPIRY_TREE).unwrap().len(), 1);
+
+        assert_eq!(prune_expired(&cache, &backend, 300).await.unwrap(), 1);
+        assert!(cache.get(b"live").unwrap().is_none());
+        assert!(cache.get(b"forever").unwrap().is_some());
+        assert!(cache.open_tree(EXPIRY_TREE).unwrap().is_empty());
+        assert_eq!(entry_stats(&cache).unwrap().expirations, 2);
+    }
+
+    #[tokio::test]
+    async fn test_prune_refreshed() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        // The entry got refreshed after expiring, so only its old index entry should go
+        insert_expiring(&cache, b"key", b"{}", 100).await.unwrap();
+        insert_expiring(&cache, b"key", b"{}", 300).await.unwrap();
+
+        assert_eq!(prune_expired(&cache, &backend, 200).await.unwrap(), 0);
+        assert!(cache.get(b"key").unwrap().is_some());
+
+        // Overwritten with a value that never expires
+        cache.insert(b"key", b"{}").unwrap();
+        assert_eq!(prune_expired(&cache, &backend, 400).await.unwrap(), 0);
+        assert!(cache.get(b"key").unwrap().is_some());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::database::{
+    backend::CacheBackend,
+    block_index::BLOCK_TREE,
+    error::DatabaseError,
+    expiry::EXPIRY_TREE,
+    method_index::{
+        method_prefix,
+        METHOD_TREE,
+    },
+};
+
+use sled::{
+    Batch,
+    Db,
+    IVec,
+    Tree,
+};
+
+// How many entries to remove at once before yielding back to the runtime,
+// so flushing a large cache doesn't stall requests.
+const FLUSH_BATCH_SIZE: usize = 1024;
+
+// Remove every index key `next` yields from `index`, along with the cache entry
+// it points to, returning how many entries were removed.
+//
+// Index keys are `prefix_len` bytes followed by the key of the entry. `next` gets
+// called until it's empty, so it needs to only yield keys that are still in the index.
+async fn flush_index<F>(
+    backend: &dyn CacheBackend,
+    index: &Tree,
+    prefix_len: usize,
+    next: F,
+) -> Result<usize, DatabaseError>
+where
+    F: Fn() -> Result<Vec<IVec>, sled::Error>,
+{
+    let mut flushed = 0;
+
+    loop {
+        let index_keys = next()?;
+        if index_keys.is_empty() {
+            return Ok(flushed);
+        }
+
+        let mut keys = Vec::with_capacity(index_keys.len());
+        let mut index_batch = Batch::default();
+        for index_key in index_keys {
+            keys.push(index_key.get(prefix_len..).unwrap_or_default().to_vec());
+            index_batch.remove(index_key);
+        }
+
+        flushed += backend.remove(keys).await?;
+        index.apply_batch(index_batch)?;
+
+        tokio::task::yield_now().await;
+    }
+}
+
+// Remove every cached response to `method`
+pub async fn flush_method(
+    cache: &Db,
+    backend: &dyn CacheBackend,
+    method: &str,
+) -> Result<usize, DatabaseError> {
+    let index = cache.open_tree(METHOD_TREE)?;
+    let prefix = method_prefix(method);
+
+    flush_index(backend, &index, prefix.len(), || {
+        index
+            .scan_prefix(&prefix)
+            .keys()
+            .take(FLUSH_BATCH_SIZE)
+            .collect()
+    })
+    .await
+}
+
+// Remove every cached response that depends on a block in `from..=to`.
+//
+// Only blocks that aren't finalized yet are indexed, see `block_index`.
+pub async fn flush_blocks(
+    cache: &Db,
+    backend: &dyn CacheBackend,
+    from: u64,
+    to: u64,
+) -> Result<usize, DatabaseError> {
+    let index = cache.open_tree(BLOCK_TREE)?;
+
+    let end = to.checked_add(1).map(u64::to_be_bytes);
+
+    flush_index(backend, &index, 8, || {
+        let keys = match end {
+            Some(end) => index.range(from.to_be_bytes()..end),
+            None => index.range(from.to_be_bytes()..),
+        };
+
+        keys.keys().take(FLUSH_BATCH_SIZE).collect()
+    })
+    .await
+}
+
+// Remove every cached response, along with the indexes pointing to them.
+//
+// The write log used for eviction is left alone, it skips over removed entries.
+pub async fn flush_all(cache: &Db, backend: &dyn CacheBackend) -> Result<usize, DatabaseError> {
+    let mut flushed = 0;
+
+    let keys = backend.scan_prefix(&[]).await?;
+    for keys in keys.chunks(FLUSH_BATCH_SIZE) {
+        flushed += backend
+            .remove(keys.iter().map(|key| key.to_vec()).collect())
+            .await?;
+
+        tokio::task::yield_now().await;
+    }
+
+    for tree in [BLOCK_TREE, EXPIRY_TREE, METHOD_TREE] {
+        cache.open_tree(tree)?.clear()?;
+    }
+
+    Ok(flushed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        block_index::index_block,
+        method_index::index_method,
+        sled_backend::SledBackend,
+    };
+    use std::sync::Arc;
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn insert(cache: &Db, method: &str, block: u64, key: &[u8]) {
+        cache.insert(key, b"{}").unwrap();
+        index_method(cache, method, key).unwrap();
+        index_block(cache, block, key).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_method() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        for i in 0..3000u32 {
+            insert(&cache, "eth_getLogs", 1, &i.to_be_bytes());
+        }
+        insert(&cache, "eth_call", 1, b"call");
+        insert(&cache, "eth_getLogsX", 1, b"other");
+
+        assert_eq!(flush_method(&cache, &backend, "eth_getLogs").await.unwrap(), 3000);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(b"call").unwrap().is_some());
+        assert!(cache.get(b"other").unwrap().is_some());
+
+        // Entries that were already removed by something else don't count
+        cache.remove(b"call").unwrap();
+        assert_eq!(flush_method(&cache, &backend, "eth_call").await.unwrap(), 0);
+        assert_eq!(cache.open_tree(METHOD_TREE).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_flush_blocks() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        for block in 0..10u64 {
+            insert(&cache, "eth_getBlockByNumber", block, &block.to_be_bytes());
+        }
+        insert(&cache, "eth_call", u64::MAX, b"max");
+
+        assert_eq!(flush_blocks(&cache, &backend, 3, 5).await.unwrap(), 3);
+        assert_eq!(cache.len(), 8);
+        assert!(cache.get(2u64.to_be_bytes()).unwrap().is_some());
+        assert!(cache.get(3u64.to_be_bytes()).unwrap().is_none());
+        assert!(cache.get(5u64.to_be_bytes()).unwrap().is_none());
+        assert!(cache.get(6u64.to_be_bytes()).unwrap().is_some());
+
+        assert_eq!(flush_blocks(&cache, &backend, 9, u64::MAX).await.unwrap(), 2);
+        assert!(cache.get(b"max").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_flush_all() {
+        let cache = create_test_cache();
+        let backend = SledBackend::new(Arc::new(cache.clone()));
+
+        for i in 0..3000u32 {
+            insert(&cache, "eth_call", 1, &i.to_be_bytes());
+        }
+
+        assert_eq!(flush_all(&cache, &backend).await.unwrap(), 3000);
+        assert!(cache.is_empty());
+        assert!(cache.open_tree(METHOD_TREE).unwrap().is_empty());
+        assert!(cache.open_tree(BLOCK_TREE).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+use crate::database::{
+    error::DatabaseError,
+    eviction::reset_entry_count,
+    meta::{
+        get_meta,
+        set_meta,
+    },
+};
+
+use sled::Db;
+use xxhash_rust::xxh3::xxh3_64;
+
+const HASHER_KEY: &[u8] = b"hasher";
+
+// Hash function used to turn requests into cache keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheHasher {
+    Blake3,
+    // 4x faster caching but potentially less secure
+    Xxh3,
+}
+
+// The `xxhash` feature only changes the default, it can still be set in the config
+impl Default for CacheHasher {
+    fn default() -> Self {
+        if cfg!(feature = "xxhash") {
+            CacheHasher::Xxh3
+        } else {
+            CacheHasher::Blake3
+        }
+    }
+}
+
+impl std::fmt::Display for CacheHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+pub enum CacheHash {
+    Blake3(blake3::Hash),
+    Xxh3([u8; 8]),
+}
+
+impl CacheHash {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            CacheHash::Blake3(hash) => hash.as_bytes(),
+            CacheHash::Xxh3(hash) => hash,
+        }
+    }
+}
+
+impl CacheHasher {
+    pub fn hash(&self, data: &[u8]) -> CacheHash {
+        match self {
+            CacheHasher::Blake3 => CacheHash::Blake3(blake3::hash(data)),
+            CacheHasher::Xxh3 => CacheHash::Xxh3(xxh3_64(data).to_be_bytes()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheHasher::Blake3 => "blake3",
+            CacheHasher::Xxh3 => "xxh3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "blake3" => Some(CacheHasher::Blake3),
+            "xxh3" | "xxhash" => Some(CacheHasher::Xxh3),
+            _ => None,
+        }
+    }
+}
+
+// Read the hash function the cache was last used with.
+//
+// Older versions marked it with a `blake3` or `xxhash` key in the default tree.
+pub fn stored_hasher(cache: &Db) -> Result<Option<CacheHasher>, sled::Error> {
+    if let Some(name) = get_meta(cache, HASHER_KEY)? {
+        return Ok(std::str::from_utf8(&name)
+            .ok()
+            .and_then(CacheHasher::from_name));
+    }
+
+    if cache.contains_key(b"xxhash")? {
+        return Ok(Some(CacheHasher::Xxh3));
+    }
+    if cache.contains_key(b"blake3")? {
+        return Ok(Some(CacheHasher::Blake3));
+    }
+
+    Ok(None)
+}
+
+// Make sure the cache was written with `hasher`, since keys from another
+// hash function would never get a hit.
+//
+// On a mismatch we refuse to start, unless `flush_on_mismatch` is set in
+// which case every cached response gets removed.
+pub fn pin_hasher(
+    cache: &Db,
+    hasher: CacheHasher,
+    flush_on_mismatch: bool,
+) -> Result<(), DatabaseError> {
+    match stored_hasher(cache)? {
+        Some(stored) if stored != hasher => {
+            if !flush_on_mismatch {
+                return Err(DatabaseError::HasherMismatch {
+                    stored,
+                    configured: hasher,
+                });
+            }
+
+            cache.clear()?;
+            reset_entry_count(cache)?;
+            println!(
+                "\x1b[93mWrn:\x1b[0m The cache was written with {} while we're using {}. All data cleared from the database.",
+                stored, hasher
+            );
+        }
+        _ => {}
+    }
+
+    cache.remove(b"xxhash")?;
+    cache.remove(b"blake3")?;
+    set_meta(cache, HASHER_KEY, hasher.name().as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(CacheHasher::Blake3.hash(b"tx").as_bytes().len(), 32);
+        assert_eq!(CacheHasher::Xxh3.hash(b"tx").as_bytes().len(), 8);
+        assert_eq!(
+            CacheHasher::Xxh3.hash(b"tx").as_bytes(),
+            CacheHasher::Xxh3.hash(b"tx").as_bytes()
+        );
+        assert_ne!(
+            CacheHasher::Xxh3.hash(b"tx").as_bytes(),
+            CacheHasher::Xxh3.hash(b"ty").as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_pin_hasher() {
+        let cache = create_test_cache();
+
+        pin_hasher(&cache, CacheHasher::Blake3, false).unwrap();
+        assert_eq!(stored_hasher(&cache).unwrap(), Some(CacheHasher::Blake3));
+        pin_hasher(&cache, CacheHasher::Blake3, false).unwrap();
+
+        // Starting with another hash function against the same DB is an error
+        cache.insert(b"entry", b"{}").unwrap();
+        assert!(matches!(
+            pin_hasher(&cache, CacheHasher::Xxh3, false),
+            Err(DatabaseError::HasherMismatch {
+                stored: CacheHasher::Blake3,
+                configured: CacheHasher::Xxh3,
+            })
+        ));
+        assert!(cache.get(b"entry").unwrap().is_some());
+
+        // Unless the operator would rather flush it
+        pin_hasher(&cache, CacheHasher::Xxh3, true).unwrap();
+        assert!(cache.get(b"entry").unwrap().is_none());
+        assert_eq!(stored_hasher(&cache).unwrap(), Some(CacheHasher::Xxh3));
+    }
+
+    #[test]
+    fn test_pin_hasher_legacy_marker() {
+        let cache = create_test_cache();
+
+        // DB from before the hash function was stored in the meta tree
+        cache.insert(b"xxhash", b"true").unwrap();
+        assert!(pin_hasher(&cache, CacheHasher::Blake3, false).is_err());
+
+        pin_hasher(&cache, CacheHasher::Xxh3, false).unwrap();
+        assert!(cache.get(b"xxhash").unwrap().is_none());
+        assert_eq!(stored_hasher(&cache).unwrap(), Some(CacheHasher::Xxh3));
+    }
+}
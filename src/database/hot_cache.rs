@@ -0,0 +1,320 @@
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
+use sled::{
+    Db,
+    Event,
+    IVec,
+};
+
+// Number of independently locked shards so requests for different keys
+// don't wait on each other. Keys end with a hash so their last byte is random enough.
+const SHARD_COUNT: usize = 16;
+
+#[derive(Debug, Default)]
+struct Shard {
+    // Key -> (entry, tick it was last used at)
+    entries: HashMap<IVec, (IVec, u64)>,
+    // Tick -> key, oldest first
+    recency: BTreeMap<u64, IVec>,
+    tick: u64,
+    // Bumped every time sled changes a key, see `HotCache::insert_if_unchanged`
+    generation: u64,
+}
+
+impl Shard {
+    fn touch(&mut self, key: &[u8]) -> Option<IVec> {
+        self.tick += 1;
+
+        let (entry, last_used) = self.entries.get_mut(key)?;
+        let key = self.recency.remove(last_used).unwrap();
+        *last_used = self.tick;
+        self.recency.insert(self.tick, key);
+
+        Some(entry.clone())
+    }
+
+    fn insert(&mut self, key: IVec, entry: IVec, capacity: usize) {
+        self.tick += 1;
+
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (entry, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, key);
+
+        // Drop the least recently used entries
+        while self.entries.len() > capacity {
+            let (_, key) = self.recency.pop_first().unwrap();
+            self.entries.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((_, last_used)) = self.entries.remove(key) {
+            self.recency.remove(&last_used);
+        }
+        self.generation += 1;
+    }
+}
+
+// Bounded in-memory LRU of the hottest sled entries, consulted before sled.
+//
+// Entries are stored exactly as they are in sled, so expiring entries
+// still need to be checked with `is_expired`.
+#[derive(Debug)]
+pub struct HotCache {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    // Larger entries are only kept in sled
+    max_entry_bytes: usize,
+}
+
+impl HotCache {
+    // Create a hot cache holding about `capacity` entries. A capacity of 0 disables it.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shards: (0..SHARD_COUNT)
+                .map(|_| Mutex::new(Shard::default()))
+                .collect(),
+            shard_capacity: (capacity + SHARD_COUNT - 1) / SHARD_COUNT,
+            max_entry_bytes: 0,
+        }
+    }
+
+    // Don't keep entries larger than `max_entry_bytes` in memory, 0 means there is no limit
+    pub fn with_max_entry_bytes(mut self, max_entry_bytes: usize) -> Self {
+        self.max_entry_bytes = max_entry_bytes;
+        self
+    }
+
+    fn too_large(&self, entry: &[u8]) -> bool {
+        self.max_entry_bytes != 0 && entry.len() > self.max_entry_bytes
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        let index = key.last().copied().unwrap_or_default() as usize % SHARD_COUNT;
+        &self.shards[index]
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<IVec> {
+        if self.shard_capacity == 0 {
+            return None;
+        }
+
+        self.shard(key).lock().unwrap().touch(key)
+    }
+
+    // Returns a token to pass to `insert_if_unchanged` when populating from sled
+    pub fn generation(&self, key: &[u8]) -> u64 {
+        self.shard(key).lock().unwrap().generation
+    }
+
+    // Insert an entry that was just written to sled
+    pub fn insert(&self, key: &[u8], entry: IVec) {
+        if self.shard_capacity == 0 {
+            return;
+        }
+
+        // Whatever we had for the key is outdated either way
+        let mut shard = self.shard(key).lock().unwrap();
+        if self.too_large(&entry) {
+            shard.remove(key);
+            return;
+        }
+        shard.insert(key.into(), entry, self.shard_capacity);
+        shard.generation += 1;
+    }
+
+    // Insert an entry we read from sled, unless sled changed anything in
+    // the shard since `generation`.
+    //
+    // Changes reach the hot cache after they happen in sled, so the entry
+    // we read might have been overwritten or removed since.
+    pub fn insert_if_unchanged(&self, key: &[u8], entry: IVec, generation: u64) {
+        if self.shard_capacity == 0 || self.too_large(&entry) {
+            return;
+        }
+
+        let mut shard = self.shard(key).lock().unwrap();
+        if shard.generation == generation {
+            shard.insert(key.into(), entry, self.shard_capacity);
+        }
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+}
+
+// Keep the hot cache in sync with sled.
+//
+// Every write to the cache populates the hot cache, and entries that get
+// removed by reorgs, expiry or eviction are removed from it too.
+pub async fn sync_hot_cache(cache: Arc<Db>, hot_cache: Arc<HotCache>) {
+    let mut subscriber = cache.watch_prefix(vec![]);
+
+    while let Some(event) = (&mut subscriber).await {
+        match event {
+            Event::Insert { key, value } => hot_cache.insert(&key, value),
+            Event::Remove { key } => hot_cache.remove(&key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::entry::payload_mut;
+
+    use serde_json::Value;
+    use std::time::{
+        Duration,
+        Instant,
+    };
+
+    fn key(shard: u8, i: u8) -> [u8; 2] {
+        [i, shard]
+    }
+
+    #[test]
+    fn test_lru() {
+        // 2 entries per shard
+        let hot_cache = HotCache::new(2 * SHARD_COUNT);
+
+        hot_cache.insert(&key(0, 1), IVec::from("1"));
+        hot_cache.insert(&key(0, 2), IVec::from("2"));
+
+        // Using 1 makes 2 the least recently used
+        assert_eq!(hot_cache.get(&key(0, 1)).unwrap(), "1");
+        hot_cache.insert(&key(0, 3), IVec::from("3"));
+
+        assert!(hot_cache.get(&key(0, 2)).is_none());
+        assert_eq!(hot_cache.get(&key(0, 1)).unwrap(), "1");
+        assert_eq!(hot_cache.get(&key(0, 3)).unwrap(), "3");
+
+        // Other shards are unaffected
+        hot_cache.insert(&key(1, 1), IVec::from("1"));
+        assert_eq!(hot_cache.get(&key(0, 1)).unwrap(), "1");
+
+        hot_cache.remove(&key(0, 1));
+        assert!(hot_cache.get(&key(0, 1)).is_none());
+    }
+
+    #[test]
+    fn test_insert_if_unchanged() {
+        let hot_cache = HotCache::new(2 * SHARD_COUNT);
+
+        // Some other key got removed from the shard after we read from sled
+        let generation = hot_cache.generation(&key(0, 1));
+        hot_cache.remove(&key(0, 2));
+        hot_cache.insert_if_unchanged(&key(0, 1), IVec::from("1"), generation);
+        assert!(hot_cache.get(&key(0, 1)).is_none());
+
+        // Our key got overwritten after we read it
+        let generation = hot_cache.generation(&key(0, 1));
+        hot_cache.insert(&key(0, 1), IVec::from("2"));
+        hot_cache.insert_if_unchanged(&key(0, 1), IVec::from("1"), generation);
+        assert_eq!(hot_cache.get(&key(0, 1)).unwrap(), "2");
+        hot_cache.remove(&key(0, 1));
+
+        let generation = hot_cache.generation(&key(0, 1));
+        hot_cache.insert_if_unchanged(&key(0, 1), IVec::from("1"), generation);
+        assert_eq!(hot_cache.get(&key(0, 1)).unwrap(), "1");
+    }
+
+    #[test]
+    fn test_max_entry_bytes() {
+        let hot_cache = HotCache::new(2 * SHARD_COUNT).with_max_entry_bytes(4);
+
+        hot_cache.insert(&key(0, 1), IVec::from("1234"));
+        assert_eq!(hot_cache.get(&key(0, 1)).unwrap(), "1234");
+
+        // Overwriting with a large entry drops the old one
+        hot_cache.insert(&key(0, 1), IVec::from("12345"));
+        assert!(hot_cache.get(&key(0, 1)).is_none());
+
+        let generation = hot_cache.generation(&key(0, 2));
+        hot_cache.insert_if_unchanged(&key(0, 2), IVec::from("12345"), generation);
+        assert!(hot_cache.get(&key(0, 2)).is_none());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let hot_cache = HotCache::new(0);
+
+        hot_cache.insert(&key(0, 1), IVec::from("1"));
+        assert!(hot_cache.get(&key(0, 1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sync_hot_cache() {
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+        let hot_cache = Arc::new(HotCache::new(1024));
+
+        tokio::task::spawn(sync_hot_cache(Arc::clone(&cache), Arc::clone(&hot_cache)));
+        tokio::task::yield_now().await;
+
+        cache.insert(key(0, 1), "1").unwrap();
+        cache.insert(key(0, 2), "2").unwrap();
+        cache.remove(key(0, 1)).unwrap();
+        cache.insert(key(0, 3), "3").unwrap();
+
+        // Wait for the events to make it through
+        for _ in 0..100 {
+            if hot_cache.get(&key(0, 3)).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(hot_cache.get(&key(0, 1)).is_none());
+        assert_eq!(hot_cache.get(&key(0, 2)).unwrap(), "2");
+    }
+
+    // Compare cache hits served by sled against hits served by the hot cache.
+    //
+    // Run with `cargo test --release bench_hot_cache -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_hot_cache() {
+        const KEYS: u32 = 512;
+        const ITERATIONS: u32 = 200_000;
+
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        // Leave some room since keys aren't perfectly spread over the shards
+        let hot_cache = HotCache::new(2 * KEYS as usize);
+
+        let response = br#"{"id":null,"jsonrpc":"2.0","result":"0x10d4f"}"#.as_slice();
+        for i in 0..KEYS {
+            let key = blake3::hash(&i.to_be_bytes());
+            cache.insert(key.as_bytes(), response).unwrap();
+            hot_cache.insert(key.as_bytes(), IVec::from(response));
+        }
+        let keys: Vec<_> = (0..KEYS).map(|i| blake3::hash(&i.to_be_bytes())).collect();
+
+        let run = |get: &dyn Fn(&[u8]) -> Option<IVec>| {
+            let time = Instant::now();
+            for i in 0..ITERATIONS {
+                let mut entry = get(keys[(i % KEYS) as usize].as_bytes()).unwrap();
+                let _: Value = simd_json::serde::from_slice(payload_mut(&mut entry)).unwrap();
+            }
+            time.elapsed() / ITERATIONS
+        };
+
+        let sled_latency = run(&|key| cache.get(key).unwrap());
+        let hot_latency = run(&|key| hot_cache.get(key));
+
+        println!(
+            "sled: {:?}/request, hot cache: {:?}/request",
+            sled_latency, hot_latency
+        );
+    }
+}
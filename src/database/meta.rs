@@ -0,0 +1,17 @@
+use sled::{
+    Db,
+    IVec,
+};
+
+// Tree holding metadata about the cache itself, like which chain and hash function it's used with
+const META_TREE: &[u8] = b"meta";
+
+pub fn get_meta(cache: &Db, key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+    cache.open_tree(META_TREE)?.get(key)
+}
+
+pub fn set_meta(cache: &Db, key: &[u8], value: &[u8]) -> Result<(), sled::Error> {
+    cache.open_tree(META_TREE)?.insert(key, value)?;
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+// Tree indexing cache entries by the method of the request they answer.
+//
+// Keys are the length of the method name as a big endian u16, the method name,
+// and the key of the cache entry, so every entry of a method sits in one contiguous range.
+// Index entries aren't removed when their entry expires or gets evicted, flushing skips over them.
+pub const METHOD_TREE: &[u8] = b"methods";
+
+// Prefix of every index key for `method`
+pub fn method_prefix(method: &str) -> Vec<u8> {
+    // Method names are picked by clients, anything past u16::MAX bytes gets cut off
+    let method = &method.as_bytes()[..method.len().min(u16::MAX as usize)];
+
+    let mut prefix = Vec::with_capacity(2 + method.len());
+    prefix.extend_from_slice(&(method.len() as u16).to_be_bytes());
+    prefix.extend_from_slice(method);
+
+    prefix
+}
+
+pub fn method_index_key(method: &str, key: &[u8]) -> Vec<u8> {
+    let mut index_key = method_prefix(method);
+    index_key.extend_from_slice(key);
+
+    index_key
+}
+
+// Record that the cache entry at `key` answers a call to `method`
+//
+// Requests go through `CacheBatch`, this is for writing entries directly in tests.
+#[cfg(test)]
+pub fn index_method(cache: &sled::Db, method: &str, key: &[u8]) -> Result<(), sled::Error> {
+    cache
+        .open_tree(METHOD_TREE)?
+        .insert(method_index_key(method, key), &[])?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_index_key() {
+        assert_eq!(method_index_key("eth_call", b"key"), b"\x00\x08eth_callkey");
+
+        // One method name can't be the prefix of another
+        assert!(!method_index_key("eth_getLogsX", b"key").starts_with(&method_prefix("eth_getLogs")));
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        RwLock,
+    },
+};
+
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+
+// Methods are named by clients so there can be any number of them.
+// Past this many, calls get counted under `OTHER_METHODS`.
+const MAX_TRACKED_METHODS: usize = 256;
+const OTHER_METHODS: &str = "other";
+
+#[derive(Debug, Default)]
+pub struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    uncacheable: AtomicU64,
+    too_large: AtomicU64,
+}
+
+impl Counters {
+    // Read the counters as JSON, optionally resetting them to 0
+    fn to_json(&self, reset: bool) -> Value {
+        let read = |counter: &AtomicU64| {
+            if reset {
+                counter.swap(0, Ordering::Relaxed)
+            } else {
+                counter.load(Ordering::Relaxed)
+            }
+        };
+
+        json!({
+            "hits": read(&self.hits),
+            "misses": read(&self.misses),
+            "inserts": read(&self.inserts),
+            "uncacheable": read(&self.uncacheable),
+            "too_large": read(&self.too_large),
+        })
+    }
+}
+
+// Cache hits that got fetched again to check them, see `balancer::revalidate`
+#[derive(Debug, Default)]
+struct Revalidations {
+    checked: AtomicU64,
+    diverged: AtomicU64,
+    // Divergences by method and by the RPC that disagreed with the cache
+    methods: Mutex<HashMap<String, u64>>,
+    nodes: Mutex<HashMap<String, u64>>,
+}
+
+impl Revalidations {
+    fn to_json(&self, reset: bool) -> Value {
+        let read = |counts: &Mutex<HashMap<String, u64>>| {
+            let mut counts = counts.lock().unwrap();
+            let json = counts
+                .iter()
+                .map(|(name, count)| (name.clone(), Value::from(*count)))
+                .collect::<Map<_, _>>();
+            if reset {
+                counts.clear();
+            }
+            Value::Object(json)
+        };
+        let (checked, diverged) = if reset {
+            (
+                self.checked.swap(0, Ordering::Relaxed),
+                self.diverged.swap(0, Ordering::Relaxed),
+            )
+        } else {
+            (
+                self.checked.load(Ordering::Relaxed),
+                self.diverged.load(Ordering::Relaxed),
+            )
+        };
+
+        json!({
+            "checked": checked,
+            "diverged": diverged,
+            "methods": read(&self.methods),
+            "nodes": read(&self.nodes),
+        })
+    }
+}
+
+// Calls that also got sent to other RPCs because the first one was slow, see `balancer::hedge`
+#[derive(Debug, Default)]
+struct Hedges {
+    sent: AtomicU64,
+    // Hedges that answered before the RPC the call went to first
+    won: AtomicU64,
+}
+
+impl Hedges {
+    fn to_json(&self, reset: bool) -> Value {
+        let read = |counter: &AtomicU64| {
+            if reset {
+                counter.swap(0, Ordering::Relaxed)
+            } else {
+                counter.load(Ordering::Relaxed)
+            }
+        };
+
+        json!({
+            "sent": read(&self.sent),
+            "won": read(&self.won),
+        })
+    }
+}
+
+// Cache hit/miss counters, both in total and by method
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    total: Counters,
+    methods: RwLock<HashMap<String, Arc<Counters>>>,
+    revalidations: Revalidations,
+    hedges: Hedges,
+}
+
+impl CacheMetrics {
+    // Get the counters to update for a call to `method`
+    pub fn call(&self, method: &str) -> CallMetrics<'_> {
+        let counters = self.methods.read().unwrap().get(method).cloned();
+        let method = match counters {
+            Some(counters) => counters,
+            None => {
+                let mut methods = self.methods.write().unwrap();
+                let method = if methods.len() < MAX_TRACKED_METHODS {
+                    method
+                } else {
+                    OTHER_METHODS
+                };
+
+                Arc::clone(methods.entry(method.to_string()).or_default())
+            }
+        };
+
+        CallMetrics {
+            total: &self.total,
+            method,
+        }
+    }
+
+    // A cache hit was fetched again from a RPC to check it
+    pub fn revalidated(&self) {
+        self.revalidations.checked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The RPC at `node` returned something else than the cached response for `method`
+    pub fn diverged(&self, method: &str, node: &str) {
+        self.revalidations.diverged.fetch_add(1, Ordering::Relaxed);
+
+        let mut methods = self.revalidations.methods.lock().unwrap();
+        let method = if methods.len() < MAX_TRACKED_METHODS || methods.contains_key(method) {
+            method
+        } else {
+            OTHER_METHODS
+        };
+        *methods.entry(method.to_string()).or_default() += 1;
+
+        *self
+            .revalidations
+            .nodes
+            .lock()
+            .unwrap()
+            .entry(node.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn hedge_sent(&self) {
+        self.hedges.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hedge_won(&self) {
+        self.hedges.won.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Read every counter as JSON, optionally resetting them to 0
+    pub fn to_json(&self, reset: bool) -> Value {
+        let methods = self.methods.read().unwrap();
+
+        let mut by_method = Map::new();
+        for (method, counters) in methods.iter() {
+            by_method.insert(method.clone(), counters.to_json(reset));
+        }
+
+        let mut rx = self.total.to_json(reset);
+        rx["methods"] = Value::Object(by_method);
+        rx["revalidations"] = self.revalidations.to_json(reset);
+        rx["hedges"] = self.hedges.to_json(reset);
+
+        rx
+    }
+}
+
+// Counters for a single call
+#[derive(Debug)]
+pub struct CallMetrics<'a> {
+    total: &'a Counters,
+    method: Arc<Counters>,
+}
+
+impl CallMetrics<'_> {
+    fn increment(&self, counter: fn(&Counters) -> &AtomicU64) {
+        counter(self.total).fetch_add(1, Ordering::Relaxed);
+        counter(&self.method).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hit(&self) {
+        self.increment(|counters| &counters.hits);
+    }
+
+    pub fn miss(&self) {
+        self.increment(|counters| &counters.misses);
+    }
+
+    pub fn insert(&self) {
+        self.increment(|counters| &counters.inserts);
+    }
+
+    // The call skipped the cache because its method is never cached
+    pub fn uncacheable(&self) {
+        self.increment(|counters| &counters.uncacheable);
+    }
+
+    // The response was too large to cache, see `cache.max_entry_bytes`
+    pub fn too_large(&self) {
+        self.increment(|counters| &counters.too_large);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_metrics() {
+        let metrics = CacheMetrics::default();
+
+        metrics.call("eth_chainId").miss();
+        metrics.call("eth_chainId").insert();
+        metrics.call("eth_chainId").hit();
+        metrics.call("eth_blockNumber").uncacheable();
+        metrics.call("debug_traceTransaction").too_large();
+
+        let stats = metrics.to_json(true);
+        assert_eq!(stats["hits"], 1);
+        assert_eq!(stats["misses"], 1);
+        assert_eq!(stats["inserts"], 1);
+        assert_eq!(stats["uncacheable"], 1);
+        assert_eq!(stats["too_large"], 1);
+        assert_eq!(
+            stats["methods"]["eth_chainId"],
+            json!({"hits": 1, "misses": 1, "inserts": 1, "uncacheable": 0, "too_large": 0})
+        );
+        assert_eq!(stats["methods"]["eth_blockNumber"]["uncacheable"], 1);
+
+        // Everything got reset by the last read
+        let stats = metrics.to_json(false);
+        assert_eq!(stats["hits"], 0);
+        assert_eq!(stats["methods"]["eth_chainId"]["hits"], 0);
+    }
+
+    #[test]
+    fn test_revalidation_metrics() {
+        let metrics = CacheMetrics::default();
+
+        metrics.revalidated();
+        metrics.revalidated();
+        metrics.diverged("eth_getBalance", "http://rpc-a");
+
+        let stats = metrics.to_json(true);
+        assert_eq!(
+            stats["revalidations"],
+            json!({
+                "checked": 2,
+                "diverged": 1,
+                "methods": {"eth_getBalance": 1},
+                "nodes": {"http://rpc-a": 1},
+            })
+        );
+
+        let stats = metrics.to_json(false);
+        assert_eq!(
+            stats["revalidations"],
+            json!({"checked": 0, "diverged": 0, "methods": {}, "nodes": {}})
+        );
+    }
+
+    #[test]
+    fn test_max_tracked_methods() {
+        let metrics = CacheMetrics::default();
+
+        for i in 0..MAX_TRACKED_METHODS + 10 {
+            metrics.call(&format!("method_{}", i)).hit();
+        }
+
+        let stats = metrics.to_json(false);
+        let methods = stats["methods"].as_object().unwrap();
+        assert_eq!(methods.len(), MAX_TRACKED_METHODS + 1);
+        assert_eq!(methods[OTHER_METHODS]["hits"], 10);
+        assert_eq!(stats["hits"], MAX_TRACKED_METHODS as u64 + 10);
+    }
+}
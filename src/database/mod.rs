@@ -0,0 +1,19 @@
+pub mod backend;
+pub mod batch;
+pub mod block_index;
+pub mod call_log;
+pub mod chain_id;
+pub mod entry;
+pub mod error;
+pub mod eviction;
+pub mod expiry;
+pub mod flush;
+pub mod hasher;
+pub mod hot_cache;
+pub mod meta;
+pub mod method_index;
+pub mod metrics;
+pub mod redis_backend;
+pub mod sled_backend;
+pub mod version;
+pub mod write_behind;
@@ -0,0 +1,218 @@
+use crate::database::{
+    backend::{
+        BackendFuture,
+        CacheBackend,
+        StoredEntry,
+    },
+    entry::unix_millis,
+    error::DatabaseError,
+    hot_cache::HotCache,
+    version::CACHE_VERSION,
+};
+
+use std::sync::{
+    atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+    Arc,
+};
+
+use redis::{
+    aio::ConnectionManager,
+    Client,
+    Cmd,
+    Pipeline,
+};
+use sled::IVec;
+
+// How many keys to remove or scan for per round trip
+const REDIS_BATCH_SIZE: usize = 1024;
+
+// Entries go in Redis so they can be shared between multiple instances.
+//
+// Keys are namespaced by the cache format version, so instances running
+// an incompatible version never read each other's entries. Expiring entries
+// get a TTL and are dropped by Redis itself.
+pub struct RedisBackend {
+    pool: Vec<ConnectionManager>,
+    next: AtomicUsize,
+    prefix: Vec<u8>,
+    // Removals don't show up in sled, so we drop them from the hot cache ourselves
+    hot_cache: Arc<HotCache>,
+}
+
+impl std::fmt::Debug for RedisBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RedisBackend")
+            .field("pool_size", &self.pool.len())
+            .field("prefix", &String::from_utf8_lossy(&self.prefix))
+            .finish()
+    }
+}
+
+impl RedisBackend {
+    pub async fn connect(
+        url: &str,
+        pool_size: usize,
+        hot_cache: Arc<HotCache>,
+    ) -> Result<Self, DatabaseError> {
+        let client = Client::open(url)?;
+
+        // Each connection is multiplexed, so a few of them go a long way
+        let mut pool = Vec::with_capacity(pool_size.max(1));
+        for _ in 0..pool_size.max(1) {
+            pool.push(ConnectionManager::new(client.clone()).await?);
+        }
+
+        Ok(RedisBackend {
+            pool,
+            next: AtomicUsize::new(0),
+            prefix: format!("blutgang:v{}:", CACHE_VERSION).into_bytes(),
+            hot_cache,
+        })
+    }
+
+    fn connection(&self) -> ConnectionManager {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        self.pool[next % self.pool.len()].clone()
+    }
+
+    fn redis_key(&self, key: &[u8]) -> Vec<u8> {
+        let mut redis_key = Vec::with_capacity(self.prefix.len() + key.len());
+        redis_key.extend_from_slice(&self.prefix);
+        redis_key.extend_from_slice(key);
+
+        redis_key
+    }
+
+    // SET command for `entry`, or None if it already expired
+    fn set_cmd(&self, entry: &StoredEntry) -> Option<Cmd> {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(self.redis_key(&entry.key)).arg(&entry.value);
+
+        if let Some(expires_at) = entry.expires_at {
+            let ttl = expires_at.saturating_sub(unix_millis());
+            if ttl == 0 {
+                return None;
+            }
+            cmd.arg("PX").arg(ttl);
+        }
+
+        Some(cmd)
+    }
+}
+
+// Escape glob characters so the prefix is matched literally by SCAN
+fn glob_escape(prefix: &[u8]) -> Vec<u8> {
+    let mut pattern = Vec::with_capacity(prefix.len() + 1);
+    for &byte in prefix {
+        if matches!(byte, b'*' | b'?' | b'[' | b']' | b'\\') {
+            pattern.push(b'\\');
+        }
+        pattern.push(byte);
+    }
+
+    pattern
+}
+
+impl CacheBackend for RedisBackend {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BackendFuture<'a, Option<IVec>> {
+        Box::pin(async move {
+            let value: Option<Vec<u8>> = redis::cmd("GET")
+                .arg(self.redis_key(key))
+                .query_async(&mut self.connection())
+                .await?;
+
+            Ok(value.map(IVec::from))
+        })
+    }
+
+    fn insert(&self, entry: StoredEntry) -> BackendFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(cmd) = self.set_cmd(&entry) {
+                cmd.query_async::<_, ()>(&mut self.connection()).await?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn insert_batch(&self, entries: Vec<StoredEntry>) -> BackendFuture<'_, ()> {
+        Box::pin(async move {
+            let mut pipe = Pipeline::new();
+            pipe.atomic();
+            for cmd in entries.iter().filter_map(|entry| self.set_cmd(entry)) {
+                pipe.add_command(cmd).ignore();
+            }
+            pipe.query_async::<_, ()>(&mut self.connection()).await?;
+
+            Ok(())
+        })
+    }
+
+    fn remove(&self, keys: Vec<Vec<u8>>) -> BackendFuture<'_, usize> {
+        Box::pin(async move {
+            let mut removed = 0;
+            for keys in keys.chunks(REDIS_BATCH_SIZE) {
+                for key in keys {
+                    self.hot_cache.remove(key);
+                }
+
+                let mut cmd = redis::cmd("DEL");
+                for key in keys {
+                    cmd.arg(self.redis_key(key));
+                }
+                removed += cmd.query_async::<_, usize>(&mut self.connection()).await?;
+            }
+
+            Ok(removed)
+        })
+    }
+
+    fn scan_prefix<'a>(&'a self, prefix: &'a [u8]) -> BackendFuture<'a, Vec<IVec>> {
+        Box::pin(async move {
+            let mut pattern = glob_escape(&self.redis_key(prefix));
+            pattern.push(b'*');
+
+            let mut connection = self.connection();
+            let mut keys = Vec::new();
+            let mut cursor = 0u64;
+            loop {
+                let (next, batch): (u64, Vec<Vec<u8>>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(REDIS_BATCH_SIZE)
+                    .query_async(&mut connection)
+                    .await?;
+
+                keys.extend(
+                    batch
+                        .into_iter()
+                        .map(|key| IVec::from(&key[self.prefix.len()..])),
+                );
+
+                // SCAN can return the same key more than once
+                if next == 0 {
+                    keys.sort_unstable();
+                    keys.dedup();
+                    return Ok(keys);
+                }
+                cursor = next;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_escape() {
+        assert_eq!(glob_escape(b"eth_call"), b"eth_call");
+        assert_eq!(glob_escape(b"a*b?[c]\\"), b"a\\*b\\?\\[c\\]\\\\");
+    }
+}
@@ -0,0 +1,91 @@
+use crate::database::{
+    backend::{
+        BackendFuture,
+        CacheBackend,
+        StoredEntry,
+    },
+    eviction::count_entries,
+};
+
+use std::sync::Arc;
+
+use sled::{
+    Batch,
+    Db,
+    IVec,
+};
+
+// Entries go in the default tree of the same DB as the indexes.
+//
+// Expired entries are left for `prune_expired` to remove. New and removed
+// entries are counted as they're written, see `eviction::entry_stats`.
+#[derive(Debug, Clone)]
+pub struct SledBackend {
+    cache: Arc<Db>,
+}
+
+impl SledBackend {
+    pub fn new(cache: Arc<Db>) -> Self {
+        SledBackend { cache }
+    }
+}
+
+impl CacheBackend for SledBackend {
+    fn get<'a>(&'a self, key: &'a [u8]) -> BackendFuture<'a, Option<IVec>> {
+        Box::pin(async move { Ok(self.cache.get(key)?) })
+    }
+
+    fn insert(&self, entry: StoredEntry) -> BackendFuture<'_, ()> {
+        Box::pin(async move {
+            if self.cache.insert(entry.key, entry.value)?.is_none() {
+                count_entries(&self.cache, 1, 0)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn insert_batch(&self, entries: Vec<StoredEntry>) -> BackendFuture<'_, ()> {
+        Box::pin(async move {
+            let mut batch = Batch::default();
+            let mut added = 0;
+            for entry in entries {
+                if !self.cache.contains_key(&entry.key)? {
+                    added += 1;
+                }
+                batch.insert(entry.key, entry.value);
+            }
+            self.cache.apply_batch(batch)?;
+            count_entries(&self.cache, added, 0)?;
+
+            Ok(())
+        })
+    }
+
+    fn remove(&self, keys: Vec<Vec<u8>>) -> BackendFuture<'_, usize> {
+        Box::pin(async move {
+            let mut batch = Batch::default();
+            let mut removed = 0;
+            for key in keys {
+                if self.cache.contains_key(&key)? {
+                    batch.remove(key);
+                    removed += 1;
+                }
+            }
+            self.cache.apply_batch(batch)?;
+            count_entries(&self.cache, 0, removed)?;
+
+            Ok(removed)
+        })
+    }
+
+    fn scan_prefix<'a>(&'a self, prefix: &'a [u8]) -> BackendFuture<'a, Vec<IVec>> {
+        Box::pin(async move {
+            Ok(self
+                .cache
+                .scan_prefix(prefix)
+                .keys()
+                .collect::<Result<_, _>>()?)
+        })
+    }
+}
@@ -0,0 +1,174 @@
+use crate::database::{
+    block_index::BLOCK_TREE,
+    chain_id::stored_chain_id,
+    error::DatabaseError,
+    eviction::{
+        STATS_TREE,
+        WRITE_ID_TREE,
+        WRITE_TREE,
+    },
+    expiry::EXPIRY_TREE,
+    method_index::METHOD_TREE,
+    meta::{
+        get_meta,
+        set_meta,
+    },
+};
+
+use sled::Db;
+
+// Version of the format cache keys and entries are stored in.
+//
+// Bump this and add a step to `migrate` whenever either of them changes.
+//
+// 0: unversioned, keys are the hash of the request
+// 1: keys are prefixed with the chain id, entries can expire and be compressed
+// 2: keys are `{chain_id}:{method}:{hash}`, where the hash only covers the params
+pub const CACHE_VERSION: u64 = 2;
+const VERSION_KEY: &[u8] = b"version";
+
+// Trees indexing cache entries, which need to go together with them
+const INDEX_TREES: [&[u8]; 6] = [
+    BLOCK_TREE,
+    EXPIRY_TREE,
+    METHOD_TREE,
+    WRITE_TREE,
+    WRITE_ID_TREE,
+    STATS_TREE,
+];
+
+// Read the format version of the cache
+pub fn stored_version(cache: &Db) -> Result<Option<u64>, sled::Error> {
+    Ok(get_meta(cache, VERSION_KEY)?
+        .and_then(|version| Some(u64::from_be_bytes(version.as_ref().try_into().ok()?))))
+}
+
+// Remove every cache entry along with its indexes, returning how many entries were removed
+fn drop_entries(cache: &Db) -> Result<usize, sled::Error> {
+    let dropped = cache.len();
+
+    cache.clear()?;
+    for tree in INDEX_TREES {
+        cache.drop_tree(tree)?;
+    }
+
+    Ok(dropped)
+}
+
+// Bring a cache at format version `from` up to `CACHE_VERSION`
+fn migrate(cache: &Db, from: u64) -> Result<(), sled::Error> {
+    // Keys used to be the hash of the whole request, which we can't get the method or
+    // params back from, so nothing in there can get a hit anymore
+    if from < 2 {
+        let dropped = drop_entries(cache)?;
+        println!(
+            "\x1b[93mWrn:\x1b[0m The cache was written by an older version of blutgang. Dropped {} incompatible entries.",
+            dropped
+        );
+    }
+
+    Ok(())
+}
+
+// Make sure we can read what's in the cache, migrating it if it was written by an older version.
+//
+// Caches written by a newer version get refused since we can't know what changed.
+// Empty caches get the current version no matter what they were marked with.
+pub fn check_version(cache: &Db) -> Result<(), DatabaseError> {
+    // Caches from before versioning that already pinned a chain id are at version 1
+    let stored = match stored_version(cache)? {
+        Some(stored) => Some(stored),
+        None => stored_chain_id(cache)?.map(|_| 1),
+    };
+
+    match stored {
+        Some(CACHE_VERSION) => {}
+        _ if cache.is_empty() => {}
+        Some(stored) if stored > CACHE_VERSION => {
+            return Err(DatabaseError::UnsupportedVersion {
+                stored,
+                supported: CACHE_VERSION,
+            });
+        }
+        stored => migrate(cache, stored.unwrap_or_default())?,
+    }
+
+    set_meta(cache, VERSION_KEY, &CACHE_VERSION.to_be_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        block_index::index_block,
+        chain_id::pin_chain_id,
+    };
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_check_version_fresh() {
+        let cache = create_test_cache();
+
+        check_version(&cache).unwrap();
+        assert_eq!(stored_version(&cache).unwrap(), Some(CACHE_VERSION));
+    }
+
+    #[test]
+    fn test_check_version_unversioned() {
+        let cache = create_test_cache();
+
+        // Entry from before keys were namespaced, which can't be looked up anymore
+        cache.insert(b"legacy", b"garbage").unwrap();
+        index_block(&cache, 10, b"legacy").unwrap();
+
+        check_version(&cache).unwrap();
+        assert!(cache.is_empty());
+        assert!(cache.open_tree(BLOCK_TREE).unwrap().is_empty());
+        assert_eq!(stored_version(&cache).unwrap(), Some(CACHE_VERSION));
+
+        // Already migrated
+        cache.insert(b"entry", b"{}").unwrap();
+        check_version(&cache).unwrap();
+        assert!(cache.get(b"entry").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_check_version_pinned_chain_id() {
+        let cache = create_test_cache();
+
+        // Caches from before versioning with a pinned chain id are at version 1,
+        // whose keys don't have the method in them
+        pin_chain_id(&cache, Some(1), false).unwrap();
+        cache.insert(b"entry", b"{}").unwrap();
+
+        check_version(&cache).unwrap();
+        assert!(cache.is_empty());
+        assert_eq!(stored_chain_id(&cache).unwrap(), Some(1));
+        assert_eq!(stored_version(&cache).unwrap(), Some(CACHE_VERSION));
+    }
+
+    #[test]
+    fn test_check_version_newer() {
+        let cache = create_test_cache();
+
+        set_meta(&cache, VERSION_KEY, &(CACHE_VERSION + 1).to_be_bytes()).unwrap();
+        cache.insert(b"entry", b"{}").unwrap();
+
+        assert!(matches!(
+            check_version(&cache),
+            Err(DatabaseError::UnsupportedVersion { stored, supported })
+                if stored == CACHE_VERSION + 1 && supported == CACHE_VERSION
+        ));
+        assert!(cache.get(b"entry").unwrap().is_some());
+
+        // Clearing the cache lets us start over
+        cache.clear().unwrap();
+        check_version(&cache).unwrap();
+        assert_eq!(stored_version(&cache).unwrap(), Some(CACHE_VERSION));
+    }
+}
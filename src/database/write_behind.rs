@@ -0,0 +1,132 @@
+use crate::database::{
+    backend::CacheBackend,
+    batch::CacheBatch,
+    hot_cache::HotCache,
+};
+
+use std::sync::Arc;
+
+use sled::{
+    Db,
+    IVec,
+};
+use tokio::sync::mpsc;
+
+// How many queued batches the writer merges into a single sled write
+const MAX_MERGED_BATCHES: usize = 64;
+
+// Hands cache writes off to `write_behind` so requests don't wait on the backend.
+//
+// The hot cache gets updated right away so the entries can be served before
+// they hit the disk. Queued writes are lost if we crash, which only costs us
+// a few cache misses.
+#[derive(Debug, Clone)]
+pub struct CacheWriter {
+    tx: mpsc::Sender<CacheBatch>,
+}
+
+impl CacheWriter {
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<CacheBatch>) {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        (CacheWriter { tx }, rx)
+    }
+
+    // Queue `batch` to be written to sled.
+    //
+    // If the writer can't keep up the batch gets dropped rather than slowing down requests.
+    pub fn write(&self, batch: CacheBatch, hot_cache: &HotCache) {
+        if batch.is_empty() {
+            return;
+        }
+
+        for (key, value) in batch.entries() {
+            hot_cache.insert(key, IVec::from(value));
+        }
+
+        if let Err(err) = self.tx.try_send(batch) {
+            println!(
+                "\x1b[93mWrn:\x1b[0m Dropping cache write, the write queue is {}.",
+                match err {
+                    mpsc::error::TrySendError::Full(_) => "full",
+                    mpsc::error::TrySendError::Closed(_) => "closed",
+                }
+            );
+        }
+    }
+}
+
+// Apply queued writes, merging whatever piled up in the meantime
+pub async fn write_behind(
+    cache: Arc<Db>,
+    backend: Arc<dyn CacheBackend>,
+    mut rx: mpsc::Receiver<CacheBatch>,
+) {
+    while let Some(mut batch) = rx.recv().await {
+        for _ in 1..MAX_MERGED_BATCHES {
+            match rx.try_recv() {
+                Ok(next) => batch.append(next),
+                Err(_) => break,
+            }
+        }
+
+        if let Err(err) = batch.apply(&cache, backend.as_ref()).await {
+            println!(
+                "\x1b[31mErr:\x1b[0m Could not write to the cache: {}",
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::sled_backend::SledBackend;
+
+    fn create_test_cache() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_write_behind() {
+        let cache = Arc::new(create_test_cache());
+        let hot_cache = HotCache::new(16);
+        let (writer, rx) = CacheWriter::new(16);
+
+        let mut batch = CacheBatch::default();
+        batch.insert(b"key", b"{}");
+        batch.index_method("eth_call", b"key");
+        writer.write(batch, &hot_cache);
+
+        // Served from memory before it hits sled
+        assert_eq!(hot_cache.get(b"key").unwrap(), b"{}");
+        assert!(cache.get(b"key").unwrap().is_none());
+
+        // The writer stops once every sender is gone
+        drop(writer);
+        let backend = Arc::new(SledBackend::new(Arc::clone(&cache)));
+        write_behind(Arc::clone(&cache), backend, rx).await;
+        assert_eq!(cache.get(b"key").unwrap().unwrap(), b"{}");
+    }
+
+    #[tokio::test]
+    async fn test_write_full_queue() {
+        let hot_cache = HotCache::new(16);
+        let (writer, mut rx) = CacheWriter::new(1);
+
+        for key in [b"first", b"other"] {
+            let mut batch = CacheBatch::default();
+            batch.insert(key, b"{}");
+            writer.write(batch, &hot_cache);
+        }
+
+        // The second batch didn't fit and got dropped, it's still in memory though
+        assert_eq!(rx.recv().await.unwrap().entries().count(), 1);
+        assert!(rx.try_recv().is_err());
+        assert!(hot_cache.get(b"other").is_some());
+
+        // Nothing to write, nothing queued
+        writer.write(CacheBatch::default(), &hot_cache);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -3,6 +3,7 @@ use crate::{
         error::HealthError,
         safe_block::{
             get_safe_block,
+            persist_named_numbers,
             NamedBlocknumbers,
         },
     },
@@ -31,6 +32,49 @@ struct HeadResult {
     reported_head: u64,
 }
 
+// Keeps track of the lowest tier with a healthy RPC, which is the one requests go to
+#[derive(Debug, Default)]
+struct TierTracker {
+    active: Option<u32>,
+    // How many times requests moved to another tier
+    switches: u64,
+}
+
+impl TierTracker {
+    // Log and count it if requests moved to another tier since the last update
+    fn update(&mut self, rpc_list: &[Rpc]) {
+        let tier = match rpc_list
+            .iter()
+            .filter(|rpc| rpc.circuit.is_closed())
+            .map(|rpc| rpc.tier)
+            .min()
+        {
+            Some(tier) => tier,
+            // Nothing to switch to
+            None => return,
+        };
+
+        match self.active {
+            Some(active) if tier > active => {
+                self.switches += 1;
+                println!(
+                    "\x1b[93mWrn:\x1b[0m No healthy tier {} RPCs, falling back to tier {} ({} tier switches so far)",
+                    active, tier, self.switches
+                );
+            }
+            Some(active) if tier < active => {
+                self.switches += 1;
+                println!(
+                    "\x1b[35mInfo:\x1b[0m Tier {} RPCs are healthy again, switching back from tier {} ({} tier switches so far)",
+                    tier, active, self.switches
+                );
+            }
+            _ => {}
+        }
+        self.active = Some(tier);
+    }
+}
+
 // Call check and safe_block in a loop
 pub async fn health_check(
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
@@ -39,13 +83,25 @@ pub async fn health_check(
     finalized_tx: tokio::sync::watch::Sender<u64>,
     named_numbers_rwlock: &Arc<RwLock<NamedBlocknumbers>>,
     config: &Arc<RwLock<Settings>>,
+    cache: &Arc<sled::Db>,
 ) -> Result<(), HealthError> {
+    let mut tiers = TierTracker::default();
     loop {
         let health_check_ttl = config.read().unwrap().health_check_ttl;
         let ttl = config.read().unwrap().ttl;
+        let max_head_lag = config.read().unwrap().max_head_lag;
 
         sleep(Duration::from_millis(health_check_ttl)).await;
-        check(&rpc_list, &poverty_list, blocknum_tx, &ttl).await?;
+        check(
+            &rpc_list,
+            &poverty_list,
+            blocknum_tx,
+            named_numbers_rwlock,
+            &ttl,
+            max_head_lag,
+        )
+        .await?;
+        tiers.update(&rpc_list.read().unwrap());
         get_safe_block(
             &rpc_list,
             &finalized_tx,
@@ -53,6 +109,14 @@ pub async fn health_check(
             health_check_ttl,
         )
         .await?;
+
+        // Save what we found so tags can be resolved right after a restart
+        let named_numbers = *named_numbers_rwlock.read().unwrap();
+        if !named_numbers.provisional && named_numbers.latest != 0 {
+            if let Err(err) = persist_named_numbers(cache, &named_numbers) {
+                println!("\x1b[93mWrn:\x1b[0m Could not save the head block: {}", err);
+            }
+        }
     }
 }
 
@@ -61,7 +125,9 @@ async fn check(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     blocknum_tx: &tokio::sync::watch::Sender<u64>,
+    named_numbers_rwlock: &Arc<RwLock<NamedBlocknumbers>>,
     ttl: &u128,
+    max_head_lag: u64,
 ) -> Result<(), HealthError> {
     print!("\x1b[35mInfo:\x1b[0m Checking RPC health... ");
     // Head blocks reported by each RPC, we also use it to mark delinquents
@@ -70,7 +136,7 @@ async fn check(
     let heads = head_check(rpc_list, *ttl).await?;
 
     // Remove RPCs that are falling behind
-    let agreed_head = make_poverty(rpc_list, poverty_list, heads)?;
+    let agreed_head = make_poverty(rpc_list, poverty_list, heads, max_head_lag)?;
     // Send new blocknumber if modified
     let send_if_changed = |number: &mut u64| {
         if number != &agreed_head {
@@ -82,13 +148,26 @@ async fn check(
 
     blocknum_tx.send_if_modified(send_if_changed);
 
+    // Keep track of the head so we know which blocks are too recent to cache
+    if agreed_head != 0 {
+        let mut named_numbers = named_numbers_rwlock.write().unwrap();
+        named_numbers.latest = agreed_head;
+        named_numbers.provisional = false;
+    }
+
     // Check if any rpc nodes made it out
     // Its ok if we call them twice because some might have been accidentally put here
 
     // Do a head check over the current poverty list to see if any nodes are back to normal
     let poverty_heads = head_check(poverty_list, *ttl).await?;
 
-    escape_poverty(rpc_list, poverty_list, poverty_heads, agreed_head)?;
+    escape_poverty(
+        rpc_list,
+        poverty_list,
+        poverty_heads,
+        agreed_head,
+        max_head_lag,
+    )?;
 
     println!("OK!");
 
@@ -158,11 +237,15 @@ async fn head_check(
     Ok(heads)
 }
 
-// Add unresponsive/erroring RPCs to the poverty list
+// Add unresponsive/erroring RPCs to the poverty list, along with the ones
+// more than `max_head_lag` blocks behind the highest head.
+//
+// If every RPC is stuck at the same block, eg. because the chain halted, none of them are behind.
 fn make_poverty(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     heads: Vec<HeadResult>,
+    max_head_lag: u64,
 ) -> Result<u64, HealthError> {
     // Get the highest head reported by the RPCs
     let mut highest_head = 0;
@@ -172,12 +255,14 @@ fn make_poverty(
         }
     }
 
-    // Mark all RPCs that dont report the highest head as erroring
+    // Mark all RPCs that are too far behind the highest head as erroring
     let mut rpc_list_guard = rpc_list.write().unwrap();
     let mut poverty_list_guard = poverty_list.write().unwrap();
 
     for head in heads {
-        if head.reported_head < highest_head {
+        rpc_list_guard[head.rpc_list_index].head = head.reported_head;
+
+        if highest_head - head.reported_head > max_head_lag {
             // Mark the RPC as erroring
             rpc_list_guard[head.rpc_list_index].status.is_erroring = true;
             println!(
@@ -196,19 +281,23 @@ fn make_poverty(
     Ok(highest_head)
 }
 
-// Go over the `poverty_list` to see if any nodes are back to normal
+// Go over the `poverty_list` to see if any nodes are back to normal,
+// ie. within `max_head_lag` blocks of the `agreed_head`
 fn escape_poverty(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_heads: Vec<HeadResult>,
     agreed_head: u64,
+    max_head_lag: u64,
 ) -> Result<(), HealthError> {
     // Check if any nodes made it 🗣️🔥🔥🔥
     let mut poverty_list_guard = poverty_list.write().unwrap();
     let mut rpc_list_guard = rpc_list.write().unwrap();
 
     for head_result in poverty_heads {
-        if head_result.reported_head >= agreed_head {
+        poverty_list_guard[head_result.rpc_list_index].head = head_result.reported_head;
+
+        if agreed_head.saturating_sub(head_result.reported_head) <= max_head_lag {
             let mut rpc = poverty_list_guard[head_result.rpc_list_index].clone();
             rpc.status.is_erroring = false;
             println!(
@@ -238,6 +327,27 @@ fn escape_poverty(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        balancer::{
+            accept_http::send_upstream,
+            hedge::Hedge,
+            selection::{
+                routing::Requirements,
+                select::SelectionStrategy,
+            },
+        },
+        rpc::mock::{
+            mock_rpc,
+            mock_rpc_raw,
+        },
+    };
+    use serde_json::json;
+    use std::sync::atomic::{
+        AtomicBool,
+        AtomicU64,
+        AtomicUsize,
+        Ordering,
+    };
 
     // Construct a hypothetical RPC and heads list for testing
     fn dummy_head_check() -> Vec<HeadResult> {
@@ -271,7 +381,7 @@ mod tests {
         let heads = dummy_head_check();
 
         // Call the make_poverty function
-        let result = make_poverty(&rpc_list, &poverty_list, heads);
+        let result = make_poverty(&rpc_list, &poverty_list, heads, 3);
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -311,7 +421,7 @@ mod tests {
         ];
 
         // Call the escape_poverty function
-        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012);
+        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012, 3);
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -323,4 +433,283 @@ mod tests {
         // The poverty list should have 1 RPC
         assert_eq!(poverty_list_guard.len(), 1);
     }
+
+    #[test]
+    fn test_poverty_head_lag() {
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default(); 3]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        // A couple of blocks behind is fine, more than `max_head_lag` isn't
+        let heads = vec![
+            HeadResult {
+                rpc_list_index: 0,
+                reported_head: 100,
+            },
+            HeadResult {
+                rpc_list_index: 1,
+                reported_head: 98,
+            },
+            HeadResult {
+                rpc_list_index: 2,
+                reported_head: 96,
+            },
+        ];
+        assert_eq!(
+            make_poverty(&rpc_list, &poverty_list, heads, 3).unwrap(),
+            100
+        );
+        assert_eq!(
+            rpc_list
+                .read()
+                .unwrap()
+                .iter()
+                .map(|rpc| rpc.head)
+                .collect::<Vec<u64>>(),
+            vec![100, 98]
+        );
+        assert_eq!(poverty_list.read().unwrap()[0].head, 96);
+
+        // Every RPC being stuck at the same block doesn't remove any of them
+        let heads = (0..2)
+            .map(|rpc_list_index| {
+                HeadResult {
+                    rpc_list_index,
+                    reported_head: 100,
+                }
+            })
+            .collect();
+        assert_eq!(
+            make_poverty(&rpc_list, &poverty_list, heads, 0).unwrap(),
+            100
+        );
+        assert_eq!(rpc_list.read().unwrap().len(), 2);
+
+        // Catching up to within the lag is enough to get back in
+        let heads = vec![HeadResult {
+            rpc_list_index: 0,
+            reported_head: 97,
+        }];
+        escape_poverty(&rpc_list, &poverty_list, heads, 100, 3).unwrap();
+        assert_eq!(rpc_list.read().unwrap().len(), 3);
+        assert_eq!(rpc_list.read().unwrap()[2].head, 97);
+        assert!(poverty_list.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tier_tracker() {
+        let mut tiers = TierTracker::default();
+        let primary = Rpc::default();
+        let fallback = Rpc::default().with_tier(1);
+
+        tiers.update(&[primary.clone(), fallback.clone()]);
+        assert_eq!((tiers.active, tiers.switches), (Some(0), 0));
+
+        tiers.update(std::slice::from_ref(&fallback));
+        assert_eq!((tiers.active, tiers.switches), (Some(1), 1));
+
+        // Losing every RPC isn't a switch, the one they come back in is
+        tiers.update(&[]);
+        assert_eq!((tiers.active, tiers.switches), (Some(1), 1));
+        tiers.update(&[fallback, primary]);
+        assert_eq!((tiers.active, tiers.switches), (Some(0), 2));
+    }
+
+    #[tokio::test]
+    async fn test_check_fallback_tier() {
+        // Mock RPC at block 1000 that counts the other calls it gets, and errors while it's down
+        async fn mock_node(up: Arc<AtomicBool>, reads: Arc<AtomicUsize>) -> String {
+            mock_rpc_raw(move |tx| {
+                if !up.load(Ordering::SeqCst) {
+                    return "<html>502 Bad Gateway</html>".to_string();
+                }
+
+                let result = match tx["method"].as_str() {
+                    Some("eth_blockNumber") => json!("0x3e8"),
+                    _ => {
+                        reads.fetch_add(1, Ordering::SeqCst);
+                        json!("0x1")
+                    }
+                };
+                json!({"jsonrpc": "2.0", "id": tx["id"], "result": result}).to_string()
+            })
+            .await
+        }
+
+        let primary_up = Arc::new(AtomicBool::new(true));
+        let primary_reads = Arc::new(AtomicUsize::new(0));
+        let fallback_reads = Arc::new(AtomicUsize::new(0));
+
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::new(
+                mock_node(Arc::new(AtomicBool::new(true)), Arc::clone(&fallback_reads)).await,
+                10,
+                5.0,
+            )
+            .with_tier(1),
+            Rpc::new(
+                mock_node(Arc::clone(&primary_up), Arc::clone(&primary_reads)).await,
+                10,
+                5.0,
+            ),
+            Rpc::new(
+                mock_node(Arc::clone(&primary_up), Arc::clone(&primary_reads)).await,
+                10,
+                5.0,
+            ),
+        ]));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let (blocknum_tx, _blocknum_rx) = tokio::sync::watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let mut tiers = TierTracker::default();
+
+        let read = || {
+            let rpc_list = Arc::clone(&rpc_list);
+            async move {
+                let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": []});
+                let mut rpc_position = None;
+                send_upstream(
+                    &tx,
+                    &rpc_list,
+                    1000,
+                    1,
+                    &[],
+                    SelectionStrategy::default(),
+                    None,
+                    Requirements::default(),
+                    Hedge::default(),
+                    &mut rpc_position,
+                )
+                .await
+                .unwrap();
+            }
+        };
+        let check_health = || {
+            check(
+                &rpc_list,
+                &poverty_list,
+                &blocknum_tx,
+                &named_numbers,
+                &1000,
+                3,
+            )
+        };
+
+        // The fallback doesn't get anything while the primaries are up
+        check_health().await.unwrap();
+        tiers.update(&rpc_list.read().unwrap());
+        for _ in 0..10 {
+            read().await;
+        }
+        assert_eq!(primary_reads.load(Ordering::SeqCst), 10);
+        assert_eq!(fallback_reads.load(Ordering::SeqCst), 0);
+
+        // All of them go down, traffic moves to the fallback
+        primary_up.store(false, Ordering::SeqCst);
+        check_health().await.unwrap();
+        tiers.update(&rpc_list.read().unwrap());
+        for _ in 0..10 {
+            read().await;
+        }
+        assert_eq!(fallback_reads.load(Ordering::SeqCst), 10);
+        assert_eq!((tiers.active, tiers.switches), (Some(1), 1));
+
+        // And back once they recover
+        primary_up.store(true, Ordering::SeqCst);
+        check_health().await.unwrap();
+        tiers.update(&rpc_list.read().unwrap());
+        for _ in 0..10 {
+            read().await;
+        }
+        assert_eq!(primary_reads.load(Ordering::SeqCst), 20);
+        assert_eq!(fallback_reads.load(Ordering::SeqCst), 10);
+        assert_eq!((tiers.active, tiers.switches), (Some(0), 2));
+    }
+
+    #[tokio::test]
+    async fn test_check_lagging_node() {
+        // Mock RPC reporting `head` that counts the other calls it gets
+        async fn mock_node(head: Arc<AtomicU64>, reads: Arc<AtomicUsize>) -> Rpc {
+            let url = mock_rpc(move |tx| {
+                match tx["method"].as_str() {
+                    Some("eth_blockNumber") => {
+                        json!(format!("0x{:x}", head.load(Ordering::SeqCst)))
+                    }
+                    _ => {
+                        reads.fetch_add(1, Ordering::SeqCst);
+                        json!("0x1")
+                    }
+                }
+            })
+            .await;
+            Rpc::new(url, 10, 5.0)
+        }
+
+        let synced_head = Arc::new(AtomicU64::new(1000));
+        let stuck_head = Arc::new(AtomicU64::new(950));
+        let synced_reads = Arc::new(AtomicUsize::new(0));
+        let stuck_reads = Arc::new(AtomicUsize::new(0));
+
+        let rpc_list = Arc::new(RwLock::new(vec![
+            mock_node(Arc::clone(&synced_head), Arc::clone(&synced_reads)).await,
+            mock_node(Arc::clone(&stuck_head), Arc::clone(&stuck_reads)).await,
+        ]));
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let (blocknum_tx, _blocknum_rx) = tokio::sync::watch::channel(0);
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+
+        let read = || {
+            let rpc_list = Arc::clone(&rpc_list);
+            async move {
+                let tx = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_call", "params": []});
+                let mut rpc_position = None;
+                send_upstream(
+                    &tx,
+                    &rpc_list,
+                    1000,
+                    1,
+                    &[],
+                    SelectionStrategy::RoundRobin,
+                    None,
+                    Requirements::default(),
+                    Hedge::default(),
+                    &mut rpc_position,
+                )
+                .await
+                .unwrap();
+            }
+        };
+
+        check(
+            &rpc_list,
+            &poverty_list,
+            &blocknum_tx,
+            &named_numbers,
+            &1000,
+            3,
+        )
+        .await
+        .unwrap();
+        for _ in 0..10 {
+            read().await;
+        }
+        assert_eq!(stuck_reads.load(Ordering::SeqCst), 0);
+        assert_eq!(synced_reads.load(Ordering::SeqCst), 10);
+
+        // Once it catches up it gets its share again
+        stuck_head.store(999, Ordering::SeqCst);
+        check(
+            &rpc_list,
+            &poverty_list,
+            &blocknum_tx,
+            &named_numbers,
+            &1000,
+            3,
+        )
+        .await
+        .unwrap();
+        for _ in 0..10 {
+            read().await;
+        }
+        assert_eq!(stuck_reads.load(Ordering::SeqCst), 5);
+    }
 }
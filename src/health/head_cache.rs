@@ -1,39 +1,115 @@
+use crate::{
+    database::{
+        backend::CacheBackend,
+        block_index::{
+            invalidate_from,
+            remove_finalized,
+        },
+        error::DatabaseError,
+    },
+    rpc::error::RpcError,
+    Rpc,
+    Settings,
+};
+
 use std::{
     collections::BTreeMap,
     sync::{
         Arc,
         RwLock,
     },
+    time::Duration,
 };
 
 use sled::Batch;
+use tokio::time::timeout;
 use tokio_stream::{
     wrappers::WatchStream,
     StreamExt,
 };
 
+// Hashes of the most recent blocks we've seen, used to detect reorgs
+type BlockHashes = BTreeMap<u64, String>;
+
+// Tree the block hashes are saved to so we can check for reorgs that
+// happened while we were down. Keys are block numbers as big endian u64s.
+const BLOCK_HASH_TREE: &[u8] = b"block_hashes";
+
+// Extra blocks to keep hashes for past `max_reorg_depth`, so a reorg right at
+// the edge of the window still has a parent hash to compare against
+const REORG_WINDOW_MARGIN: u64 = 8;
+
 // Check if we need to do a reorg or if a new block has finalized.
 pub async fn manage_cache(
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     blocknum_rx: tokio::sync::watch::Receiver<u64>,
     finalized_rx: Arc<tokio::sync::watch::Receiver<u64>>,
     cache: &Arc<sled::Db>,
-) -> Result<(), sled::Error> {
-    let mut block_number = 0;
+    backend: &Arc<dyn CacheBackend>,
+    config: &Arc<RwLock<Settings>>,
+) -> Result<(), DatabaseError> {
+    let mut block_hashes = load_block_hashes(cache)?;
     let mut last_finalized = 0;
 
+    // The blocks we saw during the last run might have reorged while we were down
+    if let Some(&last_head) = block_hashes.keys().next_back() {
+        let (ttl, max_reorg_depth) = {
+            let config_guard = config.read().unwrap();
+            (config_guard.ttl, config_guard.max_reorg_depth)
+        };
+
+        let rpc = rpc_at_head(&rpc_list.read().unwrap());
+        if let Some(rpc) = rpc {
+            match find_reorg(&rpc, &mut block_hashes, last_head, max_reorg_depth, ttl).await {
+                Ok(Some(divergence)) => invalidate_reorg(cache, backend, divergence).await?,
+                Ok(None) => {}
+                Err(err) => {
+                    println!(
+                        "\x1b[93mWrn:\x1b[0m Could not check for reorgs since the last run: {}",
+                        err
+                    );
+                }
+            }
+        }
+    }
+
     let mut blocknum_stream = WatchStream::new(blocknum_rx.clone());
 
     // Loop for waiting on new values from the finalized_rx channel
     while blocknum_stream.next().await.is_some() {
         let new_block = *blocknum_rx.borrow();
+        // The channel starts out at 0, and the health check sends 0 when no RPC answered.
+        // Neither is a head, and taking them for one would throw out every block we've seen.
+        if new_block == 0 {
+            continue;
+        }
+        let (ttl, max_reorg_depth) = {
+            let config_guard = config.read().unwrap();
+            (config_guard.ttl, config_guard.max_reorg_depth)
+        };
 
-        // If a new block is less or equal(todo) to the last block in our cache,
-        // that means that the chain has experienced a reorg and that we should
-        // remove everything from the last block to the `new_block`
-        if new_block <= block_number {
-            println!("\x1b[93mWrn:\x1b[0m Reorg detected!\nRemoving stale entries from the cache.");
-            handle_reorg(head_cache, block_number, new_block, cache)?;
+        // Check if the new head still builds on the blocks we've seen.
+        //
+        // If we can't ask a RPC, we can at least tell when the head went backwards.
+        let rpc = rpc_at_head(&rpc_list.read().unwrap());
+        let divergence = match rpc {
+            Some(rpc) => {
+                match find_reorg(&rpc, &mut block_hashes, new_block, max_reorg_depth, ttl).await {
+                    Ok(divergence) => divergence,
+                    Err(err) => {
+                        println!("\x1b[93mWrn:\x1b[0m Could not check for reorgs: {}", err);
+                        head_went_back(&mut block_hashes, new_block)
+                    }
+                }
+            }
+            None => head_went_back(&mut block_hashes, new_block),
+        };
+
+        if let Some(divergence) = divergence {
+            invalidate_reorg(cache, backend, divergence).await?;
+        }
+        if let Err(err) = persist_block_hashes(cache, &block_hashes) {
+            println!("\x1b[93mWrn:\x1b[0m Could not save block hashes: {}", err);
         }
 
         // Check if finalized_stream has changed
@@ -42,155 +118,569 @@ pub async fn manage_cache(
             println!(
                 "\x1b[35mInfo:\x1b[0m New finalized block!\nRemoving stale entries from the cache."
             );
-            // Remove stale entries from the head_cache
-            remove_stale(head_cache, last_finalized)?;
+            // Entries at or below the finalized block can't reorg anymore
+            remove_finalized(cache, last_finalized)?;
         }
-
-        block_number = new_block;
     }
     Ok(())
 }
 
-// We use the head_cache to store keys of querries we made near the tip
-// If a reorg happens, we need to remove all querries in the reorg range
-// from the sled database.
-fn handle_reorg(
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
-    block_number: u64,
-    new_block: u64,
+// The RPC with the highest head, ie. the one that reported the head we agreed on.
+//
+// The others can be up to `max_head_lag` blocks behind, so they might not have it yet.
+fn rpc_at_head(rpc_list: &[Rpc]) -> Option<Rpc> {
+    rpc_list.iter().max_by_key(|rpc| rpc.head).cloned()
+}
+
+// Remove everything from the divergence point to the new head
+async fn invalidate_reorg(
     cache: &Arc<sled::Db>,
-) -> Result<(), sled::Error> {
-    // sled batch
+    backend: &Arc<dyn CacheBackend>,
+    divergence: u64,
+) -> Result<(), DatabaseError> {
+    let removed = invalidate_from(cache, backend.as_ref(), divergence).await?;
+    println!(
+        "\x1b[93mWrn:\x1b[0m Reorg detected at block {}!\nRemoved {} stale entries from the cache.",
+        divergence, removed
+    );
+
+    Ok(())
+}
+
+// Save `block_hashes`, dropping the ones that got pruned or reorged out since the last save
+fn persist_block_hashes(cache: &sled::Db, block_hashes: &BlockHashes) -> Result<(), sled::Error> {
+    let tree = cache.open_tree(BLOCK_HASH_TREE)?;
+    let oldest = block_hashes.keys().next().copied().unwrap_or(u64::MAX);
+    let newest = block_hashes.keys().next_back().copied().unwrap_or(0);
+
     let mut batch = Batch::default();
+    for key in tree.range(..oldest.to_be_bytes()).keys() {
+        batch.remove(key?);
+    }
+    for key in tree.range(newest.saturating_add(1).to_be_bytes()..).keys() {
+        batch.remove(key?);
+    }
+    for (number, hash) in block_hashes {
+        batch.insert(&number.to_be_bytes(), hash.as_bytes());
+    }
+
+    tree.apply_batch(batch)
+}
+
+// Load the hashes saved by `persist_block_hashes` during the last run
+fn load_block_hashes(cache: &sled::Db) -> Result<BlockHashes, sled::Error> {
+    let mut block_hashes = BlockHashes::new();
+    for entry in cache.open_tree(BLOCK_HASH_TREE)?.iter() {
+        let (number, hash) = entry?;
+        if let Ok(number) = number.as_ref().try_into() {
+            block_hashes.insert(
+                u64::from_be_bytes(number),
+                String::from_utf8_lossy(&hash).into_owned(),
+            );
+        }
+    }
+
+    Ok(block_hashes)
+}
+
+// Get the hash and parent hash of block `number`, giving up after `ttl` ms
+async fn get_block_hashes(rpc: &Rpc, number: u64, ttl: u128) -> Result<(String, String), RpcError> {
+    match timeout(
+        Duration::from_millis(ttl.try_into().unwrap()),
+        rpc.get_block_hashes(number),
+    )
+    .await
+    {
+        Ok(hashes) => hashes,
+        Err(_) => Err(RpcError::Unresponsive),
+    }
+}
+
+// Compare the block at `head` with the hashes we've seen and return the
+// lowest block that got replaced, if any.
+//
+// We walk back from `head` until we reach a block we've seen whose hash matches
+// the parent hash we got, up to `max_depth` blocks. The head can move more than
+// one block between checks, so this also fetches the blocks we skipped over.
+async fn find_reorg(
+    rpc: &Rpc,
+    block_hashes: &mut BlockHashes,
+    head: u64,
+    max_depth: u64,
+    ttl: u128,
+) -> Result<Option<u64>, RpcError> {
+    // If the chain got shorter, everything above the new head is gone
+    let mut divergence = head_went_back(block_hashes, head);
+
+    let mut number = head;
+    let (mut hash, mut parent_hash) = get_block_hashes(rpc, number, ttl).await?;
+    for _ in 0..max_depth {
+        if let Some(known) = block_hashes.insert(number, hash.clone()) {
+            if known != hash {
+                divergence = Some(number);
+            }
+        }
+
+        if number == 0 {
+            break;
+        }
 
-    // Go over the head cache and get all the keys from block_number to new_block
-    let mut head_cache_guard = head_cache.write().unwrap();
-    for i in block_number..new_block + 1 {
-        if let Some(keys) = head_cache_guard.get(&i) {
-            for key in keys {
-                batch.remove(key.as_bytes());
+        // Stop once we're building on a block we've seen, or there's none left below us
+        match block_hashes.range(..number).next_back() {
+            Some((known_number, known)) if *known_number == number - 1 && *known == parent_hash => {
+                break
             }
-            // Remove the entry from the head_cache
-            head_cache_guard.remove(&i);
+            Some(_) => {
+                number -= 1;
+                (hash, parent_hash) = get_block_hashes(rpc, number, ttl).await?;
+            }
+            None => break,
         }
     }
 
-    // Apply the batch to the cache
-    cache.apply_batch(batch)?;
+    prune_block_hashes(block_hashes, head, max_depth);
 
-    Ok(())
+    Ok(divergence)
 }
 
-// Removes stale entries from `head_cache`
+// Only keep the hashes within the reorg window of `head`, returning how many got pruned.
 //
-// Once a new block finalizes, we can be sure that certain TXs wont
-// reorg, so theyre safe to be permanantly in the cache.
-fn remove_stale(
-    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<String>>>>,
-    block_number: u64,
-) -> Result<(), sled::Error> {
-    // Get the lowest block_number from the BTreeMap
-    let mut head_cache_guard = head_cache.write().unwrap();
-
-    let oldest = match head_cache_guard.iter().next() {
-        Some((oldest, _)) => *oldest,
-        None => return Ok(()), // Return early if the map is empty
-    };
+// Blocks are pruned from the oldest one up, so this only touches the pruned entries.
+fn prune_block_hashes(block_hashes: &mut BlockHashes, head: u64, max_depth: u64) -> usize {
+    let oldest = head.saturating_sub(max_depth + REORG_WINDOW_MARGIN);
 
-    // Remove all entries from the head_cache up to block_number
-    for i in oldest..=block_number + 1 {
-        head_cache_guard.remove(&i);
+    let mut pruned = 0;
+    while let Some(entry) = block_hashes.first_entry() {
+        if *entry.key() >= oldest {
+            break;
+        }
+        entry.remove();
+        pruned += 1;
     }
 
-    Ok(())
+    pruned
+}
+
+// If we've seen blocks above `head`, they're not part of the chain anymore
+fn head_went_back(block_hashes: &mut BlockHashes, head: u64) -> Option<u64> {
+    if block_hashes.split_off(&(head + 1)).is_empty() {
+        return None;
+    }
+
+    Some(head + 1)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use sled::Config;
+    use crate::{
+        database::{
+            block_index::index_block,
+            sled_backend::SledBackend,
+        },
+        rpc::mock::mock_rpc,
+    };
+    use serde_json::{
+        json,
+        Value,
+    };
+    use std::collections::HashMap;
+
+    // Chain served by the mock RPC, block number -> (hash, parent hash)
+    type Chain = Arc<RwLock<HashMap<u64, (String, String)>>>;
+
+    // Build a chain from `from` to `to` on top of `parent`, using `fork` to make the hashes unique
+    fn build_chain(chain: &Chain, from: u64, to: u64, parent: &str, fork: &str) {
+        let mut chain = chain.write().unwrap();
+        let mut parent = parent.to_string();
+        for number in from..=to {
+            let hash = format!("0x{}{:x}", fork, number);
+            chain.insert(number, (hash.clone(), parent));
+            parent = hash;
+        }
+    }
 
-    // #[tokio::test]
-    // async fn test_manage_cache() {
-    //     // Create test data and resources
-    //     let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
-    //     let (blocknum_tx, blocknum_rx) = tokio::sync::watch::channel(0);
-    //     let (finalized_tx, finalized_rx) = tokio::sync::watch::channel(0);
-    //     let cache = Arc::new(Config::new().temporary(true).open().unwrap());
+    async fn mock_chain(chain: &Chain) -> Rpc {
+        let chain = Arc::clone(chain);
+        let url = mock_rpc(move |tx| {
+            let number = u64::from_str_radix(
+                tx["params"][0].as_str().unwrap().trim_start_matches("0x"),
+                16,
+            )
+            .unwrap();
 
-    //     // Spawn the manage_cache function in a separate thread
-    //     let manage_cache_handle = tokio::spawn(async move {
-    //         let head_cache_clone = Arc::clone(&head_cache);
-    //         let cache_clone = Arc::clone(&cache);
+            match chain.read().unwrap().get(&number) {
+                Some((hash, parent_hash)) => {
+                    json!({
+                        "number": format!("0x{:x}", number),
+                        "hash": hash,
+                        "parentHash": parent_hash,
+                    })
+                }
+                None => Value::Null,
+            }
+        })
+        .await;
+
+        Rpc::new(url, 10, 5.0)
+    }
+
+    #[tokio::test]
+    async fn test_find_reorg() {
+        let chain: Chain = Arc::new(RwLock::new(HashMap::new()));
+        build_chain(&chain, 1, 10, "0x0", "a");
+        let rpc = mock_chain(&chain).await;
+        let mut block_hashes = BlockHashes::new();
+
+        for head in 8..=10 {
+            assert_eq!(
+                find_reorg(&rpc, &mut block_hashes, head, 64, 1000)
+                    .await
+                    .unwrap(),
+                None
+            );
+        }
+
+        // Blocks 9 and 10 get replaced, and the new chain is now at 11
+        let parent = chain.read().unwrap()[&8].0.clone();
+        build_chain(&chain, 9, 11, &parent, "b");
+        assert_eq!(
+            find_reorg(&rpc, &mut block_hashes, 11, 64, 1000)
+                .await
+                .unwrap(),
+            Some(9)
+        );
+        assert_eq!(block_hashes[&10], chain.read().unwrap()[&10].0);
+
+        // No reorg on top of the new chain
+        let parent = chain.read().unwrap()[&11].0.clone();
+        build_chain(&chain, 12, 12, &parent, "b");
+        assert_eq!(
+            find_reorg(&rpc, &mut block_hashes, 12, 64, 1000)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_reorg_head_jumps() {
+        let chain: Chain = Arc::new(RwLock::new(HashMap::new()));
+        build_chain(&chain, 1, 20, "0x0", "a");
+        let rpc = mock_chain(&chain).await;
+        let mut block_hashes = BlockHashes::new();
+
+        for head in 6..=8 {
+            find_reorg(&rpc, &mut block_hashes, head, 64, 1000)
+                .await
+                .unwrap();
+        }
+
+        // The head jumps from 8 to 11, the blocks in between get filled in
+        assert_eq!(
+            find_reorg(&rpc, &mut block_hashes, 11, 64, 1000)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(block_hashes[&9], chain.read().unwrap()[&9].0);
+        assert_eq!(block_hashes[&10], chain.read().unwrap()[&10].0);
+
+        // Blocks 9 to 11 get replaced while the head jumps from 11 to 14
+        let parent = chain.read().unwrap()[&8].0.clone();
+        build_chain(&chain, 9, 14, &parent, "b");
+        assert_eq!(
+            find_reorg(&rpc, &mut block_hashes, 14, 64, 1000)
+                .await
+                .unwrap(),
+            Some(9)
+        );
+        assert_eq!(block_hashes[&11], chain.read().unwrap()[&11].0);
 
-    //         let _ = manage_cache(&head_cache_clone, blocknum_rx, finalized_rx, &cache_clone).await;
-    //     });
+        // Same if the last block we've seen is the one that got replaced
+        let parent = chain.read().unwrap()[&13].0.clone();
+        build_chain(&chain, 14, 17, &parent, "c");
+        assert_eq!(
+            find_reorg(&rpc, &mut block_hashes, 17, 64, 1000)
+                .await
+                .unwrap(),
+            Some(14)
+        );
+    }
+
+    #[test]
+    fn test_rpc_at_head() {
+        let mut lagging = Rpc::new("http://lagging".to_string(), 10, 5.0);
+        lagging.head = 8;
+        let mut synced = Rpc::new("http://synced".to_string(), 10, 5.0);
+        synced.head = 10;
+
+        let rpc = rpc_at_head(&[lagging, synced]).unwrap();
+        assert_eq!(rpc.url, "http://synced");
+        assert!(rpc_at_head(&[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_find_reorg_max_depth() {
+        let chain: Chain = Arc::new(RwLock::new(HashMap::new()));
+        build_chain(&chain, 1, 10, "0x0", "a");
+        let rpc = mock_chain(&chain).await;
+        let mut block_hashes = BlockHashes::new();
+
+        for head in 1..=10 {
+            find_reorg(&rpc, &mut block_hashes, head, 2, 1000)
+                .await
+                .unwrap();
+        }
 
-    //     // Simulate changes in blocknum_rx and finalized_rx
-    //     blocknum_tx.send(5).unwrap();
-    //     finalized_tx.send(2).unwrap();
+        // We only keep hashes within the reorg window
+        assert_eq!(
+            block_hashes.keys().copied().collect::<Vec<_>>(),
+            (1..=10).collect::<Vec<_>>()
+        );
+
+        // We don't walk back further than `max_depth`
+        build_chain(&chain, 5, 11, "0x0", "b");
+        assert_eq!(
+            find_reorg(&rpc, &mut block_hashes, 11, 2, 1000)
+                .await
+                .unwrap(),
+            Some(10)
+        );
+
+        // Older blocks get pruned as the head moves on
+        assert_eq!(prune_block_hashes(&mut block_hashes, 20, 2), 9);
+        assert_eq!(block_hashes.keys().copied().collect::<Vec<_>>(), [10, 11]);
+    }
+
+    // Follow a long chain and make sure we don't hold on to every block we've seen
+    #[tokio::test]
+    async fn test_block_hashes_bounded() {
+        const BLOCKS: u64 = 10_000;
+        const MAX_DEPTH: u64 = 64;
+
+        let chain: Chain = Arc::new(RwLock::new(HashMap::new()));
+        build_chain(&chain, 1, BLOCKS, "0x0", "a");
+        let rpc = mock_chain(&chain).await;
+        let mut block_hashes = BlockHashes::new();
+
+        for head in 1..=BLOCKS {
+            find_reorg(&rpc, &mut block_hashes, head, MAX_DEPTH, 1000)
+                .await
+                .unwrap();
+            assert!(block_hashes.len() as u64 <= MAX_DEPTH + REORG_WINDOW_MARGIN + 1);
+        }
 
-    //     // Wait for the manage_cache function to complete
-    //     let result = manage_cache_handle.await;
-    //     assert!(result.is_ok());
-    // }
+        // Everything within the window is still there to compare against
+        assert_eq!(
+            *block_hashes.keys().next().unwrap(),
+            BLOCKS - MAX_DEPTH - REORG_WINDOW_MARGIN
+        );
+        assert_eq!(block_hashes[&BLOCKS], chain.read().unwrap()[&BLOCKS].0);
+    }
 
     #[test]
-    fn test_handle_reorg() {
-        // Create test data and resources
-        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
-        let cache = Arc::new(Config::new().temporary(true).open().unwrap());
-
-        let _ = cache.insert("key1", "value1");
-        let _ = cache.insert("key2", "value2");
-        let _ = cache.insert("key3", "value3");
-
-        // Add some data to the head_cache
-        {
-            let mut head_cache_guard = head_cache.write().unwrap();
-            head_cache_guard.insert(1, vec!["key1".to_string()]);
-            head_cache_guard.insert(2, vec!["key2".to_string()]);
-            head_cache_guard.insert(3, vec!["key3".to_string()]);
-        }
-
-        // Call handle_reorg
-        let result = handle_reorg(&head_cache, 2, 3, &cache);
-
-        // Verify the result and check if the data is removed from the cache
-        assert!(result.is_ok());
-        let head_cache_guard = head_cache.read().unwrap();
-        assert!(head_cache_guard.contains_key(&1));
-        assert!(!head_cache_guard.contains_key(&2));
-        assert!(!head_cache_guard.contains_key(&3));
-
-        // Check if the data is removed from the cache
-        let key1 = cache.get("key1").unwrap();
-        assert!(key1.is_some());
-        let key2 = cache.get("key2").unwrap();
-        assert!(key2.is_none());
-        let key3 = cache.get("key3").unwrap();
-        assert!(key3.is_none());
+    fn test_head_went_back() {
+        let mut block_hashes = BlockHashes::from([
+            (1, "0xa1".to_string()),
+            (2, "0xa2".to_string()),
+            (3, "0xa3".to_string()),
+        ]);
+
+        assert_eq!(head_went_back(&mut block_hashes, 3), None);
+        assert_eq!(head_went_back(&mut block_hashes, 1), Some(2));
+        assert_eq!(block_hashes.keys().copied().collect::<Vec<_>>(), [1]);
+    }
+
+    #[tokio::test]
+    async fn test_manage_cache_reorg() {
+        let chain: Chain = Arc::new(RwLock::new(HashMap::new()));
+        build_chain(&chain, 1, 10, "0x0", "a");
+        let rpc_list = Arc::new(RwLock::new(vec![mock_chain(&chain).await]));
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+        let config = Arc::new(RwLock::new(Settings::default()));
+
+        // Cache one entry per block
+        for block in 7..=10u64 {
+            let key = format!("key{}", block);
+            cache.insert(&key, "value").unwrap();
+            index_block(&cache, block, key.as_bytes()).unwrap();
+        }
+
+        let (blocknum_tx, blocknum_rx) = tokio::sync::watch::channel(0);
+        let (_finalized_tx, finalized_rx) = tokio::sync::watch::channel(0);
+        let rpc_list_cache = Arc::clone(&rpc_list);
+        let cache_manage = Arc::clone(&cache);
+        let backend: Arc<dyn CacheBackend> = Arc::new(SledBackend::new(Arc::clone(&cache)));
+        tokio::spawn(async move {
+            let _ = manage_cache(
+                &rpc_list_cache,
+                blocknum_rx,
+                Arc::new(finalized_rx),
+                &cache_manage,
+                &backend,
+                &config,
+            )
+            .await;
+        });
+
+        // Follow the chain up to 10, waiting for each block to be processed
+        for head in 8..=10 {
+            blocknum_tx.send(head).unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(cache.get("key9").unwrap().is_some());
+
+        // 2 block reorg
+        let parent = chain.read().unwrap()[&8].0.clone();
+        build_chain(&chain, 9, 11, &parent, "b");
+        blocknum_tx.send(11).unwrap();
+
+        let mut removed = false;
+        for _ in 0..50 {
+            if cache.get("key9").unwrap().is_none() {
+                removed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert!(removed);
+        assert!(cache.get("key10").unwrap().is_none());
+        assert!(cache.get("key7").unwrap().is_some());
+        assert!(cache.get("key8").unwrap().is_some());
     }
 
     #[test]
-    fn test_remove_stale() {
-        // Create test data and resources
-        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+    fn test_persist_block_hashes() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+        assert!(load_block_hashes(&cache).unwrap().is_empty());
+
+        let mut block_hashes: BlockHashes = (1..=5)
+            .map(|number| (number, format!("0xa{}", number)))
+            .collect();
+        persist_block_hashes(&cache, &block_hashes).unwrap();
+        assert_eq!(load_block_hashes(&cache).unwrap(), block_hashes);
 
-        // Add some data to the head_cache
-        {
-            let mut head_cache_guard = head_cache.write().unwrap();
-            head_cache_guard.insert(1, vec!["key1".to_string()]);
-            head_cache_guard.insert(2, vec!["key2".to_string()]);
+        // Pruned and reorged out blocks are dropped from the saved ones too
+        prune_block_hashes(&mut block_hashes, 11, 0);
+        head_went_back(&mut block_hashes, 4);
+        persist_block_hashes(&cache, &block_hashes).unwrap();
+        assert_eq!(
+            load_block_hashes(&cache)
+                .unwrap()
+                .keys()
+                .copied()
+                .collect::<Vec<_>>(),
+            [3, 4]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_manage_cache_reorg_while_down() {
+        let chain: Chain = Arc::new(RwLock::new(HashMap::new()));
+        build_chain(&chain, 1, 10, "0x0", "a");
+        let rpc_list = Arc::new(RwLock::new(vec![mock_chain(&chain).await]));
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+        let config = Arc::new(RwLock::new(Settings::default()));
+
+        // The last run saw the chain up to 10 and cached one entry per block
+        let block_hashes: BlockHashes = chain
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(number, (hash, _))| (*number, hash.clone()))
+            .collect();
+        persist_block_hashes(&cache, &block_hashes).unwrap();
+        for block in 7..=10u64 {
+            let key = format!("key{}", block);
+            cache.insert(&key, "value").unwrap();
+            index_block(&cache, block, key.as_bytes()).unwrap();
         }
 
-        // Call remove_stale
-        let result = remove_stale(&head_cache, 1);
+        // Blocks 9 and 10 got replaced in the meantime
+        let parent = chain.read().unwrap()[&8].0.clone();
+        build_chain(&chain, 9, 10, &parent, "b");
+
+        // Caught before we see a new head
+        let (_blocknum_tx, blocknum_rx) = tokio::sync::watch::channel(0);
+        let (_finalized_tx, finalized_rx) = tokio::sync::watch::channel(0);
+        let cache_manage = Arc::clone(&cache);
+        let backend: Arc<dyn CacheBackend> = Arc::new(SledBackend::new(Arc::clone(&cache)));
+        tokio::spawn(async move {
+            let _ = manage_cache(
+                &rpc_list,
+                blocknum_rx,
+                Arc::new(finalized_rx),
+                &cache_manage,
+                &backend,
+                &config,
+            )
+            .await;
+        });
 
-        // Verify the result and check if the data is removed from the cache
-        assert!(result.is_ok());
-        let head_cache_guard = head_cache.read().unwrap();
-        assert!(!head_cache_guard.contains_key(&1));
-        assert!(!head_cache_guard.contains_key(&2));
+        let mut removed = false;
+        for _ in 0..50 {
+            if cache.get("key9").unwrap().is_none() {
+                removed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert!(removed);
+        assert!(cache.get("key10").unwrap().is_none());
+        assert!(cache.get("key8").unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_manage_cache_restart_keeps_hashes() {
+        // Block 0 is served, so looking it up doesn't fail
+        let chain: Chain = Arc::new(RwLock::new(HashMap::new()));
+        build_chain(&chain, 0, 10, "0x0", "a");
+        let rpc_list = Arc::new(RwLock::new(vec![mock_chain(&chain).await]));
+        let cache = Arc::new(sled::Config::new().temporary(true).open().unwrap());
+        let config = Arc::new(RwLock::new(Settings::default()));
+
+        // The last run saw the chain up to 10 and cached one entry per block
+        let block_hashes: BlockHashes = chain
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(number, (hash, _))| (*number, hash.clone()))
+            .collect();
+        persist_block_hashes(&cache, &block_hashes).unwrap();
+        for block in 7..=10u64 {
+            let key = format!("key{}", block);
+            cache.insert(&key, "value").unwrap();
+            index_block(&cache, block, key.as_bytes()).unwrap();
+        }
+
+        // Nothing reorged, and the channel only has its initial 0 in it
+        let (blocknum_tx, blocknum_rx) = tokio::sync::watch::channel(0);
+        let (_finalized_tx, finalized_rx) = tokio::sync::watch::channel(0);
+        let cache_manage = Arc::clone(&cache);
+        let backend: Arc<dyn CacheBackend> = Arc::new(SledBackend::new(Arc::clone(&cache)));
+        tokio::spawn(async move {
+            let _ = manage_cache(
+                &rpc_list,
+                blocknum_rx,
+                Arc::new(finalized_rx),
+                &cache_manage,
+                &backend,
+                &config,
+            )
+            .await;
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Neither that nor a health check where no RPC answered drops anything
+        blocknum_tx.send(0).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        for block in 7..=10u64 {
+            assert!(cache.get(format!("key{}", block)).unwrap().is_some());
+        }
+        assert_eq!(load_block_hashes(&cache).unwrap(), block_hashes);
     }
 }
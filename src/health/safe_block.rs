@@ -1,7 +1,12 @@
 use crate::{
+    database::meta::{
+        get_meta,
+        set_meta,
+    },
     rpc::error::RpcError,
     Rpc,
 };
+use sled::Db;
 use std::sync::{
     Arc,
     RwLock,
@@ -22,9 +27,45 @@ pub struct NamedBlocknumbers {
     pub finalized: u64,
     pub pending: u64,
     pub number: u64,
+    // Loaded from the last run and not confirmed by a health check yet.
+    //
+    // Good enough to read cached entries by, but nothing gets written until it's confirmed.
+    pub provisional: bool,
+}
+
+// Meta key the latest, safe and finalized blocks get saved under, as big endian u64s
+const NAMED_NUMBERS_KEY: &[u8] = b"named_numbers";
+
+// Save the blocks we resolve tags with, so we can use them right after a restart
+pub fn persist_named_numbers(cache: &Db, named: &NamedBlocknumbers) -> Result<(), sled::Error> {
+    let mut value = Vec::with_capacity(24);
+    for number in [named.latest, named.safe, named.finalized] {
+        value.extend_from_slice(&number.to_be_bytes());
+    }
+
+    set_meta(cache, NAMED_NUMBERS_KEY, &value)
+}
+
+// Load the blocks saved by `persist_named_numbers` during the last run.
+//
+// They're marked as provisional until a health check confirms them.
+pub fn load_named_numbers(cache: &Db) -> Result<NamedBlocknumbers, sled::Error> {
+    let value = match get_meta(cache, NAMED_NUMBERS_KEY)? {
+        Some(value) if value.len() == 24 && value[..8] != [0; 8] => value,
+        _ => return Ok(NamedBlocknumbers::default()),
+    };
+    let number = |i: usize| u64::from_be_bytes(value[i * 8..(i + 1) * 8].try_into().unwrap());
+
+    Ok(NamedBlocknumbers {
+        latest: number(0),
+        safe: number(1),
+        finalized: number(2),
+        provisional: true,
+        ..Default::default()
+    })
 }
 
-// Get the latest finalized block
+// Get the latest safe and finalized blocks
 pub async fn get_safe_block(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     finalized_tx: &tokio::sync::watch::Sender<u64>,
@@ -33,10 +74,11 @@ pub async fn get_safe_block(
 ) -> Result<u64, RpcError> {
     let len = rpc_list.read().unwrap().len();
     let mut safe = 0;
+    let mut finalized = 0;
 
     // If len == 0 return 0
     if len == 0 {
-        return Ok(safe);
+        return Ok(finalized);
     }
 
     // Create a vector to store the futures of all RPC requests
@@ -52,16 +94,19 @@ pub async fn get_safe_block(
 
         // Spawn a future for each RPC
         let rpc_future = async move {
-            let a = rpc_clone.get_finalized_block();
-            let result = timeout(Duration::from_millis(ttl), a).await;
+            let (reported_safe, reported_finalized) = tokio::join!(
+                timeout(Duration::from_millis(ttl), rpc_clone.get_safe_block()),
+                timeout(Duration::from_millis(ttl), rpc_clone.get_finalized_block()),
+            );
 
-            let reported_finalized = match result {
-                Ok(response) => response.unwrap(), // Handle timeout as 0
-                Err(_) => 0,                       // Handle timeout as 0
-            };
+            // Handle errors and timeouts as 0
+            let reported = (
+                reported_safe.ok().and_then(Result::ok).unwrap_or(0),
+                reported_finalized.ok().and_then(Result::ok).unwrap_or(0),
+            );
 
             // Send the result to the main thread through the channel
-            tx.send(reported_finalized)
+            tx.send(reported)
                 .await
                 .expect("head check: Channel send error");
         };
@@ -76,17 +121,16 @@ pub async fn get_safe_block(
 
     // Collect the results in order from the channel
     for _ in 0..len {
-        if let Some(result) = rx.recv().await {
-            if result > safe {
-                safe = result;
-            }
+        if let Some((reported_safe, reported_finalized)) = rx.recv().await {
+            safe = safe.max(reported_safe);
+            finalized = finalized.max(reported_finalized);
         }
     }
 
     // Send new blocknumber if modified
     let send_if_changed = |number: &mut u64| {
-        if number != &safe {
-            *number = safe;
+        if number != &finalized {
+            *number = finalized;
             return true;
         }
         false
@@ -94,11 +138,42 @@ pub async fn get_safe_block(
 
     finalized_tx.send_if_modified(send_if_changed);
 
-    // println!("Safe block: {}", safe);
-
     // Return as NamedBlocknumbers
     let mut nn_rwlock = named_numbers_rwlock.write().unwrap();
     nn_rwlock.safe = safe;
+    nn_rwlock.finalized = finalized;
+
+    Ok(finalized)
+}
 
-    Ok(safe)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persist_named_numbers() {
+        let cache = sled::Config::new().temporary(true).open().unwrap();
+
+        // Nothing saved yet, so nothing to resolve tags with
+        assert_eq!(
+            load_named_numbers(&cache).unwrap(),
+            NamedBlocknumbers::default()
+        );
+
+        let named = NamedBlocknumbers {
+            latest: 100,
+            safe: 90,
+            finalized: 80,
+            ..Default::default()
+        };
+        persist_named_numbers(&cache, &named).unwrap();
+
+        assert_eq!(
+            load_named_numbers(&cache).unwrap(),
+            NamedBlocknumbers {
+                provisional: true,
+                ..named
+            }
+        );
+    }
 }
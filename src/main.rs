@@ -1,32 +1,67 @@
 mod admin;
 mod balancer;
 mod config;
+mod database;
 mod health;
 mod rpc;
 
 use crate::{
     admin::listener::listen_for_admin_requests,
-    balancer::accept_http::accept_request,
+    balancer::accept_http::{
+        accept_request,
+        CacheArgs,
+        RequestParams,
+    },
+    balancer::revalidate::Revalidator,
+    balancer::warmup::{
+        wait_for_head,
+        warmup,
+        warmup_calls,
+        HEAD_TIMEOUT,
+    },
     config::{
         cache_setup::setup_data,
         cli_args::create_match,
+        setup::fetch_chain_id,
         types::Settings,
     },
+    database::{
+        chain_id::pin_chain_id,
+        eviction::{
+            evict_loop,
+            reset_entry_count,
+        },
+        hasher::pin_hasher,
+        expiry::prune_expired_loop,
+        hot_cache::{
+            sync_hot_cache,
+            HotCache,
+        },
+        metrics::CacheMetrics,
+        version::check_version,
+        write_behind::{
+            write_behind,
+            CacheWriter,
+        },
+    },
     health::{
         check::health_check,
         head_cache::manage_cache,
-        safe_block::NamedBlocknumbers,
+        safe_block::{
+            load_named_numbers,
+            NamedBlocknumbers,
+        },
     },
     rpc::types::Rpc,
 };
 
 use std::{
-    collections::BTreeMap,
     println,
     sync::{
         Arc,
         RwLock,
     },
+    time::Duration,
 };
 
 use tokio::net::TcpListener;
@@ -48,13 +83,44 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = Arc::new(RwLock::new(Settings::new(create_match()).await));
 
     // Copy the configuration values we need
-    let (addr_clone, do_clear_clone, health_check_clone, admin_enabled_clone) = {
+    let (
+        addr_clone,
+        do_clear_clone,
+        health_check_clone,
+        admin_enabled_clone,
+        cache_prune_interval_clone,
+        max_cache_size_clone,
+        eviction_interval_clone,
+        hot_cache_entries_clone,
+        hot_cache_max_entry_bytes_clone,
+        write_queue_size_clone,
+        revalidate_fraction_clone,
+        warmup_clone,
+        ttl_clone,
+        allow_chain_id_change_clone,
+        cache_hasher_clone,
+        flush_on_hash_mismatch_clone,
+        cache_backend_clone,
+    ) = {
         let config_guard = config.read().unwrap();
         (
             config_guard.address,
             config_guard.do_clear,
             config_guard.health_check,
             config_guard.admin.enabled,
+            config_guard.cache_prune_interval,
+            config_guard.max_cache_size,
+            config_guard.eviction_interval,
+            config_guard.hot_cache_entries,
+            config_guard.hot_cache_max_entry_bytes,
+            config_guard.write_queue_size,
+            config_guard.revalidate_fraction,
+            config_guard.warmup.clone(),
+            config_guard.ttl,
+            config_guard.allow_chain_id_change,
+            config_guard.cache_hasher,
+            config_guard.flush_on_hash_mismatch,
+            config_guard.cache_backend.clone(),
         )
     };
 
@@ -64,19 +130,47 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Create/Open sled DB
     let cache = Arc::new(config.read().unwrap().sled_config.open().unwrap());
 
-    // Cache for storing querries near the tip
-    let head_cache = Arc::new(RwLock::new(BTreeMap::<u64, Vec<String>>::new()));
-
     // Clear database if specified
     if do_clear_clone {
         cache.clear().unwrap();
+        reset_entry_count(&cache).unwrap();
         println!("\x1b[93mWrn:\x1b[0m All data cleared from the database.");
     }
+
+    // Migrate caches written by older versions, and refuse ones written by newer versions
+    check_version(&cache)?;
+    // Make sure the cache keys we'd look up were made with the same hash function
+    pin_hasher(&cache, cache_hasher_clone, flush_on_hash_mismatch_clone)?;
     // Insert data about blutgang and our settings into the DB
     //
     // Print any relevant warnings about a misconfigured DB. Check docs for more
     setup_data(Arc::clone(&cache));
 
+    // Pin the cache to the chain our RPCs are on so we never serve responses from another one
+    let rpc_list_chain_id = rpc_list_rwlock.read().unwrap().clone();
+    let chain_id = fetch_chain_id(&rpc_list_chain_id, ttl_clone).await?;
+    let chain_id = pin_chain_id(&cache, chain_id, allow_chain_id_change_clone)?;
+    println!("\x1b[35mInfo:\x1b[0m Using chain id: {}", chain_id);
+
+    // Cache hit/miss counters, exposed through the admin namespace
+    let cache_metrics = Arc::new(CacheMetrics::default());
+
+    // In-memory cache of the hottest entries, kept in sync with sled
+    let hot_cache = Arc::new(
+        HotCache::new(hot_cache_entries_clone)
+            .with_max_entry_bytes(hot_cache_max_entry_bytes_clone),
+    );
+    if hot_cache_entries_clone != 0 {
+        let cache_hot = Arc::clone(&cache);
+        let hot_cache_sync = Arc::clone(&hot_cache);
+        tokio::task::spawn(async move {
+            sync_hot_cache(cache_hot, hot_cache_sync).await;
+        });
+    }
+
+    // Where cached responses are stored, indexes stay in sled regardless
+    let backend = cache_backend_clone.open(&cache, &hot_cache).await?;
+
     // We create a TcpListener and bind it to 127.0.0.1:3000
     let listener = TcpListener::bind(addr_clone).await?;
     println!("\x1b[35mInfo:\x1b[0m Bound to: {}", addr_clone);
@@ -85,7 +179,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     //
     // Also handle the finalized block tracking in this thread
     let rpc_poverty_list = Arc::new(RwLock::new(Vec::<Rpc>::new()));
-    let named_blocknumbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+    // Resolve tags with the blocks from the last run until the first health check is done
+    let named_blocknumbers = if health_check_clone {
+        load_named_numbers(&cache)?
+    } else {
+        NamedBlocknumbers::default()
+    };
+    if named_blocknumbers.provisional {
+        println!(
+            "\x1b[35mInfo:\x1b[0m Using head block {} from the last run until the first health check",
+            named_blocknumbers.latest
+        );
+    }
+    let named_blocknumbers = Arc::new(RwLock::new(named_blocknumbers));
     let (blocknum_tx, blocknum_rx) = watch::channel(0);
     let (finalized_tx, finalized_rx) = watch::channel(0);
     let finalized_rx_arc = Arc::new(finalized_rx);
@@ -95,6 +201,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let poverty_list_health = Arc::clone(&rpc_poverty_list);
         let named_blocknumbers_health = Arc::clone(&named_blocknumbers);
         let config_health = Arc::clone(&config);
+        let cache_health = Arc::clone(&cache);
 
         tokio::task::spawn(async move {
             let _ = health_check(
@@ -104,6 +211,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 finalized_tx,
                 &named_blocknumbers_health,
                 &config_health,
+                &cache_health,
             )
             .await;
         });
@@ -114,6 +222,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let rpc_list_admin = Arc::clone(&rpc_list_rwlock);
         let poverty_list_admin = Arc::clone(&rpc_poverty_list);
         let cache_admin = Arc::clone(&cache);
+        let backend_admin = Arc::clone(&backend);
+        let cache_metrics_admin = Arc::clone(&cache_metrics);
         let config_admin = Arc::clone(&config);
         tokio::task::spawn(async move {
             println!("\x1b[35mInfo:\x1b[0m Admin namespace enabled, accepting admin methods at admin port");
@@ -121,6 +231,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 rpc_list_admin,
                 poverty_list_admin,
                 cache_admin,
+                backend_admin,
+                cache_metrics_admin,
                 config_admin,
             )
             .await;
@@ -128,19 +240,105 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     // Spawn a thread for the head cache
-    let head_cache_clone = Arc::clone(&head_cache);
+    let rpc_list_cache = Arc::clone(&rpc_list_rwlock);
     let cache_clone = Arc::clone(&cache);
+    let backend_cache = Arc::clone(&backend);
     let finalized_rxclone = Arc::clone(&finalized_rx_arc);
+    let config_cache = Arc::clone(&config);
     tokio::task::spawn(async move {
         let _ = manage_cache(
-            &head_cache_clone,
+            &rpc_list_cache,
             blocknum_rx,
             finalized_rxclone,
             &cache_clone,
+            &backend_cache,
+            &config_cache,
+        )
+        .await;
+    });
+
+    // Spawn a thread for removing expired entries from the cache
+    let cache_prune = Arc::clone(&cache);
+    let backend_prune = Arc::clone(&backend);
+    tokio::task::spawn(async move {
+        let _ = prune_expired_loop(
+            cache_prune,
+            backend_prune,
+            Duration::from_millis(cache_prune_interval_clone),
         )
         .await;
     });
 
+    // Spawn a thread for evicting the oldest entries once the cache grows too large
+    let cache_evict = Arc::clone(&cache);
+    let backend_evict = Arc::clone(&backend);
+    tokio::task::spawn(async move {
+        let _ = evict_loop(
+            cache_evict,
+            backend_evict,
+            max_cache_size_clone,
+            Duration::from_millis(eviction_interval_clone),
+        )
+        .await;
+    });
+
+    // Spawn a thread for writing responses to the cache in the background
+    let (cache_writer, cache_writes) = CacheWriter::new(write_queue_size_clone);
+    let cache_write_behind = Arc::clone(&cache);
+    let backend_write_behind = Arc::clone(&backend);
+    tokio::task::spawn(async move {
+        write_behind(cache_write_behind, backend_write_behind, cache_writes).await;
+    });
+
+    let cache_args = CacheArgs {
+        backend,
+        hot_cache,
+        cache_metrics,
+        chain_id,
+        hasher: cache_hasher_clone,
+        writer: cache_writer,
+        revalidator: Arc::new(Revalidator::new(revalidate_fraction_clone)),
+    };
+
+    // Warm up the cache in the background so we can start serving requests right away
+    let calls = warmup_calls(&cache, &warmup_clone);
+    if !calls.is_empty() {
+        let rpc_list_warmup = Arc::clone(&rpc_list_rwlock);
+        let finalized_rx_warmup = (*finalized_rx_arc).clone();
+        let named_blocknumbers_warmup = Arc::clone(&named_blocknumbers);
+        let cache_args_warmup = cache_args.clone();
+        let params_warmup = RequestParams::new(&config.read().unwrap());
+        tokio::task::spawn(async move {
+            // Calls for `latest` only get cached once we know the head
+            if health_check_clone
+                && !wait_for_head(&named_blocknumbers_warmup, HEAD_TIMEOUT).await
+            {
+                // Nothing gets cached by the head from the last run until it's confirmed
+                if named_blocknumbers_warmup.read().unwrap().provisional {
+                    println!("\x1b[93mWrn:\x1b[0m Head block from the last run not confirmed, skipping the cache warmup.");
+                    return;
+                }
+                println!("\x1b[93mWrn:\x1b[0m Head block unknown, warming up the cache without it.");
+            }
+
+            let total = calls.len();
+            let warmed = warmup(
+                calls,
+                warmup_clone.concurrency,
+                rpc_list_warmup,
+                finalized_rx_warmup,
+                named_blocknumbers_warmup,
+                cache_args_warmup,
+                params_warmup,
+            )
+            .await;
+            println!(
+                "\x1b[35mInfo:\x1b[0m Warmed up the cache with {}/{} calls",
+                warmed, total
+            );
+        });
+    }
+
     // We start a loop to continuously accept incoming connections
     loop {
         let (stream, socketaddr) = listener.accept().await?;
@@ -152,8 +350,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         // Clone the shared `rpc_list_rwlock` and cache for use in the closure
         let rpc_list_rwlock_clone = Arc::clone(&rpc_list_rwlock);
-        let cache_clone = Arc::clone(&cache);
-        let head_cache_clone = Arc::clone(&head_cache);
+        let cache_args_clone = cache_args.clone();
         let finalized_rx_clone = Arc::clone(&finalized_rx_arc);
         let named_blocknumbers_clone = Arc::clone(&named_blocknumbers);
         let config_clone = Arc::clone(&config);
@@ -163,11 +360,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             accept!(
                 io,
                 &rpc_list_rwlock_clone,
-                &cache_clone,
+                &cache_args_clone,
                 &finalized_rx_clone,
                 &named_blocknumbers_clone,
-                &head_cache_clone,
-                &config_clone
+                &config_clone,
+                socketaddr.ip()
             );
         });
     }
@@ -0,0 +1,69 @@
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
+
+// f64 that can be updated through a shared reference, stored as its bits
+#[derive(Debug, Default)]
+pub struct AtomicF64 {
+    bits: AtomicU64,
+}
+
+impl AtomicF64 {
+    pub fn load(&self) -> f64 {
+        f64::from_bits(self.bits.load(Ordering::Relaxed))
+    }
+
+    pub fn store(&self, value: f64) {
+        self.bits.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    // Add `delta` and return the new value
+    pub fn add(&self, delta: f64) -> f64 {
+        let previous = self
+            .bits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + delta).to_bits())
+            })
+            .unwrap();
+
+        f64::from_bits(previous) + delta
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_atomic_f64() {
+        let value = AtomicF64::default();
+        assert_eq!(value.load(), 0.0);
+        value.store(1.5);
+        assert_eq!(value.add(2.0), 3.5);
+        assert_eq!(value.add(-4.5), -1.0);
+        assert_eq!(value.load(), -1.0);
+    }
+
+    #[test]
+    fn test_atomic_f64_concurrent() {
+        let value = Arc::new(AtomicF64::default());
+        let threads = (0..8)
+            .map(|_| {
+                let value = Arc::clone(&value);
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        value.add(1.0);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // No additions get lost
+        assert_eq!(value.load(), 8000.0);
+    }
+}
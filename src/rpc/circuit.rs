@@ -0,0 +1,278 @@
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+// When to stop sending requests to a failing RPC, set in `[blutgang]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CircuitSettings {
+    // How many failures in a row open the circuit, 0 to never open it
+    pub failure_threshold: u32,
+    // Failures only count as in a row if they all happened within this long
+    pub window: Duration,
+    // How long to wait before sending a probe to a RPC with an open circuit
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    // Requests go through as usual
+    Closed,
+    // The RPC failed too often and gets no requests until the cooldown is over
+    Open,
+    // A single probe request is on its way, its result decides if we close the circuit again
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    // Failures in a row, and when the first of them happened
+    failures: u32,
+    first_failure: Instant,
+    // When the circuit opened, or when the probe got sent if it's half open
+    since: Instant,
+}
+
+// Circuit breaker of a single RPC.
+//
+// Clones share their state, so it follows the RPC around the active and poverty lists.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    settings: CircuitSettings,
+    circuit: Arc<Mutex<Circuit>>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(CircuitSettings::default())
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: CircuitSettings) -> Self {
+        let now = Instant::now();
+        Self {
+            settings,
+            circuit: Arc::new(Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                failures: 0,
+                first_failure: now,
+                since: now,
+            })),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.circuit.lock().unwrap().state
+    }
+
+    // If requests can be sent to the RPC as usual
+    pub fn is_closed(&self) -> bool {
+        self.state() == CircuitState::Closed
+    }
+
+    // If the cooldown is over and the RPC should get the next request as a probe.
+    //
+    // A probe that never came back, eg. because the client went away,
+    // gets replaced by a new one after another cooldown.
+    pub fn ready_to_probe(&self) -> bool {
+        self.ready(&self.circuit.lock().unwrap())
+    }
+
+    // Mark the request about to be sent as the probe, whether the cooldown is over or not
+    #[cfg(test)]
+    pub fn probe(&self, url: &str) {
+        let mut circuit = self.circuit.lock().unwrap();
+        Self::half_open(&mut circuit, url);
+    }
+
+    // Mark the request about to be sent as the probe if the RPC is still `ready_to_probe`.
+    //
+    // Picks only read lock the RPC list, so concurrent ones can find the same RPC
+    // ready to probe. Only the first gets to send the probe, this returns true for it.
+    pub fn try_probe(&self, url: &str) -> bool {
+        let mut circuit = self.circuit.lock().unwrap();
+        if !self.ready(&circuit) {
+            return false;
+        }
+
+        Self::half_open(&mut circuit, url);
+        true
+    }
+
+    fn ready(&self, circuit: &Circuit) -> bool {
+        circuit.state != CircuitState::Closed && circuit.since.elapsed() >= self.settings.cooldown
+    }
+
+    fn half_open(circuit: &mut Circuit, url: &str) {
+        circuit.state = CircuitState::HalfOpen;
+        circuit.since = Instant::now();
+        println!(
+            "\x1b[35mInfo:\x1b[0m Circuit for {} is half open, sending a probe",
+            url
+        );
+    }
+
+    pub fn success(&self, url: &str) {
+        let mut circuit = self.circuit.lock().unwrap();
+        circuit.failures = 0;
+        if circuit.state == CircuitState::HalfOpen {
+            circuit.state = CircuitState::Closed;
+            println!(
+                "\x1b[35mInfo:\x1b[0m Probe to {} succeeded, closing its circuit",
+                url
+            );
+        }
+    }
+
+    // Count a failed request, opening the circuit if there were too many in a row
+    pub fn failure(&self, url: &str) {
+        if self.settings.failure_threshold == 0 {
+            return;
+        }
+
+        let mut circuit = self.circuit.lock().unwrap();
+        let now = Instant::now();
+        match circuit.state {
+            CircuitState::Closed => {
+                if circuit.failures == 0
+                    || now.duration_since(circuit.first_failure) > self.settings.window
+                {
+                    circuit.failures = 0;
+                    circuit.first_failure = now;
+                }
+                circuit.failures += 1;
+
+                if circuit.failures >= self.settings.failure_threshold {
+                    circuit.state = CircuitState::Open;
+                    circuit.since = now;
+                    println!(
+                        "\x1b[93mWrn:\x1b[0m Opening circuit for {} after {} failures in a row",
+                        url, circuit.failures
+                    );
+                }
+            }
+            CircuitState::HalfOpen => {
+                circuit.state = CircuitState::Open;
+                circuit.since = now;
+                println!(
+                    "\x1b[93mWrn:\x1b[0m Probe to {} failed, opening its circuit again",
+                    url
+                );
+            }
+            // Requests sent before the circuit opened
+            CircuitState::Open => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(cooldown: Duration) -> CircuitSettings {
+        CircuitSettings {
+            failure_threshold: 3,
+            window: Duration::from_secs(10),
+            cooldown,
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(settings(Duration::ZERO));
+
+        // Successes reset the failures in a row
+        breaker.failure("a");
+        breaker.failure("a");
+        breaker.success("a");
+        breaker.failure("a");
+        breaker.failure("a");
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        breaker.failure("a");
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(breaker.ready_to_probe());
+
+        // A failed probe opens it again, a successful one closes it
+        breaker.probe("a");
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.failure("a");
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        breaker.probe("a");
+        breaker.success("a");
+        assert!(breaker.is_closed());
+        assert!(!breaker.ready_to_probe());
+    }
+
+    #[test]
+    fn test_circuit_breaker_cooldown() {
+        let breaker = CircuitBreaker::new(settings(Duration::from_secs(60)));
+        for _ in 0..3 {
+            breaker.failure("a");
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.ready_to_probe());
+
+        // Clones share the state
+        assert_eq!(breaker.clone().state(), CircuitState::Open);
+
+        // Only a pick that finds it ready gets to send the probe
+        assert!(!breaker.try_probe("a"));
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_circuit_breaker_window() {
+        // Failures that are too far apart aren't in a row
+        let breaker = CircuitBreaker::new(CircuitSettings {
+            window: Duration::ZERO,
+            ..settings(Duration::ZERO)
+        });
+        for _ in 0..10 {
+            breaker.failure("a");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(breaker.is_closed());
+    }
+
+    #[test]
+    fn test_circuit_breaker_disabled() {
+        let breaker = CircuitBreaker::new(CircuitSettings {
+            failure_threshold: 0,
+            ..Default::default()
+        });
+        for _ in 0..100 {
+            breaker.failure("a");
+        }
+        assert!(breaker.is_closed());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{
+    OwnedSemaphorePermit,
+    Semaphore,
+    TryAcquireError,
+};
+
+// How often to check if a RPC at its `max_concurrent` freed up
+const SATURATED_RECHECK: Duration = Duration::from_millis(5);
+
+// Caps how many requests can be in flight to a RPC at once, set with `max_concurrent`.
+//
+// Picks only read lock the RPC list, so a RPC that had capacity when it got picked can be
+// maxed out by the time `Rpc::start_request` tries to take a permit. Then it gets picked
+// again, see `pick_upstream`. Clones share the semaphore.
+#[derive(Debug, Clone, Default)]
+pub struct ConcurrencyLimit {
+    // None if the RPC isn't limited
+    semaphore: Option<Arc<Semaphore>>,
+    max_concurrent: u32,
+}
+
+impl ConcurrencyLimit {
+    // 0 means no limit
+    pub fn new(max_concurrent: u32) -> Self {
+        if max_concurrent == 0 {
+            return Self::default();
+        }
+
+        Self {
+            semaphore: Some(Arc::new(Semaphore::new(max_concurrent as usize))),
+            max_concurrent,
+        }
+    }
+
+    pub fn max_concurrent(&self) -> u32 {
+        self.max_concurrent
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        self.semaphore
+            .as_ref()
+            .map_or(true, |semaphore| semaphore.available_permits() > 0)
+    }
+
+    // How long to wait before checking for capacity again, None if there's no limit.
+    //
+    // Requests don't say when they'll come back, so this is a guess.
+    pub fn wait(&self) -> Option<Duration> {
+        self.semaphore.as_ref()?;
+        if self.has_capacity() {
+            return Some(Duration::ZERO);
+        }
+
+        Some(SATURATED_RECHECK)
+    }
+
+    // Take a permit until it's dropped. None if there's no limit, Err if it's maxed out.
+    pub fn try_acquire(&self) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+        match &self.semaphore {
+            Some(semaphore) => Arc::clone(semaphore).try_acquire_owned().map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrency_limit() {
+        let limit = ConcurrencyLimit::new(2);
+        assert_eq!(limit.wait(), Some(Duration::ZERO));
+
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.clone().try_acquire().unwrap();
+        assert!(!limit.has_capacity());
+        assert!(limit.try_acquire().is_err());
+        assert_eq!(limit.wait(), Some(SATURATED_RECHECK));
+
+        // Freed up as soon as a request comes back
+        drop(first);
+        assert!(limit.has_capacity());
+        assert!(limit.try_acquire().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_concurrency_limit_unlimited() {
+        let limit = ConcurrencyLimit::new(0);
+        assert!(limit.has_capacity());
+        assert!(limit.try_acquire().unwrap().is_none());
+        assert_eq!(limit.wait(), None);
+        assert_eq!(limit.max_concurrent(), 0);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
+// How many of a RPC's latest consensus checks count towards its record
+const RECENT_CHECKS: usize = 32;
+
+#[derive(Debug, Default)]
+struct Record {
+    // Outcomes of the latest checks, true if the RPC agreed with the other one
+    recent: VecDeque<bool>,
+    quarantined: bool,
+}
+
+impl Record {
+    fn push(&mut self, agreed: bool) {
+        if self.recent.len() == RECENT_CHECKS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(agreed);
+    }
+
+    fn count(&self, agreed: bool) -> usize {
+        self.recent
+            .iter()
+            .filter(|recent| **recent == agreed)
+            .count()
+    }
+}
+
+// How often a RPC agreed with the others lately, see `balancer::consensus`.
+//
+// A RPC that disagrees too often gets quarantined and stops getting requests.
+// Clones share the record.
+#[derive(Debug, Clone, Default)]
+pub struct ConsensusRecord {
+    record: Arc<Mutex<Record>>,
+}
+
+impl ConsensusRecord {
+    pub fn agreed(&self) {
+        self.record.lock().unwrap().push(true);
+    }
+
+    // Count a check the RPC was found to be wrong in, quarantining it once
+    // it was wrong in `max_divergences` of its recent checks. 0 never quarantines.
+    //
+    // `last_serving` is set if no other RPC could take over its requests,
+    // in which case it keeps serving them instead of leaving none at all.
+    pub fn diverged(&self, url: &str, max_divergences: u32, last_serving: bool) {
+        let mut record = self.record.lock().unwrap();
+        record.push(false);
+
+        let divergences = record.count(false);
+        if max_divergences == 0 || divergences < max_divergences as usize || record.quarantined {
+            return;
+        }
+
+        if last_serving {
+            println!(
+                "\x1b[93mWrn:\x1b[0m {} disagreed with the other RPCs {} times, but it's the last one not quarantined!",
+                url, divergences
+            );
+            return;
+        }
+
+        record.quarantined = true;
+        println!(
+            "\x1b[31mErr:\x1b[0m {} disagreed with the other RPCs {} times, quarantining it!",
+            url, divergences
+        );
+    }
+
+    // Lift the quarantine and start the record over, see `blutgang_release_rpc`
+    pub fn release(&self) {
+        let mut record = self.record.lock().unwrap();
+        record.recent.clear();
+        record.quarantined = false;
+    }
+
+    pub fn agreements(&self) -> usize {
+        self.record.lock().unwrap().count(true)
+    }
+
+    pub fn divergences(&self) -> usize {
+        self.record.lock().unwrap().count(false)
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.record.lock().unwrap().quarantined
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consensus_record() {
+        let record = ConsensusRecord::default();
+        record.agreed();
+        record.diverged("rpc", 2, false);
+        assert_eq!((record.agreements(), record.divergences()), (1, 1));
+        assert!(!record.is_quarantined());
+
+        // Clones share the record
+        record.clone().diverged("rpc", 2, false);
+        assert!(record.is_quarantined());
+
+        // Released RPCs start over
+        record.release();
+        assert!(!record.is_quarantined());
+        assert_eq!((record.agreements(), record.divergences()), (0, 0));
+    }
+
+    #[test]
+    fn test_consensus_record_last_serving() {
+        let record = ConsensusRecord::default();
+        for _ in 0..3 {
+            record.diverged("rpc", 2, true);
+        }
+        assert!(!record.is_quarantined());
+
+        // Quarantined once another RPC can take over
+        record.diverged("rpc", 2, false);
+        assert!(record.is_quarantined());
+    }
+
+    #[test]
+    fn test_consensus_record_recent() {
+        let record = ConsensusRecord::default();
+        record.diverged("rpc", 0, false);
+        for _ in 0..RECENT_CHECKS {
+            record.agreed();
+        }
+
+        // Old divergences drop out of the record
+        assert_eq!(record.agreements(), RECENT_CHECKS);
+        assert_eq!(record.divergences(), 0);
+
+        // And a threshold of 0 never quarantines
+        for _ in 0..RECENT_CHECKS {
+            record.diverged("rpc", 0, false);
+        }
+        assert!(!record.is_quarantined());
+    }
+}
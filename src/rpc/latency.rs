@@ -0,0 +1,245 @@
+use crate::rpc::atomic::AtomicF64;
+use std::sync::{
+    atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    Arc,
+    Mutex,
+};
+
+// How many of the latest calls to a RPC its percentiles are taken over
+const WINDOW: usize = 256;
+
+// Which latency figure RPCs get compared by when picking one, set with `latency_score`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LatencyScore {
+    // Exponential moving average of every call, see `Rpc::update_latency`
+    #[default]
+    Average,
+    P50,
+    P90,
+    // Catches RPCs that are fast most of the time but stall every so often
+    P99,
+}
+
+impl LatencyScore {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "average" => Some(LatencyScore::Average),
+            "p50" => Some(LatencyScore::P50),
+            "p90" => Some(LatencyScore::P90),
+            "p99" => Some(LatencyScore::P99),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LatencyScore::Average => "average",
+            LatencyScore::P50 => "p50",
+            LatencyScore::P90 => "p90",
+            LatencyScore::P99 => "p99",
+        }
+    }
+}
+
+// Latency percentiles over the last `WINDOW` calls to a RPC.
+//
+// The percentiles get worked out when a call comes back, so reading them while picking is free.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyWindow {
+    // In the order the calls came back in
+    samples: Vec<f64>,
+    // Same samples kept in order as they come in, so the percentiles are a lookup
+    sorted: Vec<f64>,
+    // Where the next sample goes once the window is full
+    next: usize,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+impl LatencyWindow {
+    pub fn add(&mut self, latency: f64) {
+        if self.samples.len() < WINDOW {
+            self.samples.push(latency);
+        } else {
+            let oldest = std::mem::replace(&mut self.samples[self.next], latency);
+            self.next = (self.next + 1) % WINDOW;
+            self.sorted.remove(sorted_position(&self.sorted, oldest));
+        }
+        self.sorted
+            .insert(sorted_position(&self.sorted, latency), latency);
+
+        self.p50 = nearest_rank(&self.sorted, 0.50);
+        self.p90 = nearest_rank(&self.sorted, 0.90);
+        self.p99 = nearest_rank(&self.sorted, 0.99);
+    }
+
+    // How many calls the percentiles are taken over
+    pub fn samples(&self) -> usize {
+        self.samples.len()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Figures {
+    average: AtomicF64,
+    p50: AtomicF64,
+    p90: AtomicF64,
+    p99: AtomicF64,
+    // How many calls went into `average`
+    samples: AtomicU64,
+    // Only locked when a call comes back
+    window: Mutex<LatencyWindow>,
+}
+
+// Latency of the calls to a RPC, see `Rpc::update_latency`.
+//
+// Clones share it, so calls update it without write locking the RPC list,
+// and picks read it without taking any lock at all.
+#[derive(Debug, Clone, Default)]
+pub struct Latency {
+    figures: Arc<Figures>,
+}
+
+impl Latency {
+    // Add the latency of a call. `alpha` is the weight it gets in the average, between 0 and 1.
+    pub fn add(&self, latency: f64, alpha: f64) {
+        // Calls coming back at the same time take turns, so none of them get lost
+        let mut window = self.figures.window.lock().unwrap();
+        window.add(latency);
+
+        let average = match self.samples() {
+            0 => latency,
+            _ => alpha * latency + (1.0 - alpha) * self.average(),
+        };
+        self.figures.average.store(average);
+        self.figures.p50.store(window.p50);
+        self.figures.p90.store(window.p90);
+        self.figures.p99.store(window.p99);
+        self.figures.samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Exponential moving average of every call, 0 if there were none yet
+    pub fn average(&self) -> f64 {
+        self.figures.average.load()
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.figures.samples.load(Ordering::Relaxed)
+    }
+
+    // The latency figure `score` goes by
+    pub fn score(&self, score: LatencyScore) -> f64 {
+        match score {
+            LatencyScore::Average => self.average(),
+            LatencyScore::P50 => self.figures.p50.load(),
+            LatencyScore::P90 => self.figures.p90.load(),
+            LatencyScore::P99 => self.figures.p99.load(),
+        }
+    }
+
+    pub fn window(&self) -> LatencyWindow {
+        self.figures.window.lock().unwrap().clone()
+    }
+
+    // Overwrite the average, as if it came from calls
+    #[cfg(test)]
+    pub fn set(&self, average: f64) {
+        self.figures.average.store(average);
+    }
+}
+
+// Where `latency` goes in `sorted`, or where it already is
+fn sorted_position(sorted: &[f64], latency: f64) -> usize {
+    sorted.partition_point(|sample| sample.total_cmp(&latency).is_lt())
+}
+
+// Smallest of the `sorted` samples that's at least as large as `percentile` of them
+fn nearest_rank(sorted: &[f64], percentile: f64) -> f64 {
+    let rank = (percentile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_window() {
+        let mut window = LatencyWindow::default();
+        assert_eq!((window.p50, window.p90, window.p99), (0.0, 0.0, 0.0));
+
+        // 1 to 100 in any order
+        for latency in (1..=100).rev() {
+            window.add(latency as f64);
+        }
+        assert_eq!((window.p50, window.p90, window.p99), (50.0, 90.0, 99.0));
+
+        // Fast except for the odd stall, which only shows up in the tail
+        let mut window = LatencyWindow::default();
+        for i in 0..200 {
+            window.add(if i % 20 == 0 { 500.0 } else { 10.0 });
+        }
+        assert_eq!((window.p50, window.p90, window.p99), (10.0, 10.0, 500.0));
+    }
+
+    #[test]
+    fn test_latency_window_rolls_over() {
+        let mut window = LatencyWindow::default();
+        for _ in 0..WINDOW {
+            window.add(1000.0);
+        }
+
+        // Only the latest calls count
+        for _ in 0..WINDOW {
+            window.add(1.0);
+        }
+        assert_eq!(window.samples(), WINDOW);
+        assert_eq!((window.p50, window.p90, window.p99), (1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn test_latency_window_stays_sorted() {
+        let mut window = LatencyWindow::default();
+
+        // Repeats and values going in and out of the window in any order
+        for i in 0..WINDOW * 3 {
+            window.add(((i * 7919) % 101) as f64);
+
+            let mut sorted = window.samples.clone();
+            sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+            assert_eq!(window.sorted, sorted);
+            assert_eq!(window.p90, nearest_rank(&sorted, 0.90));
+        }
+    }
+
+    #[test]
+    fn test_latency_shared() {
+        let latency = Latency::default();
+        latency.add(100.0, 0.5);
+        assert_eq!(latency.score(LatencyScore::Average), 100.0);
+
+        // Clones share the figures
+        latency.clone().add(200.0, 0.5);
+        assert_eq!(latency.samples(), 2);
+        assert_eq!(latency.score(LatencyScore::Average), 150.0);
+        assert_eq!(latency.score(LatencyScore::P99), 200.0);
+        assert_eq!(latency.window().samples(), 2);
+    }
+
+    #[test]
+    fn test_latency_score_names() {
+        for score in [
+            LatencyScore::Average,
+            LatencyScore::P50,
+            LatencyScore::P90,
+            LatencyScore::P99,
+        ] {
+            assert_eq!(LatencyScore::from_name(score.name()), Some(score));
+        }
+        assert_eq!(LatencyScore::from_name("p95"), None);
+    }
+}
@@ -0,0 +1,135 @@
+// Mock RPCs for testing
+
+use http_body_util::{
+    BodyExt,
+    Full,
+};
+use hyper::{
+    body::Bytes,
+    server::conn::http1,
+    service::service_fn,
+    Request,
+};
+use hyper_util_blutgang::rt::TokioIo;
+use serde_json::{
+    json,
+    Value,
+};
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::net::TcpListener;
+
+// How many calls a mock RPC is working on, and the most it ever had at once
+#[derive(Debug, Default)]
+pub struct Load {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Load {
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+}
+
+// Spawn a mock RPC that answers every call with whatever `handler` returns as the result
+pub async fn mock_rpc<F>(handler: F) -> String
+where
+    F: Fn(&Value) -> Value + Send + Sync + 'static,
+{
+    mock_rpc_raw(move |tx| {
+        json!({
+            "jsonrpc": "2.0",
+            "id": tx["id"],
+            "result": handler(tx),
+        })
+        .to_string()
+    })
+    .await
+}
+
+// Same as `mock_rpc`, but every response takes `delay` to come back
+pub async fn mock_rpc_delayed<F>(delay: Duration, handler: F) -> String
+where
+    F: Fn(&Value) -> Value + Send + Sync + 'static,
+{
+    mock_rpc_loaded(delay, handler).await.0
+}
+
+// Same as `mock_rpc_delayed`, but also returns the `Load` the mock RPC is under
+pub async fn mock_rpc_loaded<F>(delay: Duration, handler: F) -> (String, Arc<Load>)
+where
+    F: Fn(&Value) -> Value + Send + Sync + 'static,
+{
+    let load = Arc::new(Load::default());
+    let url = serve(delay, Arc::clone(&load), move |tx| {
+        json!({
+            "jsonrpc": "2.0",
+            "id": tx["id"],
+            "result": handler(tx),
+        })
+        .to_string()
+    })
+    .await;
+
+    (url, load)
+}
+
+// Spawn a mock RPC that answers every call with the raw body `handler` returns
+pub async fn mock_rpc_raw<F>(handler: F) -> String
+where
+    F: Fn(&Value) -> String + Send + Sync + 'static,
+{
+    serve(Duration::ZERO, Arc::default(), handler).await
+}
+
+async fn serve<F>(delay: Duration, load: Arc<Load>, handler: F) -> String
+where
+    F: Fn(&Value) -> String + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let handler = Arc::clone(&handler);
+            let load = Arc::clone(&load);
+
+            tokio::spawn(async move {
+                let service = service_fn(|req: Request<hyper::body::Incoming>| {
+                    let handler = Arc::clone(&handler);
+                    let load = Arc::clone(&load);
+                    async move {
+                        let body = req.collect().await.unwrap().to_bytes();
+                        let tx: Value = serde_json::from_slice(&body).unwrap();
+
+                        let current = load.current.fetch_add(1, Ordering::SeqCst) + 1;
+                        load.peak.fetch_max(current, Ordering::SeqCst);
+                        tokio::time::sleep(delay).await;
+                        load.current.fetch_sub(1, Ordering::SeqCst);
+
+                        Ok::<_, Infallible>(hyper::Response::new(Full::new(Bytes::from(handler(
+                            &tx,
+                        )))))
+                    }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    format!("http://{}", address)
+}
@@ -1,2 +1,10 @@
+pub mod atomic;
+pub mod circuit;
+pub mod concurrency;
+pub mod consensus;
 pub mod error;
+pub mod latency;
+#[cfg(test)]
+pub mod mock;
+pub mod rate_limit;
 pub mod types;
@@ -0,0 +1,126 @@
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+// Token bucket keeping a RPC under `max_rps` requests per second.
+//
+// The bucket holds a single token, so requests are spread out evenly instead of
+// bursting at the start of every second, which providers would count against us.
+// Clones share the bucket.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimit {
+    // None if the RPC isn't rate limited
+    bucket: Option<Arc<Mutex<Bucket>>>,
+    max_rps: f64,
+}
+
+impl RateLimit {
+    // 0 means no limit
+    pub fn new(max_rps: u32) -> Self {
+        if max_rps == 0 {
+            return Self::default();
+        }
+
+        Self {
+            bucket: Some(Arc::new(Mutex::new(Bucket {
+                tokens: 1.0,
+                refilled_at: Instant::now(),
+            }))),
+            max_rps: max_rps as f64,
+        }
+    }
+
+    pub fn max_rps(&self) -> u32 {
+        self.max_rps as u32
+    }
+
+    // How long until a request can be sent, None if there's no limit
+    pub fn wait(&self) -> Option<Duration> {
+        let bucket = self.bucket.as_ref()?;
+        let mut bucket = bucket.lock().unwrap();
+        self.refill(&mut bucket);
+
+        // Rounded up, so the token is there once we're done waiting
+        let missing = (1.0 - bucket.tokens).max(0.0);
+        Some(Duration::from_nanos(
+            (missing / self.max_rps * 1e9).ceil() as u64
+        ))
+    }
+
+    pub fn has_token(&self) -> bool {
+        self.wait().map_or(true, |wait| wait.is_zero())
+    }
+
+    // Count a request against the limit, returning false if we're over it
+    pub fn take(&self) -> bool {
+        let bucket = match &self.bucket {
+            Some(bucket) => bucket,
+            None => return true,
+        };
+        let mut bucket = bucket.lock().unwrap();
+        self.refill(&mut bucket);
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+
+        true
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens += elapsed * self.max_rps;
+        // Don't leave the bucket a rounding error short of a token
+        if bucket.tokens > 1.0 - 1e-9 {
+            bucket.tokens = 1.0;
+        }
+        bucket.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let rate_limit = RateLimit::new(5);
+        assert!(rate_limit.has_token());
+        assert!(rate_limit.take());
+
+        // The next token shows up 200ms later
+        assert!(!rate_limit.has_token());
+        assert!(!rate_limit.clone().take());
+        let wait = rate_limit.wait().unwrap();
+        assert!(wait > Duration::from_millis(150) && wait <= Duration::from_millis(200));
+
+        std::thread::sleep(wait);
+        assert!(rate_limit.take());
+        assert!(!rate_limit.take());
+    }
+
+    #[test]
+    fn test_rate_limit_unlimited() {
+        let rate_limit = RateLimit::new(0);
+        for _ in 0..1000 {
+            assert!(rate_limit.take());
+        }
+        assert_eq!(rate_limit.wait(), None);
+        assert!(rate_limit.has_token());
+    }
+}
@@ -1,4 +1,18 @@
-use crate::rpc::error::RpcError;
+use crate::rpc::{
+    atomic::AtomicF64,
+    circuit::{
+        CircuitBreaker,
+        CircuitSettings,
+    },
+    concurrency::ConcurrencyLimit,
+    consensus::ConsensusRecord,
+    error::RpcError,
+    latency::{
+        Latency,
+        LatencyScore,
+    },
+    rate_limit::RateLimit,
+};
 use reqwest::Client;
 
 use serde_json::{
@@ -6,23 +20,59 @@ use serde_json::{
     Value,
 };
 use simd_json;
+use std::sync::{
+    atomic::{
+        AtomicU32,
+        AtomicUsize,
+        Ordering,
+    },
+    Arc,
+};
+use tokio::sync::OwnedSemaphorePermit;
+
+// Weight of each new latency sample if it's not set with `latency_alpha` or `ma_length`
+const DEFAULT_LATENCY_ALPHA: f64 = 0.2;
 
 // All as floats so we have an easier time getting averages, stats and terminology copied from flood.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct Status {
     // Set this to true in case the RPC becomes unavailable
     // Also set the last time it was called, so we can check again later
     pub is_erroring: bool,
     pub last_error: u64,
 
-    // The latency is a moving average of the last n calls
-    pub latency: f64,
-    pub latency_data: Vec<f64>,
-    ma_length: f64,
+    // The latency is an exponential moving average of every call, so a single
+    // slow or fast call doesn't flip traffic around. Also has percentiles of the
+    // latest calls, so a RPC that stalls every so often stands out.
+    pub latency: Latency,
+    // Weight of each new sample in the average, between 0 and 1
+    latency_alpha: f64,
+    // Which latency figure selection goes by, see `Status::score`
+    pub latency_score: LatencyScore,
     // ???
     // pub throughput: f64,
 }
 
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            is_erroring: false,
+            last_error: 0,
+            latency: Latency::default(),
+            latency_alpha: DEFAULT_LATENCY_ALPHA,
+            latency_score: LatencyScore::default(),
+        }
+    }
+}
+
+impl Status {
+    // The latency RPCs get compared by when picking one, set with `latency_score`.
+    // 0 if there's no latency for the RPC yet.
+    pub fn score(&self) -> f64 {
+        self.latency.score(self.latency_score)
+    }
+}
+
 unsafe impl Sync for Status {}
 
 #[derive(Debug, Clone)]
@@ -31,7 +81,44 @@ pub struct Rpc {
     client: Client,     // Reqwest client
     pub status: Status, // stores stats related to the rpc.
     pub max_consecutive: u32,
-    pub consecutive: u32,
+    // Picks in a row this RPC got, shared between clones
+    consecutive: Arc<AtomicU32>,
+    // Head block the RPC reported during the last health check, 0 if it didn't
+    pub head: u64,
+    // Share of the traffic this RPC gets relative to the others, set with `weight`
+    pub weight: f64,
+    // If the RPC keeps all historical state, set with `archive`
+    pub archive: bool,
+    // What else the RPC supports, eg. the `trace` namespace, set with `labels`
+    pub labels: Vec<String>,
+    // RPCs only get requests if none in a lower tier can take them, set with `tier`
+    pub tier: u32,
+    // Running total for weighted selection, see `selection::select`, shared between clones
+    current_weight: Arc<AtomicF64>,
+    // Requests sent to this RPC that haven't come back yet, shared between clones
+    in_flight: Arc<AtomicUsize>,
+    // Stops requests from going to this RPC while it keeps failing
+    pub circuit: CircuitBreaker,
+    // Keeps requests under `max_rps`, shared between clones
+    pub rate_limit: RateLimit,
+    // Keeps requests in flight under `max_concurrent`, shared between clones
+    pub concurrency: ConcurrencyLimit,
+    // How often this RPC agreed with the others lately, shared between clones
+    pub consensus: ConsensusRecord,
+}
+
+// Counts a request as in flight until it's dropped, see `Rpc::start_request`
+#[derive(Debug)]
+pub struct InFlight {
+    in_flight: Arc<AtomicUsize>,
+    // Held until the request comes back if the RPC has a `max_concurrent`
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 unsafe impl Sync for Rpc {}
@@ -43,31 +130,132 @@ impl Default for Rpc {
             client: Client::new(),
             status: Status::default(),
             max_consecutive: 0,
-            consecutive: 0,
+            consecutive: Arc::new(AtomicU32::new(0)),
+            head: 0,
+            weight: 1.0,
+            archive: false,
+            labels: Vec::new(),
+            tier: 0,
+            current_weight: Arc::new(AtomicF64::default()),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            circuit: CircuitBreaker::default(),
+            rate_limit: RateLimit::default(),
+            concurrency: ConcurrencyLimit::default(),
+            consensus: ConsensusRecord::default(),
         }
     }
 }
 
 // implement new for rpc
 impl Rpc {
+    // The latency gets smoothed about as much as a moving average over `ma_length` calls would
     pub fn new(url: String, max_consecutive: u32, ma_length: f64) -> Self {
         Self {
             url,
             client: Client::new(),
             status: Status {
-                ma_length,
+                latency_alpha: 2.0 / (ma_length.max(1.0) + 1.0),
                 ..Default::default()
             },
             max_consecutive,
-            consecutive: 0,
+            consecutive: Arc::new(AtomicU32::new(0)),
+            head: 0,
+            weight: 1.0,
+            archive: false,
+            labels: Vec::new(),
+            tier: 0,
+            current_weight: Arc::new(AtomicF64::default()),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            circuit: CircuitBreaker::default(),
+            rate_limit: RateLimit::default(),
+            concurrency: ConcurrencyLimit::default(),
+            consensus: ConsensusRecord::default(),
         }
     }
 
+    pub fn with_latency_alpha(mut self, latency_alpha: f64) -> Self {
+        self.status.latency_alpha = latency_alpha;
+        self
+    }
+
+    pub fn with_latency_score(mut self, latency_score: LatencyScore) -> Self {
+        self.status.latency_score = latency_score;
+        self
+    }
+
+    pub fn with_weight(mut self, weight: f64) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn with_archive(mut self, archive: bool) -> Self {
+        self.archive = archive;
+        self
+    }
+
+    pub fn with_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    pub fn with_tier(mut self, tier: u32) -> Self {
+        self.tier = tier;
+        self
+    }
+
+    pub fn with_max_rps(mut self, max_rps: u32) -> Self {
+        self.rate_limit = RateLimit::new(max_rps);
+        self
+    }
+
+    pub fn with_max_concurrent(mut self, max_concurrent: u32) -> Self {
+        self.concurrency = ConcurrencyLimit::new(max_concurrent);
+        self
+    }
+
+    pub fn with_circuit(mut self, settings: CircuitSettings) -> Self {
+        self.circuit = CircuitBreaker::new(settings);
+        self
+    }
+
+    // How many requests sent to this RPC haven't come back yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    // Count a request as in flight until the returned guard gets dropped.
+    //
+    // Also takes up one of the RPC's `max_concurrent` slots. None if it has none left,
+    // eg. because a concurrent pick took the last one after this RPC got picked.
+    pub fn start_request(&self) -> Option<InFlight> {
+        let permit = self.concurrency.try_acquire().ok()?;
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(InFlight {
+            in_flight: Arc::clone(&self.in_flight),
+            _permit: permit,
+        })
+    }
+
+    // How many times in a row this RPC got picked
+    pub fn consecutive(&self) -> u32 {
+        self.consecutive.load(Ordering::Relaxed)
+    }
+
+    pub fn set_consecutive(&self, consecutive: u32) {
+        self.consecutive.store(consecutive, Ordering::Relaxed);
+    }
+
+    pub fn current_weight(&self) -> f64 {
+        self.current_weight.load()
+    }
+
+    // Add `delta` to the running total for weighted selection and return the new total
+    pub fn add_current_weight(&self, delta: f64) -> f64 {
+        self.current_weight.add(delta)
+    }
+
     // Generic fn to send rpc
     pub async fn send_request(&self, tx: Value) -> Result<String, crate::rpc::types::RpcError> {
-        #[cfg(feature = "debug-verbose")]
-        println!("Sending request: {}", tx.clone());
-
         let response = match self.client.post(&self.url).json(&tx).send().await {
             Ok(response) => response,
             Err(err) => {
@@ -77,14 +265,6 @@ impl Rpc {
             }
         };
 
-        #[cfg(feature = "debug-verbose")]
-        {
-            let a = response.text().await.unwrap();
-            println!("response: {}", a);
-            return Ok(a);
-        }
-
-        #[cfg(not(feature = "debug-verbose"))]
         Ok(response.text().await.unwrap())
     }
 
@@ -103,17 +283,44 @@ impl Rpc {
         Ok(return_number)
     }
 
+    // Request the chain id and return its value
+    pub async fn chain_id(&self) -> Result<u64, crate::rpc::types::RpcError> {
+        let request = json!({
+            "method": "eth_chainId".to_string(),
+            "params": [],
+            "id": 1,
+            "jsonrpc": "2.0".to_string(),
+        });
+
+        let chain_id = self.send_request(request).await?;
+
+        extract_number(&chain_id)
+    }
+
     // Get the latest finalized block
     pub async fn get_finalized_block(&self) -> Result<u64, crate::rpc::types::RpcError> {
+        self.get_tagged_block("finalized").await
+    }
+
+    // Get the latest safe block
+    pub async fn get_safe_block(&self) -> Result<u64, crate::rpc::types::RpcError> {
+        self.get_tagged_block("safe").await
+    }
+
+    // Get the number of the block `tag` currently refers to
+    async fn get_tagged_block(&self, tag: &str) -> Result<u64, crate::rpc::types::RpcError> {
         let request = json!({
             "method": "eth_getBlockByNumber".to_string(),
-            "params": ["finalized", false],
+            "params": [tag, false],
             "id": 1,
             "jsonrpc": "2.0".to_string(),
         });
 
         let number: Value =
-            unsafe { simd_json::serde::from_str(&mut self.send_request(request).await?).unwrap() };
+            match unsafe { simd_json::serde::from_str(&mut self.send_request(request).await?) } {
+                Ok(number) => number,
+                Err(err) => return Err(RpcError::InvalidResponse(err.to_string())),
+            };
         let number = &number["result"]["number"];
 
         let number = match number.as_str() {
@@ -133,18 +340,41 @@ impl Rpc {
         Ok(return_number)
     }
 
-    // Update the latency of the last n calls.
-    // We don't do it within send_request because we might kill it if it times out.
-    pub fn update_latency(&mut self, latest: f64) {
-        // If we have data >= to ma_length, remove the first one in line
-        if self.status.latency_data.len() >= self.status.ma_length as usize {
-            self.status.latency_data.remove(0);
+    // Get the hash and parent hash of block `number`
+    pub async fn get_block_hashes(
+        &self,
+        number: u64,
+    ) -> Result<(String, String), crate::rpc::types::RpcError> {
+        let request = json!({
+            "method": "eth_getBlockByNumber".to_string(),
+            "params": [format!("0x{:x}", number), false],
+            "id": 1,
+            "jsonrpc": "2.0".to_string(),
+        });
+
+        let block: Value =
+            match unsafe { simd_json::serde::from_str(&mut self.send_request(request).await?) } {
+                Ok(block) => block,
+                Err(err) => return Err(RpcError::InvalidResponse(err.to_string())),
+            };
+
+        match (
+            block["result"]["hash"].as_str(),
+            block["result"]["parentHash"].as_str(),
+        ) {
+            (Some(hash), Some(parent_hash)) => Ok((hash.to_string(), parent_hash.to_string())),
+            _ => {
+                Err(RpcError::InvalidResponse(
+                    "error: Invalid response".to_string(),
+                ))
+            }
         }
+    }
 
-        // Update latency
-        self.status.latency_data.push(latest);
-        self.status.latency =
-            self.status.latency_data.iter().sum::<f64>() / self.status.latency_data.len() as f64;
+    // Add the latency of the latest call to the moving average and percentiles.
+    // We don't do it within send_request because we might kill it if it times out.
+    pub fn update_latency(&self, latest: f64) {
+        self.status.latency.add(latest, self.status.latency_alpha);
     }
 }
 
@@ -153,7 +383,10 @@ fn extract_number(rx: &str) -> Result<u64, RpcError> {
     // TODO: maybe this is too slow?
     let mut rx = rx.to_string();
 
-    let json: Value = unsafe { simd_json::serde::from_str(&mut rx).unwrap() };
+    let json: Value = match unsafe { simd_json::serde::from_str(&mut rx) } {
+        Ok(json) => json,
+        Err(err) => return Err(RpcError::InvalidResponse(err.to_string())),
+    };
 
     let number = match json["result"].as_str() {
         Some(number) => number,
@@ -164,7 +397,10 @@ fn extract_number(rx: &str) -> Result<u64, RpcError> {
         }
     };
 
-    let number = hex_to_decimal(number).unwrap();
+    let number = match hex_to_decimal(number) {
+        Ok(number) => number,
+        Err(err) => return Err(RpcError::InvalidResponse(err.to_string())),
+    };
     Ok(number)
 }
 
@@ -178,3 +414,84 @@ pub fn hex_to_decimal(hex_string: &str) -> Result<u64, std::num::ParseIntError>
 
     u64::from_str_radix(hex_string, 16)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_number() {
+        assert_eq!(
+            extract_number(r#"{"jsonrpc":"2.0","id":1,"result":"0x10"}"#).unwrap(),
+            16
+        );
+
+        // Malformed responses should error instead of panicking
+        assert!(extract_number("not json").is_err());
+        assert!(extract_number(r#"{"jsonrpc":"2.0","id":1,"result":"0xzz"}"#).is_err());
+        assert!(extract_number(r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000}}"#).is_err());
+    }
+
+    #[test]
+    fn test_update_latency() {
+        let rpc = Rpc::default().with_latency_alpha(0.5);
+
+        // The first sample is taken as is, after that they get smoothed
+        let smoothed = [100.0, 100.0, 1000.0, 100.0, 100.0]
+            .into_iter()
+            .map(|latency| {
+                rpc.update_latency(latency);
+                rpc.status.latency.average()
+            })
+            .collect::<Vec<f64>>();
+        assert_eq!(smoothed, vec![100.0, 100.0, 550.0, 325.0, 212.5]);
+        assert_eq!(rpc.status.latency.samples(), 5);
+
+        // Same smoothing as a moving average over 3 calls
+        let rpc = Rpc::new(String::new(), 10, 3.0);
+        assert_eq!(rpc.status.latency_alpha, 0.5);
+        assert_eq!(Rpc::default().status.latency_alpha, DEFAULT_LATENCY_ALPHA);
+    }
+
+    #[test]
+    fn test_update_latency_pick() {
+        use crate::balancer::selection::{
+            routing::Requirements,
+            select::{
+                pick,
+                SelectionStrategy,
+            },
+        };
+
+        let mut rpc_list = vec![Rpc::default(), Rpc::default()];
+        for rpc in rpc_list.iter_mut() {
+            rpc.max_consecutive = 100;
+        }
+        for _ in 0..10 {
+            rpc_list[0].update_latency(100.0);
+            rpc_list[1].update_latency(150.0);
+        }
+
+        // One slow call, like a GC pause, doesn't make it lose its spot
+        rpc_list[0].update_latency(300.0);
+        assert_eq!(rpc_list[0].status.latency.average(), 140.0);
+        let index = pick(
+            &rpc_list,
+            SelectionStrategy::LowestLatency,
+            &Requirements::default(),
+            &[],
+        );
+        assert_eq!(index, Some(0));
+
+        // But staying slow does
+        rpc_list[0].update_latency(300.0);
+        assert_eq!(rpc_list[0].status.latency.average(), 172.0);
+        let index = pick(
+            &rpc_list,
+            SelectionStrategy::LowestLatency,
+            &Requirements::default(),
+            &[],
+        );
+        assert_eq!(index, Some(1));
+    }
+}